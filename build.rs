@@ -0,0 +1,13 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()
+                          .expect("vendored protoc is available"));
+        // The generated `connect()` needs the 2021 edition prelude, so
+        // clients are built from a `Channel` instead
+        tonic_build::configure()
+            .build_transport(false)
+            .compile_protos(&["proto/filestore.proto"], &["proto"])
+            .expect("proto/filestore.proto compiles");
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! When, and how often, objects are retrieved (`Config::track_access`), so
+//! that cold data can be found for tiering or eviction, and hot data for
+//! caching.
+//!
+//! Retrievals are gathered in memory and written out in batches, to
+//! `access/` in the storage path, once enough have built up or enough time
+//! has passed since the last batch; `flush()` writes out the rest, and should
+//! be called before the process exits.  Queries from this process see
+//! retrievals not yet written out, but other processes don't.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{self,OpenOptions};
+use std::io::{self,Write};
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,OnceLock};
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
+
+use byteorder::{BigEndian,ByteOrder};
+
+use super::{Error,FileKey,handles,usage};
+use super::usage::Part;
+
+// A batch is written out when it reaches this many keys, or at the first
+// retrieval this long after it was started
+const BATCH_KEYS: usize = 1024;
+const BATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+// Retrievals of one key not yet written out
+#[derive(Clone, Copy)]
+struct Access {
+    last: SystemTime,
+    count: u64,
+}
+
+struct Batch {
+    started: Instant,
+    accesses: HashMap<FileKey, Access>,
+}
+
+type Registry = Mutex<HashMap<PathBuf, Arc<Mutex<Batch>>>>;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// The batch for a store, if retrievals from it have been recorded
+fn pending(storage_path: &Path) -> Option<Arc<Mutex<Batch>>> {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    if registry.is_empty() {
+        return None;
+    }
+    registry.get(&handles::canonical(storage_path)).cloned()
+}
+
+fn batch(storage_path: &Path) -> Arc<Mutex<Batch>> {
+    let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    registry.entry(handles::canonical(storage_path))
+        .or_insert_with(|| Arc::new(Mutex::new(Batch {
+            started: Instant::now(),
+            accesses: HashMap::new(),
+        })))
+        .clone()
+}
+
+fn access_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("access")
+}
+
+fn record_path(storage_path: &Path, key: &FileKey) -> PathBuf {
+    access_dir(storage_path).join(&key[..2]).join(&key[2..])
+}
+
+/// Note a retrieval of `key`, writing out the batch if it is due
+pub(crate) fn record(storage_path: &Path, key: &FileKey) -> Result<(), Error> {
+    let batch = batch(storage_path);
+    let due = {
+        let mut batch = batch.lock().unwrap_or_else(|e| e.into_inner());
+        let access = batch.accesses.entry(key.clone())
+            .or_insert(Access { last: UNIX_EPOCH, count: 0 });
+        access.last = SystemTime::now();
+        access.count += 1;
+        if batch.accesses.len() >= BATCH_KEYS || batch.started.elapsed() >= BATCH_INTERVAL {
+            batch.started = Instant::now();
+            Some(std::mem::take(&mut batch.accesses))
+        } else {
+            None
+        }
+    };
+    match due {
+        Some(due) => write_out(storage_path, due),
+        None => Ok(()),
+    }
+}
+
+/// Write out retrievals gathered in memory
+pub fn flush(storage_path: &Path) -> Result<(), Error>
+{
+    let batch = match pending(storage_path) {
+        Some(batch) => batch,
+        None => return Ok(()),
+    };
+    let due = {
+        let mut batch = batch.lock().unwrap_or_else(|e| e.into_inner());
+        batch.started = Instant::now();
+        std::mem::take(&mut batch.accesses)
+    };
+    write_out(storage_path, due)
+}
+
+// Add retrievals to the records on disk
+fn write_out(storage_path: &Path, due: HashMap<FileKey, Access>) -> Result<(), Error> {
+    for (key, access) in due {
+        let path = record_path(storage_path, &key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| { (e, "Unable to create access directory") } )?;
+        }
+        let count = read_record(&path)?.map(|a| a.count).unwrap_or(0)
+            .saturating_add(access.count);
+        let mut record = [0_u8; 16];
+        BigEndian::write_u64(&mut record[..8], seconds(access.last));
+        BigEndian::write_u64(&mut record[8..], count);
+        let change = usage::change(storage_path, Part::Metadata, &path);
+        OpenOptions::new().create(true).write(true).truncate(true).open(&path)
+            .and_then(|mut f| f.write_all(&record))
+            .map_err(|e| { (e, "Unable to write access record") } )?;
+        change.done();
+    }
+    Ok(())
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// When `key` was last retrieved through a handle with
+/// `Config::track_access` set, or `None` if it never has been (or the
+/// record was lost)
+pub fn last_access(storage_path: &Path, key: &FileKey) -> Result<Option<SystemTime>, Error>
+{
+    if !key.is_well_formed() {
+        return Ok(None);
+    }
+    Ok(current(storage_path, key)?.map(|a| a.last))
+}
+
+/// How many times `key` has been retrieved through handles with
+/// `Config::track_access` set
+pub fn access_count(storage_path: &Path, key: &FileKey) -> Result<u64, Error>
+{
+    if !key.is_well_formed() {
+        return Ok(0);
+    }
+    Ok(current(storage_path, key)?.map(|a| a.count).unwrap_or(0))
+}
+
+/// The `n` most often retrieved objects, with their retrieval counts, most
+/// retrieved first
+pub fn hottest(storage_path: &Path, n: usize) -> Result<Vec<(FileKey, u64)>, Error>
+{
+    let mut counts: HashMap<FileKey, u64> = HashMap::new();
+    let shards = match fs::read_dir(access_dir(storage_path)) {
+        Ok(shards) => shards,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(From::from((e, "Unable to read access directory"))),
+    };
+    for shard in shards {
+        let shard = shard
+            .map_err(|e| { (e, "Unable to read access directory") } )?;
+        let prefix = match shard.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let entries = fs::read_dir(shard.path())
+            .map_err(|e| { (e, "Unable to read access directory") } )?;
+        for entry in entries {
+            let entry = entry
+                .map_err(|e| { (e, "Unable to read access directory") } )?;
+            let key = match entry.file_name().to_str() {
+                Some(name) => FileKey(format!("{}{}", prefix, name)),
+                None => continue,
+            };
+            if !key.is_well_formed() {
+                continue;
+            }
+            if let Some(access) = read_record(&entry.path())? {
+                counts.insert(key, access.count);
+            }
+        }
+    }
+    if let Some(batch) = pending(storage_path) {
+        let batch = batch.lock().unwrap_or_else(|e| e.into_inner());
+        for (key, access) in &batch.accesses {
+            *counts.entry(key.clone()).or_insert(0) += access.count;
+        }
+    }
+    let mut counts: Vec<(FileKey, u64)> = counts.into_iter().collect();
+    counts.sort_by_key(|&(_, count)| Reverse(count));
+    counts.truncate(n);
+    Ok(counts)
+}
+
+// The record for `key`, including retrievals not yet written out
+fn current(storage_path: &Path, key: &FileKey) -> Result<Option<Access>, Error> {
+    let mut access = read_record(&record_path(storage_path, key))?;
+    if let Some(batch) = pending(storage_path) {
+        let batch = batch.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(recent) = batch.accesses.get(key) {
+            let count = access.map(|a| a.count).unwrap_or(0) + recent.count;
+            access = Some(Access { last: recent.last, count });
+        }
+    }
+    Ok(access)
+}
+
+fn read_record(path: &Path) -> Result<Option<Access>, Error> {
+    let record = match fs::read(path) {
+        Ok(record) => record,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(From::from((e, "Unable to read access record"))),
+    };
+    let last = |secs: &[u8]| UNIX_EPOCH + Duration::from_secs(BigEndian::read_u64(secs));
+    match record.len() {
+        // Written before retrievals were counted
+        8 => Ok(Some(Access { last: last(&record), count: 0 })),
+        16 => Ok(Some(Access { last: last(&record[..8]), count: BigEndian::read_u64(&record[8..]) })),
+        // A record cut short by a crash is as good as none
+        _ => Ok(None),
+    }
+}
+
+// Move the record of `old` to `new`, for `rehash_store()`, which calls this
+// once retrievals gathered in memory are written out
+pub(crate) fn rename_key(storage_path: &Path, old: &FileKey, new: &FileKey) -> Result<(), Error> {
+    let from = record_path(storage_path, old);
+    if !from.exists() {
+        return Ok(());
+    }
+    let to = record_path(storage_path, new);
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| { (e, "Unable to create access directory") } )?;
+    }
+    fs::rename(&from, &to)
+        .map_err(|e| { From::from((e, "Unable to move access record")) } )
+}
+
+/// Drop the record of an object that has been removed
+pub(crate) fn forget(storage_path: &Path, key: &FileKey) -> Result<(), Error> {
+    if let Some(batch) = pending(storage_path) {
+        let mut batch = batch.lock().unwrap_or_else(|e| e.into_inner());
+        batch.accesses.remove(key);
+    }
+    let path = record_path(storage_path, key);
+    let change = usage::change(storage_path, Part::Metadata, &path);
+    match fs::remove_file(&path) {
+        Ok(()) => {
+            change.done();
+            Ok(())
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(From::from((e, "Unable to remove access record"))),
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Streaming a set of stored objects out as a single archive (the `tar`
+//! and `zip` features), for "download all" style endpoints.  Objects are
+//! read straight from the store into the archive as it is written, so
+//! nothing is staged in temporary files, and the writer needn't be seekable.
+
+use std::borrow::Borrow;
+use std::fs::File;
+use std::io::{self,Write};
+use std::path::Path;
+#[cfg(feature = "tar")]
+use std::time::UNIX_EPOCH;
+
+use super::{Error,FileKey,storage_file_path};
+
+/// The kind of archive `export_archive()` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// A POSIX (ustar/GNU) tar archive, uncompressed
+    #[cfg(feature = "tar")]
+    Tar,
+    /// A zip archive, with each entry stored rather than compressed
+    #[cfg(feature = "zip")]
+    Zip,
+}
+
+/// Write the objects `entries` names into an archive on `writer`, each under
+/// the name given with its key, returning the writer once the archive is
+/// complete.  Names may contain `/` to place entries in directories, but
+/// must be relative and must not contain `..`.  If a key is not stored the
+/// error's `io` is of kind `NotFound`, and the archive is left incomplete.
+pub fn export_archive<I, K, N, W>(storage_path: &Path, entries: I, writer: W,
+                                  format: ArchiveFormat) -> Result<W, Error>
+    where I: IntoIterator<Item = (K, N)>,
+          K: Borrow<FileKey>,
+          N: AsRef<str>,
+          W: Write
+{
+    let op = operation!("filestore.export_archive", objects);
+    let result = match format {
+        #[cfg(feature = "tar")]
+        ArchiveFormat::Tar => export_tar(storage_path, entries, writer, &op),
+        #[cfg(feature = "zip")]
+        ArchiveFormat::Zip => export_zip(storage_path, entries, writer, &op),
+    };
+    op.finish(result)
+}
+
+fn open_object(storage_path: &Path, key: &FileKey) -> Result<(File, u64), Error> {
+    let file = File::open(storage_file_path(storage_path, key))
+        .map_err(|e| { (e, "Unable to open stored file") } )?;
+    let size = file.metadata()
+        .map_err(|e| { (e, "Unable to stat stored file") } )?
+        .len();
+    Ok((file, size))
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.starts_with('/') || name.split('/').any(|part| part == "..") {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput,
+                                              "not a relative path"),
+                               "Invalid archive entry name")));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "tar")]
+fn export_tar<I, K, N, W>(storage_path: &Path, entries: I, writer: W,
+                          op: &super::trace::Operation) -> Result<W, Error>
+    where I: IntoIterator<Item = (K, N)>,
+          K: Borrow<FileKey>,
+          N: AsRef<str>,
+          W: Write
+{
+    let mut builder = tar::Builder::new(writer);
+    let mut objects: u64 = 0;
+    for (key, name) in entries {
+        let (key, name) = (key.borrow(), name.as_ref());
+        check_name(name)?;
+        let (file, size) = open_object(storage_path, key)?;
+        let mtime = file.metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        builder.append_data(&mut header, name, file)
+            .map_err(|e| { (e, "Unable to write archive entry") } )?;
+        objects += 1;
+    }
+    op.record("objects", objects);
+    builder.into_inner()
+        .map_err(|e| { From::from((e, "Unable to finish archive")) } )
+}
+
+#[cfg(feature = "zip")]
+fn export_zip<I, K, N, W>(storage_path: &Path, entries: I, writer: W,
+                          op: &super::trace::Operation) -> Result<W, Error>
+    where I: IntoIterator<Item = (K, N)>,
+          K: Borrow<FileKey>,
+          N: AsRef<str>,
+          W: Write
+{
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod,ZipWriter};
+
+    let mut archive = ZipWriter::new_stream(writer);
+    let mut objects: u64 = 0;
+    for (key, name) in entries {
+        let (key, name) = (key.borrow(), name.as_ref());
+        check_name(name)?;
+        let (mut file, size) = open_object(storage_path, key)?;
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .unix_permissions(0o644)
+            .large_file(size > u32::MAX as u64);
+        archive.start_file(name, options)
+            .map_err(|e| { (io::Error::from(e), "Unable to write archive entry") } )?;
+        io::copy(&mut file, &mut archive)
+            .map_err(|e| { (e, "Unable to write archive entry") } )?;
+        objects += 1;
+    }
+    op.record("objects", objects);
+    archive.finish()
+        .map(|stream| stream.into_inner())
+        .map_err(|e| { From::from((io::Error::from(e), "Unable to finish archive")) } )
+}
@@ -0,0 +1,165 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! An append-only log of the changes made through handles with
+//! `Config::audit` set: every store, delete and object removed by `gc()`,
+//! with when it happened, the key, and the context the caller gave the
+//! handle (see `FileStore::with_audit_context()`), such as who asked.
+//!
+//! The log is `audit` in the storage path, one line per entry of the time
+//! in seconds and nanoseconds since the Unix epoch, the action, the key and
+//! the context, e.g. `1700000000.000000000 store <key> user=alice`.  Lines
+//! are appended whole, so handles in any number of processes can share it.
+//! Nothing in the crate removes entries.
+
+use std::fs::{self,OpenOptions};
+use std::io::{self,Write};
+use std::path::{Path,PathBuf};
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use super::{Config,Durability,Error,FileKey};
+
+/// A change recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuditAction {
+    /// A reference was taken on the object, storing it if it was new
+    Store,
+    /// A reference on the object was dropped
+    Delete,
+    /// `gc()` removed the object, which had no references left
+    Gc,
+}
+
+impl AuditAction {
+    /// The name the action is logged under
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditAction::Store => "store",
+            AuditAction::Delete => "delete",
+            AuditAction::Gc => "gc",
+        }
+    }
+
+    /// The action logged under `name`
+    pub fn from_name(name: &str) -> Option<AuditAction> {
+        [AuditAction::Store, AuditAction::Delete, AuditAction::Gc].iter().copied()
+            .find(|action| action.name() == name)
+    }
+}
+
+/// One entry of the audit log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// When the change was made
+    pub time: SystemTime,
+    /// What was done
+    pub action: AuditAction,
+    /// The object it was done to
+    pub key: FileKey,
+    /// The context of the handle it was done through, or empty if none
+    pub context: String,
+}
+
+/// Which entries `audit_log()` returns.  Entries must match every field
+/// that is set; the default matches them all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditFilter {
+    /// Only changes to this object
+    pub key: Option<FileKey>,
+    /// Only this kind of change
+    pub action: Option<AuditAction>,
+    /// Only changes made at or after this time
+    pub since: Option<SystemTime>,
+    /// Only changes made before this time
+    pub until: Option<SystemTime>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.key.as_ref().is_none_or(|key| *key == entry.key)
+            && self.action.is_none_or(|action| action == entry.action)
+            && self.since.is_none_or(|since| entry.time >= since)
+            && self.until.is_none_or(|until| entry.time < until)
+    }
+}
+
+fn log_path(storage_path: &Path) -> PathBuf {
+    storage_path.join("audit")
+}
+
+/// The entries of the audit log of the store at `storage_path` that match
+/// `filter`, oldest first
+pub fn audit_log(storage_path: &Path, filter: &AuditFilter) -> Result<Vec<AuditEntry>, Error> {
+    let text = match fs::read_to_string(log_path(storage_path)) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(From::from((e, "Unable to read audit log"))),
+    };
+    let mut entries: Vec<AuditEntry> = Vec::new();
+    for line in text.lines() {
+        match parse(line) {
+            Some(entry) => {
+                if filter.matches(&entry) {
+                    entries.push(entry);
+                }
+            },
+            None => log::warn!("Skipping malformed audit log line {:?}", line),
+        }
+    }
+    Ok(entries)
+}
+
+/// Append an entry for `action` on `key` to the log
+pub(crate) fn record(storage_path: &Path, config: &Config, action: AuditAction, key: &FileKey,
+                     context: &str) -> Result<(), Error>
+{
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let line = format!("{}.{:09} {} {} {}\n", since_epoch.as_secs(), since_epoch.subsec_nanos(),
+                       action.name(), key, escape(context));
+    let mut file = OpenOptions::new().append(true).create(true).open(log_path(storage_path))
+        .map_err(|e| { (e, "Unable to open audit log") } )?;
+    // One write, so that lines from other writers aren't interleaved
+    file.write_all(line.as_bytes())
+        .map_err(|e| { (e, "Unable to write audit log") } )?;
+    if config.durability >= Durability::FlushData {
+        file.sync_data()
+            .map_err(|e| { (e, "Unable to sync audit log") } )?;
+    }
+    Ok(())
+}
+
+fn parse(line: &str) -> Option<AuditEntry> {
+    let mut fields = line.splitn(4, ' ');
+    let (secs, nanos) = fields.next()?.split_once('.')?;
+    let time = UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    let action = AuditAction::from_name(fields.next()?)?;
+    let key = FileKey(fields.next()?.to_owned());
+    if !key.is_well_formed() {
+        return None;
+    }
+    let context = unescape(fields.next().unwrap_or(""));
+    Some(AuditEntry { time, action, key, context })
+}
+
+// Contexts are kept to one line
+fn escape(context: &str) -> String {
+    context.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> String {
+    let mut context = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            context.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => context.push('\n'),
+            Some('r') => context.push('\r'),
+            Some(c) => context.push(c),
+            None => context.push('\\'),
+        }
+    }
+    context
+}
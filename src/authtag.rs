@@ -0,0 +1,235 @@
+// Authenticity tags on stored objects (`Config::authenticate`): an
+// HMAC-SHA256, under a secret the store's users hold, of each object's key
+// and content.  Keys already show whether content was damaged, but anyone
+// who can write to the storage path can add or replace an object under the
+// right key; only holders of the secret can make its tag.
+//
+// Tags are kept under `macs/` in the storage path, one small text file of
+// hex per object laid out as the shards are.  The key is tagged along with
+// the content, so a tag moved onto another object fails to check.
+
+use std::fmt;
+use std::fs::{self,File};
+use std::io::{self,Read,Seek,SeekFrom};
+use std::path::{Path,PathBuf};
+
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac,MacResult};
+use crypto::sha2::Sha256;
+
+use super::{Error,FileKey,replace,spool,usage};
+use super::error::ErrorKind;
+use super::usage::Part;
+
+/// The secret authenticity tags are made with (see `Config::authenticate`).
+/// It should be at least 32 random bytes, kept outside the storage path,
+/// and is left out of `Debug` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthSecret(Vec<u8>);
+
+impl AuthSecret {
+    pub fn new(secret: &[u8]) -> AuthSecret {
+        AuthSecret(secret.to_vec())
+    }
+}
+
+impl fmt::Debug for AuthSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AuthSecret(..)")
+    }
+}
+
+fn macs_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("macs")
+}
+
+fn tag_path(storage_path: &Path, key: &FileKey) -> PathBuf {
+    macs_dir(storage_path).join(&key[..2]).join(&key[2..])
+}
+
+fn start(secret: &AuthSecret, key: &FileKey) -> Hmac<Sha256> {
+    let mut mac = Hmac::new(Sha256::new(), &secret.0);
+    mac.input(key.as_bytes());
+    mac.input(b"\n");
+    mac
+}
+
+fn tag_file(secret: &AuthSecret, key: &FileKey, path: &Path) -> Result<MacResult, Error> {
+    let mut file = File::open(path)
+        .map_err(|e| { (e, "Unable to open stored file") } )?;
+    tag_reader(secret, key, &mut file)
+}
+
+fn tag_reader<R: Read>(secret: &AuthSecret, key: &FileKey, file: &mut R)
+                       -> Result<MacResult, Error>
+{
+    let mut mac = start(secret, key);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let count = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(From::from((e, "Unable to read stored file"))),
+        };
+        mac.input(&buf[..count]);
+    }
+    Ok(mac.result())
+}
+
+/// Whether the object `key` has a tag
+pub(crate) fn is_tagged(storage_path: &Path, key: &FileKey) -> bool {
+    tag_path(storage_path, key).exists()
+}
+
+/// Tag the object `key`, stored at `path`, unless it has a tag already
+pub(crate) fn record(storage_path: &Path, secret: &AuthSecret, key: &FileKey, path: &Path)
+                     -> Result<(), Error>
+{
+    if is_tagged(storage_path, key) {
+        return Ok(());
+    }
+    let tag_path = tag_path(storage_path, key);
+    let hex: String = tag_file(secret, key, path)?.code().iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if let Some(dir) = tag_path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| { (e, "Unable to create authenticity tag directory") } )?;
+    }
+    // Written aside and moved into place, so readers never see part of it
+    let change = usage::change(storage_path, Part::Metadata, &tag_path);
+    let temp_path = spool::temp_path(storage_path);
+    let written = fs::write(&temp_path, hex)
+        .and_then(|()| replace::replace(&temp_path, &tag_path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(From::from((e, "Unable to write authenticity tag")));
+    }
+    change.done();
+    Ok(())
+}
+
+// The tag recorded for `key`, failing with `ErrorKind::Tampered` if there
+// is none, as removing it must not get an object past the check
+fn recorded(storage_path: &Path, key: &FileKey) -> Result<MacResult, Error> {
+    let text = match fs::read_to_string(tag_path(storage_path, key)) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(From::from((ErrorKind::Tampered,
+                                   &*format!("{} has no authenticity tag", key))));
+        },
+        Err(e) => return Err(From::from((e, "Unable to read authenticity tag"))),
+    };
+    let text = text.trim_end();
+    let code: Option<Vec<u8>> = match text.len() {
+        64 => (0..64).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect(),
+        _ => None,
+    };
+    match code {
+        Some(code) => Ok(MacResult::new_from_owned(code)),
+        None => Err(From::from((ErrorKind::Tampered,
+                                &*format!("Authenticity tag of {} is damaged", key)))),
+    }
+}
+
+fn tampered(key: &FileKey) -> Error {
+    From::from((ErrorKind::Tampered, &*format!("{} failed its authenticity check", key)))
+}
+
+/// Check the stored object `key` against its tag, failing with
+/// `ErrorKind::Tampered` if they don't match.  An object that isn't stored
+/// fails, with an `io` of kind `NotFound`.
+pub(crate) fn check(storage_path: &Path, secret: &AuthSecret, key: &FileKey)
+                    -> Result<(), Error>
+{
+    let mut file = File::open(super::storage_file_path(storage_path, key))
+        .map_err(|e| { (e, "Unable to open stored file") } )?;
+    check_file(storage_path, secret, key, &mut file)
+}
+
+/// As `check()`, for the object open as `file`, which is left at its
+/// start.  Readers check the handle they go on to read, so that the object
+/// can't be swapped for another between the check and the read.
+pub(crate) fn check_file(storage_path: &Path, secret: &AuthSecret, key: &FileKey,
+                         file: &mut File) -> Result<(), Error>
+{
+    let expected = recorded(storage_path, key)?;
+    let tag = tag_reader(secret, key, file)?;
+    file.seek(SeekFrom::Start(0))
+        .map_err(|e| { (e, "Unable to rewind stored file") } )?;
+    // Compared in constant time
+    if tag != expected {
+        return Err(tampered(key));
+    }
+    Ok(())
+}
+
+/// As `check()`, for content already read into memory
+pub(crate) fn check_data(storage_path: &Path, secret: &AuthSecret, key: &FileKey, data: &[u8])
+                         -> Result<(), Error>
+{
+    let expected = recorded(storage_path, key)?;
+    let mut mac = start(secret, key);
+    mac.input(data);
+    if mac.result() != expected {
+        return Err(tampered(key));
+    }
+    Ok(())
+}
+
+/// Move the tag of `old` to `new`, for `rehash_store()`.  A tag covers the
+/// key, so it is made again for the new key, after checking the old one.
+pub(crate) fn rename_key(storage_path: &Path, secret: Option<&AuthSecret>, old: &FileKey,
+                         new: &FileKey) -> Result<(), Error>
+{
+    if !is_tagged(storage_path, old) {
+        return Ok(());
+    }
+    match secret {
+        Some(secret) => {
+            check(storage_path, secret, old)?;
+            record(storage_path, secret, new, &super::storage_file_path(storage_path, old))?;
+        },
+        None => log::warn!("Dropping authenticity tag of {}, which needs the secret to move", old),
+    }
+    forget(storage_path, old)
+}
+
+/// Drop the tag of an object that has been removed
+pub(crate) fn forget(storage_path: &Path, key: &FileKey) -> Result<(), Error> {
+    let path = tag_path(storage_path, key);
+    let change = usage::change(storage_path, Part::Metadata, &path);
+    match fs::remove_file(&path) {
+        Ok(()) => {
+            change.done();
+            Ok(())
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(From::from((e, "Unable to remove authenticity tag"))),
+    }
+}
+
+/// Tag every object in the store at `storage_path` that has no tag yet, as
+/// when turning on `Config::authenticate` for a store already in use, and
+/// return their keys.  Objects whose content no longer matches their key
+/// are left untagged, and so fail the check.  An object put in place by
+/// someone else's write to the storage path, under the right key, would be
+/// tagged like any other, so run this only on a store known to be
+/// untouched.
+pub fn add_auth_tags(storage_path: &Path, secret: &AuthSecret) -> Result<Vec<FileKey>, Error> {
+    let mut tagged: Vec<FileKey> = Vec::new();
+    for key in super::keys(storage_path)? {
+        let key = key?;
+        if is_tagged(storage_path, &key) {
+            continue;
+        }
+        if !super::verify_key(storage_path, &key)? {
+            log::warn!("Not tagging {}, whose content doesn't match its key", key);
+            continue;
+        }
+        record(storage_path, secret, &key, &super::storage_file_path(storage_path, &key))?;
+        tagged.push(key);
+    }
+    Ok(tagged)
+}
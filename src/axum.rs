@@ -0,0 +1,186 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Helpers for storing uploads received by an axum application (requires
+//! the `axum` feature).
+//!
+//! The `StoredUpload` extractor streams every file field of a
+//! `multipart/form-data` request straight into the store as it arrives, and
+//! hands the route handler the resulting keys:
+//!
+//! ```ignore
+//! async fn upload(upload: StoredUpload) -> String {
+//!     upload.files.iter().map(|f| f.key.to_string()).collect::<Vec<_>>().join("\n")
+//! }
+//!
+//! let app = Router::new()
+//!     .route("/upload", post(upload))
+//!     .layer(DefaultBodyLimit::disable())
+//!     .with_state(FileStore::new(storage_path));
+//! ```
+//!
+//! The application state must provide a `FileStore` through `FromRef`.
+//! axum limits request bodies to 2MB by default, so uploads usually want a
+//! larger (or disabled) `DefaultBodyLimit`.
+
+use std::io;
+
+use axum::async_trait;
+use axum::extract::{FromRef,FromRequest,Request};
+use axum::extract::multipart::{Field,Multipart,MultipartError,MultipartRejection};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse,Response};
+
+use super::{Error,FileKey,FileStore,Store};
+use super::bridge;
+use super::error::ErrorKind;
+
+/// A file field of a multipart upload, after storage
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    /// The form field name
+    pub field: Option<String>,
+    /// The filename supplied by the client, if any
+    pub file_name: Option<String>,
+    /// The content type supplied by the client, if any
+    pub content_type: Option<String>,
+    /// Key of the stored content
+    pub key: FileKey,
+}
+
+/// Extractor that stores every file field of a multipart body.  Fields
+/// without a filename (plain form values) are collected as text in `fields`.
+///
+/// If any part of the upload fails, references already taken on its files
+/// are released before the request is rejected.
+#[derive(Debug, Clone, Default)]
+pub struct StoredUpload {
+    pub files: Vec<StoredFile>,
+    pub fields: Vec<(String, String)>,
+}
+
+/// Why a `StoredUpload` could not be extracted
+#[derive(Debug)]
+pub enum UploadRejection {
+    /// The request was not a valid multipart request
+    Multipart(MultipartRejection),
+    /// The multipart body was malformed or interrupted
+    Body(MultipartError),
+    /// The store failed
+    Store(Error),
+}
+
+impl IntoResponse for UploadRejection {
+    fn into_response(self) -> Response {
+        match self {
+            UploadRejection::Multipart(r) => r.into_response(),
+            UploadRejection::Body(e) => e.into_response(),
+            UploadRejection::Store(ref e) if matches!(e.kind, ErrorKind::TooLarge { .. }) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "Upload is too large").into_response()
+            },
+            UploadRejection::Store(e) => {
+                log::log!(e.log_level(), "filestore axum: {:?}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Unable to store upload").into_response()
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequest<S> for StoredUpload
+    where S: Send + Sync,
+          FileStore: FromRef<S>
+{
+    type Rejection = UploadRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<StoredUpload, UploadRejection> {
+        let store = FileStore::from_ref(state);
+        let mut multipart = Multipart::from_request(req, state).await
+            .map_err(UploadRejection::Multipart)?;
+        let mut upload = StoredUpload::default();
+        match read_fields(&store, &mut multipart, &mut upload).await {
+            Ok(()) => Ok(upload),
+            Err(e) => {
+                release(&store, upload.files.into_iter().map(|f| f.key).collect()).await;
+                Err(e)
+            },
+        }
+    }
+}
+
+async fn read_fields(store: &FileStore, multipart: &mut Multipart, upload: &mut StoredUpload)
+                     -> Result<(), UploadRejection>
+{
+    while let Some(field) = multipart.next_field().await.map_err(UploadRejection::Body)? {
+        let name = field.name().map(|n| n.to_owned());
+        if field.file_name().is_none() {
+            let text = field.text().await.map_err(UploadRejection::Body)?;
+            upload.fields.push((name.unwrap_or_default(), text));
+            continue;
+        }
+        let file_name = field.file_name().map(|n| n.to_owned());
+        let content_type = field.content_type().map(|t| t.to_owned());
+        let key = store_field(store, field).await?;
+        upload.files.push(StoredFile { field: name, file_name, content_type, key });
+    }
+    Ok(())
+}
+
+// Drop the references taken by a failed upload
+async fn release(store: &FileStore, keys: Vec<FileKey>) {
+    let store = store.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        for key in keys {
+            if let Err(e) = store.delete(&key) {
+                log::warn!("filestore axum: unable to release {}: {:?}", key, e);
+            }
+        }
+    }).await;
+    if let Err(e) = result {
+        log::warn!("filestore axum: unable to release failed upload: {}", e);
+    }
+}
+
+/// Stream a single multipart field into the store, for applications that
+/// walk the `Multipart` themselves
+pub async fn store_field(store: &FileStore, mut field: Field<'_>)
+                         -> Result<FileKey, UploadRejection>
+{
+    let (tx, mut reader) = bridge::channel();
+    let blocking_store = store.clone();
+    let task = tokio::task::spawn_blocking(move || blocking_store.store_reader(&mut reader));
+
+    let mut body_error: Option<MultipartError> = None;
+    loop {
+        match field.chunk().await {
+            Ok(Some(bytes)) => {
+                // A send error means the store already failed, reported below
+                if tx.send(Ok(bytes.to_vec())).await.is_err() {
+                    break;
+                }
+            },
+            Ok(None) => break,
+            Err(e) => {
+                let _ = tx.send(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof, "upload interrupted"))).await;
+                body_error = Some(e);
+                break;
+            },
+        }
+    }
+    drop(tx);
+
+    let result = task.await
+        .map_err(|e| UploadRejection::Store(From::from(
+            (io::Error::other(e), "Upload storage task failed"))))?;
+    match (result, body_error) {
+        (Ok(key), None) => Ok(key),
+        (Ok(key), Some(e)) => {
+            // The body failed after the last chunk was taken; don't keep it
+            release(store, vec![key]).await;
+            Err(UploadRejection::Body(e))
+        },
+        (Err(_), Some(e)) => Err(UploadRejection::Body(e)),
+        (Err(e), None) => Err(UploadRejection::Store(e)),
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A store in an Azure Blob Storage container (requires the `azure`
+//! feature).
+//!
+//! `AzureStore` implements `Store` over the Blob service REST API, keeping
+//! each object as a block blob named by its key, with its reference count in
+//! the blob's metadata (`x-ms-meta-refcount`).  Reference counts are updated
+//! with ETag preconditions, so any number of hosts can share a container.
+//! Requests are authorized with a shared access signature (SAS) granting
+//! read, write, delete and list on the container.
+
+use std::io::{self,Read};
+
+use super::{Error,FileKey};
+use super::cloud::{self,BlobInfo,Blobs,Content};
+use super::store::{ObjectStat,Store};
+
+const VERSION: &str = "2021-08-06";
+
+// Larger blobs are uploaded as a list of blocks of this size, as a single
+// upload is limited (and can't be resumed)
+const BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A store in a Blob Storage container
+#[derive(Clone)]
+pub struct AzureStore {
+    agent: ureq::Agent,
+    endpoint: String,
+    container: String,
+    sas: String,
+    prefix: String,
+}
+
+impl AzureStore {
+    /// A store in `container` of the storage account `account`, authorized
+    /// by the SAS token `sas` (the query string, with or without its `?`)
+    pub fn new(account: &str, container: &str, sas: &str) -> AzureStore {
+        AzureStore {
+            agent: ureq::Agent::new(),
+            endpoint: format!("https://{}.blob.core.windows.net", account),
+            container: container.to_owned(),
+            sas: sas.trim_start_matches('?').to_owned(),
+            prefix: String::new(),
+        }
+    }
+
+    /// Keep objects under `prefix` (such as `filestore/`), so that the
+    /// container can be shared with other data
+    pub fn with_prefix(mut self, prefix: &str) -> AzureStore {
+        self.prefix = prefix.to_owned();
+        self
+    }
+
+    /// Send requests to `endpoint` (including the account, as in
+    /// `http://127.0.0.1:10000/devstoreaccount1` for Azurite) rather than
+    /// the account's public endpoint
+    pub fn with_endpoint(mut self, endpoint: &str) -> AzureStore {
+        self.endpoint = endpoint.trim_end_matches('/').to_owned();
+        self
+    }
+
+    // A URL with the given query parameters and the SAS
+    fn url(&self, name: Option<&str>, query: &str) -> String {
+        let mut url = format!("{}/{}", self.endpoint, cloud::encode(&self.container, false));
+        if let Some(name) = name {
+            url.push('/');
+            url.push_str(&cloud::encode(name, true));
+        }
+        url.push('?');
+        if !query.is_empty() {
+            url.push_str(query);
+            url.push('&');
+        }
+        url.push_str(&self.sas);
+        url
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        self.agent.request(method, url).set("x-ms-version", VERSION)
+    }
+
+    fn name(&self, key: &FileKey) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    // Upload in blocks, then commit them all at once as the new blob
+    fn create_in_blocks(&self, name: &str, content: &mut dyn Content, size: u64)
+                        -> Result<bool, Error>
+    {
+        let mut ids: Vec<String> = Vec::new();
+        let mut offset: u64 = 0;
+        while offset < size {
+            let length = BLOCK_SIZE.min(size - offset);
+            // Block ids must be base64 and all the same length, which
+            // fixed-width decimal digits are
+            let id = format!("{:016}", ids.len());
+            let url = self.url(Some(name), &format!("comp=block&blockid={}", id));
+            let result = self.request("PUT", &url)
+                .set("Content-Length", &length.to_string())
+                .send((&mut *content).take(length));
+            cloud::check(result, "Unable to upload block")?;
+            ids.push(id);
+            offset += length;
+        }
+        let mut list = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>");
+        for id in &ids {
+            list.push_str(&format!("<Latest>{}</Latest>", id));
+        }
+        list.push_str("</BlockList>");
+        let result = self.request("PUT", &self.url(Some(name), "comp=blocklist"))
+            .set("If-None-Match", "*")
+            .set("x-ms-meta-refcount", "1")
+            .send_string(&list);
+        Ok(cloud::reply(result, &[409, 412], "Unable to commit blocks")?.is_some())
+    }
+}
+
+// The text of the first `<tag>` element from `start` in `xml`, and where it
+// ends
+fn element<'a>(xml: &'a str, tag: &str, start: usize) -> Option<(&'a str, usize)> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let begin = xml[start..].find(&open)? + start + open.len();
+    let end = xml[begin..].find(&close)? + begin;
+    Some((&xml[begin..end], end + close.len()))
+}
+
+impl Blobs for AzureStore {
+    fn head(&self, name: &str) -> Result<Option<BlobInfo>, Error> {
+        let result = self.request("HEAD", &self.url(Some(name), "")).call();
+        let response = match cloud::reply(result, &[404], "Unable to read blob properties")? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        let size = response.header("Content-Length").and_then(|s| s.parse::<u64>().ok());
+        let version = response.header("ETag").map(|s| s.to_owned());
+        let (size, version) = match (size, version) {
+            (Some(size), Some(version)) => (size, version),
+            _ => return Err(From::from((io::Error::from(io::ErrorKind::InvalidData),
+                                        "Blob properties are incomplete"))),
+        };
+        // Blobs written by anything else hold no references
+        let refcount = response.header("x-ms-meta-refcount")
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(0);
+        Ok(Some(BlobInfo { size, refcount, version }))
+    }
+
+    fn create(&self, name: &str, content: &mut dyn Content, size: u64) -> Result<bool, Error> {
+        if size > BLOCK_SIZE {
+            return self.create_in_blocks(name, content, size);
+        }
+        let result = self.request("PUT", &self.url(Some(name), ""))
+            .set("x-ms-blob-type", "BlockBlob")
+            .set("If-None-Match", "*")
+            .set("x-ms-meta-refcount", "1")
+            .set("Content-Length", &size.to_string())
+            .send(content.take(size));
+        Ok(cloud::reply(result, &[409, 412], "Unable to upload blob")?.is_some())
+    }
+
+    fn set_refcount(&self, name: &str, refcount: u64, version: &str) -> Result<bool, Error> {
+        let result = self.request("PUT", &self.url(Some(name), "comp=metadata"))
+            .set("If-Match", version)
+            .set("x-ms-meta-refcount", &refcount.to_string())
+            .call();
+        Ok(cloud::reply(result, &[404, 412], "Unable to update refcount")?.is_some())
+    }
+
+    fn remove(&self, name: &str, version: &str) -> Result<bool, Error> {
+        let result = self.request("DELETE", &self.url(Some(name), ""))
+            .set("If-Match", version)
+            .call();
+        Ok(cloud::reply(result, &[404, 412], "Unable to delete blob")?.is_some())
+    }
+
+    fn open(&self, name: &str) -> Result<Option<Box<dyn Read + Send>>, Error> {
+        let result = self.request("GET", &self.url(Some(name), "")).call();
+        Ok(cloud::reply(result, &[404], "Unable to read blob")?
+            .map(|response| Box::new(response.into_reader()) as Box<dyn Read + Send>))
+    }
+
+    fn list(&self, prefix: &str, token: Option<&str>)
+            -> Result<(Vec<String>, Option<String>), Error>
+    {
+        let mut query = format!("restype=container&comp=list&prefix={}", cloud::encode(prefix, false));
+        if let Some(token) = token {
+            query.push_str(&format!("&marker={}", cloud::encode(token, false)));
+        }
+        let result = self.request("GET", &self.url(None, &query)).call();
+        let xml = cloud::check(result, "Unable to list blobs")?
+            .into_string()
+            .map_err(|e| { (e, "Unable to read blob listing") } )?;
+        // Names are keys after the prefix, which need no unescaping, and
+        // anything else is skipped by the caller
+        let mut names: Vec<String> = Vec::new();
+        let mut at = 0;
+        while let Some((name, end)) = element(&xml, "Name", at) {
+            names.push(name.to_owned());
+            at = end;
+        }
+        let next = element(&xml, "NextMarker", 0)
+            .map(|(marker, _)| marker.to_owned())
+            .filter(|marker| !marker.is_empty());
+        Ok((names, next))
+    }
+}
+
+impl Store for AzureStore {
+    fn store_data(&self, input: &[u8]) -> Result<FileKey, Error> {
+        cloud::store_data(self, &self.prefix, input)
+    }
+
+    fn store_reader(&self, input: &mut dyn Read) -> Result<FileKey, Error> {
+        cloud::store_reader(self, &self.prefix, input)
+    }
+
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>
+    {
+        self.open(&self.name(key))
+    }
+
+    fn delete(&self, key: &FileKey) -> Result<(), Error> {
+        cloud::release(self, &self.name(key))
+    }
+
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error> {
+        cloud::stat(self, &self.name(key))
+    }
+
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error> {
+        Ok(Box::new(cloud::Keys::new(self, &self.prefix)))
+    }
+}
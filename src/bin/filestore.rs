@@ -0,0 +1,637 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Command line access to a filestore directory, for operators who need to
+//! inspect or repair a store without writing Rust.
+
+use std::env;
+use std::fs::File;
+use std::io::{self,Write};
+use std::path::Path;
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use filestore::{DeleteOutcome,FileKey,FileStore,HashAlgorithm,KeyEncoding};
+use filestore::audit::AuditFilter;
+use filestore::scrub::Scrubber;
+use filestore::error::{Error,ErrorKind};
+
+const USAGE: &str = "\
+Usage: filestore <store-dir> <command> [args...]
+
+Commands:
+    init                   Create a store in <store-dir>
+    store <file>...        Store files, printing a key for each
+    get <key> [<out>]      Write stored content to <out> (or stdout)
+    delete <key>...        Drop one reference to each key, exiting non-zero
+                           if any had none
+    ls                     List keys with their refcount and size, from
+                           the index if there is one
+    stats                  Summarise objects, bytes and references
+    du [<n>]               Show space used per shard, by refcounts and by
+                           metadata, and the <n> (default 10) largest
+                           objects
+    dedup                  Show how many objects have each number of
+                           references, and the bytes saved by sharing
+    diff <other-dir>       List keys held by only one of the stores, and
+                           keys whose refcounts differ, exiting non-zero
+                           if there are any
+    verify                 Re-hash all objects, listing corrupt keys and
+                           moving them to quarantine
+    bloom [<n>]            Build a Bloom filter of the keys, sized for <n>
+                           objects (default: those stored now)
+    index                  Build an index of the keys for `ls` to read
+                           (needs the `redb` feature)
+    scrub [<n>]            Re-hash the next <n> (default 100) objects,
+                           carrying on from the last scrub, and quarantine
+                           corrupt ones
+    pin <key>...           Keep objects in the store, even with no
+                           references, until unpinned
+    unpin <key>...         Remove pins, deleting objects left unreferenced
+    pinned                 List the pinned keys
+    owners <key>           List the owners of an object's references, and
+                           how many references have none
+    quarantined            List the keys in quarantine
+    restore <key>          Put a quarantined object back (full key only)
+    repair <other-dir>     Fetch quarantined and missing objects from the
+                           store in <other-dir>, exiting non-zero if any
+                           remain broken
+    clone <dest-dir>       Create a copy of the store in <dest-dir>,
+                           sharing content through links
+    snapshot <name>        Take a snapshot of the keys and refcounts
+    snapshots              List snapshots
+    gc                     Remove unreferenced objects and stray refcounts
+    recover                Undo interrupted changes and remove temporary
+                           files left by crashed writers
+    health                 Check that the store is usable, exiting non-zero
+                           if not
+    rehash <algorithm>     Move every object to its key under <algorithm>
+                           (sha224, sha256 or sha512-tree), printing old
+                           and new keys
+    audit [<key>]          Show the audit log, or its entries for <key>
+    encoding [<encoding>]  Show, or set, the encoding keys are given out
+                           and taken in (hex, base32 or base64url)
+    prefix [on|off]        Show, or set, whether keys are given out behind
+                           their algorithm's name (as sha224-<digest>)
+
+Keys may be abbreviated to a unique prefix of at least four hex digits
+in stores giving keys out in plain hex.
+";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 || args[0] == "-h" || args[0] == "--help" {
+        eprint!("{}", USAGE);
+        process::exit(2);
+    }
+    let storage_path = Path::new(&args[0]);
+    let command = &args[1][..];
+    let rest = &args[2..];
+
+    let result = match command {
+        "init" => init(storage_path),
+        "store" => store(storage_path, rest),
+        "get" => get(storage_path, rest),
+        "delete" => delete(storage_path, rest),
+        "ls" => ls(storage_path),
+        "stats" => stats(storage_path),
+        "du" => du(storage_path, rest),
+        "dedup" => dedup(storage_path),
+        "diff" => diff(storage_path, rest),
+        "verify" => verify(storage_path),
+        "bloom" => bloom(storage_path, rest),
+        "index" => index(storage_path),
+        "scrub" => scrub(storage_path, rest),
+        "pin" => pin(storage_path, rest),
+        "unpin" => unpin(storage_path, rest),
+        "pinned" => pinned(storage_path),
+        "owners" => owners(storage_path, rest),
+        "quarantined" => quarantined(storage_path),
+        "restore" => restore(storage_path, rest),
+        "repair" => repair(storage_path, rest),
+        "clone" => clone(storage_path, rest),
+        "snapshot" => snapshot(storage_path, rest),
+        "snapshots" => snapshots(storage_path),
+        "gc" => gc(storage_path),
+        "recover" => recover(storage_path),
+        "health" => health(storage_path),
+        "rehash" => rehash(storage_path, rest),
+        "audit" => audit(storage_path, rest),
+        "encoding" => encoding(storage_path, rest),
+        "prefix" => prefix(storage_path, rest),
+        _ => {
+            eprint!("Unknown command '{}'\n\n{}", command, USAGE);
+            process::exit(2);
+        }
+    };
+
+    match result {
+        Ok(true) => {},
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("filestore: {:?}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Each command returns Ok(false) for a completed run with a bad outcome
+// (e.g. corruption found), which maps to a non-zero exit status.
+type CmdResult = Result<bool, Error>;
+
+// A key given in full as the store takes keys in, or abbreviated in hex,
+// or None if no stored key matches
+fn parse_key(storage_path: &Path, arg: &str) -> Result<Option<FileKey>, Error> {
+    if let Some(key) = filestore::parse_key(storage_path, arg)? {
+        return Ok(Some(key));
+    }
+    if filestore::key_encoding(storage_path)? != KeyEncoding::Hex
+        || filestore::key_prefixed(storage_path)?
+    {
+        return Ok(None);
+    }
+    match filestore::resolve_prefix(storage_path, arg) {
+        Ok(key) => Ok(key),
+        Err(Error { kind: ErrorKind::AmbiguousPrefix { matches }, .. }) => {
+            eprintln!("filestore: '{}' could be any of:", arg);
+            for key in matches {
+                eprintln!("    {}", key);
+            }
+            process::exit(2);
+        },
+        Err(Error { kind: ErrorKind::Io, ref io, .. }) if io.kind() == io::ErrorKind::InvalidInput => {
+            eprintln!("filestore: '{}' is not a valid key", arg);
+            process::exit(2);
+        },
+        Err(e) => Err(e),
+    }
+}
+
+fn expect_args(rest: &[String], min: usize, max: usize) {
+    if rest.len() < min || rest.len() > max {
+        eprint!("{}", USAGE);
+        process::exit(2);
+    }
+}
+
+fn init(storage_path: &Path) -> CmdResult {
+    FileStore::init(storage_path, filestore::Config::default())?;
+    Ok(true)
+}
+
+fn store(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, usize::MAX);
+    for file in rest {
+        let key = filestore::store_file(storage_path, Path::new(file))?;
+        println!("{}  {}", filestore::encode_key(storage_path, &key)?, file);
+    }
+    Ok(true)
+}
+
+fn get(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, 2);
+    let path = match parse_key(storage_path, &rest[0])? {
+        Some(key) => filestore::retrieve_file(storage_path, &key),
+        None => None,
+    };
+    let path = match path {
+        Some(p) => p,
+        None => {
+            eprintln!("filestore: {} not found", rest[0]);
+            return Ok(false);
+        }
+    };
+    let mut input = File::open(&path)
+        .map_err(|e| { (e, "Unable to open stored file") } )?;
+    if rest.len() == 2 {
+        let mut output = File::create(&rest[1])
+            .map_err(|e| { (e, "Unable to create output file") } )?;
+        io::copy(&mut input, &mut output)?;
+    } else {
+        let stdout = io::stdout();
+        let mut output = stdout.lock();
+        io::copy(&mut input, &mut output)?;
+        output.flush()?;
+    }
+    Ok(true)
+}
+
+fn delete(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, usize::MAX);
+    let mut all = true;
+    for arg in rest {
+        let outcome = match parse_key(storage_path, arg)? {
+            Some(key) => filestore::delete_checked(storage_path, &key)?,
+            None => DeleteOutcome::NotFound,
+        };
+        if outcome == DeleteOutcome::NotFound {
+            eprintln!("filestore: {} not found", arg);
+            all = false;
+        }
+    }
+    Ok(all)
+}
+
+fn ls(storage_path: &Path) -> CmdResult {
+    #[cfg(feature = "redb")]
+    if filestore::has_index(storage_path) {
+        for entry in filestore::indexed_objects(storage_path)? {
+            let entry = entry?;
+            println!("{}  {:>6}  {:>12}", entry.key, entry.refcount, entry.size);
+        }
+        return Ok(true);
+    }
+    for key in filestore::keys(storage_path)? {
+        let key = key?;
+        let stat = match filestore::stat(storage_path, &key) {
+            Ok(stat) => stat,
+            // Deleted while listing
+            Err(ref e) if e.io.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        println!("{}  {:>6}  {:>12}", key, stat.refcount, stat.size);
+    }
+    Ok(true)
+}
+
+fn stats(storage_path: &Path) -> CmdResult {
+    let stats = filestore::stats(storage_path)?;
+    println!("objects:    {}", stats.objects);
+    println!("bytes:      {}", stats.bytes);
+    println!("references: {}", stats.references);
+    Ok(true)
+}
+
+fn du(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 0, 1);
+    let largest = match rest.first() {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => {
+                eprint!("{}", USAGE);
+                process::exit(2);
+            }
+        },
+        None => 10,
+    };
+    let report = filestore::space_report(storage_path, largest)?;
+    for (shard, usage) in &report.shards {
+        println!("{}  {:>8} objects  {:>14} bytes  {:>14} on disk",
+                 shard, usage.objects, usage.bytes, usage.disk_bytes);
+    }
+    println!("total  {} objects  {} bytes  {} on disk",
+             report.total.objects, report.total.bytes, report.total.disk_bytes);
+    let usage = filestore::usage(storage_path)?;
+    println!("refcounts  {} on disk", usage.refcount_bytes);
+    println!("metadata  {} on disk", usage.metadata_bytes);
+    for (key, bytes) in &report.largest {
+        println!("{}  {:>12}", key, bytes);
+    }
+    Ok(true)
+}
+
+fn dedup(storage_path: &Path) -> CmdResult {
+    let report = filestore::dedup_report(storage_path)?;
+    let between = |low: u64, high: u64| {
+        report.objects_with_at_least(low) - report.objects_with_at_least(high)
+    };
+    println!("unreferenced:       {}", between(0, 1));
+    println!("1 reference:        {}", between(1, 2));
+    println!("2 references:       {}", between(2, 3));
+    println!("3-9 references:     {}", between(3, 10));
+    println!("10+ references:     {}", report.objects_with_at_least(10));
+    println!("logical bytes:      {}", report.logical_bytes);
+    println!("physical bytes:     {}", report.physical_bytes);
+    println!("dedup ratio:        {:.2}", report.ratio());
+    Ok(true)
+}
+
+fn diff(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, 1);
+    let a = FileStore::new(storage_path);
+    let b = FileStore::new(Path::new(&rest[0]));
+    let diff = filestore::diff(&a, &b)?;
+    for key in &diff.only_in_a {
+        println!("{}  only here", key);
+    }
+    for key in &diff.only_in_b {
+        println!("{}  only in {}", key, rest[0]);
+    }
+    for (key, here, there) in &diff.refcounts_differ {
+        println!("{}  refcount {} here, {} there", key, here, there);
+    }
+    Ok(diff.is_empty())
+}
+
+fn verify(storage_path: &Path) -> CmdResult {
+    let corrupt = filestore::verify(storage_path)?;
+    for key in &corrupt {
+        println!("{}  CORRUPT", key);
+    }
+    Ok(corrupt.is_empty())
+}
+
+fn bloom(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 0, 1);
+    let expected = match rest.first() {
+        Some(arg) => match arg.parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => {
+                eprint!("{}", USAGE);
+                process::exit(2);
+            }
+        },
+        None => 0,
+    };
+    filestore::build_bloom_filter(storage_path, expected)?;
+    Ok(true)
+}
+
+#[cfg(feature = "redb")]
+fn index(storage_path: &Path) -> CmdResult {
+    let objects = filestore::build_index(storage_path)?;
+    println!("indexed {} objects", objects);
+    Ok(true)
+}
+
+#[cfg(not(feature = "redb"))]
+fn index(_storage_path: &Path) -> CmdResult {
+    eprintln!("This build lacks the redb feature an index needs");
+    Ok(false)
+}
+
+fn scrub(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 0, 1);
+    let objects = match rest.first() {
+        Some(arg) => match arg.parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => {
+                eprint!("{}", USAGE);
+                process::exit(2);
+            }
+        },
+        None => 100,
+    };
+    let report = Scrubber::new(storage_path).with_objects_per_tick(objects).tick()?;
+    for key in &report.corrupt {
+        println!("{}  CORRUPT", key);
+    }
+    for (key, regions) in &report.corrupt_regions {
+        for region in regions {
+            println!("{}  bad bytes {}..{}", key, region.start, region.end);
+        }
+    }
+    println!("{} objects, {} bytes checked", report.objects, report.bytes);
+    if report.pass_complete {
+        println!("pass complete");
+    }
+    Ok(report.corrupt.is_empty())
+}
+
+fn pin(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, usize::MAX);
+    let mut all = true;
+    for arg in rest {
+        let pinned = match parse_key(storage_path, arg)? {
+            Some(key) => filestore::pin(storage_path, &key)?,
+            None => false,
+        };
+        if !pinned {
+            eprintln!("filestore: {} not found", arg);
+            all = false;
+        }
+    }
+    Ok(all)
+}
+
+fn unpin(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, usize::MAX);
+    let mut all = true;
+    for arg in rest {
+        let unpinned = match parse_key(storage_path, arg)? {
+            Some(key) => filestore::unpin(storage_path, &key)?,
+            None => false,
+        };
+        if !unpinned {
+            eprintln!("filestore: {} is not pinned", arg);
+            all = false;
+        }
+    }
+    Ok(all)
+}
+
+fn pinned(storage_path: &Path) -> CmdResult {
+    for key in filestore::list_pinned(storage_path)? {
+        println!("{}", filestore::encode_key(storage_path, &key)?);
+    }
+    Ok(true)
+}
+
+fn owners(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, 1);
+    let key = match parse_key(storage_path, &rest[0])? {
+        Some(key) => key,
+        None => {
+            eprintln!("filestore: {} not found", rest[0]);
+            return Ok(false);
+        },
+    };
+    let refcount = match filestore::stat(storage_path, &key) {
+        Ok(stat) => stat.refcount,
+        Err(ref e) if e.io.kind() == io::ErrorKind::NotFound => {
+            eprintln!("filestore: {} not found", rest[0]);
+            return Ok(false);
+        },
+        Err(e) => return Err(e),
+    };
+    let owners = filestore::list_owners(storage_path, &key)?;
+    for owner in &owners {
+        println!("{}", owner);
+    }
+    println!("unowned  {}", refcount.saturating_sub(owners.len() as u64));
+    Ok(true)
+}
+
+fn quarantined(storage_path: &Path) -> CmdResult {
+    for key in filestore::list_quarantined(storage_path)? {
+        println!("{}", key);
+    }
+    Ok(true)
+}
+
+fn restore(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, 1);
+    let key = filestore::parse_key(storage_path, &rest[0])?
+        .unwrap_or_else(|| FileKey(rest[0].clone()));
+    if !filestore::restore_from_quarantine(storage_path, &key)? {
+        eprintln!("filestore: {} is not in quarantine", rest[0]);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+fn repair(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, 1);
+    let other = FileStore::open_read_only(Path::new(&rest[0]))?;
+    let report = filestore::repair_from(storage_path, &other)?;
+    for key in &report.healed {
+        println!("{}  healed", key);
+    }
+    for key in &report.broken {
+        println!("{}  BROKEN", key);
+    }
+    Ok(report.broken.is_empty())
+}
+
+fn clone(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, 1);
+    let objects = filestore::clone_to(storage_path, Path::new(&rest[0]))?;
+    println!("cloned {} objects", objects);
+    Ok(true)
+}
+
+fn snapshot(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, 1);
+    let snapshot = filestore::snapshot(storage_path, &rest[0])?;
+    println!("{} objects, {} refs", snapshot.objects().len(), snapshot.refs().len());
+    Ok(true)
+}
+
+fn snapshots(storage_path: &Path) -> CmdResult {
+    for name in filestore::list_snapshots(storage_path)? {
+        if let Some(snapshot) = filestore::open_snapshot(storage_path, &name)? {
+            let secs = snapshot.time().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            println!("{}  {:>10}  {:>8}", name, secs, snapshot.objects().len());
+        }
+    }
+    Ok(true)
+}
+
+fn gc(storage_path: &Path) -> CmdResult {
+    let report = filestore::gc(storage_path)?;
+    for key in &report.objects_removed {
+        println!("removed object    {}", key);
+    }
+    for key in &report.refcounts_removed {
+        println!("removed refcount  {}", key);
+    }
+    for (key, tag) in &report.tags_removed {
+        println!("removed tag       {}  {}", key, tag);
+    }
+    for (key, transform) in &report.derived_released {
+        println!("released derived  {}  {}", key, transform);
+    }
+    println!("{} bytes freed", report.bytes_freed);
+    Ok(true)
+}
+
+fn recover(storage_path: &Path) -> CmdResult {
+    let report = filestore::recover(storage_path)?;
+    for key in &report.rolled_back {
+        println!("rolled back       {}", key);
+    }
+    for key in &report.rolled_forward {
+        println!("rolled forward    {}", key);
+    }
+    for path in &report.temp_files_removed {
+        println!("removed temp file {}", path.display());
+    }
+    for path in &report.pins_removed {
+        println!("removed stale pin {}", path.display());
+    }
+    for path in &report.transactions_resolved {
+        println!("resolved transaction {}", path.display());
+    }
+    Ok(true)
+}
+
+fn rehash(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 1, 1);
+    let algorithm = match HashAlgorithm::from_name(&rest[0]) {
+        Some(algorithm) => algorithm,
+        None => {
+            eprint!("Unknown algorithm '{}'\n\n{}", rest[0], USAGE);
+            process::exit(2);
+        }
+    };
+    for (old, new) in &filestore::rehash_store(storage_path, algorithm)? {
+        println!("{}  {}", old, new);
+    }
+    Ok(true)
+}
+
+fn encoding(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 0, 1);
+    let encoding = match rest.first() {
+        Some(arg) => arg,
+        None => {
+            println!("{}", filestore::key_encoding(storage_path)?);
+            return Ok(true);
+        }
+    };
+    match KeyEncoding::from_name(encoding) {
+        Some(encoding) => filestore::set_key_encoding(storage_path, encoding)?,
+        None => {
+            eprint!("Unknown encoding '{}'\n\n{}", encoding, USAGE);
+            process::exit(2);
+        }
+    }
+    Ok(true)
+}
+
+fn prefix(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 0, 1);
+    let prefixed = match rest.first().map(|arg| &**arg) {
+        Some("on") => true,
+        Some("off") => false,
+        Some(_) => {
+            eprint!("{}", USAGE);
+            process::exit(2);
+        },
+        None => {
+            let prefixed = filestore::key_prefixed(storage_path)?;
+            println!("{}", if prefixed { "on" } else { "off" });
+            return Ok(true);
+        }
+    };
+    filestore::set_key_prefixed(storage_path, prefixed)?;
+    Ok(true)
+}
+
+fn audit(storage_path: &Path, rest: &[String]) -> CmdResult {
+    expect_args(rest, 0, 1);
+    let mut filter = AuditFilter::default();
+    if let Some(arg) = rest.first() {
+        // Keys of objects since removed can only be given in full
+        filter.key = match parse_key(storage_path, arg)? {
+            Some(key) => Some(key),
+            None => {
+                eprintln!("filestore: {} not found", arg);
+                return Ok(false);
+            }
+        };
+    }
+    for entry in filestore::audit::audit_log(storage_path, &filter)? {
+        let time = entry.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        println!("{}.{:09}  {:<6}  {}  {}", time.as_secs(), time.subsec_nanos(),
+                 entry.action.name(), entry.key, entry.context.escape_debug());
+    }
+    Ok(true)
+}
+
+fn health(storage_path: &Path) -> CmdResult {
+    let health = filestore::health(storage_path)?;
+    println!("root exists       {}", health.root_exists);
+    println!("writable          {}", health.writable);
+    match health.manifest_valid {
+        Some(valid) => println!("manifest valid    {}", valid),
+        None => println!("manifest valid    none"),
+    }
+    match health.available {
+        Some(bytes) => println!("available         {} bytes", bytes),
+        None => println!("available         unknown"),
+    }
+    println!("temp leftovers    {}", health.temp_leftovers);
+    println!("missing shards    {}", health.missing_shards.len());
+    Ok(health.is_healthy())
+}
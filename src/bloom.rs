@@ -0,0 +1,247 @@
+// A Bloom filter of the keys in a store (`build_bloom_filter()`), so that
+// `exists()` can answer "not stored" without a stat, which is nearly always
+// the answer when a bulk ingester asks before sending content.
+//
+// The filter is `bloom` in the storage path: a header giving the size of
+// its blocks and the number of bits set per key, then one block for each
+// value of a key's leading byte.  All of a key's bits are in its block,
+// and new objects are added while their key's refcount lock is held (see
+// keylock.rs), whose stripes are the same leading bytes, so that writers
+// in any number of processes can update the file in place without losing
+// each other's bits.  Keys are digests already, so the bit positions are
+// taken from the key itself.
+//
+// Each process keeps a copy of the filter, read again when the file has
+// changed, checking at most once a second.  Objects stored meanwhile by
+// other processes may be reported missing until then.  Bits are never
+// cleared, so removed objects are only ruled out by building it again.
+
+use std::collections::HashMap;
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,Read,Seek,SeekFrom,Write};
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,MutexGuard,OnceLock};
+use std::time::{Duration,Instant,SystemTime};
+
+use byteorder::{BigEndian,ByteOrder};
+
+use super::{Error,FileKey,Keys,handles,writelock};
+use super::spool::Spool;
+
+const FILE_NAME: &str = "bloom";
+const MAGIC: &[u8; 8] = b"FSBLOOM1";
+const HEADER_LEN: u64 = 16;
+const BLOCKS: u64 = 256;
+
+// Bits set per key and bits per key, for about 1% false positives
+const HASHES: u32 = 7;
+const BITS_PER_KEY: u64 = 10;
+
+const MIN_BLOCK_LEN: u32 = 64;
+
+// How long a process trusts its copy without checking the file
+const REFRESH: Duration = Duration::from_secs(1);
+
+struct Filter {
+    block_len: u32,
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl Filter {
+    fn new(block_len: u32, hashes: u32) -> Filter {
+        Filter { block_len, hashes, bits: vec![0; block_len as usize * BLOCKS as usize] }
+    }
+
+    // The positions of `key`'s bits within the whole filter
+    fn positions(&self, key: &FileKey) -> Vec<u64> {
+        positions(key, self.block_len, self.hashes)
+    }
+
+    fn insert(&mut self, key: &FileKey) {
+        for bit in self.positions(key) {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    fn may_contain(&self, key: &FileKey) -> bool {
+        self.positions(key).iter().all(|&bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+}
+
+fn positions(key: &FileKey, block_len: u32, hashes: u32) -> Vec<u64> {
+    let block = u64::from_str_radix(&key[..2], 16).unwrap_or(0);
+    let h1 = u64::from_str_radix(&key[2..18], 16).unwrap_or(0);
+    let h2 = u64::from_str_radix(&key[18..34], 16).unwrap_or(0) | 1;
+    let block_bits = u64::from(block_len) * 8;
+    (0..u64::from(hashes))
+        .map(|i| block * block_bits + h1.wrapping_add(i.wrapping_mul(h2)) % block_bits)
+        .collect()
+}
+
+// A process's copy of a store's filter, or `None` if it has none
+struct Cached {
+    filter: Option<Filter>,
+    checked: Instant,
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+// Copies by storage path as given, so that lookups needn't resolve it, and
+// by canonical path, so that every spelling of a store shares one copy
+#[derive(Default)]
+struct Registry {
+    by_path: HashMap<PathBuf, Arc<Mutex<Cached>>>,
+    by_canonical: HashMap<PathBuf, Arc<Mutex<Cached>>>,
+}
+
+static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+
+fn registry() -> MutexGuard<'static, Registry> {
+    REGISTRY.get_or_init(|| Mutex::new(Registry::default()))
+        .lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn filter_path(storage_path: &Path) -> PathBuf {
+    storage_path.join(FILE_NAME)
+}
+
+fn cached(storage_path: &Path) -> Result<Arc<Mutex<Cached>>, Error> {
+    if let Some(cached) = registry().by_path.get(storage_path) {
+        return Ok(cached.clone());
+    }
+    let canonical = handles::canonical(storage_path);
+    let loaded = Arc::new(Mutex::new(load(storage_path)?));
+    let mut registry = registry();
+    let cached = registry.by_canonical.entry(canonical).or_insert(loaded).clone();
+    registry.by_path.insert(storage_path.to_path_buf(), cached.clone());
+    Ok(cached)
+}
+
+fn load(storage_path: &Path) -> Result<Cached, Error> {
+    let checked = Instant::now();
+    let mut file = match File::open(filter_path(storage_path)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Cached { filter: None, checked, modified: None, len: 0 });
+        },
+        Err(e) => return Err(From::from((e, "Unable to open Bloom filter"))),
+    };
+    let metadata = file.metadata()
+        .map_err(|e| { (e, "Unable to stat Bloom filter") } )?;
+    let (block_len, hashes) = read_header(&mut file)?;
+    let mut filter = Filter::new(block_len, hashes);
+    file.read_exact(&mut filter.bits)
+        .map_err(|e| { (e, "Unable to read Bloom filter") } )?;
+    Ok(Cached {
+        filter: Some(filter),
+        checked,
+        modified: metadata.modified().ok(),
+        len: metadata.len(),
+    })
+}
+
+// The block length and hashes per key
+fn read_header(file: &mut File) -> Result<(u32, u32), Error> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    file.read_exact(&mut header)
+        .map_err(|e| { (e, "Unable to read Bloom filter") } )?;
+    let block_len = BigEndian::read_u32(&header[8..12]);
+    let hashes = BigEndian::read_u32(&header[12..16]);
+    if &header[..8] != MAGIC || block_len == 0 || hashes == 0 {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidData, "bad header"),
+                               "Bloom filter is damaged")));
+    }
+    Ok((block_len, hashes))
+}
+
+/// Whether the filter shows that `key` is not stored.  `false` if there
+/// is no filter.
+pub(crate) fn rules_out(storage_path: &Path, key: &FileKey) -> Result<bool, Error> {
+    let cached = cached(storage_path)?;
+    let mut cached = cached.lock().unwrap_or_else(|e| e.into_inner());
+    if cached.checked.elapsed() >= REFRESH {
+        let (modified, len) = match fs::metadata(filter_path(storage_path)) {
+            Ok(metadata) => (metadata.modified().ok(), metadata.len()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (None, 0),
+            Err(e) => return Err(From::from((e, "Unable to stat Bloom filter"))),
+        };
+        if modified != cached.modified || len != cached.len || modified.is_none() {
+            *cached = load(storage_path)?;
+        } else {
+            cached.checked = Instant::now();
+        }
+    }
+    Ok(match cached.filter {
+        Some(ref filter) => !filter.may_contain(key),
+        None => false,
+    })
+}
+
+/// Add the newly placed object `key` to the filter, if the store has one.
+/// Must be called holding `key`'s refcount lock, or the store exclusively.
+pub(crate) fn add(storage_path: &Path, key: &FileKey) -> Result<(), Error> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(filter_path(storage_path)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(From::from((e, "Unable to open Bloom filter"))),
+    };
+    let (block_len, hashes) = read_header(&mut file)?;
+    let block = u64::from_str_radix(&key[..2], 16).unwrap_or(0);
+    let start = block * u64::from(block_len);
+    let mut bits = vec![0u8; block_len as usize];
+    file.seek(SeekFrom::Start(HEADER_LEN + start))
+        .and_then(|_| file.read_exact(&mut bits))
+        .map_err(|e| { (e, "Unable to read Bloom filter") } )?;
+    for bit in positions(key, block_len, hashes) {
+        let bit = bit - start * 8;
+        bits[(bit / 8) as usize] |= 1 << (bit % 8);
+    }
+    file.seek(SeekFrom::Start(HEADER_LEN + start))
+        .and_then(|_| file.write_all(&bits))
+        .map_err(|e| { (e, "Unable to write Bloom filter") } )?;
+
+    // Seen by this process at once
+    if let Some(ref mut filter) = cached(storage_path)?.lock().unwrap_or_else(|e| e.into_inner()).filter {
+        filter.insert(key);
+    }
+    Ok(())
+}
+
+/// Build a Bloom filter of the keys in the store at `storage_path`, sized
+/// for `expected_objects` (or the number stored now, if more) with about 1%
+/// false positives, replacing any it has.  From then on, objects stored by
+/// any process using this crate are added to it, and `exists()` consults
+/// it.  The filter takes about 10 bits per object.
+///
+/// The store is taken exclusively while the filter is built, failing with
+/// `ErrorKind::Locked` if another process is writing to it.  Build it again
+/// to drop objects since removed, or once the store outgrows its size;
+/// remove `bloom` from the storage path to do without one.
+pub fn build_bloom_filter(storage_path: &Path, expected_objects: u64) -> Result<(), Error> {
+    let _exclusive = writelock::exclusive_or_held(storage_path)?;
+    let keys = Keys::new(storage_path)?.collect::<Result<Vec<FileKey>, Error>>()?;
+    let objects = expected_objects.max(keys.len() as u64);
+    let block_len = (objects * BITS_PER_KEY).div_ceil(8 * BLOCKS)
+        .clamp(u64::from(MIN_BLOCK_LEN), u64::from(u32::MAX)) as u32;
+    let mut filter = Filter::new(block_len, HASHES);
+    for key in &keys {
+        filter.insert(key);
+    }
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[..8].copy_from_slice(MAGIC);
+    BigEndian::write_u32(&mut header[8..12], filter.block_len);
+    BigEndian::write_u32(&mut header[12..16], filter.hashes);
+    let mut spool = Spool::new(storage_path, false)?;
+    spool.write_all(&header)
+        .and_then(|()| spool.write_all(&filter.bits))
+        .map_err(|e| { (e, "Unable to write Bloom filter") } )?;
+    spool.persist(&filter_path(storage_path))?;
+
+    // Replaced outright, as the new file may have the old one's length and
+    // modification time
+    let cached = cached(storage_path)?;
+    *cached.lock().unwrap_or_else(|e| e.into_inner()) = load(storage_path)?;
+    Ok(())
+}
@@ -0,0 +1,35 @@
+
+use std::io::{self,Read};
+use tokio::sync::mpsc;
+
+/// Adapts chunks arriving on a channel into a `Read`, so content streamed in
+/// by async code can be handed to the blocking store API on another thread.
+/// An `Err` sent on the channel fails the read; dropping the sender ends it.
+pub struct ChannelReader {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+/// A sender for feeding a `ChannelReader`, with room for a few chunks in
+/// flight
+pub fn channel() -> (mpsc::Sender<io::Result<Vec<u8>>>, ChannelReader) {
+    let (tx, rx) = mpsc::channel(4);
+    (tx, ChannelReader { rx, buf: Vec::new(), pos: 0 })
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            match self.rx.blocking_recv() {
+                Some(Ok(buf)) => { self.buf = buf; self.pos = 0; },
+                Some(Err(e)) => return Err(e),
+                None => return Ok(0),
+            }
+        }
+        let count = out.len().min(self.buf.len() - self.pos);
+        out[..count].copy_from_slice(&self.buf[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
@@ -0,0 +1,420 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A read-through cache in front of another store.
+//!
+//! `CachedStore` answers retrievals from a local `FileStore` where it can,
+//! and otherwise fetches the object from the remote store, keeping a copy so
+//! that the next retrieval is local.  Stores, deletes, stats and listings go
+//! to the remote store, which stays the authority on what exists; deleting
+//! a key also drops any cached copy of it.
+//!
+//! Content fetched from the remote is checked against its key as it is
+//! cached.  With `with_max_bytes()`, objects are evicted to keep the cache
+//! within that size, chosen by an `EvictionPolicy`: the least recently
+//! retrieved (`Lru`, the default), the least often retrieved (`Lfu`), or
+//! the cheapest to fetch again for the space they take (`SizeWeighted`).
+//! Objects pinned in the local store (see `pin()`) are never evicted.  The
+//! local store should be given over to the cache, as it holds each cached
+//! object by a single reference.
+
+use std::collections::{BTreeMap,HashMap};
+use std::io::{self,Read};
+use std::sync::{Arc,Mutex};
+
+use super::{Error,FileKey};
+use super::store::{FileStore,ObjectStat,Store};
+
+/// A store that caches retrievals from `remote` in `local`
+#[derive(Clone)]
+pub struct CachedStore<R> {
+    local: FileStore,
+    remote: R,
+    max_bytes: Option<u64>,
+    max_object_size: Option<u64>,
+    cached: Arc<Mutex<Cached>>,
+}
+
+/// Decides which cached objects go first when the cache is over its size.
+/// The cache tells the policy of every object added, retrieved and
+/// removed, and evicts in the order the policy gives.
+pub trait EvictionPolicy: Send {
+    /// `key`, of `size` bytes, was added to the cache
+    fn inserted(&mut self, key: &FileKey, size: u64);
+
+    /// `key` was retrieved from the cache
+    fn accessed(&mut self, key: &FileKey);
+
+    /// `key` left the cache: evicted by the policy's choice if `evicted`,
+    /// or deleted
+    fn removed(&mut self, key: &FileKey, evicted: bool);
+
+    /// Every cached object, the first to be evicted first
+    fn eviction_order(&self) -> Box<dyn Iterator<Item = &FileKey> + '_>;
+}
+
+/// Evict the least recently retrieved objects first
+#[derive(Debug, Default)]
+pub struct Lru {
+    ticks: HashMap<FileKey, u64>,
+    by_tick: BTreeMap<u64, FileKey>,
+    tick: u64,
+}
+
+impl EvictionPolicy for Lru {
+    fn inserted(&mut self, key: &FileKey, _size: u64) {
+        self.accessed(key);
+    }
+
+    fn accessed(&mut self, key: &FileKey) {
+        self.tick += 1;
+        if let Some(tick) = self.ticks.insert(key.clone(), self.tick) {
+            self.by_tick.remove(&tick);
+        }
+        self.by_tick.insert(self.tick, key.clone());
+    }
+
+    fn removed(&mut self, key: &FileKey, _evicted: bool) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.by_tick.remove(&tick);
+        }
+    }
+
+    fn eviction_order(&self) -> Box<dyn Iterator<Item = &FileKey> + '_> {
+        Box::new(self.by_tick.values())
+    }
+}
+
+/// Evict the least often retrieved objects first, and of those the least
+/// recently retrieved.  Counts start again when an object is cached again.
+#[derive(Debug, Default)]
+pub struct Lfu {
+    // Retrieval count and tick of each object
+    counts: HashMap<FileKey, (u64, u64)>,
+    by_count: BTreeMap<(u64, u64), FileKey>,
+    tick: u64,
+}
+
+impl EvictionPolicy for Lfu {
+    fn inserted(&mut self, key: &FileKey, _size: u64) {
+        self.accessed(key);
+    }
+
+    fn accessed(&mut self, key: &FileKey) {
+        self.tick += 1;
+        let count = match self.counts.get(key) {
+            Some(rank) => {
+                self.by_count.remove(rank);
+                rank.0 + 1
+            },
+            None => 1,
+        };
+        self.counts.insert(key.clone(), (count, self.tick));
+        self.by_count.insert((count, self.tick), key.clone());
+    }
+
+    fn removed(&mut self, key: &FileKey, _evicted: bool) {
+        if let Some(rank) = self.counts.remove(key) {
+            self.by_count.remove(&rank);
+        }
+    }
+
+    fn eviction_order(&self) -> Box<dyn Iterator<Item = &FileKey> + '_> {
+        Box::new(self.by_count.values())
+    }
+}
+
+/// Evict large objects sooner than small ones, unless retrieved more
+/// recently (GreedyDual-Size, with every object equally costly to fetch
+/// again).  Each retrieval gives an object credit in inverse proportion to
+/// its size, on top of the credit of the last object evicted, so objects
+/// left alone age out whatever their size.
+#[derive(Debug, Default)]
+pub struct SizeWeighted {
+    // Size and credit of each object
+    credits: HashMap<FileKey, (u64, (u64, u64))>,
+    by_credit: BTreeMap<(u64, u64), FileKey>,
+    // Credit of the last object evicted
+    floor: u64,
+    tick: u64,
+}
+
+impl SizeWeighted {
+    // Credit for a retrieval of a one byte object
+    const SCALE: u64 = 1 << 40;
+}
+
+impl EvictionPolicy for SizeWeighted {
+    fn inserted(&mut self, key: &FileKey, size: u64) {
+        self.removed(key, false);
+        self.credits.insert(key.clone(), (size, (0, 0)));
+        self.accessed(key);
+    }
+
+    fn accessed(&mut self, key: &FileKey) {
+        self.tick += 1;
+        let entry = match self.credits.get_mut(key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        self.by_credit.remove(&entry.1);
+        // Ties go to the least recently retrieved
+        entry.1 = (self.floor.saturating_add(SizeWeighted::SCALE / entry.0.max(1)), self.tick);
+        self.by_credit.insert(entry.1, key.clone());
+    }
+
+    fn removed(&mut self, key: &FileKey, evicted: bool) {
+        if let Some((_, credit)) = self.credits.remove(key) {
+            self.by_credit.remove(&credit);
+            if evicted {
+                self.floor = self.floor.max(credit.0);
+            }
+        }
+    }
+
+    fn eviction_order(&self) -> Box<dyn Iterator<Item = &FileKey> + '_> {
+        Box::new(self.by_credit.values())
+    }
+}
+
+// The objects in the cache
+struct Cached {
+    // Size of each object
+    objects: HashMap<FileKey, u64>,
+    policy: Box<dyn EvictionPolicy>,
+    bytes: u64,
+}
+
+impl Default for Cached {
+    fn default() -> Cached {
+        Cached {
+            objects: HashMap::new(),
+            policy: Box::new(Lru::default()),
+            bytes: 0,
+        }
+    }
+}
+
+impl Cached {
+    // Note a retrieval of `key`, returning whether it was already known
+    fn touch(&mut self, key: &FileKey, size: u64) -> bool {
+        if self.objects.contains_key(key) {
+            self.policy.accessed(key);
+            return true;
+        }
+        self.objects.insert(key.clone(), size);
+        self.bytes += size;
+        self.policy.inserted(key, size);
+        false
+    }
+
+    fn forget(&mut self, key: &FileKey, evicted: bool) {
+        if let Some(size) = self.objects.remove(key) {
+            self.policy.removed(key, evicted);
+            self.bytes -= size;
+        }
+    }
+
+    // The next object to evict other than `keep` and those pinned in
+    // `local`
+    fn victim(&self, keep: &FileKey, local: &FileStore) -> Result<Option<FileKey>, Error> {
+        for key in self.policy.eviction_order() {
+            if key != keep && !local.is_pinned(key)? {
+                return Ok(Some(key.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    // Hand the objects over to `policy`, first to be evicted first
+    fn set_policy(&mut self, mut policy: Box<dyn EvictionPolicy>) {
+        for key in self.policy.eviction_order() {
+            policy.inserted(key, self.objects.get(key).copied().unwrap_or(0));
+        }
+        self.policy = policy;
+    }
+}
+
+impl<R: Store> CachedStore<R> {
+    /// A cache in `local` in front of `remote`.  Objects already in `local`
+    /// (from an earlier run) are counted as cached.
+    pub fn new(local: FileStore, remote: R) -> Result<CachedStore<R>, Error> {
+        let mut found: Vec<(FileKey, ObjectStat)> = Vec::new();
+        for key in local.keys()? {
+            let key = key?;
+            if let Some(stat) = local.stat(&key)? {
+                found.push((key, stat));
+            }
+        }
+        // Oldest first, so that they are evicted first
+        found.sort_by_key(|(_, stat)| stat.last_access);
+        let mut cached = Cached::default();
+        for (key, stat) in found {
+            cached.touch(&key, stat.size);
+        }
+        Ok(CachedStore {
+            local,
+            remote,
+            max_bytes: None,
+            max_object_size: None,
+            cached: Arc::new(Mutex::new(cached)),
+        })
+    }
+
+    /// Evict objects once the cache holds more than `max_bytes` of content
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> CachedStore<R> {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Choose the objects to evict with `policy` rather than `Lru`.  Objects
+    /// already cached are handed to it in the order the old policy would
+    /// have evicted them.
+    pub fn with_eviction_policy<P: EvictionPolicy + 'static>(self, policy: P) -> CachedStore<R> {
+        self.lock().set_policy(Box::new(policy));
+        self
+    }
+
+    /// Don't cache objects larger than `max_object_size`, so that one large
+    /// retrieval can't empty the cache.  Objects larger than the cache
+    /// itself are never cached.
+    pub fn with_max_object_size(mut self, max_object_size: u64) -> CachedStore<R> {
+        self.max_object_size = Some(max_object_size);
+        self
+    }
+
+    /// The local store holding the cache
+    pub fn local(&self) -> &FileStore {
+        &self.local
+    }
+
+    /// The store being cached
+    pub fn remote(&self) -> &R {
+        &self.remote
+    }
+
+    /// Bytes of content currently cached
+    pub fn cached_bytes(&self) -> u64 {
+        self.lock().bytes
+    }
+
+    /// Drop every cached object
+    pub fn clear(&self) -> Result<(), Error> {
+        let mut cached = self.lock();
+        let keys: Vec<FileKey> = cached.objects.keys().cloned().collect();
+        for key in keys {
+            self.local.delete(&key)?;
+            cached.forget(&key, false);
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Cached> {
+        self.cached.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Whether an object of `size` bytes may be cached
+    fn fits(&self, size: u64) -> bool {
+        self.max_object_size.is_none_or(|max| size <= max)
+            && self.max_bytes.is_none_or(|max| size <= max)
+    }
+
+    // Copy an object from the remote into the cache, returning whether it
+    // was there to copy
+    fn fetch(&self, key: &FileKey) -> Result<bool, Error> {
+        let mut reader = match self.remote.retrieve_reader(key)? {
+            Some(reader) => reader,
+            None => return Ok(false),
+        };
+        let fetched = self.local.store_reader(&mut reader)?;
+        if fetched != *key {
+            self.local.delete(&fetched)?;
+            return Err(From::from((io::Error::new(io::ErrorKind::InvalidData,
+                                                  "remote content does not match its key"),
+                                   &*format!("Unable to cache {}", key))));
+        }
+        let size = match self.local.stat(key)? {
+            Some(stat) => stat.size,
+            None => 0,
+        };
+        let mut cached = self.lock();
+        if cached.touch(key, size) {
+            // Cached by another retrieval meanwhile, so this copy took a
+            // second reference
+            return self.local.delete(key).map(|()| true);
+        }
+        self.evict(&mut cached, key)?;
+        Ok(true)
+    }
+
+    // Evict objects, other than the one just cached, until within bounds
+    fn evict(&self, cached: &mut Cached, keep: &FileKey) -> Result<(), Error> {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return Ok(()),
+        };
+        while cached.bytes > max_bytes {
+            let key = match cached.victim(keep, &self.local)? {
+                Some(key) => key,
+                None => break,
+            };
+            self.local.delete(&key)?;
+            cached.forget(&key, true);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Store> Store for CachedStore<R> {
+    fn store_data(&self, input: &[u8]) -> Result<FileKey, Error> {
+        self.remote.store_data(input)
+    }
+
+    fn store_reader(&self, input: &mut dyn Read) -> Result<FileKey, Error> {
+        self.remote.store_reader(input)
+    }
+
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>
+    {
+        if let Some(reader) = self.local.retrieve_reader(key)? {
+            if let Some(stat) = self.local.stat(key)? {
+                self.lock().touch(key, stat.size);
+            }
+            return Ok(Some(reader));
+        }
+        let size = match self.remote.stat(key)? {
+            Some(stat) => stat.size,
+            None => return Ok(None),
+        };
+        if !self.fits(size) {
+            return self.remote.retrieve_reader(key);
+        }
+        if !self.fetch(key)? {
+            return Ok(None);
+        }
+        match self.local.retrieve_reader(key)? {
+            Some(reader) => Ok(Some(reader)),
+            // Evicted already by another retrieval
+            None => self.remote.retrieve_reader(key),
+        }
+    }
+
+    fn delete(&self, key: &FileKey) -> Result<(), Error> {
+        self.remote.delete(key)?;
+        let mut cached = self.lock();
+        if cached.objects.contains_key(key) {
+            self.local.delete(key)?;
+            cached.forget(key, false);
+        }
+        Ok(())
+    }
+
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error> {
+        self.remote.stat(key)
+    }
+
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error> {
+        self.remote.keys()
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Export of stored objects as a CAR (Content Addressable aRchive, version
+//! 1) file, for IPFS tooling and archival systems that take CAR.
+//!
+//! Each object becomes a raw block whose CID (version 1, `raw` codec) is
+//! made from its key as a `sha2-224` (or, in stores moved to sha256,
+//! `sha2-256`) multihash, so no content is hashed again and CIDs map back
+//! to keys one to one.  Keys of `sha512-tree` stores have no registered
+//! multihash code and are given one from the private use range, which
+//! other tools won't verify.  Every exported object is also listed as a
+//! root, as the objects are not linked to one another.
+
+use std::borrow::Borrow;
+use std::fs::File;
+use std::io::{self,Write};
+use std::path::Path;
+
+use super::{Error,FileKey,storage_file_path};
+use super::hashable::HashAlgorithm;
+
+// Multicodec codes
+const CID_VERSION: u64 = 1;
+const RAW_CODEC: u64 = 0x55;
+const SHA2_224: u64 = 0x1013;
+const SHA2_256: u64 = 0x12;
+// From the multicodec private use range, as tree digests have no code
+const SHA512_TREE: u64 = 0x30_0000;
+
+// The CBOR tag for CIDs in DAG-CBOR
+const CID_TAG: u64 = 42;
+
+/// The binary CID of the object stored under `key`
+pub fn cid(key: &FileKey) -> Vec<u8> {
+    let digest: Vec<u8> = (0..key.len()).step_by(2)
+        .filter_map(|i| u8::from_str_radix(&key[i..i + 2], 16).ok())
+        .collect();
+    let hash = match key.algorithm() {
+        Some(HashAlgorithm::Sha256) => SHA2_256,
+        Some(HashAlgorithm::Sha512Tree) => SHA512_TREE,
+        _ => SHA2_224,
+    };
+    let mut cid = Vec::with_capacity(4 + digest.len());
+    for value in [CID_VERSION, RAW_CODEC, hash, digest.len() as u64] {
+        varint(&mut cid, value);
+    }
+    cid.extend_from_slice(&digest);
+    cid
+}
+
+/// The CID of the object stored under `key` as text, in the lowercase
+/// base32 form IPFS tools print (starting `b`)
+pub fn cid_string(key: &FileKey) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut text = String::from("b");
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for byte in cid(key) {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    text
+}
+
+/// Write the objects `keys` names as a CARv1 file on `writer`, returning
+/// the writer once the file is complete.  If a key is not stored the
+/// error's `io` is of kind `NotFound`, and the file is left incomplete.
+pub fn export_car<I, K, W>(storage_path: &Path, keys: I, mut writer: W) -> Result<W, Error>
+    where I: IntoIterator<Item = K>,
+          K: Borrow<FileKey>,
+          W: Write
+{
+    let op = operation!("filestore.export_car", objects);
+    let keys: Vec<K> = keys.into_iter().collect();
+    let result = write_header(&mut writer, &keys)
+        .map_err(|e| { From::from((e, "Unable to write CAR header")) } )
+        .and_then(|()| {
+            for key in &keys {
+                write_block(storage_path, &mut writer, key.borrow())?;
+            }
+            op.record("objects", keys.len() as u64);
+            Ok(writer)
+        });
+    op.finish(result)
+}
+
+// The header: a length-prefixed DAG-CBOR map of the roots and the version
+fn write_header<K, W>(writer: &mut W, roots: &[K]) -> io::Result<()>
+    where K: Borrow<FileKey>,
+          W: Write
+{
+    let mut header: Vec<u8> = Vec::new();
+    cbor_head(&mut header, 5, 2);
+    // DAG-CBOR orders map keys by length first
+    cbor_head(&mut header, 3, 5);
+    header.extend_from_slice(b"roots");
+    cbor_head(&mut header, 4, roots.len() as u64);
+    for root in roots {
+        let cid = cid(root.borrow());
+        cbor_head(&mut header, 6, CID_TAG);
+        // With the multibase prefix for binary
+        cbor_head(&mut header, 2, cid.len() as u64 + 1);
+        header.push(0);
+        header.extend_from_slice(&cid);
+    }
+    cbor_head(&mut header, 3, 7);
+    header.extend_from_slice(b"version");
+    cbor_head(&mut header, 0, 1);
+
+    let mut prefix: Vec<u8> = Vec::new();
+    varint(&mut prefix, header.len() as u64);
+    writer.write_all(&prefix)?;
+    writer.write_all(&header)
+}
+
+// A block: its length, CID and content
+fn write_block<W: Write>(storage_path: &Path, writer: &mut W, key: &FileKey)
+                         -> Result<(), Error>
+{
+    let mut file = File::open(storage_file_path(storage_path, key))
+        .map_err(|e| { (e, "Unable to open stored file") } )?;
+    let size = file.metadata()
+        .map_err(|e| { (e, "Unable to stat stored file") } )?
+        .len();
+    let cid = cid(key);
+    let mut prefix: Vec<u8> = Vec::new();
+    varint(&mut prefix, cid.len() as u64 + size);
+    prefix.extend_from_slice(&cid);
+    writer.write_all(&prefix)
+        .map_err(|e| { (e, "Unable to write CAR block") } )?;
+    let copied = io::copy(&mut file, writer)
+        .map_err(|e| { (e, "Unable to write CAR block") } )?;
+    if copied != size {
+        return Err(From::from((io::Error::from(io::ErrorKind::UnexpectedEof),
+                               "Stored file changed size while being exported")));
+    }
+    Ok(())
+}
+
+// An unsigned LEB128 varint, as multiformats use
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// The head of a CBOR data item of major type `major` with argument `value`
+fn cbor_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(value as u16).to_be_bytes());
+        },
+        0x10000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(value as u32).to_be_bytes());
+        },
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&value.to_be_bytes());
+        },
+    }
+}
@@ -0,0 +1,184 @@
+// Copying a whole store cheaply (`clone_to()`), as to give a test
+// environment a writable copy of a production store.  Objects never change,
+// so the copy shares their content with the original: reflinked where the
+// filesystem can (btrfs, XFS), so that the two are wholly independent, and
+// otherwise hard linked, which is as safe while content is only ever
+// replaced and never written in place (secure deletion leaves content that
+// is linked elsewhere alone).  Only across filesystems is content copied.
+//
+// Refcounts are the clone's own from the start, as are the refs, tags,
+// namespaces, snapshots and other records beside them, which are small
+// and are copied.  Each object is cloned holding its refcount lock, so
+// it arrives with a refcount that matches its content, but objects are
+// cloned one after another while writes to the original carry on, so
+// the clone is not of one moment unless the original is left alone (or
+// held with `FileStore::open_exclusive()`) meanwhile.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::{Config,Error,Keys,keylock,manifest,storage_file_path};
+use super::{create_shards,get_refcount,set_refcount};
+use super::error::ErrorKind;
+
+// Records copied with the objects, as their refcounts count the
+// references some of them hold
+const RECORDS: &[&str] = &[
+    "refs", "tags", "namespaces", "derived", "types", "macs", "key-map", "snapshots", "bloom",
+    "merkle", "weak", "owners",
+];
+
+// How content is shared with the clone, settling on the cheapest that
+// works for the first object
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Method {
+    Reflink,
+    HardLink,
+    Copy,
+}
+
+/// Create a new store at `dest`, which must be empty or not yet exist,
+/// holding the same objects as the store at `storage_path` with the same
+/// refcounts, refs, tags, namespaces and snapshots, and return the number
+/// of objects cloned.  Content is reflinked or hard linked rather than
+/// copied where the filesystem allows, so cloning takes about as long as
+/// listing the store.
+///
+/// From then on the two stores are independent: storing and deleting in
+/// one does not affect the other.  Objects are cloned one at a time while
+/// the original may be changing, so the clone is only of one moment if
+/// the original isn't written to meanwhile.
+pub fn clone_to(storage_path: &Path, dest: &Path) -> Result<u64, Error> {
+    let op = operation!("filestore.clone_to", objects);
+    let result = clone_store(storage_path, dest);
+    if let Ok(objects) = result {
+        op.record("objects", objects);
+    }
+    op.finish(result)
+}
+
+fn clone_store(storage_path: &Path, dest: &Path) -> Result<u64, Error> {
+    let manifest = manifest::read(storage_path)?;
+    fs::create_dir_all(dest)
+        .map_err(|e| { (e, "Unable to create storage directory") } )?;
+    match manifest::read(dest) {
+        Ok(_) => {
+            return Err(From::from((io::Error::from(io::ErrorKind::AlreadyExists),
+                                   "A store already exists there")));
+        },
+        Err(ref e) if e.kind == ErrorKind::NotAStore => {},
+        Err(e) => return Err(e),
+    }
+    if !manifest::holds_only_store_entries(dest)? {
+        return Err(From::from((io::Error::from(io::ErrorKind::DirectoryNotEmpty),
+                               "Storage directory holds files that are not a store's")));
+    }
+    create_shards(dest)?;
+
+    let config = Config::default();
+    let mut method = match cfg!(target_os = "linux") {
+        true => Method::Reflink,
+        false => Method::HardLink,
+    };
+    let mut objects: u64 = 0;
+    for key in Keys::new(storage_path)? {
+        let key = key?;
+        let _lock = keylock::lock(storage_path, &key)?;
+        // Unreferenced, and so due to be collected
+        let refcount = get_refcount(storage_path, &key)?;
+        if refcount == 0 {
+            continue;
+        }
+        let source = storage_file_path(storage_path, &key);
+        match share(&source, &storage_file_path(dest, &key), &mut method) {
+            Ok(()) => {},
+            // Deleted since it was listed
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && !source.exists() => continue,
+            Err(e) => return Err(From::from((e, "Unable to clone stored file"))),
+        }
+        set_refcount(dest, &config, &key, refcount)?;
+        objects += 1;
+    }
+
+    for record in RECORDS {
+        copy_tree(&storage_path.join(record), &dest.join(record))?;
+    }
+    // Last, so that an interrupted clone isn't taken for a store
+    manifest::write(dest, &manifest)?;
+    Ok(objects)
+}
+
+// Give `dest` the content of `source` by `method`, falling back to the next
+// where it isn't supported
+fn share(source: &Path, dest: &Path, method: &mut Method) -> io::Result<()> {
+    if *method == Method::Reflink {
+        if reflink(source, dest)? {
+            return Ok(());
+        }
+        *method = Method::HardLink;
+    }
+    if *method == Method::HardLink {
+        match fs::hard_link(source, dest) {
+            Ok(()) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => *method = Method::Copy,
+            Err(e) => return Err(e),
+        }
+    }
+    let permissions = fs::metadata(source)?.permissions();
+    fs::copy(source, dest)?;
+    fs::set_permissions(dest, permissions)
+}
+
+// Make `dest` a copy-on-write clone of `source`, returning false if the
+// filesystem can't
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, dest: &Path) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let input = fs::File::open(source)?;
+    let output = fs::OpenOptions::new().write(true).create_new(true).open(dest)?;
+    if unsafe { libc::ioctl(output.as_raw_fd(), libc::FICLONE, input.as_raw_fd()) } == 0 {
+        drop(output);
+        fs::set_permissions(dest, input.metadata()?.permissions())?;
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    drop(output);
+    fs::remove_file(dest)?;
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::EXDEV) | Some(libc::ENOTTY) => {
+            Ok(false)
+        },
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _dest: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+// Copy the file or directory tree at `from`, if there is one, to `to`
+fn copy_tree(from: &Path, to: &Path) -> Result<(), Error> {
+    let metadata = match fs::metadata(from) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(From::from((e, "Unable to stat store record"))),
+    };
+    if !metadata.is_dir() {
+        fs::copy(from, to)
+            .map_err(|e| { (e, "Unable to copy store record") } )?;
+        return Ok(());
+    }
+    fs::create_dir_all(to)
+        .map_err(|e| { (e, "Unable to create directory in clone") } )?;
+    for entry in fs::read_dir(from)
+        .map_err(|e| { (e, "Unable to read store records") } )?
+    {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read store records") } )?;
+        copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
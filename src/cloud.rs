@@ -0,0 +1,237 @@
+// Refcounted, content-addressed storage on a cloud object store, shared by
+// the `gcs` and `azure` backends.
+//
+// Each object is a blob named by its key (after an optional prefix), with
+// its reference count kept in the blob's own metadata.  Object stores have
+// no locks, so counts are changed optimistically: the blob is read with its
+// version (a metageneration or ETag), and the change is made conditional on
+// that version, starting again from the read if someone else got there
+// first.  New blobs are created on condition that none exists yet, so two
+// writers storing the same content end up with one blob and two references.
+
+use std::env;
+use std::fs::File;
+use std::io::{self,Cursor,Read,Seek,SeekFrom};
+
+use super::{Error,FileKey,HashAlgorithm,KeyHasher};
+use super::error::ErrorKind;
+use super::spool::Spool;
+use super::store::ObjectStat;
+
+// Give up on a change after this many lost races
+const ATTEMPTS: usize = 64;
+
+/// A blob as last read
+pub(crate) struct BlobInfo {
+    pub size: u64,
+    pub refcount: u64,
+    pub version: String,
+}
+
+/// Content to upload, which is rewound for each attempt
+pub(crate) trait Content: Read + Seek {}
+
+impl<T: Read + Seek> Content for T {}
+
+/// The operations on a bucket or container that refcounting is built on
+pub(crate) trait Blobs {
+    /// Read a blob's size, refcount and version, or `None` if there is none
+    fn head(&self, name: &str) -> Result<Option<BlobInfo>, Error>;
+
+    /// Upload `size` bytes of `content` as a new blob with a refcount of
+    /// one, returning `false` if a blob of that name already exists
+    fn create(&self, name: &str, content: &mut dyn Content, size: u64) -> Result<bool, Error>;
+
+    /// Set a blob's refcount if it is still at `version`, returning `false`
+    /// if it has changed or gone
+    fn set_refcount(&self, name: &str, refcount: u64, version: &str) -> Result<bool, Error>;
+
+    /// Remove a blob if it is still at `version`, returning `false` if it
+    /// has changed or gone
+    fn remove(&self, name: &str, version: &str) -> Result<bool, Error>;
+
+    /// Stream a blob's content, or `None` if there is none
+    fn open(&self, name: &str) -> Result<Option<Box<dyn Read + Send>>, Error>;
+
+    /// One page of the names starting with `prefix`, and the token for the
+    /// next page if there is one
+    fn list(&self, prefix: &str, token: Option<&str>)
+            -> Result<(Vec<String>, Option<String>), Error>;
+}
+
+/// Take a reference on the object `content` holds, uploading it if new
+pub(crate) fn store(blobs: &dyn Blobs, name: &str, content: &mut dyn Content, size: u64)
+                    -> Result<(), Error>
+{
+    for _ in 0..ATTEMPTS {
+        let done = match blobs.head(name)? {
+            Some(info) => {
+                let refcount = info.refcount.checked_add(1)
+                    .ok_or_else(|| Error::from((ErrorKind::RefcountOverflow,
+                                                &*format!("Too many references to {}", name))))?;
+                blobs.set_refcount(name, refcount, &info.version)?
+            },
+            None => {
+                content.seek(SeekFrom::Start(0))
+                    .map_err(|e| { (e, "Unable to rewind content") } )?;
+                blobs.create(name, content, size)?
+            },
+        };
+        if done {
+            return Ok(());
+        }
+    }
+    Err(contended())
+}
+
+/// Store data from memory
+pub(crate) fn store_data(blobs: &dyn Blobs, prefix: &str, input: &[u8])
+                         -> Result<FileKey, Error>
+{
+    let mut hasher = KeyHasher::new();
+    io::Write::write_all(&mut hasher, input)
+        .map_err(|e| { (e, "Unable to hash input") } )?;
+    let key = hasher.finish();
+    store(blobs, &format!("{}{}", prefix, key), &mut Cursor::new(input), input.len() as u64)?;
+    Ok(key)
+}
+
+/// Store everything that can be read from `input`, spooling it to a
+/// temporary file first, as the key must be known before uploading
+pub(crate) fn store_reader(blobs: &dyn Blobs, prefix: &str, input: &mut dyn Read)
+                           -> Result<FileKey, Error>
+{
+    let mut spool = Spool::with_algorithm(&env::temp_dir(), false, HashAlgorithm::Sha224)?;
+    let size = io::copy(input, &mut spool)
+        .map_err(|e| { (e, "Unable to spool input") } )?;
+    let key = FileKey(spool.hash());
+    let mut file = File::open(spool.path())
+        .map_err(|e| { (e, "Unable to read spooled input") } )?;
+    store(blobs, &format!("{}{}", prefix, key), &mut file, size)?;
+    Ok(key)
+}
+
+/// Drop one reference, removing the blob with the last
+pub(crate) fn release(blobs: &dyn Blobs, name: &str) -> Result<(), Error> {
+    for _ in 0..ATTEMPTS {
+        let done = match blobs.head(name)? {
+            None => return Ok(()),
+            Some(ref info) if info.refcount == 0 => return Ok(()),
+            Some(ref info) if info.refcount == 1 => blobs.remove(name, &info.version)?,
+            Some(info) => blobs.set_refcount(name, info.refcount - 1, &info.version)?,
+        };
+        if done {
+            return Ok(());
+        }
+    }
+    Err(contended())
+}
+
+/// Size and refcount of a blob; when it was stored isn't asked for
+pub(crate) fn stat(blobs: &dyn Blobs, name: &str) -> Result<Option<ObjectStat>, Error> {
+    Ok(blobs.head(name)?.map(|info| ObjectStat {
+        size: info.size,
+        refcount: info.refcount,
+        stored: None,
+        last_access: None,
+    }))
+}
+
+fn contended() -> Error {
+    From::from((io::Error::new(io::ErrorKind::WouldBlock, "too many concurrent updates"),
+                "Unable to update refcount"))
+}
+
+/// The keys of the objects under a prefix, a page at a time
+pub(crate) struct Keys<'a> {
+    blobs: &'a (dyn Blobs + Sync),
+    prefix: String,
+    page: std::vec::IntoIter<String>,
+    token: Option<String>,
+    done: bool,
+}
+
+impl<'a> Keys<'a> {
+    pub fn new(blobs: &'a (dyn Blobs + Sync), prefix: &str) -> Keys<'a> {
+        Keys {
+            blobs,
+            prefix: prefix.to_owned(),
+            page: Vec::new().into_iter(),
+            token: None,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for Keys<'_> {
+    type Item = Result<FileKey, Error>;
+
+    fn next(&mut self) -> Option<Result<FileKey, Error>> {
+        loop {
+            for name in self.page.by_ref() {
+                // Skip anything else sharing the bucket
+                let key = FileKey(name[self.prefix.len()..].to_owned());
+                if key.is_well_formed() {
+                    return Some(Ok(key));
+                }
+            }
+            if self.done {
+                return None;
+            }
+            match self.blobs.list(&self.prefix, self.token.as_deref()) {
+                Ok((names, token)) => {
+                    self.page = names.into_iter();
+                    self.done = token.is_none();
+                    self.token = token;
+                },
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
+
+/// The response to a request, or `None` if it failed with one of the
+/// `expected` statuses
+pub(crate) fn reply(result: Result<ureq::Response, ureq::Error>, expected: &[u16],
+                    message: &str) -> Result<Option<ureq::Response>, Error>
+{
+    match result {
+        Ok(response) => Ok(Some(response)),
+        Err(ureq::Error::Status(code, _)) if expected.contains(&code) => Ok(None),
+        Err(ureq::Error::Status(code, response)) => {
+            let kind = match code {
+                401 | 403 => io::ErrorKind::PermissionDenied,
+                404 => io::ErrorKind::NotFound,
+                _ => io::ErrorKind::Other,
+            };
+            let body = response.into_string().unwrap_or_default();
+            let detail = format!("HTTP {}: {}", code, body.chars().take(200).collect::<String>());
+            Err(From::from((io::Error::new(kind, detail), message)))
+        },
+        Err(e) => Err(From::from((io::Error::other(e), message))),
+    }
+}
+
+/// The response to a request that is expected to succeed
+pub(crate) fn check(result: Result<ureq::Response, ureq::Error>, message: &str)
+                    -> Result<ureq::Response, Error>
+{
+    reply(result, &[], message)
+        .map(|response| response.expect("no failures expected"))
+}
+
+/// Percent-encode `s` for use in a URL, leaving `/` alone if `keep_slash`
+pub(crate) fn encode(s: &str, keep_slash: bool) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) || (keep_slash && b == b'/') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
@@ -0,0 +1,150 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Settings for a `FileStore` handle.  The crate's free functions behave as
+//! a handle with the default `Config` does.
+
+use super::Error;
+use super::authtag::AuthSecret;
+use super::error::ErrorKind;
+use super::hashable::{HashAlgorithm,DEFAULT_BUFFER_SIZE};
+
+/// Limits on how much a store may hold
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Maximum bytes of object content (each object counted once)
+    pub max_bytes: Option<u64>,
+    /// Maximum number of distinct objects
+    pub max_objects: Option<u64>,
+}
+
+/// How hard to work to make changes survive a crash or power loss, at the
+/// cost of throughput
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Durability {
+    /// Leave writes to the operating system.  A crash may lose recent
+    /// stores, or leave objects whose content or refcount is incomplete.
+    #[default]
+    None,
+    /// Flush object content and refcount files to disk before returning
+    FlushData,
+    /// As `FlushData`, and also flush the directories holding them, so
+    /// that newly created (or removed) files are themselves durable
+    FlushDataAndDir,
+}
+
+/// Settings applied to operations through a `FileStore`
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Stores that would add an object beyond these limits fail with
+    /// `ErrorKind::QuotaExceeded`.  Storing more references to content
+    /// already present always succeeds.
+    pub quota: Option<Quota>,
+    /// Refuse content longer than this many bytes, failing the store with
+    /// `ErrorKind::TooLarge`.  Content of unknown length is read only until
+    /// it goes over, and what was spooled of it is removed, so that one
+    /// upload can't fill a shared store.
+    pub max_object_size: Option<u64>,
+    /// Keep at least this many bytes free on the storage filesystem.  A
+    /// store through the handle that would leave less first runs `gc()` to
+    /// reclaim what space it can (at most once a minute), and fails with
+    /// `ErrorKind::DiskFull` if that wasn't enough.  Content of unknown
+    /// length is checked before it is spooled, as if empty.
+    pub low_space_watermark: Option<u64>,
+    /// When to fsync after stores and refcount updates
+    pub durability: Durability,
+    /// Write new object content with direct I/O (O_DIRECT), bypassing the
+    /// page cache, for bulk ingestion that shouldn't evict the rest of
+    /// the system's working set.  Only has an effect on Linux, and only on
+    /// filesystems that support it.
+    pub direct_io: bool,
+    /// When the last reference to an object is dropped, overwrite its
+    /// content with zeros (flushed to disk) before removing the file, for
+    /// deployments with requirements around data remanence.  This cannot
+    /// reach copies kept by copy-on-write filesystems, snapshots or SSD
+    /// wear levelling.
+    pub secure_delete: bool,
+    /// Make stored object files read-only (mode 0444 on Unix), so that the
+    /// canonical copies returned by `retrieve_file()` aren't modified by
+    /// mistake.  Write permission is restored internally before an object
+    /// is removed.
+    pub read_only_objects: bool,
+    /// Record when, and how often, each object is retrieved through the
+    /// handle, for `access::last_access()`, `access::access_count()` and
+    /// `access::hottest()`.  Retrievals are written out in batches (see
+    /// the `access` module), so call `access::flush()` before exiting.
+    pub track_access: bool,
+    /// When content hashes to a key that is already stored, compare it
+    /// byte for byte with the stored object rather than assuming they are
+    /// the same, failing with `ErrorKind::HashCollision` if they differ.
+    /// This costs a temporary copy and a full read of both.
+    pub compare_on_match: bool,
+    /// Before hashing a file to store it, check a cheap fingerprint (an
+    /// xxh3 hash of its size and first 4 KiB) against those of the stored
+    /// objects.  Files that match none are hashed while being copied in,
+    /// reading them once rather than twice.  Worthwhile for bulk ingestion
+    /// of mostly new files; the fingerprints of a store are gathered on
+    /// first use, which reads the start of every object.
+    pub precheck: bool,
+    /// Remember the digests of files stored by path, keyed by device,
+    /// inode, size and modification time, so that storing or importing an
+    /// unchanged file again skips hashing it.  The cache is kept in the
+    /// storage path.  Only has an effect on Unix.
+    pub hash_cache: bool,
+    /// Let at most this many stores (and imported files) hash and copy
+    /// content at once, with the rest waiting their turn, so that a burst
+    /// of stores doesn't exhaust file descriptors or thrash the disk.  The
+    /// count is shared by every handle on the store in this process.
+    pub max_concurrent_operations: Option<usize>,
+    /// Accept keys of this digest, from before the store was moved off it
+    /// by `rehash_store()`, when retrieving through the handle: a key with
+    /// nothing stored under it is looked up in the store's key map, and
+    /// the object it was moved to is retrieved instead.  This lets
+    /// applications move the keys they hold over gradually (see
+    /// `translate_key()`).  Stores and deletes take current keys only.
+    pub legacy_hash: Option<HashAlgorithm>,
+    /// Sniff the MIME type of content from its first bytes when storing it
+    /// through the handle, and record it for `content_type()`, so that
+    /// servers can set `Content-Type` without reading the object.  Content
+    /// that is neither a recognised format nor UTF-8 text is recorded as
+    /// `application/octet-stream`.
+    pub detect_content_type: bool,
+    /// Tag each object stored through the handle with an HMAC of its key
+    /// and content under this secret, and check the tag whenever an object
+    /// is retrieved through the handle, failing with `ErrorKind::Tampered`
+    /// if it is missing or wrong.  This catches objects changed or added by
+    /// anyone with access to the storage path but not the secret.  Costs a
+    /// second read of new content, and a full read of objects retrieved as
+    /// files or readers before they are handed over.  Tag objects stored
+    /// before this was set with `add_auth_tags()`.
+    pub authenticate: Option<AuthSecret>,
+    /// Read content in blocks of this many bytes when hashing it and
+    /// copying it in through the handle, rather than
+    /// `hashable::DEFAULT_BUFFER_SIZE`.  Larger blocks mean fewer system
+    /// calls, which tells on fast disks, at the cost of a buffer of this
+    /// size for each store under way.
+    pub io_buffer_size: Option<usize>,
+    /// Append an entry to the store's audit log (see the `audit` module)
+    /// for every store and delete through the handle, and every object
+    /// removed by `FileStore::gc()`.  An operation whose entry can't be
+    /// written fails, though its change has been made.
+    pub audit: bool,
+}
+
+impl Config {
+    // The block size to read content in
+    pub(crate) fn buffer_size(&self) -> usize {
+        self.io_buffer_size.filter(|&size| size > 0).unwrap_or(DEFAULT_BUFFER_SIZE)
+    }
+
+    // Fails with `ErrorKind::TooLarge` if `len` bytes of content is more
+    // than the handle accepts
+    pub(crate) fn check_object_size(&self, len: u64) -> Result<(), Error> {
+        match self.max_object_size {
+            Some(limit) if len > limit => {
+                Err(From::from((ErrorKind::TooLarge { limit }, "Content is too large to store")))
+            },
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+// The MIME types of stored objects (`Config::detect_content_type`), sniffed
+// from the first bytes of their content when they are stored, so that
+// servers can set `Content-Type` without reading objects to find it.
+//
+// Types are kept under `types/` in the storage path, one small text file
+// per object laid out as the shards are.  As content never changes once
+// stored, a type is recorded once and only goes when its object does.
+
+use std::fs::{self,File};
+use std::io::{self,Read};
+use std::path::{Path,PathBuf};
+
+use super::{Error,FileKey,replace,spool,usage};
+use super::usage::Part;
+
+// Enough for every signature below
+const SNIFF_LEN: usize = 512;
+
+fn types_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("types")
+}
+
+fn type_path(storage_path: &Path, key: &FileKey) -> PathBuf {
+    types_dir(storage_path).join(&key[..2]).join(&key[2..])
+}
+
+/// The MIME type recorded for the object `key` when it was stored (see
+/// `Config::detect_content_type`), or `None` if none was
+pub fn content_type(storage_path: &Path, key: &FileKey) -> Result<Option<String>, Error> {
+    if !key.is_well_formed() {
+        return Ok(None);
+    }
+    match fs::read_to_string(type_path(storage_path, key)) {
+        Ok(text) => Ok(Some(text.trim_end().to_owned())),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(From::from((e, "Unable to read content type"))),
+    }
+}
+
+/// Sniff and record the type of the object `key`, stored at `path`, unless
+/// it has one already
+pub(crate) fn record(storage_path: &Path, key: &FileKey, path: &Path) -> Result<(), Error> {
+    let type_path = type_path(storage_path, key);
+    if type_path.exists() {
+        return Ok(());
+    }
+    let mut head: Vec<u8> = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)
+        .and_then(|f| f.take(SNIFF_LEN as u64).read_to_end(&mut head))
+        .map_err(|e| { (e, "Unable to read stored file") } )?;
+    if let Some(dir) = type_path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| { (e, "Unable to create types directory") } )?;
+    }
+    // Written aside and moved into place, so readers never see part of it
+    let change = usage::change(storage_path, Part::Metadata, &type_path);
+    let temp_path = spool::temp_path(storage_path);
+    let written = fs::write(&temp_path, sniff(&head))
+        .and_then(|()| replace::replace(&temp_path, &type_path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(From::from((e, "Unable to write content type")));
+    }
+    change.done();
+    Ok(())
+}
+
+/// Move the type recorded for `old` to `new`, for `rehash_store()`
+pub(crate) fn rename_key(storage_path: &Path, old: &FileKey, new: &FileKey) -> Result<(), Error> {
+    let from = type_path(storage_path, old);
+    if !from.exists() {
+        return Ok(());
+    }
+    let to = type_path(storage_path, new);
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| { (e, "Unable to create types directory") } )?;
+    }
+    fs::rename(&from, &to)
+        .map_err(|e| { From::from((e, "Unable to move content type")) } )
+}
+
+/// Drop the type of an object that has been removed
+pub(crate) fn forget(storage_path: &Path, key: &FileKey) -> Result<(), Error> {
+    let path = type_path(storage_path, key);
+    let change = usage::change(storage_path, Part::Metadata, &path);
+    match fs::remove_file(&path) {
+        Ok(()) => {
+            change.done();
+            Ok(())
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(From::from((e, "Unable to remove content type"))),
+    }
+}
+
+// Signatures at the start of content, most specific first
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"II*\0", "image/tiff"),
+    (b"MM\0*", "image/tiff"),
+    (b"\0\0\x01\0", "image/vnd.microsoft.icon"),
+    (b"BM", "image/bmp"),
+    (b"%PDF-", "application/pdf"),
+    (b"%!PS", "application/postscript"),
+    (b"{\\rtf", "application/rtf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"PK\x05\x06", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\0", "application/x-xz"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"Rar!\x1a\x07", "application/vnd.rar"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"\0asm", "application/wasm"),
+    (b"SQLite format 3\0", "application/vnd.sqlite3"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1aE\xdf\xa3", "video/x-matroska"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OTTO", "font/otf"),
+    (b"\0\x01\0\0", "font/ttf"),
+];
+
+// The MIME type of content starting with `head`
+fn sniff(head: &[u8]) -> &'static str {
+    if let Some((_, mime)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return mime;
+    }
+    // Containers naming their format a few bytes in
+    if head.len() >= 12 && head.starts_with(b"RIFF") {
+        match &head[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            b"AVI " => return "video/x-msvideo",
+            _ => {},
+        }
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return match &head[8..12] {
+            b"qt  " => "video/quicktime",
+            b"avif" => "image/avif",
+            b"heic" | b"heix" => "image/heic",
+            b"M4A " => "audio/mp4",
+            _ => "video/mp4",
+        };
+    }
+    if head.len() >= 262 && &head[257..262] == b"ustar" {
+        return "application/x-tar";
+    }
+    if !is_text(head) {
+        return "application/octet-stream";
+    }
+    let text = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    let start = text.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(text.len());
+    let text = &text[start..];
+    let starts_with_tag = |tag: &[u8]| {
+        text.len() >= tag.len() && text[..tag.len()].eq_ignore_ascii_case(tag)
+    };
+    if starts_with_tag(b"<!doctype html") || starts_with_tag(b"<html") {
+        "text/html; charset=utf-8"
+    } else if starts_with_tag(b"<svg") {
+        "image/svg+xml"
+    } else if starts_with_tag(b"<?xml") {
+        "application/xml"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+// Whether `head` looks like the start of UTF-8 text: valid, but for a
+// character cut off at the end, and free of control characters other than
+// whitespace
+fn is_text(head: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(head) {
+        Ok(_) => head.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => return false,
+    };
+    head[..valid].iter()
+        .all(|&b| (b >= 0x20 && b != 0x7f) || matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+}
@@ -0,0 +1,264 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A cache of artifacts derived from stored objects, such as thumbnails,
+//! transcodes or extracted text, looked up by the source object's key and
+//! the name of the transform that made them.
+//!
+//! Derived content is stored, and deduplicated, as any other object, and
+//! kept under `derived/<source key>/<transform>` in the storage path as the
+//! key it was stored under.  Each entry holds a reference on its artifact
+//! for as long as the source is stored: deleting the source's last
+//! reference releases them, and `gc()` releases any left behind (as when
+//! the source was removed through the last pin on it, or deleted while an
+//! artifact was being added), but not those of a quarantined source.  Lookups treat the entries of a source that
+//! is no longer stored as gone.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self,Write};
+use std::path::{Path,PathBuf};
+
+use super::{Config,Error,FileKey};
+use super::quarantine;
+use super::spool::Spool;
+use super::writelock;
+
+fn derived_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("derived")
+}
+
+fn source_dir(storage_path: &Path, source: &FileKey) -> PathBuf {
+    derived_dir(storage_path).join(&source.0)
+}
+
+fn entry_path(storage_path: &Path, source: &FileKey, transform: &str) -> Result<PathBuf, Error> {
+    if !source.is_well_formed() {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "malformed key"),
+                               "Invalid key")));
+    }
+    if !super::is_valid_name(transform) {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput,
+                                              "malformed transform name"),
+                               "Invalid transform name")));
+    }
+    Ok(source_dir(storage_path, source).join(transform))
+}
+
+fn is_stored(storage_path: &Path, key: &FileKey) -> Result<bool, Error> {
+    match fs::metadata(super::storage_file_path(storage_path, key)) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(From::from((e, "Unable to stat stored file"))),
+    }
+}
+
+/// Store `data` as what `transform` makes of the stored object `source`,
+/// replacing any artifact already cached for them, and return the key it
+/// was stored under.  Fails with an `io` of kind `NotFound` if `source`
+/// is not stored.
+///
+/// Transform names may use ASCII letters, digits, `-`, `_` and `.`, and
+/// should say everything the artifact depends on, e.g. `thumb-256.webp`.
+pub fn store_derived<D: AsRef<[u8]> + ?Sized>(storage_path: &Path, source: &FileKey,
+                                              transform: &str, data: &D)
+                                              -> Result<FileKey, Error>
+{
+    let path = entry_path(storage_path, source, transform)?;
+    let _writing = writelock::begin(storage_path)?;
+    if !is_stored(storage_path, source)? {
+        return Err(From::from((io::Error::from(io::ErrorKind::NotFound),
+                               "Unable to find stored file")));
+    }
+    let config = Config::default();
+    let previous = read_entry(&path)?;
+
+    // As for refs, the new artifact is referenced before the entry is
+    // written, and the old one released after
+    let (key, _) = super::store_data_new(storage_path, &config, data.as_ref())?;
+    if let Err(e) = write_entry(storage_path, source, &path, &key) {
+        if let Err(e) = super::release(storage_path, &config, &key) {
+            log::warn!("Unable to release {} after failed derived store: {:?}", key, e);
+        }
+        return Err(e);
+    }
+    // The same artifact again holds one reference, not two
+    if let Some(previous) = previous {
+        super::release(storage_path, &config, &previous)?;
+    }
+    Ok(key)
+}
+
+/// The key of what `transform` made of `source`, or `None` if nothing is
+/// cached for them (or `source` is no longer stored)
+pub fn get_derived(storage_path: &Path, source: &FileKey, transform: &str)
+                   -> Result<Option<FileKey>, Error>
+{
+    let path = entry_path(storage_path, source, transform)?;
+    if !is_stored(storage_path, source)? {
+        return Ok(None);
+    }
+    read_entry(&path)
+}
+
+/// Every artifact cached for `source`, by transform
+pub fn list_derived(storage_path: &Path, source: &FileKey)
+                    -> Result<BTreeMap<String, FileKey>, Error>
+{
+    let mut derived: BTreeMap<String, FileKey> = BTreeMap::new();
+    if !source.is_well_formed() || !is_stored(storage_path, source)? {
+        return Ok(derived);
+    }
+    for (transform, key) in entries(storage_path, source)? {
+        derived.insert(transform, key);
+    }
+    Ok(derived)
+}
+
+/// Drop the artifact `transform` made of `source`, releasing its reference.
+/// Returns its key, or `None` if none was cached.
+pub fn delete_derived(storage_path: &Path, source: &FileKey, transform: &str)
+                      -> Result<Option<FileKey>, Error>
+{
+    let path = entry_path(storage_path, source, transform)?;
+    let _writing = writelock::begin(storage_path)?;
+    let key = match read_entry(&path)? {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    fs::remove_file(&path)
+        .map_err(|e| { (e, "Unable to remove derived entry") } )?;
+    // Fails, harmlessly, while other artifacts are cached for the source
+    let _ = fs::remove_dir(source_dir(storage_path, source));
+    super::release(storage_path, &Config::default(), &key)?;
+    Ok(Some(key))
+}
+
+fn write_entry(storage_path: &Path, source: &FileKey, path: &Path, key: &FileKey)
+               -> Result<(), Error>
+{
+    fs::create_dir_all(source_dir(storage_path, source))
+        .map_err(|e| { (e, "Unable to create derived directory") } )?;
+    let mut spool = Spool::new(storage_path, false)?;
+    writeln!(spool, "{}", key)
+        .map_err(|e| { (e, "Unable to write derived entry") } )?;
+    spool.persist(path)
+}
+
+fn read_entry(path: &Path) -> Result<Option<FileKey>, Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(From::from((e, "Unable to read derived entry"))),
+    };
+    let key = FileKey(text.trim_end().to_owned());
+    if !key.is_well_formed() {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidData, "malformed entry"),
+                               "Derived entry is damaged")));
+    }
+    Ok(Some(key))
+}
+
+// The entries of `source`, whether or not it is still stored
+fn entries(storage_path: &Path, source: &FileKey) -> Result<Vec<(String, FileKey)>, Error> {
+    let mut found: Vec<(String, FileKey)> = Vec::new();
+    let dir = match fs::read_dir(source_dir(storage_path, source)) {
+        Ok(dir) => dir,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(found),
+        Err(e) => return Err(From::from((e, "Unable to read derived directory"))),
+    };
+    for entry in dir {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read derived directory") } )?;
+        let transform = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if !super::is_valid_name(&transform) {
+            continue;
+        }
+        if let Some(key) = read_entry(&entry.path())? {
+            found.push((transform, key));
+        }
+    }
+    Ok(found)
+}
+
+// The sources that have entries, whether or not they are still stored
+fn sources(storage_path: &Path) -> Result<Vec<FileKey>, Error> {
+    let mut found: Vec<FileKey> = Vec::new();
+    let dir = match fs::read_dir(derived_dir(storage_path)) {
+        Ok(dir) => dir,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(found),
+        Err(e) => return Err(From::from((e, "Unable to read derived directory"))),
+    };
+    for entry in dir {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read derived directory") } )?;
+        if let Ok(name) = entry.file_name().into_string() {
+            let key = FileKey(name);
+            if key.is_well_formed() {
+                found.push(key);
+            }
+        }
+    }
+    Ok(found)
+}
+
+// Drop every entry of `source`, releasing the artifacts, once the source
+// is gone.  Returns the (source, transform) of each.
+pub(crate) fn release_all(storage_path: &Path, source: &FileKey)
+                          -> Result<Vec<(FileKey, String)>, Error>
+{
+    let mut released: Vec<(FileKey, String)> = Vec::new();
+    for (transform, key) in entries(storage_path, source)? {
+        fs::remove_file(source_dir(storage_path, source).join(&transform))
+            .map_err(|e| { (e, "Unable to remove derived entry") } )?;
+        super::release(storage_path, &Config::default(), &key)?;
+        released.push((source.clone(), transform));
+    }
+    let _ = fs::remove_dir(source_dir(storage_path, source));
+    Ok(released)
+}
+
+// Release the artifacts of sources that are no longer stored, for `gc()`
+pub(crate) fn prune(storage_path: &Path) -> Result<Vec<(FileKey, String)>, Error> {
+    let mut released: Vec<(FileKey, String)> = Vec::new();
+    for source in sources(storage_path)? {
+        if !is_stored(storage_path, &source)? && !quarantine::is_quarantined(storage_path, &source) {
+            released.extend(release_all(storage_path, &source)?);
+        }
+    }
+    Ok(released)
+}
+
+// Every entry as (source, transform, artifact), for `rehash_store()`
+pub(crate) fn list_all(storage_path: &Path) -> Result<Vec<(FileKey, String, FileKey)>, Error> {
+    let mut all: Vec<(FileKey, String, FileKey)> = Vec::new();
+    for source in sources(storage_path)? {
+        for (transform, key) in entries(storage_path, &source)? {
+            all.push((source.clone(), transform, key));
+        }
+    }
+    Ok(all)
+}
+
+// Move the entries of `old` to `new`, for `rehash_store()`
+pub(crate) fn rename_source(storage_path: &Path, old: &FileKey, new: &FileKey)
+                            -> Result<(), Error>
+{
+    let from = source_dir(storage_path, old);
+    if !from.exists() {
+        return Ok(());
+    }
+    fs::rename(&from, source_dir(storage_path, new))
+        .map_err(|e| { From::from((e, "Unable to move derived entries")) } )
+}
+
+// Point an entry at `key` without moving any reference, for
+// `rehash_store()`, which moves the references along with the object
+pub(crate) fn retarget(storage_path: &Path, source: &FileKey, transform: &str, key: &FileKey)
+                       -> Result<(), Error>
+{
+    write_entry(storage_path, source, &entry_path(storage_path, source, transform)?, key)
+}
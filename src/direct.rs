@@ -0,0 +1,109 @@
+// Writing object content around the page cache (`Config::direct_io`), so
+// that bulk ingestion doesn't evict everything else from memory.
+//
+// On Linux files are switched to O_DIRECT, which needs writes whose memory,
+// length and file offset are all block aligned.  Content is gathered in an
+// aligned buffer and written a whole buffer at a time; the unaligned tail is
+// written at the end with O_DIRECT switched off.  Elsewhere, or where the
+// filesystem refuses O_DIRECT, writes go straight to the file as usual.
+
+use std::fs::{File,OpenOptions};
+use std::io::{self,Write};
+use std::path::Path;
+
+// Covers the logical block size of any device in common use
+const ALIGN: usize = 4096;
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+pub(crate) struct DirectWriter {
+    file: File,
+    // Over-allocated by ALIGN so that an aligned window can be taken from it
+    buf: Vec<u8>,
+    start: usize,
+    len: usize,
+    direct: bool,
+}
+
+impl DirectWriter {
+    /// Open `path` with `options`, switching to O_DIRECT if `direct` is
+    /// asked for and available
+    pub fn open(options: &OpenOptions, path: &Path, direct: bool) -> io::Result<DirectWriter> {
+        let file = options.open(path)?;
+        if direct && set_direct(&file, true)? {
+            let buf = vec![0_u8; BUFFER_SIZE + ALIGN];
+            let start = buf.as_ptr().align_offset(ALIGN);
+            return Ok(DirectWriter { file, buf, start, len: 0, direct: true });
+        }
+        Ok(DirectWriter { file, buf: Vec::new(), start: 0, len: 0, direct: false })
+    }
+
+    /// The file being written
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Write out anything still buffered, returning the file
+    pub fn finish(mut self) -> io::Result<File> {
+        if self.direct && self.len > 0 {
+            let aligned = self.len - self.len % ALIGN;
+            let (start, len) = (self.start, self.len);
+            self.file.write_all(&self.buf[start..start + aligned])?;
+            if aligned < len {
+                set_direct(&self.file, false)?;
+                self.file.write_all(&self.buf[start + aligned..start + len])?;
+            }
+            self.len = 0;
+        }
+        Ok(self.file)
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if !self.direct {
+            return self.file.write(data);
+        }
+        if self.len == BUFFER_SIZE {
+            let start = self.start;
+            self.file.write_all(&self.buf[start..start + BUFFER_SIZE])?;
+            self.len = 0;
+        }
+        let count = data.len().min(BUFFER_SIZE - self.len);
+        let at = self.start + self.len;
+        self.buf[at..at + count].copy_from_slice(&data[..count]);
+        self.len += count;
+        Ok(count)
+    }
+
+    // Buffered content can only be written in whole blocks, so waits for
+    // `finish()`
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Switch O_DIRECT on or off, returning whether that was possible
+#[cfg(target_os = "linux")]
+fn set_direct(file: &File, on: bool) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if on { flags | libc::O_DIRECT } else { flags & !libc::O_DIRECT };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        let e = io::Error::last_os_error();
+        // The filesystem doesn't support O_DIRECT (tmpfs, for one)
+        if e.raw_os_error() == Some(libc::EINVAL) {
+            return Ok(false);
+        }
+        return Err(e);
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_direct(_file: &File, _on: bool) -> io::Result<bool> {
+    Ok(false)
+}
@@ -0,0 +1,226 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Writing keys as text other than hex, for shorter identifiers in URLs and
+//! QR codes.
+//!
+//! A `FileKey` is always lowercase hex, as are the object paths, so the
+//! layout on disk is the same whatever the encoding.  A store's manifest
+//! records the encoding its keys are shown to users in
+//! (`set_key_encoding()`), and `FileStore::encode_key()` and
+//! `FileStore::parse_key()` use it, so that a store's keys are all given
+//! out one way and keys meant for another store aren't taken by mistake.
+//! Base32 is RFC 4648's alphabet in lowercase, and base64url is RFC 4648's
+//! URL-safe alphabet, both without padding.
+//!
+//! Keys can also be given out with the name of their algorithm in front,
+//! as in `sha224-2d6d67d9…` (`set_key_prefixed()`), so that a key says how
+//! its content is to be verified without leaning on its length, and keys
+//! from stores using different algorithms can be told apart at a glance.
+//! Prefixed keys are taken by `parse_key()` whether or not the store gives
+//! them out, as they can't be mistaken for anything else; plain keys only
+//! where the store gives out plain keys.
+
+use std::fmt;
+use std::path::Path;
+
+use super::{Error,FileKey,manifest,writelock};
+use super::hashable::HashAlgorithm;
+
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+const BASE64URL: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// How keys are written as text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum KeyEncoding {
+    /// Lowercase hex, as `FileKey` holds them: 56 characters for sha224
+    #[default]
+    Hex,
+    /// Lowercase base32: 45 characters for sha224
+    Base32,
+    /// Base64url: 38 characters for sha224
+    Base64Url,
+}
+
+impl KeyEncoding {
+    /// Every encoding supported
+    pub const ALL: [KeyEncoding; 3] =
+        [KeyEncoding::Hex, KeyEncoding::Base32, KeyEncoding::Base64Url];
+
+    /// The name the manifest records, e.g. `base32`
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyEncoding::Hex => "hex",
+            KeyEncoding::Base32 => "base32",
+            KeyEncoding::Base64Url => "base64url",
+        }
+    }
+
+    /// The encoding with the given `name()`
+    pub fn from_name(name: &str) -> Option<KeyEncoding> {
+        KeyEncoding::ALL.iter().copied().find(|encoding| encoding.name() == name)
+    }
+
+    /// `key` written in this encoding.  Keys that aren't well formed are
+    /// given back as they are.
+    pub fn encode(self, key: &FileKey) -> String {
+        let digest = match key.compact() {
+            Some(compact) => compact,
+            None => return key.0.clone(),
+        };
+        let digest = digest.digest();
+        match self {
+            KeyEncoding::Hex => key.0.clone(),
+            KeyEncoding::Base32 => encode_bits(digest, 5, BASE32),
+            KeyEncoding::Base64Url => encode_bits(digest, 6, BASE64URL),
+        }
+    }
+
+    /// The key written as `text` in this encoding, or `None` if `text`
+    /// isn't a key so written
+    pub fn decode(self, text: &str) -> Option<FileKey> {
+        let key = match self {
+            KeyEncoding::Hex => FileKey(text.to_owned()),
+            KeyEncoding::Base32 => FileKey(to_hex(&decode_bits(text, 5, BASE32)?)),
+            KeyEncoding::Base64Url => FileKey(to_hex(&decode_bits(text, 6, BASE64URL)?)),
+        };
+        // Reencoded to turn away stray trailing bits
+        match key.is_well_formed() && self.encode(&key) == text {
+            true => Some(key),
+            false => None,
+        }
+    }
+
+    /// `key` written in this encoding behind the name of its algorithm and
+    /// a `-`.  Keys that aren't well formed are given back as they are.
+    pub fn encode_prefixed(self, key: &FileKey) -> String {
+        match key.algorithm() {
+            Some(algorithm) if key.is_well_formed() => {
+                format!("{}-{}", algorithm.name(), self.encode(key))
+            },
+            _ => key.0.clone(),
+        }
+    }
+
+    /// The key written as `text` in this encoding behind the name of its
+    /// algorithm, or `None` if `text` isn't a key so written or the name
+    /// doesn't match the digest
+    pub fn decode_prefixed(self, text: &str) -> Option<FileKey> {
+        let (algorithm, rest) = HashAlgorithm::ALL.iter()
+            .find_map(|algorithm| {
+                text.strip_prefix(algorithm.name())
+                    .and_then(|rest| rest.strip_prefix('-'))
+                    .map(|rest| (*algorithm, rest))
+            })?;
+        self.decode(rest).filter(|key| key.algorithm() == Some(algorithm))
+    }
+}
+
+impl fmt::Display for KeyEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The encoding the store at `storage_path` gives keys out in: hex for
+/// stores without a manifest
+pub fn key_encoding(storage_path: &Path) -> Result<KeyEncoding, Error> {
+    Ok(manifest::current(storage_path)?.keys)
+}
+
+/// Record in the manifest of the store at `storage_path` that its keys are
+/// given out in `encoding`.  Keys handed out before in another encoding
+/// are no longer taken by `FileStore::parse_key()`.
+pub fn set_key_encoding(storage_path: &Path, encoding: KeyEncoding) -> Result<(), Error> {
+    let _writing = writelock::begin(storage_path)?;
+    let mut manifest = manifest::read(storage_path)?;
+    if manifest.keys != encoding {
+        manifest.keys = encoding;
+        manifest::write(storage_path, &manifest)?;
+    }
+    Ok(())
+}
+
+/// Whether the store at `storage_path` gives keys out behind the names of
+/// their algorithms
+pub fn key_prefixed(storage_path: &Path) -> Result<bool, Error> {
+    Ok(manifest::current(storage_path)?.prefixed)
+}
+
+/// Record in the manifest of the store at `storage_path` whether its keys
+/// are given out behind the names of their algorithms.  Plain keys handed
+/// out before are no longer taken by `parse_key()` once they are.
+pub fn set_key_prefixed(storage_path: &Path, prefixed: bool) -> Result<(), Error> {
+    let _writing = writelock::begin(storage_path)?;
+    let mut manifest = manifest::read(storage_path)?;
+    if manifest.prefixed != prefixed {
+        manifest.prefixed = prefixed;
+        manifest::write(storage_path, &manifest)?;
+    }
+    Ok(())
+}
+
+/// `key` written as the store at `storage_path` gives keys out, in its key
+/// encoding and with its algorithm's name in front if the store asks
+pub fn encode_key(storage_path: &Path, key: &FileKey) -> Result<String, Error> {
+    let manifest = manifest::current(storage_path)?;
+    Ok(match manifest.prefixed {
+        true => manifest.keys.encode_prefixed(key),
+        false => manifest.keys.encode(key),
+    })
+}
+
+/// The key written as `text` in the key encoding of the store at
+/// `storage_path`, prefixed with its algorithm's name or, if the store
+/// gives out plain keys, not.  `None` if `text` isn't a key so written.
+pub fn parse_key(storage_path: &Path, text: &str) -> Result<Option<FileKey>, Error> {
+    let manifest = manifest::current(storage_path)?;
+    if let Some(key) = manifest.keys.decode_prefixed(text) {
+        return Ok(Some(key));
+    }
+    Ok(match manifest.prefixed {
+        true => None,
+        false => manifest.keys.decode(text),
+    })
+}
+
+// `bytes` written `bits` at a time from `alphabet`, the last group padded
+// with zero bits
+fn encode_bits(bytes: &[u8], bits: u32, alphabet: &[u8]) -> String {
+    let mut text = String::with_capacity((bytes.len() * 8).div_ceil(bits as usize));
+    let mut buffer: u32 = 0;
+    let mut held: u32 = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        held += 8;
+        while held >= bits {
+            held -= bits;
+            text.push(alphabet[((buffer >> held) & ((1 << bits) - 1)) as usize] as char);
+        }
+    }
+    if held > 0 {
+        text.push(alphabet[((buffer << (bits - held)) & ((1 << bits) - 1)) as usize] as char);
+    }
+    text
+}
+
+fn decode_bits(text: &str, bits: u32, alphabet: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(text.len() * bits as usize / 8);
+    let mut buffer: u32 = 0;
+    let mut held: u32 = 0;
+    for c in text.bytes() {
+        let value = alphabet.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << bits) | value;
+        held += bits;
+        if held >= 8 {
+            held -= 8;
+            bytes.push((buffer >> held) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
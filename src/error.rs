@@ -7,9 +7,93 @@ use std::io;
 
 use log::Level;
 
+use super::filekey::FileKey;
+
+/// The filestore-specific reason for an error, for callers that need to
+/// tell them apart.  Most errors are `Io`, described by the `io` field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An I/O (or data) error
+    Io,
+    /// A store or delete was attempted through a read-only handle
+    ReadOnly,
+    /// Storing would take the store beyond its configured quota
+    QuotaExceeded,
+    /// The content is longer than `Config::max_object_size` allows
+    TooLarge {
+        /// The most bytes an object may have
+        limit: u64,
+    },
+    /// An object's refcount file is damaged, so its reference count is
+    /// unknown.  The object is left alone until this is repaired.
+    CorruptRefcount,
+    /// Content hashed to the key of a stored object whose content differs:
+    /// a hash collision, or a corrupted object
+    HashCollision,
+    /// Taking another reference would overflow an object's reference count
+    RefcountOverflow,
+    /// The storage filesystem lacks the space for the content
+    DiskFull {
+        /// Bytes the content needs
+        required: u64,
+        /// Bytes available when checked
+        available: u64,
+    },
+    /// Another process holds the store's exclusive writer lock (see
+    /// `FileStore::open_exclusive()`), or is writing to it while that lock
+    /// was being taken
+    Locked,
+    /// The directory is not a store made by `FileStore::init()`
+    NotAStore,
+    /// An abbreviated key (see `resolve_prefix()`) matches more than one
+    /// stored object
+    AmbiguousPrefix {
+        /// The keys of the objects it matches
+        matches: Vec<FileKey>,
+    },
+    /// An access token (see `token::TokenSigner`) is malformed or its
+    /// signature is wrong
+    InvalidToken,
+    /// An access token was valid, but its time is up
+    TokenExpired,
+    /// A stored object's authenticity tag (see `Config::authenticate`) is
+    /// missing or doesn't match it: it was changed, or added, by something
+    /// other than a handle holding the secret
+    Tampered,
+    /// The object was found corrupt by `verify()` and moved to quarantine
+    /// (see the `quarantine` module)
+    Quarantined,
+}
+
+impl ErrorKind {
+    fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            ErrorKind::Io => io::ErrorKind::Other,
+            ErrorKind::ReadOnly => io::ErrorKind::ReadOnlyFilesystem,
+            ErrorKind::QuotaExceeded => io::ErrorKind::QuotaExceeded,
+            ErrorKind::TooLarge { .. } => io::ErrorKind::FileTooLarge,
+            ErrorKind::CorruptRefcount => io::ErrorKind::InvalidData,
+            ErrorKind::RefcountOverflow => io::ErrorKind::Other,
+            ErrorKind::HashCollision => io::ErrorKind::InvalidData,
+            ErrorKind::DiskFull { .. } => io::ErrorKind::StorageFull,
+            ErrorKind::Locked => io::ErrorKind::WouldBlock,
+            ErrorKind::NotAStore => io::ErrorKind::InvalidInput,
+            ErrorKind::AmbiguousPrefix { .. } => io::ErrorKind::InvalidInput,
+            ErrorKind::InvalidToken => io::ErrorKind::PermissionDenied,
+            ErrorKind::TokenExpired => io::ErrorKind::PermissionDenied,
+            ErrorKind::Tampered => io::ErrorKind::InvalidData,
+            ErrorKind::Quarantined => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
 pub struct Error {
+    pub kind: ErrorKind,
     pub io: io::Error,
     pub message: String,
+    /// How many times the operation was tried: more than one where a
+    /// `RetryPolicy` retried it
+    pub attempts: u32,
 }
 
 impl Error {
@@ -19,6 +103,18 @@ impl Error {
             _ => Level::Warn,
         }
     }
+
+    /// Whether the error is likely to go away if the operation is tried
+    /// again, as network filesystems' stale handles and timeouts do
+    pub fn is_transient(&self) -> bool {
+        if self.kind != ErrorKind::Io {
+            return false;
+        }
+        matches!(self.io.kind(),
+                 io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::TimedOut
+                 | io::ErrorKind::StaleNetworkFileHandle | io::ErrorKind::ResourceBusy
+                 | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted)
+    }
 }
 
 impl StdError for Error {
@@ -30,13 +126,16 @@ impl StdError for Error {
 // This is for the Developer and Log files
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(&*self.io.to_string())?;
+        f.write_str(&self.io.to_string())?;
         if ! self.message.is_empty() {
             write!(f, " = {}", self.message)?;
         }
         if self.source().is_some() {
             write!(f, ": {:?}", self.source().unwrap())?;
         }
+        if self.attempts > 1 {
+            write!(f, " (after {} attempts)", self.attempts)?;
+        }
         Ok(())
     }
 }
@@ -44,12 +143,47 @@ impl fmt::Debug for Error {
 // This is for the end user
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::ReadOnly => return write!(f, "The store is read-only."),
+            ErrorKind::QuotaExceeded => return write!(f, "The store is full."),
+            ErrorKind::TooLarge { limit } => {
+                return write!(f, "The content is larger than the store accepts ({} bytes).",
+                              limit);
+            },
+            ErrorKind::DiskFull { required, available } => {
+                return write!(f, "Not enough disk space: {} bytes needed, {} available.",
+                              required, available);
+            },
+            ErrorKind::CorruptRefcount => {
+                return write!(f, "The reference count of a stored file is damaged.");
+            },
+            ErrorKind::HashCollision => {
+                return write!(f, "The content does not match the stored file with the same key.");
+            },
+            ErrorKind::RefcountOverflow => {
+                return write!(f, "A stored file has too many references.");
+            },
+            ErrorKind::Locked => return write!(f, "The store is locked by another writer."),
+            ErrorKind::NotAStore => return write!(f, "The directory is not a file store."),
+            ErrorKind::AmbiguousPrefix { ref matches } => {
+                return write!(f, "The abbreviated key matches {} stored files.", matches.len());
+            },
+            ErrorKind::InvalidToken => return write!(f, "The access token is not valid."),
+            ErrorKind::TokenExpired => return write!(f, "The access token has expired."),
+            ErrorKind::Tampered => {
+                return write!(f, "A stored file failed its authenticity check.");
+            },
+            ErrorKind::Quarantined => {
+                return write!(f, "A stored file was found corrupt and set aside.");
+            },
+            ErrorKind::Io => {},
+        }
         match self.io.kind() {
             io::ErrorKind::NotFound => {
                 write!(f, "The file requested was not found.")
             }
             _ => {
-                write!(f, "{}: ", self.io.to_string())?;
+                write!(f, "{}: ", self.io)?;
                 self.io.fmt(f) // trust upstream?
             },
         }
@@ -59,8 +193,10 @@ impl fmt::Display for Error {
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error {
+            kind: ErrorKind::Io,
             io: err,
-            message: "".to_owned()
+            message: "".to_owned(),
+            attempts: 1,
         }
     }
 }
@@ -68,8 +204,21 @@ impl From<io::Error> for Error {
 impl<'a> From<(io::Error, &'a str)> for Error {
     fn from((err, message): (io::Error, &'a str)) -> Error {
         Error {
+            kind: ErrorKind::Io,
             io: err,
             message: message.to_owned(),
+            attempts: 1,
+        }
+    }
+}
+
+impl<'a> From<(ErrorKind, &'a str)> for Error {
+    fn from((kind, message): (ErrorKind, &'a str)) -> Error {
+        Error {
+            io: io::Error::from(kind.io_kind()),
+            kind,
+            message: message.to_owned(),
+            attempts: 1,
         }
     }
 }
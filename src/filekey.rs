@@ -1,15 +1,20 @@
 // Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
 // This code is licensed under the MIT license (see LICENSE-MIT for details)
 
+use std::cmp::Ordering;
 use std::fmt;
+use std::hash::{Hash,Hasher};
+use std::io::{self,Read};
 use std::ops::Deref;
+use super::Error;
+use super::hashable::{HashAlgorithm,KeyHasher};
 #[cfg(feature = "serde")]
 use serde::{Serialize, Deserialize};
 #[cfg(feature = "postgres")]
 use postgres::types::{ToSql, FromSql};
 
 /// A key issued at storage, used to retrieve your file
-#[derive(PartialEq, Eq, Debug, Clone)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[cfg_attr(feature = "postgres", derive(ToSql, FromSql))]
 pub struct FileKey(pub String);
@@ -18,7 +23,139 @@ impl fmt::Display for FileKey
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error>
     {
-        write!(f, "{}", &*self)
+        write!(f, "{}", &self.0)
+    }
+}
+
+impl FileKey {
+    /// Whether this key has the shape of a key issued by this crate (a
+    /// lowercase hex digest from one of the `HashAlgorithm`s).  Keys from
+    /// untrusted sources (command lines, URLs) should be checked before use.
+    pub fn is_well_formed(&self) -> bool {
+        self.algorithm().is_some() &&
+            self.0.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    }
+
+    /// The algorithm whose digests have this key's length
+    pub fn algorithm(&self) -> Option<HashAlgorithm> {
+        HashAlgorithm::ALL.iter().copied().find(|algorithm| algorithm.key_len() == self.0.len())
+    }
+
+    /// Whether `input` is the content this key stands for, hashed with the
+    /// algorithm the key itself tells, so that content can be checked
+    /// without the store that keyed it.  Fails for keys that aren't well
+    /// formed.
+    pub fn verify(&self, input: &mut dyn Read) -> Result<bool, Error> {
+        let algorithm = match self.algorithm() {
+            Some(algorithm) if self.is_well_formed() => algorithm,
+            _ => return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput,
+                                                       "malformed key"),
+                                        "Invalid key"))),
+        };
+        let mut hasher = KeyHasher::with_algorithm(algorithm);
+        io::copy(input, &mut hasher)
+            .map_err(|e| { (e, "Unable to read content to verify") } )?;
+        Ok(hasher.finish() == *self)
+    }
+
+    /// The key held inline as its raw digest, or `None` if it isn't well
+    /// formed
+    pub fn compact(&self) -> Option<CompactKey> {
+        if !self.is_well_formed() {
+            return None;
+        }
+        let mut digest = [0_u8; MAX_DIGEST_LEN];
+        for (byte, pair) in digest.iter_mut().zip(self.0.as_bytes().chunks(2)) {
+            *byte = nibble(pair[0]) << 4 | nibble(pair[1]);
+        }
+        Some(CompactKey { len: (self.0.len() / 2) as u8, digest })
+    }
+}
+
+// The value of a lowercase hex digit
+fn nibble(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        _ => digit - b'a' + 10,
+    }
+}
+
+// The longest digest of any `HashAlgorithm`, in bytes
+const MAX_DIGEST_LEN: usize = 64;
+
+/// A key held as its raw digest, inline, rather than as hex in a `String`.
+/// It is `Copy` and needs no allocation, for sets and maps of a great many
+/// keys, and sorts as the `FileKey` it stands for does.  Made by
+/// `FileKey::compact()`, and turned back with `to_file_key()`.
+#[derive(Clone, Copy)]
+pub struct CompactKey {
+    len: u8,
+    digest: [u8; MAX_DIGEST_LEN],
+}
+
+impl CompactKey {
+    /// The raw digest
+    pub fn digest(&self) -> &[u8] {
+        &self.digest[..self.len as usize]
+    }
+
+    /// The algorithm whose digests have this key's length
+    pub fn algorithm(&self) -> Option<HashAlgorithm> {
+        HashAlgorithm::ALL.iter().copied()
+            .find(|algorithm| algorithm.key_len() == self.len as usize * 2)
+    }
+
+    /// The key as a `FileKey`
+    pub fn to_file_key(&self) -> FileKey {
+        FileKey(self.to_string())
+    }
+}
+
+impl PartialEq for CompactKey {
+    fn eq(&self, other: &CompactKey) -> bool {
+        self.digest() == other.digest()
+    }
+}
+
+impl Eq for CompactKey {}
+
+impl Hash for CompactKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.digest().hash(state);
+    }
+}
+
+impl PartialOrd for CompactKey {
+    fn partial_cmp(&self, other: &CompactKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Bytes compare as their pairs of hex digits do
+impl Ord for CompactKey {
+    fn cmp(&self, other: &CompactKey) -> Ordering {
+        self.digest().cmp(other.digest())
+    }
+}
+
+impl fmt::Display for CompactKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for byte in self.digest() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CompactKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "CompactKey({})", self)
+    }
+}
+
+impl From<CompactKey> for FileKey {
+    fn from(key: CompactKey) -> FileKey {
+        key.to_file_key()
     }
 }
 
@@ -26,7 +163,7 @@ impl Deref for FileKey {
     type Target = str;
 
     fn deref(&self) -> &str {
-        &*self.0
+        &self.0
     }
 }
 
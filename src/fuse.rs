@@ -0,0 +1,291 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A read-only FUSE filesystem over a store (requires the `fuse` feature,
+//! Unix only), so existing tools can read stored content in place.
+//!
+//! Every object appears as `/<key>`.  If names are supplied with
+//! `FuseFs::with_names()`, they also appear as `/by-name/<name>`, sharing the
+//! inode of the object they name.
+
+use std::collections::{BTreeMap,HashMap};
+use std::ffi::OsStr;
+use std::fs::{self,File};
+use std::io;
+use std::os::unix::fs::{FileExt,MetadataExt};
+use std::path::{Path,PathBuf};
+use std::time::{Duration,UNIX_EPOCH};
+
+use fuser::{FileAttr,FileType,Filesystem,MountOption,ReplyAttr,ReplyData,
+            ReplyDirectory,ReplyEmpty,ReplyEntry,ReplyOpen,Request};
+use fuser::consts::FOPEN_KEEP_CACHE;
+use libc::{EBADF,EIO,ENOENT,ENOTDIR};
+
+use super::{Error,FileKey};
+
+const ROOT_INO: u64 = fuser::FUSE_ROOT_ID;
+const BY_NAME_INO: u64 = 2;
+const FIRST_OBJECT_INO: u64 = 3;
+const BY_NAME_DIR: &str = "by-name";
+
+// Objects can be deleted at any time, so the kernel is only allowed to
+// cache lookups briefly.  Content itself never changes.
+const TTL: Duration = Duration::from_secs(1);
+
+/// The filesystem, ready to be mounted with `mount()` or `spawn_mount()`
+pub struct FuseFs {
+    storage_path: PathBuf,
+    names: BTreeMap<String, FileKey>,
+    inodes: HashMap<u64, FileKey>,
+    by_key: HashMap<FileKey, u64>,
+    next_ino: u64,
+    handles: HashMap<u64, File>,
+    next_fh: u64,
+}
+
+impl FuseFs {
+    pub fn new(storage_path: &Path) -> FuseFs {
+        FuseFs {
+            storage_path: storage_path.to_path_buf(),
+            names: BTreeMap::new(),
+            inodes: HashMap::new(),
+            by_key: HashMap::new(),
+            next_ino: FIRST_OBJECT_INO,
+            handles: HashMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    /// Expose objects under `/by-name/` using names recorded by the
+    /// application, e.g. the original filenames of stored files.  Names must
+    /// not contain `/`; those that do are skipped.
+    pub fn with_names<I>(mut self, names: I) -> FuseFs
+        where I: IntoIterator<Item = (String, FileKey)>
+    {
+        self.names.extend(names.into_iter()
+                          .filter(|(name, _)| !name.contains('/') && !name.is_empty()));
+        self
+    }
+
+    /// Mount at `mountpoint`, serving requests until it is unmounted
+    pub fn mount(self, mountpoint: &Path) -> Result<(), Error> {
+        fuser::mount2(self, mountpoint, &mount_options())
+            .map_err(|e| { From::from((e, "Unable to mount FUSE filesystem")) } )
+    }
+
+    /// Mount at `mountpoint`, serving requests on a background thread until
+    /// the returned session is dropped
+    pub fn spawn_mount(self, mountpoint: &Path) -> Result<fuser::BackgroundSession, Error> {
+        fuser::spawn_mount2(self, mountpoint, &mount_options())
+            .map_err(|e| { From::from((e, "Unable to mount FUSE filesystem")) } )
+    }
+
+    fn ino_for(&mut self, key: &FileKey) -> u64 {
+        if let Some(ino) = self.by_key.get(key) {
+            return *ino;
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes.insert(ino, key.clone());
+        self.by_key.insert(key.clone(), ino);
+        ino
+    }
+
+    fn object_attr(&mut self, key: &FileKey) -> io::Result<FileAttr> {
+        let metadata = fs::metadata(super::storage_file_path(&self.storage_path, key))?;
+        let ino = self.ino_for(key);
+        Ok(FileAttr {
+            ino,
+            size: metadata.len(),
+            blocks: metadata.blocks(),
+            atime: metadata.accessed().unwrap_or(UNIX_EPOCH),
+            mtime: metadata.modified().unwrap_or(UNIX_EPOCH),
+            ctime: metadata.modified().unwrap_or(UNIX_EPOCH),
+            crtime: metadata.modified().unwrap_or(UNIX_EPOCH),
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            rdev: 0,
+            blksize: metadata.blksize() as u32,
+            flags: 0,
+        })
+    }
+
+    fn dir_attr(&self, ino: u64) -> FileAttr {
+        let metadata = fs::metadata(&self.storage_path).ok();
+        let mtime = metadata.as_ref()
+            .and_then(|m| m.modified().ok())
+            .unwrap_or(UNIX_EPOCH);
+        FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: FileType::Directory,
+            perm: 0o555,
+            nlink: 2,
+            uid: metadata.as_ref().map(|m| m.uid()).unwrap_or(0),
+            gid: metadata.as_ref().map(|m| m.gid()).unwrap_or(0),
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    // The key an inode refers to, if it is an object
+    fn key_for(&self, ino: u64) -> Option<FileKey> {
+        self.inodes.get(&ino).cloned()
+    }
+}
+
+fn mount_options() -> Vec<MountOption> {
+    vec![
+        MountOption::RO,
+        MountOption::FSName("filestore".to_owned()),
+        MountOption::DefaultPermissions,
+    ]
+}
+
+fn errno(e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        _ => e.raw_os_error().unwrap_or(EIO),
+    }
+}
+
+impl Filesystem for FuseFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = match name.to_str() {
+            Some(n) => n,
+            None => return reply.error(ENOENT),
+        };
+        let key = match parent {
+            ROOT_INO if name == BY_NAME_DIR && !self.names.is_empty() => {
+                return reply.entry(&TTL, &self.dir_attr(BY_NAME_INO), 0);
+            },
+            ROOT_INO => FileKey(name.to_owned()),
+            BY_NAME_INO => match self.names.get(name) {
+                Some(key) => key.clone(),
+                None => return reply.error(ENOENT),
+            },
+            _ => return reply.error(ENOTDIR),
+        };
+        if !key.is_well_formed() {
+            return reply.error(ENOENT);
+        }
+        match self.object_attr(&key) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        if ino == ROOT_INO || ino == BY_NAME_INO {
+            return reply.attr(&TTL, &self.dir_attr(ino));
+        }
+        let key = match self.key_for(ino) {
+            Some(k) => k,
+            None => return reply.error(ENOENT),
+        };
+        match self.object_attr(&key) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let key = match self.key_for(ino) {
+            Some(k) => k,
+            None => return reply.error(ENOENT),
+        };
+        match File::open(super::storage_file_path(&self.storage_path, &key)) {
+            Ok(file) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.handles.insert(fh, file);
+                // Content under a key never changes
+                reply.opened(fh, FOPEN_KEEP_CACHE);
+            },
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn read(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, offset: i64, size: u32,
+            _flags: i32, _lock_owner: Option<u64>, reply: ReplyData)
+    {
+        let file = match self.handles.get(&fh) {
+            Some(f) => f,
+            None => return reply.error(EBADF),
+        };
+        let mut buf = vec![0_u8; size as usize];
+        let mut filled = 0;
+        while filled < buf.len() {
+            match file.read_at(&mut buf[filled..], offset as u64 + filled as u64) {
+                Ok(0) => break,
+                Ok(count) => filled += count,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return reply.error(errno(&e)),
+            }
+        }
+        reply.data(&buf[..filled]);
+    }
+
+    fn release(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: i32,
+               _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty)
+    {
+        self.handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64,
+               mut reply: ReplyDirectory)
+    {
+        let mut entries: Vec<(u64, FileType, String)> = vec![
+            (ino, FileType::Directory, ".".to_owned()),
+            (ROOT_INO, FileType::Directory, "..".to_owned()),
+        ];
+        match ino {
+            ROOT_INO => {
+                if !self.names.is_empty() {
+                    entries.push((BY_NAME_INO, FileType::Directory, BY_NAME_DIR.to_owned()));
+                }
+                let keys = match super::keys(&self.storage_path) {
+                    Ok(keys) => keys,
+                    Err(e) => return reply.error(errno(&e.io)),
+                };
+                for key in keys {
+                    match key {
+                        Ok(key) => {
+                            let ino = self.ino_for(&key);
+                            entries.push((ino, FileType::RegularFile, key.0));
+                        },
+                        Err(e) => return reply.error(errno(&e.io)),
+                    }
+                }
+            },
+            BY_NAME_INO => {
+                let names: Vec<(String, FileKey)> = self.names.iter()
+                    .map(|(n, k)| (n.clone(), k.clone()))
+                    .collect();
+                for (name, key) in names {
+                    let ino = self.ino_for(&key);
+                    entries.push((ino, FileType::RegularFile, name));
+                }
+            },
+            _ => return reply.error(ENOTDIR),
+        }
+
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // The offset given is that of the next entry to return
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
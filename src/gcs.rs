@@ -0,0 +1,211 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A store in a Google Cloud Storage bucket (requires the `gcs` feature).
+//!
+//! `GcsStore` implements `Store` over the Cloud Storage JSON API, keeping
+//! each object as a blob named by its key, with its reference count in the
+//! blob's custom metadata (`refcount`).  Reference counts are updated with
+//! metageneration preconditions, so any number of hosts can share a bucket.
+//! Keys are the same as those of a local store, so content can be moved
+//! between the two.
+
+use std::io::{self,Read};
+use std::sync::Arc;
+
+use serde_json::{json,Value};
+
+use super::{Error,FileKey};
+use super::cloud::{self,BlobInfo,Blobs,Content};
+use super::store::{ObjectStat,Store};
+
+const ENDPOINT: &str = "https://storage.googleapis.com";
+
+type TokenSource = dyn Fn() -> Result<String, Error> + Send + Sync;
+
+/// A store in a Cloud Storage bucket
+#[derive(Clone)]
+pub struct GcsStore {
+    agent: ureq::Agent,
+    endpoint: String,
+    bucket: String,
+    prefix: String,
+    token: Arc<TokenSource>,
+}
+
+impl GcsStore {
+    /// A store in `bucket`, authorizing requests with the OAuth 2 access
+    /// token `token`
+    pub fn new(bucket: &str, token: &str) -> GcsStore {
+        let token = token.to_owned();
+        GcsStore::with_token_source(bucket, move || Ok(token.clone()))
+    }
+
+    /// A store in `bucket`, calling `token` for an access token before each
+    /// request, so that it can be refreshed as it expires
+    pub fn with_token_source<F>(bucket: &str, token: F) -> GcsStore
+        where F: Fn() -> Result<String, Error> + Send + Sync + 'static
+    {
+        GcsStore {
+            agent: ureq::Agent::new(),
+            endpoint: ENDPOINT.to_owned(),
+            bucket: bucket.to_owned(),
+            prefix: String::new(),
+            token: Arc::new(token),
+        }
+    }
+
+    /// Keep objects under `prefix` (such as `filestore/`), so that the
+    /// bucket can be shared with other data
+    pub fn with_prefix(mut self, prefix: &str) -> GcsStore {
+        self.prefix = prefix.to_owned();
+        self
+    }
+
+    /// Send requests to `endpoint` rather than Cloud Storage itself, such
+    /// as an emulator
+    pub fn with_endpoint(mut self, endpoint: &str) -> GcsStore {
+        self.endpoint = endpoint.trim_end_matches('/').to_owned();
+        self
+    }
+
+    fn object_url(&self, name: &str) -> String {
+        format!("{}/storage/v1/b/{}/o/{}", self.endpoint, cloud::encode(&self.bucket, false),
+                cloud::encode(name, false))
+    }
+
+    fn request(&self, method: &str, url: &str) -> Result<ureq::Request, Error> {
+        let token = (self.token)()?;
+        Ok(self.agent.request(method, url)
+           .set("Authorization", &format!("Bearer {}", token)))
+    }
+
+    fn name(&self, key: &FileKey) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+fn parse_u64(value: &Value) -> Option<u64> {
+    // The JSON API gives 64-bit numbers as strings
+    value.as_str().and_then(|s| s.parse().ok())
+}
+
+fn read_json(response: ureq::Response) -> Result<Value, Error> {
+    serde_json::from_reader(response.into_reader())
+        .map_err(|e| { From::from((io::Error::new(io::ErrorKind::InvalidData, e),
+                                   "Malformed response from Cloud Storage")) } )
+}
+
+impl Blobs for GcsStore {
+    fn head(&self, name: &str) -> Result<Option<BlobInfo>, Error> {
+        let result = self.request("GET", &self.object_url(name))?
+            .query("fields", "size,metageneration,metadata")
+            .call();
+        let response = match cloud::reply(result, &[404], "Unable to read object metadata")? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        let object = read_json(response)?;
+        let (size, version) = match (parse_u64(&object["size"]), object["metageneration"].as_str()) {
+            (Some(size), Some(version)) => (size, version.to_owned()),
+            _ => return Err(From::from((io::Error::from(io::ErrorKind::InvalidData),
+                                        "Object metadata is incomplete"))),
+        };
+        // Blobs written by anything else hold no references
+        let refcount = parse_u64(&object["metadata"]["refcount"]).unwrap_or(0);
+        Ok(Some(BlobInfo { size, refcount, version }))
+    }
+
+    fn create(&self, name: &str, content: &mut dyn Content, size: u64) -> Result<bool, Error> {
+        // A multipart upload, so that the refcount is set along with the
+        // content
+        let boundary = format!("filestore-{:032x}", rand::random::<u128>());
+        let metadata = json!({ "name": name, "metadata": { "refcount": "1" } });
+        let head = format!("--{b}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{m}\r\n\
+                            --{b}\r\nContent-Type: application/octet-stream\r\n\r\n",
+                           b = boundary, m = metadata);
+        let tail = format!("\r\n--{}--\r\n", boundary);
+        let length = head.len() as u64 + size + tail.len() as u64;
+        let body = io::Cursor::new(head).chain(content.take(size)).chain(io::Cursor::new(tail));
+        let url = format!("{}/upload/storage/v1/b/{}/o", self.endpoint,
+                          cloud::encode(&self.bucket, false));
+        let result = self.request("POST", &url)?
+            .query("uploadType", "multipart")
+            .query("ifGenerationMatch", "0")
+            .set("Content-Type", &format!("multipart/related; boundary={}", boundary))
+            .set("Content-Length", &length.to_string())
+            .send(body);
+        Ok(cloud::reply(result, &[412], "Unable to upload object")?.is_some())
+    }
+
+    fn set_refcount(&self, name: &str, refcount: u64, version: &str) -> Result<bool, Error> {
+        let result = self.request("PATCH", &self.object_url(name))?
+            .query("ifMetagenerationMatch", version)
+            .query("fields", "metageneration")
+            .send_json(json!({ "metadata": { "refcount": refcount.to_string() } }));
+        Ok(cloud::reply(result, &[404, 412], "Unable to update refcount")?.is_some())
+    }
+
+    fn remove(&self, name: &str, version: &str) -> Result<bool, Error> {
+        let result = self.request("DELETE", &self.object_url(name))?
+            .query("ifMetagenerationMatch", version)
+            .call();
+        Ok(cloud::reply(result, &[404, 412], "Unable to delete object")?.is_some())
+    }
+
+    fn open(&self, name: &str) -> Result<Option<Box<dyn Read + Send>>, Error> {
+        let result = self.request("GET", &self.object_url(name))?
+            .query("alt", "media")
+            .call();
+        Ok(cloud::reply(result, &[404], "Unable to read object")?
+            .map(|response| Box::new(response.into_reader()) as Box<dyn Read + Send>))
+    }
+
+    fn list(&self, prefix: &str, token: Option<&str>)
+            -> Result<(Vec<String>, Option<String>), Error>
+    {
+        let url = format!("{}/storage/v1/b/{}/o", self.endpoint, cloud::encode(&self.bucket, false));
+        let mut request = self.request("GET", &url)?
+            .query("prefix", prefix)
+            .query("fields", "items(name),nextPageToken");
+        if let Some(token) = token {
+            request = request.query("pageToken", token);
+        }
+        let result = request.call();
+        let page = read_json(cloud::check(result, "Unable to list objects")?)?;
+        let names: Vec<String> = page["items"].as_array()
+            .map(|items| items.iter()
+                 .filter_map(|item| item["name"].as_str().map(|s| s.to_owned()))
+                 .collect())
+            .unwrap_or_default();
+        Ok((names, page["nextPageToken"].as_str().map(|s| s.to_owned())))
+    }
+}
+
+impl Store for GcsStore {
+    fn store_data(&self, input: &[u8]) -> Result<FileKey, Error> {
+        cloud::store_data(self, &self.prefix, input)
+    }
+
+    fn store_reader(&self, input: &mut dyn Read) -> Result<FileKey, Error> {
+        cloud::store_reader(self, &self.prefix, input)
+    }
+
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>
+    {
+        self.open(&self.name(key))
+    }
+
+    fn delete(&self, key: &FileKey) -> Result<(), Error> {
+        cloud::release(self, &self.name(key))
+    }
+
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error> {
+        cloud::stat(self, &self.name(key))
+    }
+
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error> {
+        Ok(Box::new(cloud::Keys::new(self, &self.prefix)))
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Import of the content in a git object database (requires the `git`
+//! feature), for moving artifacts that were kept in a git repository into a
+//! store.
+//!
+//! Every blob in the repository is stored, whether or not any commit still
+//! reaches it, loose and packed objects alike.  Trees, commits and tags are
+//! skipped, as only file content has a place in a store.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use git2::{ObjectType,Oid,Repository};
+
+use super::{Config,Error,FileKey};
+
+fn git_error(e: git2::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Store every blob in the git repository at `repo` (bare or not), taking
+/// one reference on each, and return the key of each keyed by its object
+/// id in hex.  On failure, references already taken by this import are
+/// released again before the error is returned.
+///
+/// Blobs are read into memory one at a time to be stored.
+pub fn import_git(storage_path: &Path, repo: &Path) -> Result<BTreeMap<String, FileKey>, Error>
+{
+    let config = Config::default();
+    let repo = Repository::open(repo)
+        .map_err(|e| { (git_error(e), "Unable to open git repository") } )?;
+    let odb = repo.odb()
+        .map_err(|e| { (git_error(e), "Unable to open git object database") } )?;
+
+    let mut oids: Vec<Oid> = Vec::new();
+    odb.foreach(|oid| {
+        oids.push(*oid);
+        true
+    }).map_err(|e| { (git_error(e), "Unable to list git objects") } )?;
+    // Packs may repeat objects held loose, or in another pack
+    oids.sort();
+    oids.dedup();
+
+    let mut imported: BTreeMap<String, FileKey> = BTreeMap::new();
+    let result = oids.iter().try_for_each(|oid| {
+        let (_, kind) = odb.read_header(*oid)
+            .map_err(|e| { (git_error(e), "Unable to read git object") } )?;
+        if kind != ObjectType::Blob {
+            return Ok(());
+        }
+        let object = odb.read(*oid)
+            .map_err(|e| { (git_error(e), "Unable to read git blob") } )?;
+        let (key, _) = super::store_data_new(storage_path, &config, object.data())?;
+        imported.insert(oid.to_string(), key);
+        Ok(())
+    });
+    if let Err(e) = result {
+        for key in imported.values() {
+            if let Err(e) = super::release(storage_path, &config, key) {
+                log::warn!("Unable to release {} after failed import: {:?}", key, e);
+            }
+        }
+        return Err(e);
+    }
+    Ok(imported)
+}
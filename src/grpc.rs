@@ -0,0 +1,407 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! gRPC access to a store (requires the `grpc` feature).  The service is
+//! defined in `proto/filestore.proto`.
+//!
+//! `GrpcService` serves any `Store` (usually a local `FileStore`), and
+//! `RemoteStore` is a blocking client which itself implements `Store`, so
+//! applications can move between embedded and remote deployments by
+//! swapping one for the other.
+
+use std::io::{self,Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration,UNIX_EPOCH};
+
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Code,Request,Response,Status,Streaming};
+use tonic::transport::{Channel,Endpoint};
+
+use super::{Error,FileKey};
+use super::error::ErrorKind;
+use super::bridge;
+use super::store::{ObjectStat,Store};
+
+/// Types generated from `proto/filestore.proto`
+pub mod proto {
+    tonic::include_proto!("filestore");
+}
+
+use self::proto::{Chunk,DeleteReply,Key,ListRequest,StatReply};
+use self::proto::file_store_client::FileStoreClient;
+use self::proto::file_store_server::{FileStore as FileStoreRpc,FileStoreServer};
+
+// Size of the content chunks streamed in each direction
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Serves a `Store` over gRPC
+pub struct GrpcService<S> {
+    store: Arc<S>,
+}
+
+impl<S: Store + Send + Sync + 'static> GrpcService<S> {
+    pub fn new(store: S) -> GrpcService<S> {
+        GrpcService { store: Arc::new(store) }
+    }
+
+    /// Wrap as a tonic service, for adding to a `tonic::transport::Server`
+    /// alongside other services
+    pub fn into_server(self) -> FileStoreServer<GrpcService<S>> {
+        FileStoreServer::new(self)
+    }
+}
+
+/// Serve `store` on `addr` until the server fails.  Must be called from
+/// within a tokio runtime.
+pub async fn serve<S>(store: S, addr: SocketAddr) -> Result<(), Error>
+    where S: Store + Send + Sync + 'static
+{
+    tonic::transport::Server::builder()
+        .add_service(GrpcService::new(store).into_server())
+        .serve(addr)
+        .await
+        .map_err(|e| { From::from((io::Error::other(e), "gRPC server failed")) } )
+}
+
+fn to_status(e: Error) -> Status {
+    if e.kind == ErrorKind::ReadOnly {
+        return Status::failed_precondition(e.to_string());
+    }
+    if let ErrorKind::TooLarge { .. } = e.kind {
+        return Status::resource_exhausted(e.to_string());
+    }
+    match e.io.kind() {
+        io::ErrorKind::NotFound => Status::not_found(format!("{:?}", e)),
+        _ => Status::internal(format!("{:?}", e)),
+    }
+}
+
+#[allow(clippy::result_large_err)] // Status is what tonic handlers return
+fn parse_key(key: Key) -> Result<FileKey, Status> {
+    let key = FileKey(key.key);
+    if key.is_well_formed() {
+        Ok(key)
+    } else {
+        Err(Status::invalid_argument("malformed key"))
+    }
+}
+
+// Run a blocking store operation off the async executor
+async fn blocking<T, F>(f: F) -> Result<T, Status>
+    where F: FnOnce() -> Result<T, Error> + Send + 'static,
+          T: Send + 'static
+{
+    tokio::task::spawn_blocking(f).await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(to_status)
+}
+
+#[tonic::async_trait]
+impl<S: Store + Send + Sync + 'static> FileStoreRpc for GrpcService<S> {
+    async fn store(&self, request: Request<Streaming<Chunk>>)
+                   -> Result<Response<Key>, Status>
+    {
+        let mut stream = request.into_inner();
+        let (tx, mut reader) = bridge::channel();
+        let store = self.store.clone();
+        let task = blocking(move || store.store_reader(&mut reader));
+        let forward = async move {
+            loop {
+                let item = match stream.message().await {
+                    Ok(Some(chunk)) => Ok(chunk.data),
+                    Ok(None) => break,
+                    Err(status) => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof, status.message().to_owned())),
+                };
+                let failed = item.is_err();
+                // A send error means the store already gave up; its error is
+                // reported below
+                if tx.send(item).await.is_err() || failed {
+                    break;
+                }
+            }
+        };
+        let (key, ()) = tokio::join!(task, forward);
+        Ok(Response::new(Key { key: key?.0 }))
+    }
+
+    type RetrieveStream = ReceiverStream<Result<Chunk, Status>>;
+
+    async fn retrieve(&self, request: Request<Key>)
+                      -> Result<Response<Self::RetrieveStream>, Status>
+    {
+        let key = parse_key(request.into_inner())?;
+        let store = self.store.clone();
+        let reader = blocking(move || store.retrieve_reader(&key)).await?;
+        let mut reader = match reader {
+            Some(r) => r,
+            None => return Err(Status::not_found("no such key")),
+        };
+        let (tx, rx) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0_u8; CHUNK_SIZE];
+            loop {
+                let item = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(count) => Ok(Chunk { data: buf[..count].to_vec() }),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(Status::internal(e.to_string())),
+                };
+                let failed = item.is_err();
+                if tx.blocking_send(item).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn delete(&self, request: Request<Key>)
+                    -> Result<Response<DeleteReply>, Status>
+    {
+        let key = parse_key(request.into_inner())?;
+        let store = self.store.clone();
+        let found = blocking(move || {
+            match store.stat(&key)? {
+                Some(_) => store.delete(&key).map(|()| true),
+                None => Ok(false),
+            }
+        }).await?;
+        if found {
+            Ok(Response::new(DeleteReply {}))
+        } else {
+            Err(Status::not_found("no such key"))
+        }
+    }
+
+    async fn stat(&self, request: Request<Key>)
+                  -> Result<Response<StatReply>, Status>
+    {
+        let key = parse_key(request.into_inner())?;
+        let store = self.store.clone();
+        match blocking(move || store.stat(&key)).await? {
+            Some(stat) => Ok(Response::new(StatReply {
+                size: stat.size,
+                refcount: stat.refcount,
+                stored: stat.stored
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                last_access: stat.last_access
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            })),
+            None => Err(Status::not_found("no such key")),
+        }
+    }
+
+    type ListStream = ReceiverStream<Result<Key, Status>>;
+
+    async fn list(&self, _request: Request<ListRequest>)
+                  -> Result<Response<Self::ListStream>, Status>
+    {
+        let (tx, rx) = mpsc::channel(64);
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || {
+            let keys = match store.keys() {
+                Ok(keys) => keys,
+                Err(e) => { let _ = tx.blocking_send(Err(to_status(e))); return; },
+            };
+            for key in keys {
+                let item = key.map(|k| Key { key: k.0 }).map_err(to_status);
+                let failed = item.is_err();
+                if tx.blocking_send(item).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// A blocking client for a store served by `GrpcService`.
+///
+/// This runs its own tokio runtime, so (like the `postgres` crate's client)
+/// it must not be used from within an async context.
+pub struct RemoteStore {
+    runtime: Arc<Runtime>,
+    client: FileStoreClient<Channel>,
+}
+
+fn from_status(status: Status) -> Error {
+    let kind = match status.code() {
+        Code::NotFound => io::ErrorKind::NotFound,
+        Code::InvalidArgument => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+    From::from((io::Error::new(kind, status.message().to_owned()), "Remote store request failed"))
+}
+
+impl RemoteStore {
+    /// Connect to a `GrpcService` at `endpoint`, e.g. `"http://[::1]:50051"`
+    pub fn connect(endpoint: &str) -> Result<RemoteStore, Error> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .map_err(|e| { (e, "Unable to start tokio runtime") } )?;
+        let endpoint = Endpoint::from_shared(endpoint.to_owned())
+            .map_err(|e| { (io::Error::new(io::ErrorKind::InvalidInput, e),
+                            "Invalid remote store endpoint") } )?;
+        let channel = runtime.block_on(endpoint.connect())
+            .map_err(|e| { (io::Error::other(e), "Unable to connect to remote store") } )?;
+        let client = FileStoreClient::new(channel);
+        Ok(RemoteStore {
+            runtime: Arc::new(runtime),
+            client,
+        })
+    }
+}
+
+impl Store for RemoteStore {
+    fn store_data(&self, input: &[u8]) -> Result<FileKey, Error> {
+        let chunks: Vec<Chunk> = input.chunks(CHUNK_SIZE)
+            .map(|c| Chunk { data: c.to_vec() })
+            .collect();
+        let mut client = self.client.clone();
+        let key = self.runtime.block_on(client.store(tokio_stream::iter(chunks)))
+            .map_err(from_status)?;
+        Ok(FileKey(key.into_inner().key))
+    }
+
+    fn store_reader(&self, input: &mut dyn Read) -> Result<FileKey, Error> {
+        let (tx, rx) = mpsc::channel(4);
+        let mut client = self.client.clone();
+        let call = self.runtime.spawn(async move {
+            client.store(ReceiverStream::new(rx)).await
+        });
+        let mut buf = vec![0_u8; CHUNK_SIZE];
+        loop {
+            let count = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    call.abort();
+                    return Err(From::from((e, "Unable to read input")));
+                },
+            };
+            if tx.blocking_send(Chunk { data: buf[..count].to_vec() }).is_err() {
+                break; // the call failed; its status is reported below
+            }
+        }
+        drop(tx);
+        let key = self.runtime.block_on(call)
+            .map_err(|e| { (io::Error::other(e), "Remote store request failed") } )?
+            .map_err(from_status)?;
+        Ok(FileKey(key.into_inner().key))
+    }
+
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>
+    {
+        let mut client = self.client.clone();
+        let request = Key { key: key.0.clone() };
+        match self.runtime.block_on(client.retrieve(request)) {
+            Ok(response) => Ok(Some(Box::new(RemoteReader {
+                runtime: self.runtime.clone(),
+                stream: response.into_inner(),
+                buf: Vec::new(),
+                pos: 0,
+            }))),
+            Err(ref status) if status.code() == Code::NotFound => Ok(None),
+            Err(status) => Err(from_status(status)),
+        }
+    }
+
+    fn delete(&self, key: &FileKey) -> Result<(), Error> {
+        let mut client = self.client.clone();
+        match self.runtime.block_on(client.delete(Key { key: key.0.clone() })) {
+            Ok(_) => Ok(()),
+            // Deleting a missing key is not an error locally either
+            Err(ref status) if status.code() == Code::NotFound => Ok(()),
+            Err(status) => Err(from_status(status)),
+        }
+    }
+
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error> {
+        let mut client = self.client.clone();
+        match self.runtime.block_on(client.stat(Key { key: key.0.clone() })) {
+            Ok(response) => {
+                let reply = response.into_inner();
+                let last_access = match reply.last_access {
+                    0 => None,
+                    secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+                };
+                let stored = match reply.stored {
+                    0 => None,
+                    secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+                };
+                Ok(Some(ObjectStat {
+                    size: reply.size,
+                    refcount: reply.refcount,
+                    stored,
+                    last_access,
+                }))
+            },
+            Err(ref status) if status.code() == Code::NotFound => Ok(None),
+            Err(status) => Err(from_status(status)),
+        }
+    }
+
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error> {
+        let mut client = self.client.clone();
+        let stream = self.runtime.block_on(client.list(ListRequest {}))
+            .map_err(from_status)?
+            .into_inner();
+        Ok(Box::new(RemoteKeys {
+            runtime: &self.runtime,
+            stream,
+        }))
+    }
+}
+
+// Content of a remote object, pulled from the response stream as it is read
+struct RemoteReader {
+    runtime: Arc<Runtime>,
+    stream: Streaming<Chunk>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for RemoteReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            match self.runtime.block_on(self.stream.message()) {
+                Ok(Some(chunk)) => { self.buf = chunk.data; self.pos = 0; },
+                Ok(None) => return Ok(0),
+                Err(status) => return Err(from_status(status).io),
+            }
+        }
+        let count = out.len().min(self.buf.len() - self.pos);
+        out[..count].copy_from_slice(&self.buf[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+struct RemoteKeys<'a> {
+    runtime: &'a Runtime,
+    stream: Streaming<Key>,
+}
+
+impl<'a> Iterator for RemoteKeys<'a> {
+    type Item = Result<FileKey, Error>;
+
+    fn next(&mut self) -> Option<Result<FileKey, Error>> {
+        match self.runtime.block_on(self.stream.message()) {
+            Ok(Some(key)) => Some(Ok(FileKey(key.key))),
+            Ok(None) => None,
+            Err(status) => Some(Err(from_status(status))),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+// Caches that save system calls on the hot paths of storing and deleting.
+//
+// Stores are found by their canonical path, so that every spelling of a
+// storage path shares one lock file, limiter, usage count and so on, but
+// canonicalizing takes a system call for each component of the path, and
+// a store does it several times over.  `canonical()` remembers the answer
+// for each absolute storage path.  Relative paths are canonicalized every
+// time, as they change meaning with the working directory.  A storage
+// path whose symlinks are pointed elsewhere while the process runs goes on
+// meaning the store it first did.
+//
+// Shard directories, once seen to exist, are remembered by `ensure_dir()`,
+// so that storing needn't try to create one each time.  The crate never
+// removes them; should one be removed from outside, storing into it fails
+// once, and `forget_dir()` has it created again on the next try.
+//
+// Both caches are dropped whole once they reach a bound, as a process
+// using a great many stores is unusual and needn't be fast.
+
+use std::collections::{HashMap,HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+use std::sync::{Mutex,MutexGuard,OnceLock};
+
+use super::Error;
+
+const MAX_PATHS: usize = 4096;
+const MAX_DIRS: usize = 65536;
+
+static CANONICAL: OnceLock<Mutex<HashMap<PathBuf, PathBuf>>> = OnceLock::new();
+static DIRS: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+
+fn canonical_paths() -> MutexGuard<'static, HashMap<PathBuf, PathBuf>> {
+    CANONICAL.get_or_init(|| Mutex::new(HashMap::new()))
+        .lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn dirs() -> MutexGuard<'static, HashSet<PathBuf>> {
+    DIRS.get_or_init(|| Mutex::new(HashSet::new()))
+        .lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The canonical form of `storage_path`, or the path itself if it can't be
+/// canonicalized (as when it doesn't exist yet)
+pub(crate) fn canonical(storage_path: &Path) -> PathBuf {
+    if !storage_path.is_absolute() {
+        return fs::canonicalize(storage_path).unwrap_or_else(|_| storage_path.to_path_buf());
+    }
+    if let Some(canonical) = canonical_paths().get(storage_path) {
+        return canonical.clone();
+    }
+    let canonical = match fs::canonicalize(storage_path) {
+        Ok(canonical) => canonical,
+        // Not remembered, as it may yet be created
+        Err(_) => return storage_path.to_path_buf(),
+    };
+    let mut paths = canonical_paths();
+    if paths.len() >= MAX_PATHS {
+        paths.clear();
+    }
+    paths.insert(storage_path.to_path_buf(), canonical.clone());
+    canonical
+}
+
+/// Create the directory `dir` unless it is known to exist, returning
+/// whether it was created
+pub(crate) fn ensure_dir(dir: &Path) -> Result<bool, Error> {
+    if dir.is_absolute() && dirs().contains(dir) {
+        return Ok(false);
+    }
+    let created = match fs::create_dir(dir) {
+        Ok(()) => true,
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => false,
+        Err(e) => return Err(From::from((e, "Unable to create shard directory"))),
+    };
+    if dir.is_absolute() {
+        let mut dirs = dirs();
+        if dirs.len() >= MAX_DIRS {
+            dirs.clear();
+        }
+        dirs.insert(dir.to_path_buf());
+    }
+    Ok(created)
+}
+
+/// Stop taking `dir` to exist, after something in it wasn't found
+pub(crate) fn forget_dir(dir: &Path) {
+    dirs().remove(dir);
+}
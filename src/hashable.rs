@@ -1,47 +1,209 @@
 
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Computing the key of content to be stored.  See `Storable` for storing
+//! your own types.
+
 use std::fs::File;
-use std::path::PathBuf;
-use std::io::Read;
-use crypto::sha2::Sha224;
+use std::path::{Path,PathBuf};
+use std::io::{self,Read,Write};
+use crypto::sha2::{Sha224,Sha256};
 use crypto::digest::Digest;
-use super::Error;
+use super::{Error,FileKey};
+use super::merkle::TreeHasher;
+
+/// The size of the blocks content is read in, when hashing and copying it
+/// in, unless `Config::io_buffer_size` says otherwise
+pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+
+/// The digest a store keys content by, recorded in its manifest.  Stores
+/// use sha224 unless moved to another digest by `rehash_store()`.
+///
+/// `Sha512Tree` hashes content in 1 MiB chunks on all cores at once and
+/// joins their digests in a binary tree, so that very large files hash as
+/// fast as the disk can read them rather than as fast as one core can
+/// hash.  Small content gains nothing from it.  Parts of such objects can
+/// be checked without reading them whole (see the `merkle` module).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HashAlgorithm {
+    /// SHA-224, giving 56 hex digit keys
+    #[default]
+    Sha224,
+    /// SHA-256, giving 64 hex digit keys
+    Sha256,
+    /// A tree of SHA-512 digests over 1 MiB chunks, giving 128 hex digit
+    /// keys
+    Sha512Tree,
+}
+
+impl HashAlgorithm {
+    /// Every algorithm supported
+    pub const ALL: [HashAlgorithm; 3] =
+        [HashAlgorithm::Sha224, HashAlgorithm::Sha256, HashAlgorithm::Sha512Tree];
+
+    /// The name the manifest records, e.g. `sha224`
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha224 => "sha224",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512Tree => "sha512-tree",
+        }
+    }
+
+    /// The algorithm with the given `name()`
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        HashAlgorithm::ALL.iter().copied().find(|algorithm| algorithm.name() == name)
+    }
+
+    /// The length of its keys, in hex digits
+    pub fn key_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha224 => 56,
+            HashAlgorithm::Sha256 => 64,
+            HashAlgorithm::Sha512Tree => 128,
+        }
+    }
+
+    pub(crate) fn hasher(self) -> Box<dyn Digest + Send + Sync> {
+        match self {
+            HashAlgorithm::Sha224 => Box::new(Sha224::new()),
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+            HashAlgorithm::Sha512Tree => Box::new(TreeHasher::new()),
+        }
+    }
+}
 
-/// A private trait Hashable (with Sha224 only)
+/// Content that can be hashed to give its key.
+///
+/// `hash()` must return the lowercase hex sha224 digest of exactly the bytes
+/// that the type's `Storable::store()` writes, as that is the object's key.
+/// A mismatch stores content under the wrong key, which `verify()` reports as
+/// corruption.
 pub trait Hashable {
     /// Hash (with sha224) to result in a String or io::Error
     fn hash(&self) -> Result<String,Error>;
+
+    /// Hash with `algorithm`, for stores keyed by another digest.  Types
+    /// that only implement `hash()` can only be stored in sha224 stores.
+    fn hash_with(&self, algorithm: HashAlgorithm) -> Result<String, Error> {
+        match algorithm {
+            HashAlgorithm::Sha224 => self.hash(),
+            _ => Err(From::from((io::Error::from(io::ErrorKind::Unsupported),
+                                 &*format!("Unable to hash this type with {}", algorithm.name())))),
+        }
+    }
 }
 
-impl Hashable for Vec<u8> {
+impl Hashable for [u8] {
     fn hash(&self) -> Result<String, Error> {
+        self.hash_with(HashAlgorithm::Sha224)
+    }
+
+    fn hash_with(&self, algorithm: HashAlgorithm) -> Result<String, Error> {
         // Start the hash
-        let mut hash = Box::new(Sha224::new());
+        let mut hash = algorithm.hasher();
 
         // Add the content
-        hash.input( &*self );
+        hash.input( self );
 
         // Get the result
         Ok(hash.result_str())
     }
 }
 
+impl Hashable for Vec<u8> {
+    fn hash(&self) -> Result<String, Error> {
+        self[..].hash()
+    }
+
+    fn hash_with(&self, algorithm: HashAlgorithm) -> Result<String, Error> {
+        self[..].hash_with(algorithm)
+    }
+}
+
 impl Hashable for PathBuf {
     fn hash(&self) -> Result<String, Error> {
-        // Start the hash
-        let mut hash = Box::new(Sha224::new());
-
-        // Open the file
-        let mut file =
-            File::open(self)
-                .map_err(|e| { (e, "Cannot open content file for hashing") } )?;
-
-        // Digest 4096 bytes at a time
-        let mut buf: [u8; 4096] = [0_u8; 4096];
-        loop {
-            let count = file.read(&mut buf)
-                .map_err(|e| { (e, "Unable to read file to hash") } )?;
-            if count==0 { return Ok(hash.result_str()); }
-            hash.input(&buf[..count]); // Add to hash input
+        self.hash_with(HashAlgorithm::Sha224)
+    }
+
+    fn hash_with(&self, algorithm: HashAlgorithm) -> Result<String, Error> {
+        hash_file(self, algorithm, DEFAULT_BUFFER_SIZE)
+    }
+}
+
+// Hash the file at `path`, reading `buffer_size` bytes at a time
+pub(crate) fn hash_file(path: &Path, algorithm: HashAlgorithm, buffer_size: usize)
+                        -> Result<String, Error>
+{
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    {
+        if let Some(result) = super::uring::hash_file(path, algorithm) {
+            return result;
         }
     }
+
+    // Start the hash
+    let mut hash = algorithm.hasher();
+
+    // Open the file
+    let mut file =
+        File::open(path)
+            .map_err(|e| { (e, "Cannot open content file for hashing") } )?;
+
+    // Digest a block at a time
+    let mut buf = vec![0_u8; buffer_size];
+    loop {
+        let count = file.read(&mut buf)
+            .map_err(|e| { (e, "Unable to read file to hash") } )?;
+        if count==0 { return Ok(hash.result_str()); }
+        hash.input(&buf[..count]); // Add to hash input
+    }
+}
+
+/// Computes the key filestore would assign to content written to it, a
+/// piece at a time, without storing anything.  Useful for checking an upload
+/// against a digest the client supplied before committing it.
+pub struct KeyHasher {
+    hash: Box<dyn Digest + Send + Sync>,
+    written: u64,
+}
+
+impl KeyHasher {
+    pub fn new() -> KeyHasher {
+        KeyHasher::with_algorithm(HashAlgorithm::Sha224)
+    }
+
+    /// A hasher giving keys for a store keyed by `algorithm`
+    pub fn with_algorithm(algorithm: HashAlgorithm) -> KeyHasher {
+        KeyHasher { hash: algorithm.hasher(), written: 0 }
+    }
+
+    /// The number of bytes written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// The key of everything written
+    pub fn finish(mut self) -> FileKey {
+        FileKey(self.hash.result_str())
+    }
+}
+
+impl Default for KeyHasher {
+    fn default() -> KeyHasher {
+        KeyHasher::new()
+    }
+}
+
+impl Write for KeyHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hash.input(buf);
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
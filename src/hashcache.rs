@@ -0,0 +1,176 @@
+// Digests of files stored by path, remembered by their stat signature
+// (`Config::hash_cache`), so that importing the same unchanged files again
+// doesn't hash them again.
+//
+// The signature is the device, inode, size, and modification and change
+// times.  The change time can't be set by ordinary programs, so a file
+// rewritten with its old mtime restored is still noticed.  Files changed in
+// the last couple of seconds aren't cached, as a further change within the
+// timestamp granularity could go unseen.
+//
+// The cache lives in `hash-cache` in the storage path, a file that entries
+// are appended to, later entries for a file replacing earlier ones.  It is
+// read once per process.  On platforms without inode numbers nothing is
+// cached.
+
+use std::collections::HashMap;
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,Write};
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,OnceLock};
+#[cfg(unix)]
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use super::{Error,FileKey,handles};
+use super::hashable::{HashAlgorithm,hash_file};
+
+const HEADER: &str = "filestore-hashcache 1";
+
+// How recently a file may have changed and still be cached
+#[cfg(unix)]
+const SETTLE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Signature {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+struct Cache {
+    digests: HashMap<(u64, u64), (Signature, FileKey)>,
+    file: Option<File>,
+}
+
+type Registry = Mutex<HashMap<PathBuf, Arc<Mutex<Cache>>>>;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+fn cache_path(storage_path: &Path) -> PathBuf {
+    storage_path.join("hash-cache")
+}
+
+fn cache(storage_path: &Path) -> Result<Arc<Mutex<Cache>>, Error> {
+    let canonical = handles::canonical(storage_path);
+    let mut registry = REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+        .lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(cache) = registry.get(&canonical) {
+        return Ok(cache.clone());
+    }
+    let cache = Arc::new(Mutex::new(load(storage_path)?));
+    registry.insert(canonical, cache.clone());
+    Ok(cache)
+}
+
+fn load(storage_path: &Path) -> Result<Cache, Error> {
+    let mut digests = HashMap::new();
+    let text = match fs::read_to_string(cache_path(storage_path)) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(From::from((e, "Unable to read hash cache"))),
+    };
+    let mut lines = text.lines();
+    if lines.next() == Some(HEADER) {
+        for line in lines {
+            // Skips lines cut short by a crash
+            if let Some((signature, key)) = parse(line) {
+                digests.insert((signature.dev, signature.ino), (signature, key));
+            }
+        }
+    }
+    Ok(Cache { digests, file: None })
+}
+
+fn parse(line: &str) -> Option<(Signature, FileKey)> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    if fields.len() != 8 {
+        return None;
+    }
+    let number = |i: usize| fields[i].parse::<i64>().ok();
+    let signature = Signature {
+        dev: fields[0].parse().ok()?,
+        ino: fields[1].parse().ok()?,
+        size: fields[2].parse().ok()?,
+        mtime: (number(3)?, number(4)?),
+        ctime: (number(5)?, number(6)?),
+    };
+    let key = FileKey(fields[7].to_owned());
+    if !key.is_well_formed() {
+        return None;
+    }
+    Some((signature, key))
+}
+
+#[cfg(unix)]
+fn signature(metadata: &fs::Metadata) -> Option<Signature> {
+    use std::os::unix::fs::MetadataExt;
+    let settled = SystemTime::now().checked_sub(SETTLE)?
+        .duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    if metadata.mtime() >= settled || metadata.ctime() >= settled {
+        return None;
+    }
+    Some(Signature {
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+        size: metadata.size(),
+        mtime: (metadata.mtime(), metadata.mtime_nsec()),
+        ctime: (metadata.ctime(), metadata.ctime_nsec()),
+    })
+}
+
+#[cfg(not(unix))]
+fn signature(_metadata: &fs::Metadata) -> Option<Signature> {
+    None
+}
+
+/// The key of the file at `path`, from the cache if the file is unchanged
+/// since it was last hashed, and otherwise by hashing it
+pub(crate) fn file_key(storage_path: &Path, path: &Path, algorithm: HashAlgorithm,
+                       buffer_size: usize) -> Result<FileKey, Error>
+{
+    let metadata = fs::metadata(path)
+        .map_err(|e| { (e, "Cannot stat content file") } )?;
+    let signature = match signature(&metadata) {
+        Some(signature) => signature,
+        None => return Ok(FileKey(hash_file(path, algorithm, buffer_size)?)),
+    };
+    let cache = cache(storage_path)?;
+    {
+        let cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((cached, key)) = cache.digests.get(&(signature.dev, signature.ino)) {
+            // Entries from before a rehash_store() are of no use
+            if *cached == signature && key.algorithm() == Some(algorithm) {
+                return Ok(key.clone());
+            }
+        }
+    }
+
+    let key = FileKey(hash_file(path, algorithm, buffer_size)?);
+    let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = append(storage_path, &mut cache, &signature, &key) {
+        // The key is still good; it just won't be remembered
+        log::warn!("Unable to update hash cache: {:?}", e);
+    }
+    cache.digests.insert((signature.dev, signature.ino), (signature, key.clone()));
+    Ok(key)
+}
+
+fn append(storage_path: &Path, cache: &mut Cache, signature: &Signature, key: &FileKey)
+          -> io::Result<()>
+{
+    if cache.file.is_none() {
+        let path = cache_path(storage_path);
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", HEADER)?;
+        }
+        cache.file = Some(file);
+    }
+    let file = cache.file.as_mut().expect("opened above");
+    writeln!(file, "{} {} {} {} {} {} {} {}",
+             signature.dev, signature.ino, signature.size,
+             signature.mtime.0, signature.mtime.1, signature.ctime.0, signature.ctime.1,
+             key)
+}
@@ -0,0 +1,275 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A small embedded HTTP server exposing a store as a content-addressed
+//! blob service (requires the `http` feature).
+//!
+//! * `PUT /objects` stores the request body and responds `201 Created` with
+//!   the key as the body and a `Location` header, or `413 Payload Too
+//!   Large` if the body is longer than `Server::with_max_object_size()`
+//!   allows.
+//! * `GET /objects/{key}` streams the content, with the key as its `ETag`
+//!   and the type recorded for it as its `Content-Type` (see
+//!   `Config::detect_content_type`), or `application/octet-stream`.
+//! * `DELETE /objects/{key}` drops one reference, responding `204 No Content`.
+//! * `GET /download/{token}` streams the object (or byte range, with
+//!   `206 Partial Content`) that a token from `token::TokenSigner` grants,
+//!   responding `403 Forbidden` if the token is invalid or has expired.
+//!   Only served when the server is given a signer (see
+//!   `Server::with_token_signer()`).
+//! * Keys in URLs and responses are written as the store gives keys out
+//!   (see the `encoding` module).
+//! * `GET /health` responds `200 OK` if the store is usable and
+//!   `503 Service Unavailable` if not (see `health()`), for readiness probes.
+
+use std::fs::File;
+use std::io::{self,Read,Seek,SeekFrom};
+use std::net::ToSocketAddrs;
+use std::path::{Path,PathBuf};
+
+use tiny_http::{Header,Method,Request,Response,StatusCode};
+
+use super::{Config,Error,FileKey};
+use super::error::ErrorKind;
+use super::token::TokenSigner;
+
+/// An HTTP server over a single store
+pub struct Server {
+    inner: tiny_http::Server,
+    storage_path: PathBuf,
+    config: Config,
+    signer: Option<TokenSigner>,
+}
+
+impl Server {
+    /// Listen on `addr`, serving the store at `storage_path`
+    pub fn bind<A: ToSocketAddrs>(addr: A, storage_path: &Path) -> Result<Server, Error> {
+        let inner = tiny_http::Server::http(addr)
+            .map_err(|e| { (io::Error::other(e), "Unable to bind HTTP server") } )?;
+        Ok(Server {
+            inner,
+            storage_path: storage_path.to_path_buf(),
+            config: Config::default(),
+            signer: None,
+        })
+    }
+
+    /// Refuse uploads longer than `limit` bytes, as `Config::max_object_size`
+    /// does
+    pub fn with_max_object_size(mut self, limit: u64) -> Server {
+        self.config.max_object_size = Some(limit);
+        self
+    }
+
+    /// Serve `GET /download/{token}` for tokens checked with `signer`
+    pub fn with_token_signer(mut self, signer: TokenSigner) -> Server {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Serve requests until the listener fails.  Requests are handled one
+    /// at a time; call `handle()` from several threads with requests from
+    /// `recv()` for concurrency.
+    pub fn run(&self) -> Result<(), Error> {
+        loop {
+            let request = self.recv()?;
+            self.handle(request);
+        }
+    }
+
+    /// Block until the next request arrives
+    pub fn recv(&self) -> Result<Request, Error> {
+        self.inner.recv()
+            .map_err(|e| { From::from((e, "Unable to receive HTTP request")) } )
+    }
+
+    /// Respond to a single request.  Failures to write the response (e.g. the
+    /// client went away) are logged and otherwise ignored.
+    pub fn handle(&self, request: Request) {
+        let url = request.url().to_owned();
+        let path = url.split('?').next().unwrap_or("");
+        let result = match (request.method(), path) {
+            (&Method::Put, "/objects") => self.put(request),
+            (method, p) if p.starts_with("/objects/") => {
+                match self.parse_key(&p["/objects/".len()..]) {
+                    Ok(None) => respond_status(request, 404),
+                    Ok(Some(key)) => {
+                        if *method == Method::Get || *method == Method::Head {
+                            self.get(request, &key)
+                        } else if *method == Method::Delete {
+                            self.delete(request, &key)
+                        } else {
+                            respond_status(request, 405)
+                        }
+                    },
+                    Err(e) => Err(e),
+                }
+            },
+            (_, "/objects") => respond_status(request, 405),
+            (method, p) if p.starts_with("/download/") && self.signer.is_some() => {
+                if *method == Method::Get || *method == Method::Head {
+                    let token = p["/download/".len()..].to_owned();
+                    self.download(request, &token)
+                } else {
+                    respond_status(request, 405)
+                }
+            },
+            (&Method::Get, "/health") => self.health(request),
+            (_, "/health") => respond_status(request, 405),
+            _ => respond_status(request, 404),
+        };
+        if let Err(e) = result {
+            log::log!(e.log_level(), "filestore http: {:?}", e);
+        }
+    }
+
+    // The key in a URL, written as the store gives keys out
+    fn parse_key(&self, text: &str) -> Result<Option<FileKey>, Error> {
+        super::parse_key(&self.storage_path, text)
+    }
+
+    fn put(&self, mut request: Request) -> Result<(), Error> {
+        let stored = match request.body_length() {
+            Some(len) => super::store_reader_sized_new(&self.storage_path, &self.config,
+                                                       request.as_reader(), len as u64),
+            None => super::store_reader_new(&self.storage_path, &self.config,
+                                            request.as_reader()),
+        };
+        let key = match stored {
+            Ok((key, _)) => key,
+            Err(ref e) if matches!(e.kind, ErrorKind::TooLarge { .. }) => {
+                return respond_status(request, 413);
+            },
+            Err(e) => {
+                log::warn!("filestore http: store failed: {:?}", e);
+                return respond_status(request, 500);
+            }
+        };
+        let key = super::encode_key(&self.storage_path, &key)?;
+        let location = format!("/objects/{}", key);
+        let response = Response::from_string(key)
+            .with_status_code(201)
+            .with_header(header("Location", &location))
+            .with_header(header("Content-Type", "text/plain"));
+        request.respond(response)?;
+        Ok(())
+    }
+
+    fn get(&self, request: Request, key: &FileKey) -> Result<(), Error> {
+        let etag = format!("\"{}\"", key);
+        let not_modified = request.headers().iter()
+            .any(|h| h.field.equiv("If-None-Match") && h.value.as_str() == etag);
+
+        let path = match super::retrieve_file(&self.storage_path, key) {
+            Some(p) => p,
+            None => return respond_status(request, 404),
+        };
+        if not_modified {
+            let response = Response::empty(304)
+                .with_header(header("ETag", &etag));
+            request.respond(response)?;
+            return Ok(());
+        }
+        let file = File::open(&path)
+            .map_err(|e| { (e, "Unable to open stored file") } )?;
+        let content_type = super::content_type(&self.storage_path, key)?
+            .unwrap_or_else(|| "application/octet-stream".to_owned());
+        let response = Response::from_file(file)
+            .with_header(header("ETag", &etag))
+            .with_header(header("Content-Type", &content_type))
+            .with_header(header("Cache-Control", "public, max-age=31536000, immutable"));
+        request.respond(response)?;
+        Ok(())
+    }
+
+    fn download(&self, request: Request, token: &str) -> Result<(), Error> {
+        let signer = match self.signer {
+            Some(ref signer) => signer,
+            None => return respond_status(request, 404),
+        };
+        let grant = match signer.verify(token) {
+            Ok(grant) => grant,
+            Err(ref e) if e.kind == ErrorKind::InvalidToken || e.kind == ErrorKind::TokenExpired => {
+                return respond_status(request, 403);
+            },
+            Err(e) => return Err(e),
+        };
+        let mut file = match super::retrieve_open(&self.storage_path, &grant.key) {
+            Ok(file) => file,
+            Err(ref e) if e.io.kind() == io::ErrorKind::NotFound => {
+                return respond_status(request, 404);
+            },
+            Err(e) => return Err(e),
+        };
+        let size = file.metadata()
+            .map_err(|e| { (e, "Unable to stat stored file") } )?
+            .len();
+        let content_type = super::content_type(&self.storage_path, &grant.key)?
+            .unwrap_or_else(|| "application/octet-stream".to_owned());
+        let mut headers = vec![
+            header("ETag", &format!("\"{}\"", grant.key)),
+            header("Content-Type", &content_type),
+            header("Cache-Control", "private"),
+        ];
+        let range = match grant.range {
+            Some(range) => range.start.min(size)..range.end.min(size),
+            None => {
+                let response = Response::new(StatusCode(200), headers, file,
+                                             Some(size as usize), None);
+                request.respond(response)?;
+                return Ok(());
+            },
+        };
+        file.seek(SeekFrom::Start(range.start))
+            .map_err(|e| { (e, "Unable to seek in stored file") } )?;
+        let len = range.end - range.start;
+        if len > 0 {
+            headers.push(header("Content-Range",
+                                &format!("bytes {}-{}/{}", range.start, range.end - 1, size)));
+        }
+        let response = Response::new(StatusCode(206), headers, file.take(len),
+                                     Some(len as usize), None);
+        request.respond(response)?;
+        Ok(())
+    }
+
+    fn health(&self, request: Request) -> Result<(), Error> {
+        match super::health(&self.storage_path) {
+            Ok(ref health) if health.is_healthy() => respond_status(request, 200),
+            Ok(health) => {
+                log::warn!("filestore http: store is unhealthy: {:?}", health);
+                respond_status(request, 503)
+            },
+            Err(e) => {
+                log::warn!("filestore http: health check failed: {:?}", e);
+                respond_status(request, 503)
+            }
+        }
+    }
+
+    fn delete(&self, request: Request, key: &FileKey) -> Result<(), Error> {
+        if super::refcount(&self.storage_path, key)? < 1 {
+            return respond_status(request, 404);
+        }
+        match super::delete(&self.storage_path, key) {
+            Ok(()) => respond_status(request, 204),
+            Err(e) => {
+                log::warn!("filestore http: delete failed: {:?}", e);
+                respond_status(request, 500)
+            }
+        }
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes())
+        .expect("header fields and values are ASCII")
+}
+
+fn respond_status(request: Request, code: u16) -> Result<(), Error> {
+    let status = StatusCode(code);
+    let response = Response::from_string(status.default_reason_phrase())
+        .with_status_code(status);
+    request.respond(response)?;
+    Ok(())
+}
@@ -0,0 +1,177 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Bulk import of a directory tree, hashing and storing files on several
+//! threads at once.
+
+use std::collections::{BTreeMap,HashMap,HashSet};
+use std::fs::{self,File};
+use std::path::{Path,PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use std::thread;
+
+use super::{Config,Error,FileKey};
+use super::limit;
+use super::precheck;
+use super::spool::Spool;
+use super::storable::Storable;
+
+/// Store every regular file under `dir`, using up to `concurrency` threads,
+/// and return the key of each keyed by its path relative to `dir`.
+///
+/// Symbolic links are not followed.  If `dir` contains the storage path, the
+/// storage path is skipped.  On failure, references already taken by this
+/// import are released again before the error is returned.
+pub fn import_dir(storage_path: &Path, dir: &Path, concurrency: usize)
+                  -> Result<BTreeMap<PathBuf, FileKey>, Error>
+{
+    import_dir_new(storage_path, &Config::default(), dir, concurrency).map(|(files, _)| files)
+}
+
+// As `import_dir()`, also returning the keys that were newly stored
+pub(crate) fn import_dir_new(storage_path: &Path, config: &Config, dir: &Path,
+                             concurrency: usize)
+                             -> Result<(BTreeMap<PathBuf, FileKey>, HashSet<FileKey>), Error>
+{
+    let mut files: Vec<PathBuf> = Vec::new();
+    let skip = fs::canonicalize(storage_path).ok();
+    walk(dir, skip.as_deref(), &mut files)?;
+
+    let (keys, new_objects) = import_files(storage_path, config, &files, concurrency)?;
+
+    let files = files.into_iter()
+        .zip(keys)
+        .map(|(path, key)| {
+            let relative = path.strip_prefix(dir).map(|p| p.to_path_buf()).unwrap_or(path);
+            (relative, key)
+        })
+        .collect();
+    Ok((files, new_objects))
+}
+
+// Store each of `files`, returning their keys in the same order, and the
+// keys that were newly stored
+pub(crate) fn import_files(storage_path: &Path, config: &Config, files: &[PathBuf],
+                           concurrency: usize)
+                           -> Result<(Vec<FileKey>, HashSet<FileKey>), Error>
+{
+    // Hash everything first, so that files with the same content can be
+    // stored once with all their references taken together.  Files that
+    // the precheck shows to be new are copied in while they are hashed.
+    let hashed: Vec<(FileKey, Mutex<Option<Spool>>)> = parallel(files, concurrency, |path| {
+        let _permit = limit::acquire(storage_path, config.max_concurrent_operations);
+        if config.max_object_size.is_some() {
+            let len = fs::metadata(path)
+                .map_err(|e| { (e, "Cannot stat content file") } )?
+                .len();
+            config.check_object_size(len)?;
+        }
+        if config.precheck && !precheck::may_be_stored(storage_path, path)? {
+            let mut spool = Spool::new(storage_path, config.direct_io)?;
+            let mut file = File::open(path)
+                .map_err(|e| { (e, "Cannot open content file") } )?;
+            super::spool_input(config, &mut file, &mut spool)?;
+            return Ok((FileKey(spool.hash()), Mutex::new(Some(spool))));
+        }
+        Ok((super::file_key(storage_path, config, path)?, Mutex::new(None)))
+    })?;
+
+    let mut distinct: HashMap<&FileKey, (u64, usize)> = HashMap::new();
+    for (i, (key, _)) in hashed.iter().enumerate() {
+        distinct.entry(key).or_insert((0, i)).0 += 1;
+    }
+    let distinct: Vec<(&FileKey, (u64, usize))> = distinct.into_iter().collect();
+
+    let committed: Mutex<Vec<(&FileKey, u64, bool)>> = Mutex::new(Vec::new());
+    let result = parallel(&distinct, concurrency, |&(key, (references, i))| {
+        let _permit = limit::acquire(storage_path, config.max_concurrent_operations);
+        let spool = hashed[i].1.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let placed = super::commit(storage_path, config, key, references, |dest_path| {
+            match spool {
+                Some(spool) => spool.persist(dest_path),
+                None => files[i].store(dest_path),
+            }
+        })?;
+        committed.lock().unwrap_or_else(|e| e.into_inner()).push((key, references, placed));
+        Ok(())
+    });
+    let committed = committed.into_inner().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = result {
+        for (key, references, _) in committed {
+            for _ in 0..references {
+                if let Err(e) = super::release(storage_path, config, key) {
+                    log::warn!("Unable to release {} after failed import: {:?}", key, e);
+                }
+            }
+        }
+        return Err(e);
+    }
+    let new_objects = committed.into_iter()
+        .filter(|&(_, _, placed)| placed)
+        .map(|(key, _, _)| key.clone())
+        .collect();
+    Ok((hashed.into_iter().map(|(key, _)| key).collect(), new_objects))
+}
+
+// Collect the regular files under `dir`, not descending into `skip`
+fn walk(dir: &Path, skip: Option<&Path>, files: &mut Vec<PathBuf>) -> Result<(), Error>
+{
+    for entry in fs::read_dir(dir)
+        .map_err(|e| { (e, "Unable to read import directory") } )?
+    {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read import directory") } )?;
+        let file_type = entry.file_type()
+            .map_err(|e| { (e, "Unable to stat import file") } )?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            if let Some(skip) = skip {
+                if fs::canonicalize(&path).ok().as_deref() == Some(skip) {
+                    continue;
+                }
+            }
+            walk(&path, skip, files)?;
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Apply `f` to every item on up to `concurrency` threads, returning the
+// results in order.  Stops handing out work after the first failure.
+fn parallel<T, U, F>(items: &[T], concurrency: usize, f: F) -> Result<Vec<U>, Error>
+    where T: Sync,
+          U: Send,
+          F: Fn(&T) -> Result<U, Error> + Sync
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<U, Error>>>> =
+        Mutex::new((0..items.len()).map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..concurrency.max(1).min(items.len()) {
+            scope.spawn(|| {
+                while !failed.load(Ordering::SeqCst) {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= items.len() {
+                        break;
+                    }
+                    let result = f(&items[i]);
+                    if result.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+                }
+            });
+        }
+    });
+
+    // Items are only left unprocessed after a failure, which collect() finds
+    results.into_inner().unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .flatten()
+        .collect()
+}
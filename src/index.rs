@@ -0,0 +1,351 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! An index of the objects in a store (requires the `redb` feature), so
+//! that listing tens of millions of them needn't walk as many files.
+//!
+//! The index is `index` in the storage path, an embedded redb database
+//! recording each object's size, refcount, and when it was stored and its
+//! references last changed.  Once `build_index()` has made it, every change
+//! made through this crate updates it while the object's refcount lock is
+//! held, before the change is marked complete in the journal, so that it
+//! agrees with the object files; if a process dies between the two,
+//! `recover()` puts the object's entry right as it replays the change.
+//!
+//! Builds without the feature remove the index when they change a store
+//! that has one, rather than let it fall behind.  Call `build_index()`
+//! again to rebuild it from a scan of the object files, as after changing
+//! them by hand, or remove `index` to do without it.
+//!
+//! The database can only be open in one process at a time, so it is opened
+//! for each change and closed again, which makes each store and delete
+//! slower.  Listing takes it a page at a time, so as not to hold up
+//! writers.
+
+use std::io;
+use std::path::{Path,PathBuf};
+#[cfg(feature = "redb")]
+use std::fs;
+#[cfg(feature = "redb")]
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+#[cfg(feature = "redb")]
+use redb::{Database,ReadableTable,TableDefinition};
+
+use super::{Config,Error,FileKey};
+#[cfg(feature = "redb")]
+use super::{Durability,Keys,get_refcount,keylock,storage_file_path,writelock};
+#[cfg(feature = "redb")]
+use super::error::ErrorKind;
+
+const FILE_NAME: &str = "index";
+
+// Size, refcount, and stored and updated times in nanoseconds since the
+// Unix epoch, by key
+#[cfg(feature = "redb")]
+const OBJECTS: TableDefinition<&str, (u64, u64, u64, u64)> = TableDefinition::new("objects");
+
+// Entries read at a time by `IndexedObjects`
+#[cfg(feature = "redb")]
+const PAGE: usize = 4096;
+
+fn index_path(storage_path: &Path) -> PathBuf {
+    storage_path.join(FILE_NAME)
+}
+
+/// An object as recorded in the index
+#[cfg(feature = "redb")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// The object's key
+    pub key: FileKey,
+    /// Bytes of content
+    pub size: u64,
+    /// References held on it
+    pub refcount: u64,
+    /// When it was stored, or for objects stored before the index was
+    /// built, when its file was last modified
+    pub stored: SystemTime,
+    /// When its references last changed
+    pub updated: SystemTime,
+}
+
+#[cfg(feature = "redb")]
+impl IndexEntry {
+    fn from_record(key: &str, (size, refcount, stored, updated): (u64, u64, u64, u64))
+                   -> IndexEntry
+    {
+        IndexEntry {
+            key: FileKey(key.to_owned()),
+            size,
+            refcount,
+            stored: UNIX_EPOCH + Duration::from_nanos(stored),
+            updated: UNIX_EPOCH + Duration::from_nanos(updated),
+        }
+    }
+}
+
+#[cfg(feature = "redb")]
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+#[cfg(feature = "redb")]
+fn db_error<E: Into<redb::Error>>(e: E, message: &str) -> Error {
+    From::from((io::Error::other(e.into()), message))
+}
+
+#[cfg(feature = "redb")]
+fn no_index() -> Error {
+    From::from((io::Error::from(io::ErrorKind::NotFound), "Store has no index"))
+}
+
+// The index, if the store has one.  Must be called holding the index lock.
+#[cfg(feature = "redb")]
+fn open(storage_path: &Path) -> Result<Option<Database>, Error> {
+    let path = index_path(storage_path);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Database::open(&path)
+        .map(Some)
+        .map_err(|e| db_error(e, "Unable to open index"))
+}
+
+/// Whether the store at `storage_path` has an index
+pub fn has_index(storage_path: &Path) -> bool {
+    index_path(storage_path).exists()
+}
+
+/// Bring the index entry of `key` into line with its files, if the store
+/// has an index.  Must be called holding `key`'s refcount lock, or the
+/// store exclusively.
+#[cfg(feature = "redb")]
+pub(crate) fn update(storage_path: &Path, config: &Config, key: &FileKey) -> Result<(), Error> {
+    let _lock = keylock::lock_index(storage_path)?;
+    let db = match open(storage_path)? {
+        Some(db) => db,
+        None => return Ok(()),
+    };
+    let size = match fs::metadata(storage_file_path(storage_path, key)) {
+        Ok(metadata) => Some(metadata.len()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(From::from((e, "Unable to stat stored file"))),
+    };
+    let refcount = get_refcount(storage_path, key)?;
+
+    let mut txn = db.begin_write()
+        .map_err(|e| db_error(e, "Unable to update index"))?;
+    txn.set_durability(match config.durability {
+        Durability::None => redb::Durability::Eventual,
+        _ => redb::Durability::Immediate,
+    });
+    {
+        let mut table = txn.open_table(OBJECTS)
+            .map_err(|e| db_error(e, "Unable to update index"))?;
+        match size {
+            Some(size) => {
+                let now = nanos(SystemTime::now());
+                let previous = table.get(&**key)
+                    .map_err(|e| db_error(e, "Unable to read index"))?
+                    .map(|record| record.value());
+                let record = match previous {
+                    // Unchanged, as when a retried change is replayed
+                    Some(record) if record.0 == size && record.1 == refcount => None,
+                    Some((_, _, stored, _)) => Some((size, refcount, stored, now)),
+                    None => Some((size, refcount, now, now)),
+                };
+                if let Some(record) = record {
+                    table.insert(&**key, record)
+                        .map_err(|e| db_error(e, "Unable to update index"))?;
+                }
+            },
+            None => {
+                table.remove(&**key)
+                    .map_err(|e| db_error(e, "Unable to update index"))?;
+            },
+        }
+    }
+    txn.commit()
+        .map_err(|e| db_error(e, "Unable to update index"))
+}
+
+/// Without the `redb` feature the index can't be kept up to date, so it is
+/// removed rather than left to fall behind
+#[cfg(not(feature = "redb"))]
+pub(crate) fn update(storage_path: &Path, _config: &Config, _key: &FileKey) -> Result<(), Error> {
+    let path = index_path(storage_path);
+    if !path.exists() {
+        return Ok(());
+    }
+    log::warn!("Removing the index of {}, which this build can't maintain",
+               storage_path.display());
+    match std::fs::remove_file(&path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(From::from((e, "Unable to remove index"))),
+    }
+}
+
+/// Build an index of the objects in the store at `storage_path` from a
+/// scan of its files, replacing any it has, and return the number of
+/// objects indexed.  From then on, changes made by any process using this
+/// crate with the `redb` feature keep it up to date.  Stored times are
+/// kept from the index being replaced.
+///
+/// The store is taken exclusively while the index is built, failing with
+/// `ErrorKind::Locked` if another process is writing to it.
+#[cfg(feature = "redb")]
+pub fn build_index(storage_path: &Path) -> Result<u64, Error> {
+    let op = operation!("filestore.build_index", objects);
+    let result = build(storage_path);
+    if let Ok(objects) = result {
+        op.record("objects", objects);
+    }
+    op.finish(result)
+}
+
+#[cfg(feature = "redb")]
+fn build(storage_path: &Path) -> Result<u64, Error> {
+    let _exclusive = writelock::exclusive_or_held(storage_path)?;
+    let _lock = keylock::lock_index(storage_path)?;
+    let db = Database::create(index_path(storage_path))
+        .map_err(|e| db_error(e, "Unable to create index"))?;
+    let txn = db.begin_write()
+        .map_err(|e| db_error(e, "Unable to build index"))?;
+    let mut objects: u64 = 0;
+    {
+        let mut table = txn.open_table(OBJECTS)
+            .map_err(|e| db_error(e, "Unable to build index"))?;
+        let now = nanos(SystemTime::now());
+        for key in Keys::new(storage_path)? {
+            let key = key?;
+            let metadata = match fs::metadata(storage_file_path(storage_path, &key)) {
+                Ok(metadata) => metadata,
+                // Removed since it was listed
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(From::from((e, "Unable to stat stored file"))),
+            };
+            let refcount = match get_refcount(storage_path, &key) {
+                Ok(refcount) => refcount,
+                Err(ref e) if e.kind == ErrorKind::CorruptRefcount => {
+                    log::warn!("Indexing {}, whose refcount file is damaged, as unreferenced",
+                               key);
+                    0
+                },
+                Err(e) => return Err(e),
+            };
+            let previous = table.get(&*key)
+                .map_err(|e| db_error(e, "Unable to read index"))?
+                .map(|record| record.value().2);
+            let stored = match previous {
+                Some(stored) => stored,
+                None => metadata.modified().map(nanos).unwrap_or(now),
+            };
+            table.insert(&*key, (metadata.len(), refcount, stored, now))
+                .map_err(|e| db_error(e, "Unable to build index"))?;
+            objects += 1;
+        }
+        // Every object found was just written, so what's left is gone
+        table.retain(|_, (_, _, _, updated)| updated == now)
+            .map_err(|e| db_error(e, "Unable to build index"))?;
+    }
+    txn.commit()
+        .map_err(|e| db_error(e, "Unable to build index"))?;
+    Ok(objects)
+}
+
+/// The index entry of `key`, or `None` if it is not stored.  Fails with an
+/// error of kind `NotFound` if the store has no index.
+#[cfg(feature = "redb")]
+pub fn index_entry(storage_path: &Path, key: &FileKey) -> Result<Option<IndexEntry>, Error> {
+    let _lock = keylock::lock_index(storage_path)?;
+    let db = open(storage_path)?.ok_or_else(no_index)?;
+    let txn = db.begin_read()
+        .map_err(|e| db_error(e, "Unable to read index"))?;
+    let table = txn.open_table(OBJECTS)
+        .map_err(|e| db_error(e, "Unable to read index"))?;
+    let record = table.get(&**key)
+        .map_err(|e| db_error(e, "Unable to read index"))?;
+    Ok(record.map(|record| IndexEntry::from_record(key, record.value())))
+}
+
+/// Iterate over the index entries of every object in the store at
+/// `storage_path`, in key order.  Fails with an error of kind `NotFound`
+/// if the store has no index.
+///
+/// Entries are read a page at a time, so objects stored or removed while
+/// iterating may or may not be seen.
+#[cfg(feature = "redb")]
+pub fn indexed_objects(storage_path: &Path) -> Result<IndexedObjects, Error> {
+    if !has_index(storage_path) {
+        return Err(no_index());
+    }
+    Ok(IndexedObjects {
+        storage_path: storage_path.to_path_buf(),
+        page: Vec::new().into_iter(),
+        last: None,
+        done: false,
+    })
+}
+
+/// Iterator over index entries, returned by `indexed_objects()`
+#[cfg(feature = "redb")]
+pub struct IndexedObjects {
+    storage_path: PathBuf,
+    page: std::vec::IntoIter<IndexEntry>,
+    last: Option<FileKey>,
+    done: bool,
+}
+
+#[cfg(feature = "redb")]
+impl IndexedObjects {
+    // The entries after the last one returned
+    fn next_page(&self) -> Result<Vec<IndexEntry>, Error> {
+        let _lock = keylock::lock_index(&self.storage_path)?;
+        let db = open(&self.storage_path)?.ok_or_else(no_index)?;
+        let txn = db.begin_read()
+            .map_err(|e| db_error(e, "Unable to read index"))?;
+        let table = txn.open_table(OBJECTS)
+            .map_err(|e| db_error(e, "Unable to read index"))?;
+        let range = match self.last {
+            Some(ref last) => table.range::<&str>((std::ops::Bound::Excluded(&**last),
+                                                   std::ops::Bound::Unbounded)),
+            None => table.range::<&str>(..),
+        }.map_err(|e| db_error(e, "Unable to read index"))?;
+        let mut page: Vec<IndexEntry> = Vec::with_capacity(PAGE);
+        for record in range.take(PAGE) {
+            let (key, value) = record.map_err(|e| db_error(e, "Unable to read index"))?;
+            page.push(IndexEntry::from_record(key.value(), value.value()));
+        }
+        Ok(page)
+    }
+}
+
+#[cfg(feature = "redb")]
+impl Iterator for IndexedObjects {
+    type Item = Result<IndexEntry, Error>;
+
+    fn next(&mut self) -> Option<Result<IndexEntry, Error>> {
+        if let Some(entry) = self.page.next() {
+            self.last = Some(entry.key.clone());
+            return Some(Ok(entry));
+        }
+        if self.done {
+            return None;
+        }
+        match self.next_page() {
+            Ok(page) => {
+                self.done = page.len() < PAGE;
+                self.page = page.into_iter();
+                let entry = self.page.next()?;
+                self.last = Some(entry.key.clone());
+                Some(Ok(entry))
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// A write-ahead journal for refcount changes.  Before an object's refcount
+// is changed (and before new content is put in place) an intent file is
+// written under `journal/` in the storage path, giving the key and the
+// refcount before and after.  It is removed once the change is complete.
+//
+// An intent left behind by a process that died is resolved by `replay()`:
+// if the refcount on disk already has the new value the change is finished
+// off (removing the object if that was its last reference), and otherwise
+// the old refcount is restored (removing the object if it had none), since
+// the caller never saw the operation succeed.
+
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,Read,Write};
+use std::path::{Path,PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+use super::{Config,Durability,Error,FileKey};
+use super::error::ErrorKind;
+use super::{forget_object,get_refcount,index,keylock,pin,remove_object,set_refcount,spool,
+            sync};
+
+static JOURNAL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+const HEADER: &str = "filestore-journal 1";
+
+fn journal_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("journal")
+}
+
+/// A recorded, not yet completed, refcount change
+pub(crate) struct Intent {
+    path: PathBuf,
+}
+
+impl Intent {
+    pub fn record(storage_path: &Path, config: &Config, key: &FileKey, before: u64, after: u64)
+                  -> Result<Intent, Error>
+    {
+        let dir = journal_dir(storage_path);
+        let content = format!("{}\n{} {} {}\n", HEADER, key, before, after);
+        loop {
+            let path = dir.join(format!(
+                "{}-{}", process::id(), JOURNAL_COUNTER.fetch_add(1, Ordering::SeqCst)));
+            let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => file,
+                // Left over from an earlier process with the same pid
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    if let Err(e) = fs::create_dir(&dir) {
+                        if e.kind() != io::ErrorKind::AlreadyExists {
+                            return Err(From::from((e, "Unable to create journal directory")));
+                        }
+                    }
+                    continue;
+                },
+                Err(e) => return Err(From::from((e, "Unable to create journal entry"))),
+            };
+            let intent = Intent { path };
+            let written = file.write_all(content.as_bytes())
+                .and_then(|()| match config.durability >= Durability::FlushData {
+                    true => file.sync_data(),
+                    false => Ok(()),
+                });
+            if let Err(e) = written {
+                let _ = intent.done();
+                return Err(From::from((e, "Unable to write journal entry")));
+            }
+            if config.durability == Durability::FlushDataAndDir {
+                sync::sync_dir(&dir)?;
+            }
+            return Ok(intent);
+        }
+    }
+
+    /// Mark the change complete
+    pub fn done(self) -> Result<(), Error> {
+        fs::remove_file(&self.path)
+            .map_err(|e| { From::from((e, "Unable to remove journal entry")) } )
+    }
+}
+
+/// How a journalled change was resolved by `replay()`
+pub(crate) enum Resolution {
+    RolledBack(FileKey),
+    RolledForward(FileKey),
+}
+
+/// Resolve the intents left by processes that are no longer running
+pub(crate) fn replay(storage_path: &Path) -> Result<Vec<Resolution>, Error> {
+    let mut resolved: Vec<Resolution> = Vec::new();
+    let entries = match fs::read_dir(journal_dir(storage_path)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(resolved),
+        Err(e) => return Err(From::from((e, "Unable to read journal directory"))),
+    };
+    let config = Config::default();
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read journal directory") } )?;
+        let pid = match entry.file_name().to_str()
+            .and_then(|n| n.split_once('-'))
+            .and_then(|(pid, _)| pid.parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if spool::is_running(pid) {
+            continue;
+        }
+        let path = entry.path();
+        // An unreadable intent was cut short, so nothing was done after it
+        if let Some((key, before, after)) = read_intent(&path)? {
+            let _lock = keylock::lock(storage_path, &key)?;
+            // A damaged count was being written when the process died
+            let current = match get_refcount(storage_path, &key) {
+                Ok(count) => Some(count),
+                Err(ref e) if e.kind == ErrorKind::CorruptRefcount => None,
+                Err(e) => return Err(e),
+            };
+            let (refcount, resolution) = if current == Some(after) {
+                (after, Resolution::RolledForward(key.clone()))
+            } else {
+                if current != Some(before) {
+                    set_refcount(storage_path, &config, &key, before)?;
+                }
+                (before, Resolution::RolledBack(key.clone()))
+            };
+            if refcount == 0 && !pin::is_pinned(storage_path, &key)? {
+                match remove_object(storage_path, &config, &key) {
+                    Ok(()) => {},
+                    // Removed before the process died, perhaps leaving what
+                    // is kept about it
+                    Err(ref e) if e.io.kind() == io::ErrorKind::NotFound => {
+                        forget_object(storage_path, &key)?;
+                    },
+                    Err(e) => return Err(e),
+                }
+            }
+            index::update(storage_path, &config, &key)?;
+            resolved.push(resolution);
+        }
+        fs::remove_file(&path)
+            .map_err(|e| { (e, "Unable to remove journal entry") } )?;
+    }
+    Ok(resolved)
+}
+
+fn read_intent(path: &Path) -> Result<Option<(FileKey, u64, u64)>, Error> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| { (e, "Unable to read journal entry") } )?;
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = match lines.next() {
+        Some(line) => line.split(' ').collect(),
+        None => return Ok(None),
+    };
+    if fields.len() != 3 || !text.ends_with('\n') {
+        return Ok(None);
+    }
+    let key = FileKey(fields[0].to_owned());
+    match (key.is_well_formed(), fields[1].parse::<u64>(), fields[2].parse::<u64>()) {
+        (true, Ok(before), Ok(after)) => Ok(Some((key, before, after))),
+        _ => Ok(None),
+    }
+}
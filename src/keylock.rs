@@ -0,0 +1,167 @@
+// Serialization of refcount changes.  Reading an object's refcount,
+// deciding on the new count and writing it back (and placing or removing
+// the content to match) must not interleave with another thread or process
+// doing the same to the same object: a store and a delete racing could
+// otherwise remove content that was just referenced again, and concurrent
+// stores could lose a reference.
+//
+// Rather than a lock per key, keys are spread over a fixed set of stripes by
+// their leading byte, so that work on different objects rarely waits.  Within
+// the process each stripe is a mutex; between processes it is an advisory
+// lock on one byte of `refcount.lock` in the storage path (fcntl record
+// locks on Unix, which also work over NFS, and LockFileEx on Windows).  The
+// lock file is opened once per store and kept open, as closing any handle
+// on it would drop this process's record locks.
+//
+// The byte after the stripes serializes use of the key index (see
+// index.rs), which is taken holding a key's lock, never the other way
+// round.
+//
+// Holding a key's lock also counts as a change in progress for the store's
+// writer lock (see writelock.rs), so it fails rather than waits while
+// another process has the store open exclusively.
+
+use std::collections::HashMap;
+use std::fs::{File,OpenOptions};
+use std::io;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,MutexGuard,OnceLock};
+
+use super::{Error,FileKey,handles};
+use super::writelock::{self,Writing};
+
+const STRIPES: usize = 256;
+
+const LOCK_FILE: &str = "refcount.lock";
+
+static LOCKS: [Mutex<()>; STRIPES] = [const { Mutex::new(()) }; STRIPES];
+
+#[cfg(feature = "redb")]
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+type Registry = Mutex<HashMap<PathBuf, Arc<File>>>;
+
+static LOCK_FILES: OnceLock<Registry> = OnceLock::new();
+
+/// Exclusive use of a key's refcount, until dropped
+pub(crate) struct KeyLock {
+    file: Arc<File>,
+    stripe: usize,
+    // Released after the record lock, as the fields are dropped after
+    // drop(), in order
+    _guard: MutexGuard<'static, ()>,
+    _writing: Writing,
+}
+
+impl Drop for KeyLock {
+    fn drop(&mut self) {
+        if let Err(e) = lock_range(&self.file, self.stripe, false) {
+            log::warn!("Unable to release refcount lock: {:?}", e);
+        }
+    }
+}
+
+/// Wait for, then take, the lock on `key` in the store at `storage_path`.
+/// Must not be called while already holding a lock, as two keys may share
+/// a stripe.
+pub(crate) fn lock(storage_path: &Path, key: &FileKey) -> Result<KeyLock, Error> {
+    let stripe = key.get(..2)
+        .and_then(|prefix| usize::from_str_radix(prefix, 16).ok())
+        .unwrap_or(0);
+    let writing = writelock::begin(storage_path)?;
+    let guard = LOCKS[stripe].lock().unwrap_or_else(|e| e.into_inner());
+    let file = lock_file(storage_path)?;
+    lock_range(&file, stripe, true)
+        .map_err(|e| { (e, "Unable to lock refcount") } )?;
+    Ok(KeyLock { file, stripe, _guard: guard, _writing: writing })
+}
+
+/// Exclusive use of the store's key index, until dropped
+#[cfg(feature = "redb")]
+pub(crate) struct IndexLock {
+    file: Arc<File>,
+    _guard: MutexGuard<'static, ()>,
+}
+
+#[cfg(feature = "redb")]
+impl Drop for IndexLock {
+    fn drop(&mut self) {
+        if let Err(e) = lock_range(&self.file, STRIPES, false) {
+            log::warn!("Unable to release index lock: {:?}", e);
+        }
+    }
+}
+
+/// Wait for, then take, the lock on the key index of the store at
+/// `storage_path`
+#[cfg(feature = "redb")]
+pub(crate) fn lock_index(storage_path: &Path) -> Result<IndexLock, Error> {
+    let guard = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let file = lock_file(storage_path)?;
+    lock_range(&file, STRIPES, true)
+        .map_err(|e| { (e, "Unable to lock index") } )?;
+    Ok(IndexLock { file, _guard: guard })
+}
+
+// The store's lock file, opened on first use
+fn lock_file(storage_path: &Path) -> Result<Arc<File>, Error> {
+    let canonical = handles::canonical(storage_path);
+    let mut registry = LOCK_FILES.get_or_init(|| Mutex::new(HashMap::new()))
+        .lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(file) = registry.get(&canonical) {
+        return Ok(file.clone());
+    }
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+        .open(canonical.join(LOCK_FILE))
+        .map_err(|e| { (e, "Unable to open refcount lock file") } )?;
+    let file = Arc::new(file);
+    registry.insert(canonical, file.clone());
+    Ok(file)
+}
+
+#[cfg(unix)]
+fn lock_range(file: &File, stripe: usize, lock: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut range: libc::flock = unsafe { std::mem::zeroed() };
+    range.l_type = if lock { libc::F_WRLCK } else { libc::F_UNLCK } as _;
+    range.l_whence = libc::SEEK_SET as _;
+    range.l_start = stripe as libc::off_t;
+    range.l_len = 1;
+    let command = if lock { libc::F_SETLKW } else { libc::F_SETLK };
+    loop {
+        if unsafe { libc::fcntl(file.as_raw_fd(), command, &range) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn lock_range(file: &File, stripe: usize, lock: bool) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{LockFileEx,UnlockFileEx,LOCKFILE_EXCLUSIVE_LOCK};
+    use windows_sys::Win32::System::IO::OVERLAPPED;
+
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    overlapped.Anonymous.Anonymous.Offset = stripe as u32;
+    let handle = file.as_raw_handle() as _;
+    let done = unsafe {
+        match lock {
+            true => LockFileEx(handle, LOCKFILE_EXCLUSIVE_LOCK, 0, 1, 0, &mut overlapped),
+            false => UnlockFileEx(handle, 0, 1, 0, &mut overlapped),
+        }
+    };
+    if done == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lock_range(_file: &File, _stripe: usize, _lock: bool) -> io::Result<()> {
+    Ok(())
+}
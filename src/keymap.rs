@@ -0,0 +1,79 @@
+// The translation index from keys of a digest a store has been moved off
+// to their current keys: `key-map` in the storage path, which
+// `rehash_store()` appends a line of the old and new key to for each
+// object it moves.  Later lines win, should a store be moved more than
+// once.
+//
+// The index is read whole on first use and kept per store, and read again
+// whenever the file's size or modification time changes.
+
+use std::collections::HashMap;
+use std::fs::{self,File,OpenOptions};
+use std::io;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,OnceLock};
+use std::time::SystemTime;
+
+use super::{Error,FileKey,handles};
+
+const FILE_NAME: &str = "key-map";
+
+struct Index {
+    len: u64,
+    modified: Option<SystemTime>,
+    keys: Arc<HashMap<FileKey, FileKey>>,
+}
+
+type Registry = Mutex<HashMap<PathBuf, Index>>;
+
+static INDEXES: OnceLock<Registry> = OnceLock::new();
+
+fn key_map_path(storage_path: &Path) -> PathBuf {
+    storage_path.join(FILE_NAME)
+}
+
+/// Open the key map to append moves to, creating it if need be
+pub(crate) fn open_for_append(storage_path: &Path) -> Result<File, Error> {
+    OpenOptions::new().append(true).create(true).open(key_map_path(storage_path))
+        .map_err(|e| { From::from((e, "Unable to open key map")) } )
+}
+
+/// The key that `key`, from before the store at `storage_path` was moved
+/// to another digest by `rehash_store()`, is stored under now, or `None` if
+/// it was never moved
+pub fn translate_key(storage_path: &Path, key: &FileKey) -> Result<Option<FileKey>, Error> {
+    Ok(index(storage_path)?.get(key).cloned())
+}
+
+fn index(storage_path: &Path) -> Result<Arc<HashMap<FileKey, FileKey>>, Error> {
+    let path = key_map_path(storage_path);
+    let (len, modified) = match fs::metadata(&path) {
+        Ok(metadata) => (metadata.len(), metadata.modified().ok()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Arc::default()),
+        Err(e) => return Err(From::from((e, "Unable to stat key map"))),
+    };
+    let canonical = handles::canonical(storage_path);
+    let registry = INDEXES.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(index) = registry.lock().unwrap_or_else(|e| e.into_inner()).get(&canonical) {
+        if index.len == len && modified.is_some() && index.modified == modified {
+            return Ok(index.keys.clone());
+        }
+    }
+    let text = fs::read_to_string(&path)
+        .map_err(|e| { (e, "Unable to read key map") } )?;
+    let mut keys: HashMap<FileKey, FileKey> = HashMap::new();
+    for line in text.lines() {
+        // A line cut short by an interrupted migration is passed over
+        let (old, new) = match line.split_once(' ') {
+            Some((old, new)) => (FileKey(old.to_owned()), FileKey(new.to_owned())),
+            None => continue,
+        };
+        if old.is_well_formed() && new.is_well_formed() {
+            keys.insert(old, new);
+        }
+    }
+    let keys = Arc::new(keys);
+    registry.lock().unwrap_or_else(|e| e.into_inner())
+        .insert(canonical, Index { len, modified, keys: keys.clone() });
+    Ok(keys)
+}
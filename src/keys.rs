@@ -0,0 +1,170 @@
+
+use std::fs::{self,DirEntry,ReadDir};
+use std::io;
+use std::path::{Path,PathBuf};
+use std::time::SystemTime;
+use super::{Error,FileKey};
+
+/// An iterator over the keys of all objects in a store, returned by `keys()`
+///
+/// Keys are yielded in directory order, which is unspecified.  The `with_*`
+/// filters skip objects during the walk, so that finding cleanup
+/// candidates doesn't take a stat per object in the caller as well.
+pub struct Keys {
+    storage_path: PathBuf,
+    shards: ReadDir,
+    current: Option<(String, ReadDir)>,
+    // Suffix of the file names yielded, after the key
+    suffix: &'static str,
+    filter: Filter,
+}
+
+#[derive(Default)]
+struct Filter {
+    min_size: Option<u64>,
+    stored_before: Option<SystemTime>,
+    refcount: Option<u64>,
+}
+
+impl Filter {
+    // Whether the object at `entry` passes the filters.  Objects removed
+    // since they were listed don't.
+    fn matches(&self, storage_path: &Path, entry: &DirEntry, key: &FileKey)
+               -> Result<bool, Error>
+    {
+        if self.min_size.is_some() || self.stored_before.is_some() {
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(From::from((e, "Unable to stat stored file"))),
+            };
+            if self.min_size.is_some_and(|min_size| metadata.len() < min_size) {
+                return Ok(false);
+            }
+            if let Some(stored_before) = self.stored_before {
+                let modified = metadata.modified()
+                    .map_err(|e| { (e, "Unable to read stored file's modification time") } )?;
+                if modified >= stored_before {
+                    return Ok(false);
+                }
+            }
+        }
+        if let Some(refcount) = self.refcount {
+            if super::get_refcount(storage_path, key)? != refcount {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Keys {
+    pub(crate) fn new(storage_path: &Path) -> Result<Keys, Error> {
+        let shards = fs::read_dir(storage_path)
+            .map_err(|e| { (e, "Unable to read storage directory") } )?;
+        Ok(Keys {
+            storage_path: storage_path.to_path_buf(),
+            shards,
+            current: None,
+            suffix: "",
+            filter: Filter::default(),
+        })
+    }
+
+    // The keys of the refcount files under `path`, for directories that
+    // hold refcounts without objects
+    pub(crate) fn refcounted(path: &Path) -> Result<Keys, Error> {
+        Ok(Keys { suffix: ".refcount", ..Keys::new(path)? })
+    }
+
+    /// Only yield objects of at least `bytes`
+    pub fn with_min_size(mut self, bytes: u64) -> Keys {
+        self.filter.min_size = Some(bytes);
+        self
+    }
+
+    /// Only yield objects whose content was first stored before `time`
+    /// (going by the modification time of the object file)
+    pub fn with_stored_before(mut self, time: SystemTime) -> Keys {
+        self.filter.stored_before = Some(time);
+        self
+    }
+
+    /// Only yield objects holding exactly `refcount` references, such as 1
+    /// for content that only one owner is keeping
+    pub fn with_refcount(mut self, refcount: u64) -> Keys {
+        self.filter.refcount = Some(refcount);
+        self
+    }
+
+    /// Yield each key with when it was last retrieved, as given by
+    /// `access::last_access()`
+    pub fn with_last_access(self)
+                            -> impl Iterator<Item = Result<(FileKey, Option<SystemTime>), Error>>
+    {
+        let storage_path = self.storage_path.clone();
+        self.map(move |key| {
+            let key = key?;
+            let last_access = super::access::last_access(&storage_path, &key)?;
+            Ok((key, last_access))
+        })
+    }
+
+    // Advance to the next shard directory, returning false when exhausted
+    fn next_shard(&mut self) -> Result<bool, Error> {
+        for entry in &mut self.shards {
+            let entry = entry
+                .map_err(|e| { (e, "Unable to read storage directory") } )?;
+            let name = match entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            if name.len() != 2 || !entry.path().is_dir() {
+                continue;
+            }
+            let files = fs::read_dir(entry.path())
+                .map_err(|e| { (e, "Unable to read shard directory") } )?;
+            self.current = Some((name, files));
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+impl Iterator for Keys {
+    type Item = Result<FileKey, Error>;
+
+    fn next(&mut self) -> Option<Result<FileKey, Error>> {
+        let suffix = self.suffix;
+        loop {
+            if let Some((ref prefix, ref mut files)) = self.current {
+                for entry in files {
+                    let entry = match entry {
+                        Ok(e) => e,
+                        Err(e) => return Some(Err(From::from(
+                            (e, "Unable to read shard directory")))),
+                    };
+                    let file_name = entry.file_name();
+                    let name = file_name.to_str()
+                        .and_then(|n| n.strip_suffix(suffix));
+                    if let Some(name) = name {
+                        let key = FileKey(format!("{}{}", prefix, name));
+                        if !key.is_well_formed() {
+                            continue;
+                        }
+                        match self.filter.matches(&self.storage_path, &entry, &key) {
+                            Ok(true) => return Some(Ok(key)),
+                            Ok(false) => {},
+                            Err(e) => return Some(Err(e)),
+                        }
+                    }
+                }
+            }
+            match self.next_shard() {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
@@ -6,6 +6,13 @@
 //! at storage.  Content is deduplicated at storage time, so only one
 //! copy of each distinct file is stored, with potentially multiple
 //! references to it.
+//!
+//! Refcount and ref updates, and moving new content into place, replace
+//! files atomically on every platform.  On Windows, storage paths longer
+//! than `MAX_PATH` work without special handling, as the standard library
+//! adds the `\\?\` prefix itself; paths may also be given in that form.
+//! Stale temporary files and journal entries are told apart from those of
+//! running processes on Unix and Windows (see `recover()`).
 
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
@@ -13,34 +20,270 @@
 extern crate log;
 extern crate byteorder;
 extern crate crypto;
+extern crate rand;
+extern crate xxhash_rust;
 #[cfg(feature = "serde")]
 extern crate serde;
 #[cfg(feature = "postgres")]
 extern crate postgres;
+#[cfg(feature = "http")]
+extern crate tiny_http;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(windows)]
+extern crate windows_sys;
+#[cfg(all(feature = "fuse", unix))]
+extern crate fuser;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+extern crate io_uring;
+#[cfg(feature = "grpc")]
+extern crate tonic;
+#[cfg(feature = "grpc")]
+extern crate prost;
+#[cfg(any(feature = "grpc", feature = "axum"))]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "bincode")]
+extern crate bincode;
+#[cfg(feature = "ciborium")]
+extern crate ciborium;
+#[cfg(feature = "tar")]
+extern crate tar;
+#[cfg(feature = "zip")]
+extern crate zip;
+#[cfg(any(feature = "gcs", feature = "azure"))]
+extern crate ureq;
+#[cfg(feature = "gcs")]
+extern crate serde_json;
+#[cfg(feature = "sftp")]
+extern crate ssh2;
+#[cfg(feature = "git")]
+extern crate git2;
+#[cfg(feature = "redb")]
+extern crate redb;
+#[cfg(feature = "watch")]
+extern crate notify;
 
+#[macro_use]
+mod trace;
+
+pub mod access;
+pub mod audit;
+mod authtag;
+#[cfg(any(feature = "tar", feature = "zip"))]
+pub mod archive;
+#[cfg(feature = "axum")]
+pub mod axum;
+#[cfg(feature = "azure")]
+pub mod azure;
+mod bloom;
+#[cfg(any(feature = "grpc", feature = "axum"))]
+mod bridge;
+pub mod cache;
+pub mod car;
+mod clone;
+#[cfg(any(feature = "gcs", feature = "azure"))]
+mod cloud;
+mod direct;
+pub mod config;
+mod contenttype;
+pub mod derived;
+pub mod encoding;
 pub mod error;
 pub mod filekey;
-mod hashable;
-mod storable;
+#[cfg(all(feature = "fuse", unix))]
+pub mod fuse;
+#[cfg(feature = "gcs")]
+pub mod gcs;
+#[cfg(feature = "git")]
+pub mod git;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod handles;
+pub mod hashable;
+mod hashcache;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod import;
+pub mod index;
+mod journal;
+mod keylock;
+mod keymap;
+mod keys;
+mod limit;
+mod manifest;
+pub mod maintenance;
+pub mod merkle;
+pub mod meta;
+pub mod namespace;
+pub mod quarantine;
+pub mod observer;
+pub mod owners;
+pub mod pin;
+mod precheck;
+mod progress;
+mod protect;
+mod refcount;
+mod rehash;
+pub mod refs;
+#[cfg(feature = "postgres")]
+pub mod registry;
+mod repair;
+mod replace;
+mod sendfile;
+pub mod retry;
+pub mod scrub;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+mod shred;
+pub mod snapshot;
+mod space;
+mod sparse;
+mod spool;
+pub mod staged;
+pub mod store;
+pub mod storable;
+mod sync;
+pub mod tags;
+pub mod token;
+pub mod transaction;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+pub mod tree;
+pub mod upload;
+#[cfg(feature = "serde")]
+pub mod value;
+mod usage;
+#[cfg(feature = "watch")]
+pub mod watch;
+pub mod weak;
+mod writelock;
+pub mod writeback;
+pub mod writer;
 
 use std::fs;
 use std::fs::{File,OpenOptions};
-use std::io;
+use std::io::{self,BufReader,Read,Write};
+use std::net::TcpStream;
+use std::ops::Range;
 use std::path::{Path,PathBuf};
 
-use byteorder::{ReadBytesExt,WriteBytesExt,BigEndian};
-
-use error::Error;
+use error::{Error,ErrorKind};
 
-pub use filekey::FileKey;
-use hashable::Hashable;
-use storable::Storable;
+#[cfg(any(feature = "tar", feature = "zip"))]
+pub use archive::{export_archive,ArchiveFormat};
+pub use authtag::{add_auth_tags,AuthSecret};
+pub use bloom::build_bloom_filter;
+pub use car::export_car;
+pub use clone::clone_to;
+pub use config::{Config,Durability,Quota};
+pub use contenttype::content_type;
+pub use encoding::{KeyEncoding,encode_key,key_encoding,key_prefixed,parse_key,set_key_encoding,
+                   set_key_prefixed};
+pub use merkle::{ChunkDigests,chunk_digests,corrupt_regions,retrieve_range_verified};
+pub use filekey::{CompactKey,FileKey};
+pub use hashable::{Hashable,HashAlgorithm,KeyHasher};
+pub use import::import_dir;
+pub use index::has_index;
+#[cfg(feature = "redb")]
+pub use index::{build_index,index_entry,indexed_objects,IndexEntry,IndexedObjects};
+pub use keymap::translate_key;
+pub use keys::Keys;
+pub use maintenance::{stats,verify,verify_key,gc,recover,space_report,dedup_report,health};
+pub use maintenance::{Stats,GcReport,RecoveryReport,SpaceReport,ShardUsage,DedupReport,Health};
+pub use maintenance::{audit_refcounts,RefcountAudit};
+pub use meta::FileMetadata;
+pub use observer::Observer;
+pub use owners::{store_owned,add_owned_reference,delete_owned,list_owners};
+pub use pin::{retrieve_pinned,Pinned,pin,unpin,is_pinned,list_pinned};
+pub use quarantine::{list_quarantined,restore_from_quarantine};
+pub use rehash::rehash_store;
+pub use repair::{repair_from,RepairReport};
+pub use snapshot::{snapshot,open_snapshot,restore_snapshot,delete_snapshot,list_snapshots,Snapshot};
+pub use staged::StagedObject;
+pub use storable::Storable;
+pub use store::{Store,FileStore,ObjectStat,DeleteOutcome,StoreDiff,diff};
+pub use transaction::Transaction;
+pub use tree::{store_tree,store_tree_with_metadata,read_tree,retrieve_tree,delete_tree};
+pub use tree::{TreeEntry,TreeEntryKind};
+pub use upload::{begin_upload,append_upload,upload_offset,finish_upload,abort_upload,UploadToken};
+pub use usage::{usage,DiskUsage};
+pub use weak::{store_weak,add_weak_reference,release_weak,weak_count,resolves,upgrade_weak};
+pub use weak::dangling_weak;
+pub use writer::StoreWriter;
+use direct::DirectWriter;
+use journal::Intent;
+use progress::ProgressReader;
+use spool::Spool;
 
 /// Store data from memory.  The returned `FileKey` can be used later to
 /// retrieve the data.
-pub fn store_data(storage_path: &Path, input: &Vec<u8>) -> Result<FileKey, Error>
+///
+/// Anything that can be borrowed as bytes is accepted (`Vec<u8>`, `[u8]`,
+/// `Cow<[u8]>`, `bytes::Bytes`, ...), so data is never copied to be stored.
+pub fn store_data<D: AsRef<[u8]> + ?Sized>(storage_path: &Path, input: &D)
+                                          -> Result<FileKey, Error>
+{
+    store_data_new(storage_path, &Config::default(), input.as_ref()).map(|(key, _)| key)
+}
+
+// As `store_data()`, also returning whether the content was newly stored
+pub(crate) fn store_data_new(storage_path: &Path, config: &Config, input: &[u8])
+                             -> Result<(FileKey, bool), Error>
+{
+    let op = operation!("filestore.store_data", new_object);
+    op.record("bytes", input.len() as u64);
+    let _permit = limit::acquire(storage_path, config.max_concurrent_operations);
+    let result = config.check_object_size(input.len() as u64)
+        .and_then(|()| space::ensure_room(storage_path, config, input.len() as u64))
+        .and_then(|()| match config.direct_io {
+            true => store_direct(storage_path, config, input),
+            false => store(storage_path, config, input),
+        });
+    op.finish_stored(result)
+}
+
+// As `store()`, writing the content with direct I/O
+fn store_direct(storage_path: &Path, config: &Config, input: &[u8])
+                -> Result<(FileKey, bool), Error>
+{
+    let key: FileKey = FileKey(input.hash_with(manifest::algorithm(storage_path)?)?);
+    let new_object = commit(storage_path, config, &key, 1, |dest_path| {
+        let mut options = OpenOptions::new();
+        options.create(true).write(true).truncate(true);
+        let mut writer = DirectWriter::open(&options, dest_path, true)
+            .map_err(|e| { (e, "Unable to open/creat new file") } )?;
+        space::preallocate(writer.file(), dest_path, input.len() as u64)?;
+        writer.write_all(input)
+            .and_then(|_| writer.finish())
+            .map_err(|e| { From::from((e, "Unable to write new file")) } )
+            .map(|_| ())
+    })?;
+    Ok((key, new_object))
+}
+
+/// Store content of your own type, keyed by its `Hashable::hash()` and
+/// written by its `Storable::store()`
+pub fn store_storable<T: Storable + Hashable + ?Sized>(storage_path: &Path, input: &T)
+                                                      -> Result<FileKey, Error>
+{
+    let op = operation!("filestore.store_storable", new_object);
+    let result = store(storage_path, &Config::default(), input);
+    op.finish_stored(result).map(|(key, _)| key)
+}
+
+/// Retrieve stored content as a value of your own type, or `None` if the key
+/// is not stored
+pub fn retrieve_storable<T: Storable>(storage_path: &Path, key: &FileKey)
+                                      -> Result<Option<T>, Error>
 {
-    store(storage_path, input)
+    let path = storage_file_path(storage_path, key);
+    match fs::metadata(&path) {
+        Ok(_) => T::retrieve(&path).map(Some),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(From::from((e, "Unable to stat stored file"))),
+    }
 }
 
 /// Store a copy of a file.  The returned `FileKey` can be used later to
@@ -50,23 +293,246 @@ pub fn store_data(storage_path: &Path, input: &Vec<u8>) -> Result<FileKey, Error
 /// storage path.
 pub fn store_file(storage_path: &Path, input: &Path) -> Result<FileKey, Error>
 {
-    store(storage_path, &input.to_path_buf())
+    store_file_new(storage_path, &Config::default(), input).map(|(key, _)| key)
+}
+
+/// Store a copy of a file as `store_file()` does, also returning its
+/// permissions, modification time and ownership, to keep with the key and
+/// give to `retrieve_to_with_metadata()`
+pub fn store_file_with_metadata(storage_path: &Path, input: &Path)
+                                -> Result<(FileKey, FileMetadata), Error>
+{
+    // Captured first, so they are those of the content stored
+    let metadata = FileMetadata::capture(input)?;
+    let key = store_file(storage_path, input)?;
+    Ok((key, metadata))
+}
+
+// As `store_file()`, also returning whether the content was newly stored
+pub(crate) fn store_file_new(storage_path: &Path, config: &Config, input: &Path)
+                             -> Result<(FileKey, bool), Error>
+{
+    let op = operation!("filestore.store_file", new_object);
+    let _permit = limit::acquire(storage_path, config.max_concurrent_operations);
+    let result = fs::metadata(input)
+        .map_err(|e| From::from((e, "Cannot stat content file")))
+        .and_then(|metadata| {
+            op.record("bytes", metadata.len());
+            config.check_object_size(metadata.len())?;
+            // Before hashing, which reads the whole file
+            space::ensure_room(storage_path, config, sparse::allocated(&metadata))?;
+            Ok(metadata.len())
+        })
+        .and_then(|len| {
+            if config.precheck && !precheck::may_be_stored(storage_path, input)? {
+                // New content, so hash it while copying it in
+                let mut file = File::open(input)
+                    .map_err(|e| { (e, "Cannot open content file") } )?;
+                return spool_and_store(storage_path, config, &mut file, Some(len), &op);
+            }
+            let key = file_key(storage_path, config, input)?;
+            let new_object = commit(storage_path, config, &key, 1,
+                                    |dest_path| input.to_path_buf().store(dest_path))?;
+            Ok((key, new_object))
+        });
+    op.finish_stored(result)
+}
+
+/// Store a copy of a file as `store_file()` does, calling `progress` with
+/// `(bytes_done, total)` as the file is read.
+///
+/// The file is read only once, being copied into the store while it is
+/// hashed, so `bytes_done` reaches `total` when the store is nearly complete.
+pub fn store_file_with_progress<P>(storage_path: &Path, input: &Path, progress: P)
+                                   -> Result<FileKey, Error>
+    where P: FnMut(u64, u64)
+{
+    let file = File::open(input)
+        .map_err(|e| { (e, "Cannot open content file") } )?;
+    let total = file.metadata()
+        .map_err(|e| { (e, "Cannot stat content file") } )?
+        .len();
+    store_reader_sized(storage_path, &mut ProgressReader::new(file, total, progress), total)
+}
+
+/// Store everything that can be read from `input`.  The returned `FileKey`
+/// can be used later to retrieve the content as either data or a file.
+///
+/// The content is written to a temporary file inside the storage path while
+/// it is hashed, so it is never held in memory as a whole.
+pub fn store_reader<R: Read + ?Sized>(storage_path: &Path, input: &mut R) -> Result<FileKey, Error>
+{
+    store_reader_new(storage_path, &Config::default(), input).map(|(key, _)| key)
+}
+
+// As `store_reader()`, also returning whether the content was newly stored
+pub(crate) fn store_reader_new<R: Read + ?Sized>(storage_path: &Path, config: &Config,
+                                                 input: &mut R)
+                                                 -> Result<(FileKey, bool), Error>
+{
+    let op = operation!("filestore.store_reader", new_object);
+    let _permit = limit::acquire(storage_path, config.max_concurrent_operations);
+    let result = space::ensure_room(storage_path, config, 0)
+        .and_then(|()| spool_and_store(storage_path, config, input, None, &op));
+    op.finish_stored(result)
+}
+
+/// Store everything that can be read from `input`, as `store_reader()`
+/// does, when the content is known to be `len` bytes long.  Disk is
+/// reserved for it before it is read, so that a store that won't fit fails
+/// at once with `ErrorKind::DiskFull`.  Fails if `input` turns out to be of
+/// some other length, storing nothing.
+pub fn store_reader_sized<R: Read + ?Sized>(storage_path: &Path, input: &mut R, len: u64)
+                                            -> Result<FileKey, Error>
+{
+    store_reader_sized_new(storage_path, &Config::default(), input, len).map(|(key, _)| key)
+}
+
+// As `store_reader_sized()`, also returning whether the content was newly
+// stored
+pub(crate) fn store_reader_sized_new<R: Read + ?Sized>(storage_path: &Path, config: &Config,
+                                                       input: &mut R, len: u64)
+                                                       -> Result<(FileKey, bool), Error>
+{
+    let op = operation!("filestore.store_reader", new_object);
+    let _permit = limit::acquire(storage_path, config.max_concurrent_operations);
+    let result = config.check_object_size(len)
+        .and_then(|()| space::ensure_room(storage_path, config, len))
+        .and_then(|()| spool_and_store(storage_path, config, input, Some(len), &op));
+    op.finish_stored(result)
+}
+
+// Spool `input` and store it.  If its length `len` is known, disk is
+// reserved for it first, and it must be that long.
+fn spool_and_store<R: Read + ?Sized>(storage_path: &Path, config: &Config, input: &mut R,
+                                     len: Option<u64>, op: &trace::Operation)
+                                     -> Result<(FileKey, bool), Error>
+{
+    let mut spool = Spool::new(storage_path, config.direct_io)?;
+    if let Some(len) = len {
+        spool.preallocate(len)?;
+    }
+    let bytes = spool_input(config, input, &mut spool)?;
+    op.record("bytes", bytes);
+    if len.is_some_and(|len| len != bytes) {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidData, "length mismatch"),
+                               "Input was not of the length expected")));
+    }
+    commit_spool(storage_path, config, spool)
+}
+
+// Copy `input` into `spool`, failing with `ErrorKind::TooLarge` as soon as
+// it runs over `config.max_object_size`
+pub(crate) fn spool_input<R: Read + ?Sized>(config: &Config, input: &mut R, spool: &mut Spool)
+                                            -> Result<u64, Error>
+{
+    // One byte more than allowed is enough to know it is too much
+    let limit = config.max_object_size.map_or(u64::MAX, |limit| limit.saturating_add(1));
+    let mut input = BufReader::with_capacity(config.buffer_size(), input).take(limit);
+    let bytes = io::copy(&mut input, spool)
+        .map_err(|e| { (e, "Unable to spool input") } )?;
+    config.check_object_size(bytes)?;
+    Ok(bytes)
+}
+
+// Store the content written to `spool`, also returning whether it was newly
+// stored
+pub(crate) fn commit_spool(storage_path: &Path, config: &Config, mut spool: Spool)
+                           -> Result<(FileKey, bool), Error>
+{
+    let key = FileKey(spool.hash());
+    let new_object = commit(storage_path, config, &key, 1, |dest_path| spool.persist(dest_path))?;
+    Ok((key, new_object))
+}
+
+/// Begin storing content that will be written a piece at a time.  Call
+/// `finalize()` on the returned writer to store it and get its key.
+pub fn store_writer(storage_path: &Path) -> Result<StoreWriter, Error>
+{
+    FileStore::new(storage_path).writer()
 }
 
 /// Retrieve data into memory, using a `FileKey` that was returned from an earlier
 /// call to `store_data()`
 pub fn retrieve_data(storage_path: &Path, key: &FileKey) -> Option<Vec<u8>>
 {
+    let op = operation!("filestore.retrieve_data");
+    op.key(key);
     let path = storage_file_path(storage_path, key);
-    match fs::metadata(&path) {
+    let data: Option<Vec<u8>> = match fs::metadata(&path) {
         Err(_) => None,
         Ok(_) => {
-            match Storable::retrieve(&path) {
-                Ok(p) => Some(p),
-                Err(_) => None,
-            }
+            Storable::retrieve(&path).ok()
         }
+    };
+    if let Some(ref data) = data {
+        op.record("bytes", data.len() as u64);
+    }
+    op.finish_option(data)
+}
+
+/// Retrieve data into `buf`, replacing what it held, and return its
+/// length.  Readers going through many objects can reuse one buffer rather
+/// than have a new one allocated for each.  If the key is not stored the
+/// error's `io` is of kind `NotFound`.
+pub fn retrieve_data_into(storage_path: &Path, key: &FileKey, buf: &mut Vec<u8>)
+                          -> Result<usize, Error>
+{
+    let op = operation!("filestore.retrieve_data_into");
+    op.key(key);
+    let result = read_into(storage_path, key, buf);
+    if let Ok(len) = result {
+        op.record("bytes", len as u64);
+    }
+    op.finish(result)
+}
+
+fn read_into(storage_path: &Path, key: &FileKey, buf: &mut Vec<u8>) -> Result<usize, Error> {
+    let mut file = open_stored(storage_path, key)?;
+    buf.clear();
+    if let Ok(metadata) = file.metadata() {
+        buf.reserve(std::convert::TryFrom::try_from(metadata.len()).unwrap_or(0));
     }
+    file.read_to_end(buf)
+        .map_err(|e| { From::from((e, "Unable to read stored file")) } )
+}
+
+/// Whether an object is stored under `key`.  If the store has a Bloom
+/// filter (see `build_bloom_filter()`), most keys that aren't stored are
+/// answered from it without touching the disk.
+pub fn exists(storage_path: &Path, key: &FileKey) -> Result<bool, Error>
+{
+    if !key.is_well_formed() || bloom::rules_out(storage_path, key)? {
+        return Ok(false);
+    }
+    match fs::metadata(storage_file_path(storage_path, key)) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(From::from((e, "Unable to stat stored file"))),
+    }
+}
+
+/// The size, refcount and times of the object stored under `key`, without
+/// going through the path from `retrieve_file()`.  The last access is only
+/// known where `Config::track_access` is set.  If the key is not stored the
+/// error's `io` is of kind `NotFound`.
+pub fn stat(storage_path: &Path, key: &FileKey) -> Result<ObjectStat, Error>
+{
+    if !key.is_well_formed() {
+        return Err(From::from((io::Error::from(io::ErrorKind::NotFound),
+                               "Key is not stored")));
+    }
+    let metadata = fs::metadata(storage_file_path(storage_path, key))
+        .map_err(|e| { (e, "Unable to stat stored file") } )?;
+    // Stored files are never written again once in place, so where the
+    // filesystem has no creation time the modification time serves
+    let stored = metadata.created().or_else(|_| metadata.modified()).ok();
+    Ok(ObjectStat {
+        size: metadata.len(),
+        refcount: get_refcount(storage_path, key)?,
+        stored,
+        last_access: access::last_access(storage_path, key)?,
+    })
 }
 
 /// Retrieve a file by learning it's storage path, using a `FileKey` that was
@@ -77,51 +543,374 @@ pub fn retrieve_data(storage_path: &Path, key: &FileKey) -> Option<Vec<u8>>
 /// manages the refcount properly.
 pub fn retrieve_file(storage_path: &Path, key: &FileKey) -> Option<PathBuf>
 {
+    let op = operation!("filestore.retrieve_file");
+    op.key(key);
     let pathbuf = storage_file_path(storage_path, key);
-    match fs::metadata(&pathbuf) {
+    let path: Option<PathBuf> = match fs::metadata(&pathbuf) {
         Err(_) => None,
-        Ok(_) => {
-            match Storable::retrieve(&pathbuf) {
-                Ok(p) => Some(p),
-                Err(_) => None,
-            }
+        Ok(metadata) => {
+            op.record("bytes", metadata.len());
+            Storable::retrieve(&pathbuf).ok()
         }
+    };
+    op.finish_option(path)
+}
+
+/// Open stored content for reading.  Unlike the path from `retrieve_file()`,
+/// the open file stays readable on Unix even if the object is deleted
+/// afterwards.  If the key is not stored the error's `io` is of kind
+/// `NotFound`.
+pub fn retrieve_open(storage_path: &Path, key: &FileKey) -> Result<File, Error>
+{
+    let op = operation!("filestore.retrieve_open");
+    op.key(key);
+    let result = open_stored(storage_path, key);
+    op.finish(result)
+}
+
+/// Send the stored content under `key` (or the byte range `range` of it,
+/// clamped to its length) to `stream`, returning the number of bytes sent.
+/// On Linux the kernel sends it straight from the file (with sendfile), so
+/// servers can hand out objects without copying them through buffers of
+/// their own.  `stream` must be in blocking mode.
+pub fn send_to(storage_path: &Path, key: &FileKey, stream: &TcpStream,
+               range: Option<Range<u64>>) -> Result<u64, Error>
+{
+    let op = operation!("filestore.send_to");
+    op.key(key);
+    let result = open_stored(storage_path, key)
+        .and_then(|file| sendfile::send_range(&file, stream, range));
+    if let Ok(bytes) = result {
+        op.record("bytes", bytes);
+    }
+    op.finish(result)
+}
+
+// Open the object `key`, failing with `ErrorKind::Quarantined` rather than
+// `NotFound` if it was found corrupt and set aside
+fn open_stored(storage_path: &Path, key: &FileKey) -> Result<File, Error> {
+    match File::open(storage_file_path(storage_path, key)) {
+        Ok(file) => Ok(file),
+        Err(e) => {
+            if e.kind() == io::ErrorKind::NotFound {
+                quarantine::check(storage_path, key)?;
+            }
+            Err(From::from((e, "Unable to open stored file")))
+        },
     }
 }
 
+/// Copy stored content out to `dest`, creating or truncating it.  Unlike
+/// the path returned by `retrieve_file()`, the copy belongs to the caller.
+pub fn retrieve_to(storage_path: &Path, key: &FileKey, dest: &Path) -> Result<(), Error>
+{
+    retrieve_to_with_progress(storage_path, key, dest, |_, _| {})
+}
+
+/// Copy stored content out as `retrieve_to()` does, calling `progress`
+/// with `(bytes_done, total)` as it is copied
+pub fn retrieve_to_with_progress<P>(storage_path: &Path, key: &FileKey, dest: &Path,
+                                    progress: P) -> Result<(), Error>
+    where P: FnMut(u64, u64)
+{
+    let op = operation!("filestore.retrieve_to");
+    op.key(key);
+    let result = copy_out(storage_path, key, dest, progress, &op);
+    op.finish(result)
+}
+
+fn copy_out<P>(storage_path: &Path, key: &FileKey, dest: &Path, progress: P,
+               op: &trace::Operation) -> Result<(), Error>
+    where P: FnMut(u64, u64)
+{
+    let file = open_stored(storage_path, key)?;
+    let metadata = file.metadata()
+        .map_err(|e| { (e, "Unable to stat stored file") } )?;
+    let total = metadata.len();
+    op.record("bytes", total);
+    let mut output = File::create(dest)
+        .map_err(|e| { (e, "Unable to create destination file") } )?;
+    let mut progress = progress;
+    if sparse::has_holes(&metadata)
+        && sparse::copy(&file, &output, total, &mut |done| progress(done, total))
+            .map_err(|e| { (e, "Unable to copy stored file") } )?
+    {
+        return Ok(());
+    }
+    io::copy(&mut ProgressReader::new(file, total, progress), &mut output)
+        .map_err(|e| { (e, "Unable to copy stored file") } )?;
+    Ok(())
+}
+
+/// Copy stored content out as `retrieve_to()` does, then give the copy the
+/// attributes `metadata` holds (see `store_file_with_metadata()`)
+pub fn retrieve_to_with_metadata(storage_path: &Path, key: &FileKey, dest: &Path,
+                                 metadata: &FileMetadata) -> Result<(), Error>
+{
+    retrieve_to(storage_path, key, dest)?;
+    metadata.apply(dest)
+}
+
+/// Make stored content available as `name` in `dest_dir`, such as a web
+/// root, returning the new path.  It is hard linked to the stored file where
+/// possible, and copied where `dest_dir` is on another filesystem.
+///
+/// The store keeps its own copy: deleting the object leaves the linked file
+/// in place, and removing the linked file doesn't affect the store.  As a
+/// hard link shares content with the stored file, it must not be modified
+/// in place (see `Config::read_only_objects`).  `name` must be a plain file
+/// name, and fails with `AlreadyExists` if taken.
+pub fn link_into(storage_path: &Path, key: &FileKey, dest_dir: &Path, name: &str)
+                 -> Result<PathBuf, Error>
+{
+    let op = operation!("filestore.link_into");
+    op.key(key);
+    let result = link_or_copy(storage_path, key, dest_dir, name);
+    op.finish(result)
+}
+
+fn link_or_copy(storage_path: &Path, key: &FileKey, dest_dir: &Path, name: &str)
+                -> Result<PathBuf, Error>
+{
+    if Path::new(name).file_name() != Some(name.as_ref()) {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput,
+                                              "not a plain file name"),
+                               "Invalid destination name")));
+    }
+    let source = storage_file_path(storage_path, key);
+    let dest = dest_dir.join(name);
+    match fs::hard_link(&source, &dest) {
+        Ok(()) => return Ok(dest),
+        Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => {},
+        Err(e) => {
+            if e.kind() == io::ErrorKind::NotFound && !source.exists() {
+                quarantine::check(storage_path, key)?;
+            }
+            return Err(From::from((e, "Unable to link stored file")));
+        },
+    }
+
+    let mut input = open_stored(storage_path, key)?;
+    let mut output = OpenOptions::new().write(true).create_new(true).open(&dest)
+        .map_err(|e| { (e, "Unable to create destination file") } )?;
+    if let Err(e) = io::copy(&mut input, &mut output) {
+        let _ = fs::remove_file(&dest);
+        return Err(From::from((e, "Unable to copy stored file")));
+    }
+    Ok(dest)
+}
+
 /// Delete stored data (or file) based on a `FileKey` that was returned
 /// from an earlier call to `store_file()` or `store_data()`.
 pub fn delete(storage_path: &Path, key: &FileKey) -> Result<(), Error>
 {
-    let path = storage_file_path(storage_path, key);
+    release(storage_path, &Config::default(), key).map(|_| ())
+}
+
+/// As `delete()`, but telling what happened: whether a reference was
+/// dropped and the object removed with it, or there was no reference to
+/// drop, which `delete()` passes over in silence
+pub fn delete_checked(storage_path: &Path, key: &FileKey) -> Result<DeleteOutcome, Error>
+{
+    release_checked(storage_path, &Config::default(), key)
+}
 
+// As `release()`, telling whether the object went
+pub(crate) fn release_checked(storage_path: &Path, config: &Config, key: &FileKey)
+                              -> Result<DeleteOutcome, Error>
+{
+    Ok(match release_removing(storage_path, config, key)? {
+        None => DeleteOutcome::NotFound,
+        Some((_, true)) => DeleteOutcome::ObjectRemoved,
+        Some((remaining, false)) => DeleteOutcome::RefDecremented(remaining),
+    })
+}
+
+/// As `delete()`, but if this drops the last reference the content is
+/// overwritten before the file is removed (see `Config::secure_delete`)
+pub fn delete_secure(storage_path: &Path, key: &FileKey) -> Result<(), Error>
+{
+    let config = Config { secure_delete: true, ..Config::default() };
+    release(storage_path, &config, key).map(|_| ())
+}
+
+// As `delete()`, returning the references left afterwards, or `None` if
+// there were none to drop
+pub(crate) fn release(storage_path: &Path, config: &Config, key: &FileKey)
+                      -> Result<Option<u64>, Error>
+{
+    release_removing(storage_path, config, key).map(|released| released.map(|(left, _)| left))
+}
+
+// As `release()`, also returning whether the object was removed
+fn release_removing(storage_path: &Path, config: &Config, key: &FileKey)
+                    -> Result<Option<(u64, bool)>, Error>
+{
+    let op = operation!("filestore.delete", refcount);
+    op.key(key);
+    let result = decrement(storage_path, config, key, &op);
+    // Artifacts derived from the object go with it, once its lock is
+    // released, as theirs may share its stripe
+    if let Ok(Some((_, true))) = result {
+        if let Err(e) = derived::release_all(storage_path, key) {
+            log::warn!("Unable to release artifacts derived from {}: {:?}", key, e);
+        }
+    }
+    op.finish(result)
+}
+
+// Drop a reference on `key`, returning the references left and whether
+// the object was removed, decided while its lock is held, or `None` if
+// there were none to drop
+fn decrement(storage_path: &Path, config: &Config, key: &FileKey, op: &trace::Operation)
+             -> Result<Option<(u64, bool)>, Error>
+{
     // Decrement the ref count
-    let mut refcount: u32 = get_refcount(storage_path, key)?;
+    let _lock = keylock::lock(storage_path, key)?;
+    let mut refcount: u64 = get_refcount(storage_path, key)?;
     if refcount < 1 {
-        return Ok(()); // nothing to delete
+        return Ok(None); // nothing to delete
     }
+    let intent = Intent::record(storage_path, config, key, refcount, refcount - 1)?;
     refcount -= 1;
-    set_refcount(storage_path, key, refcount)?;
+    set_refcount(storage_path, config, key, refcount)?;
+    op.record("refcount", refcount);
 
-    // Actually delete if there are no more references
-    if refcount < 1 {
-        fs::remove_file( &path )
-            .map_err(|e| { (e, "Unable to remove file") } )?;
+    // Actually delete if there are no more references, unless the file is
+    // pinned, in which case the last pin to go removes it
+    let removed = refcount < 1 && !pin::is_pinned(storage_path, key)?;
+    if removed {
+        remove_object(storage_path, config, key)?;
+    }
+    index::update(storage_path, config, key)?;
+    intent.done()?;
+    if config.durability == Durability::FlushDataAndDir {
+        sync::sync_dir(&storage_file_dir(storage_path, key))?;
+    }
+
+    Ok(Some((refcount, removed)))
+}
+
+// Remove an object's content
+pub(crate) fn remove_object(storage_path: &Path, config: &Config, key: &FileKey)
+                            -> Result<(), Error>
+{
+    let path = storage_file_path(storage_path, key);
+    let metadata = match usage::is_tracked(storage_path) {
+        true => fs::metadata(&path).ok(),
+        false => None,
+    };
+    protect::make_writable(&path)?;
+    if config.secure_delete {
+        shred::overwrite(&path)?;
     }
+    fs::remove_file( &path )
+        .map_err(|e| { (e, "Unable to remove file") } )?;
+    if let Some(ref metadata) = metadata {
+        usage::remove(storage_path, metadata);
+    }
+    forget_object(storage_path, key)
+}
 
+// Drop what is kept about an object besides its content, once that is gone
+pub(crate) fn forget_object(storage_path: &Path, key: &FileKey) -> Result<(), Error>
+{
+    if let Err(e) = access::forget(storage_path, key) {
+        log::warn!("Unable to remove access record for {}: {:?}", key, e);
+    }
+    if let Err(e) = contenttype::forget(storage_path, key) {
+        log::warn!("Unable to remove content type of {}: {:?}", key, e);
+    }
+    if let Err(e) = merkle::forget(storage_path, key) {
+        log::warn!("Unable to remove chunk digests of {}: {:?}", key, e);
+    }
+    if let Err(e) = owners::forget(storage_path, key) {
+        log::warn!("Unable to remove owners of {}: {:?}", key, e);
+    }
+    authtag::forget(storage_path, key)?;
     Ok(())
 }
 
+/// Iterate over the keys of every object in the store
+pub fn keys(storage_path: &Path) -> Result<Keys, Error>
+{
+    Keys::new(storage_path)
+}
+
+/// The key of the one stored object whose key begins with `prefix`, so
+/// that people can refer to objects by abbreviated keys as they do git
+/// commits.  Returns `None` if no stored key matches, and fails with
+/// `ErrorKind::AmbiguousPrefix` if several do.  Prefixes must be at least
+/// four hex digits, and no longer than the longest key.
+pub fn resolve_prefix(storage_path: &Path, prefix: &str) -> Result<Option<FileKey>, Error>
+{
+    let longest = HashAlgorithm::ALL.iter().map(|algorithm| algorithm.key_len()).max().unwrap_or(0);
+    if prefix.len() < 4 || prefix.len() > longest
+        || !prefix.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "malformed prefix"),
+                               &*format!("Abbreviated keys are 4 to {} lowercase hex digits",
+                                         longest))));
+    }
+    let shard = storage_path.join(&prefix[..2]);
+    let entries = match fs::read_dir(&shard) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(From::from((e, "Unable to read shard directory"))),
+    };
+    let mut matches: Vec<FileKey> = Vec::new();
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read shard directory") } )?;
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let key = FileKey(format!("{}{}", &prefix[..2], name));
+        if key.is_well_formed() && key.starts_with(prefix) {
+            matches.push(key);
+        }
+    }
+    match matches.len() {
+        0 => Ok(None),
+        1 => Ok(matches.pop()),
+        _ => {
+            matches.sort();
+            Err(From::from((ErrorKind::AmbiguousPrefix { matches },
+                            &*format!("Abbreviated key {} is ambiguous", prefix))))
+        },
+    }
+}
+
+/// Get the number of references currently held on a stored object.  Returns
+/// zero if the key is not in the store.
+pub fn refcount(storage_path: &Path, key: &FileKey) -> Result<u64, Error>
+{
+    get_refcount(storage_path, key)
+}
+
+
+// Create the 256 shard directories of a new store
+fn create_shards(storage_path: &Path) -> Result<(), Error> {
+    for shard in 0..=255u8 {
+        match fs::create_dir(storage_path.join(format!("{:02x}", shard))) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+            Err(e) => return Err(From::from((e, "Unable to create shard directory"))),
+        }
+    }
+    Ok(())
+}
 
 // Returns `PathBuf` for directory that data will be stored into
 fn storage_file_dir(storage_path: &Path, key: &FileKey) -> PathBuf {
-    let r: &str = &**key;
+    let r: &str = key;
     storage_path.to_path_buf().join( &r[..2] )
 }
 
 // Returns short name of file that data will be stored into
 fn storage_file_name(key: &FileKey) -> String {
-    let r: &str = &*key;
+    let r: &str = key;
     r[2..].to_owned()
 }
 
@@ -133,7 +922,7 @@ fn storage_file_path(storage_path: &Path, key: &FileKey) -> PathBuf
 
 // Returns short name of file that refcount will be stored into
 fn storage_refcount_name(key: &FileKey) -> String {
-    let r: &str = &*key;
+    let r: &str = key;
     (r[2..]).to_owned() + ".refcount"
 }
 
@@ -143,60 +932,240 @@ fn storage_refcount_path(storage_path: &Path, key: &FileKey) -> PathBuf
     storage_file_dir(storage_path, key).to_path_buf().join( &storage_refcount_name(key)[..] )
 }
 
+// Whether `name` (of a namespace or ref) is safe to use as a file name
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".."
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+// The key of the file at `input`, using the hash cache if `config` asks
+fn file_key(storage_path: &Path, config: &Config, input: &Path) -> Result<FileKey, Error>
+{
+    let algorithm = manifest::algorithm(storage_path)?;
+    if config.hash_cache {
+        return hashcache::file_key(storage_path, input, algorithm, config.buffer_size());
+    }
+    Ok(FileKey(hashable::hash_file(input, algorithm, config.buffer_size())?))
+}
+
 // Store the input at the storage_path.  Hashes, uses that as a key and
-// also the filename, and manages refcounts (in case it is pre-existing)
-fn store<T: Storable + Hashable>(storage_path: &Path, input: &T)
-                                 -> Result<FileKey, Error>
+// also the filename, and manages refcounts (in case it is pre-existing).
+// Also returns whether the content was newly stored.
+fn store<T: Storable + Hashable + ?Sized>(storage_path: &Path, config: &Config, input: &T)
+                                          -> Result<(FileKey, bool), Error>
 {
-    let key: FileKey = FileKey(input.hash()?);
+    let key: FileKey = FileKey(input.hash_with(manifest::algorithm(storage_path)?)?);
+    let new_object = commit(storage_path, config, &key, 1, |dest_path| input.store(dest_path))?;
+    Ok( (key, new_object) )
+}
 
-    // Make storage_file_dir, if it doesn't already exist
-    let storage_file_dir = storage_file_dir(storage_path, &key);
-    if let Err(e) = fs::create_dir(&storage_file_dir) {
-        if e.kind() != io::ErrorKind::AlreadyExists { return Err( From::from(e) ); }
+// Take another reference on `key`, which must already be stored
+fn add_reference(storage_path: &Path, config: &Config, key: &FileKey) -> Result<(), Error>
+{
+    if let Err(e) = fs::metadata(storage_file_path(storage_path, key)) {
+        return Err(From::from((e, "Unable to find stored file")));
     }
+    commit(storage_path, config, key, 1, |_| {
+        // Removed since it was looked for
+        Err(From::from((io::Error::from(io::ErrorKind::NotFound), "Unable to find stored file")))
+    }).map(|_| ())
+}
+
+// Take `references` references on `key`, first calling `place` to put the
+// content at the given path if it is not already stored.  Returns whether
+// `place` was called.
+fn commit<F>(storage_path: &Path, config: &Config, key: &FileKey, references: u64, place: F)
+             -> Result<bool, Error>
+    where F: FnOnce(&Path) -> Result<(), Error>
+{
+    // Make storage_file_dir, if it doesn't already exist
+    let storage_file_dir = storage_file_dir(storage_path, key);
+    let created_dir = handles::ensure_dir(&storage_file_dir)?;
+
+    // Held until the new count is written, so that other threads and
+    // processes neither change the count meanwhile nor remove the content
+    // being referenced
+    let _lock = keylock::lock(storage_path, key)?;
+
+    // Journal the refcount change before touching anything, so that if
+    // this process dies part way it can be undone
+    let before: u64 = get_refcount(storage_path, key)?;
+    let after = before.checked_add(references)
+        .ok_or_else(|| Error::from((ErrorKind::RefcountOverflow,
+                                    &*format!("Too many references to {}", key))))?;
+    let intent = Intent::record(storage_path, config, key, before, after)?;
 
     // Check if file content exists, and copy as needed
-    let storage_file_path = storage_file_path(storage_path, &key);
-    match fs::metadata(&storage_file_path) {
+    let storage_file_path = storage_file_path(storage_path, key);
+    let placed = match place_if_absent(storage_path, config, key, &storage_file_path, place) {
+        Ok(placed) => placed,
+        Err(e) => {
+            // Perhaps for want of the directory
+            handles::forget_dir(&storage_file_dir);
+            // Nothing has changed that needs undoing
+            if let Err(e) = intent.done() {
+                log::warn!("Unable to remove journal entry for {}: {:?}", key, e);
+            }
+            return Err(e);
+        }
+    };
+
+    // Increment the ref count, unless storing with none (`store_weak()`)
+    if after != before {
+        set_refcount(storage_path, config, key, after)?;
+    }
+    index::update(storage_path, config, key)?;
+    intent.done()?;
+
+    // One directory flush covers both the new object and its refcount file
+    if config.durability == Durability::FlushDataAndDir {
+        sync::sync_dir(&storage_file_dir)?;
+        if created_dir {
+            sync::sync_dir(storage_path)?;
+        }
+    }
+    Ok(placed)
+}
+
+// Call `place` to put the content at `storage_file_path` if it isn't there
+// already, returning whether it did
+fn place_if_absent<F>(storage_path: &Path, config: &Config, key: &FileKey,
+                      storage_file_path: &Path, place: F) -> Result<bool, Error>
+    where F: FnOnce(&Path) -> Result<(), Error>
+{
+    let mut placed = false;
+    match fs::metadata(storage_file_path) {
         Ok(_) => {
             // We presume no hash collisions due to the cryptographically
-            // large hash space
+            // large hash space, unless asked to check
+            if config.compare_on_match {
+                compare_with_stored(storage_path, key, storage_file_path, place)?;
+            }
+            // Content stored before the type or tag was wanted
+            record_content_type(storage_path, config, key, storage_file_path);
+            if let Some(ref secret) = config.authenticate {
+                // Not vouching for content that isn't what its key says
+                if !authtag::is_tagged(storage_path, key) && !verify_key(storage_path, key)? {
+                    return Err(From::from((ErrorKind::Tampered,
+                                           &*format!("{} doesn't match its key", key))));
+                }
+                authtag::record(storage_path, secret, key, storage_file_path)?;
+            }
         },
         Err(e) => {
             if e.kind() == io::ErrorKind::NotFound {
-                // Store content
-                input.store(&storage_file_path)?;
+                // Added first, so that the filter never rules out content
+                // that is stored
+                bloom::add(storage_path, key)?;
+                // Store content, not leaving a partial copy behind
+                if let Err(e) = place(storage_file_path) {
+                    if let Err(e) = fs::remove_file(storage_file_path) {
+                        if e.kind() != io::ErrorKind::NotFound {
+                            log::warn!("Unable to remove partial {}: {:?}", key, e);
+                        }
+                    }
+                    return Err(e);
+                }
+                placed = true;
+                // Flushed first, as Windows can't open a read-only file
+                // for writing to flush it
+                if config.durability >= Durability::FlushData {
+                    sync::sync_file(storage_file_path)?;
+                }
+                if config.read_only_objects {
+                    protect::make_read_only(storage_file_path)?;
+                }
+                precheck::add(storage_path, storage_file_path);
+                if let Err(e) = usage::add(storage_path, storage_file_path,
+                                           config.quota.as_ref()) {
+                    if let Err(e) = protect::make_writable(storage_file_path)
+                        .and_then(|()| fs::remove_file(storage_file_path)
+                                  .map_err(|e| From::from((e, "Unable to remove file"))))
+                    {
+                        log::warn!("Unable to remove {} after failed store: {:?}", key, e);
+                    }
+                    return Err(e);
+                }
+                record_content_type(storage_path, config, key, storage_file_path);
+                // Left untagged on failure, the object fails retrieval
+                // until it is stored again
+                if let Some(ref secret) = config.authenticate {
+                    authtag::record(storage_path, secret, key, storage_file_path)?;
+                }
             }
             else {
                 return Err( From::from(e) );
             }
         }
     }
+    Ok(placed)
+}
 
-    // Increment the ref count
-    let mut refcount: u32 = get_refcount(storage_path, &key)?;
-    refcount += 1;
-    set_refcount(storage_path, &key, refcount)?;
-    Ok( key )
+// Record the object's type if `config` asks.  Only a hint for servers, so
+// failing to is no reason to fail the store.
+fn record_content_type(storage_path: &Path, config: &Config, key: &FileKey,
+                       storage_file_path: &Path)
+{
+    if config.detect_content_type {
+        if let Err(e) = contenttype::record(storage_path, key, storage_file_path) {
+            log::warn!("Unable to record content type of {}: {:?}", key, e);
+        }
+    }
 }
 
-fn get_refcount(storage_path: &Path, key: &FileKey) -> Result<u32, Error>
+// Have `place` write the new content to a temporary file, and check it is
+// the same as what is stored.  Fails with `ErrorKind::HashCollision` if not.
+fn compare_with_stored<F>(storage_path: &Path, key: &FileKey, storage_file_path: &Path,
+                          place: F) -> Result<(), Error>
+    where F: FnOnce(&Path) -> Result<(), Error>
+{
+    let temp_path = spool::temp_path(storage_path);
+    let same = place(&temp_path).and_then(|()| {
+        same_content(&temp_path, storage_file_path)
+            .map_err(|e| { From::from((e, "Unable to compare with stored file")) } )
+    });
+    if let Err(e) = fs::remove_file(&temp_path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            log::warn!("Unable to remove {}: {:?}", temp_path.display(), e);
+        }
+    }
+    if !same? {
+        return Err(From::from((ErrorKind::HashCollision,
+                               &*format!("Content differs from stored object {}", key))));
+    }
+    Ok(())
+}
+
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = File::open(a)?;
+    let mut b = File::open(b)?;
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut buf_a = vec![0_u8; 64 * 1024];
+    let mut buf_b = vec![0_u8; 64 * 1024];
+    loop {
+        let count = a.read(&mut buf_a)?;
+        if count == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..count])?;
+        if buf_a[..count] != buf_b[..count] {
+            return Ok(false);
+        }
+    }
+}
+
+// Fails with `ErrorKind::CorruptRefcount` if the refcount file is damaged
+fn get_refcount(storage_path: &Path, key: &FileKey) -> Result<u64, Error>
 {
     let storage_refcount_path = storage_refcount_path(storage_path, key);
-    match fs::metadata(&storage_refcount_path) {
-        Ok(_) => {
-            let mut f = File::open(&storage_refcount_path)
-                .map_err(|e| { (e, "Unable to open refcount file") } )?;
-            match f.read_u32::<BigEndian>() {
-                Ok(u) => Ok(u),
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::UnexpectedEof {
-                        Ok(0)
-                    } else {
-                        Err(From::from(e))
-                    }
-                }
+    match fs::read(&storage_refcount_path) {
+        Ok(record) => {
+            match refcount::decode(&record) {
+                Some(u) => Ok(u),
+                None => Err(From::from((ErrorKind::CorruptRefcount,
+                                        &*format!("Refcount file for {} is damaged", key)))),
             }
         },
         Err(e) => {
@@ -210,21 +1179,41 @@ fn get_refcount(storage_path: &Path, key: &FileKey) -> Result<u32, Error>
     }
 }
 
-fn set_refcount(storage_path: &Path, key: &FileKey, refcount: u32) -> Result<(), Error>
+// Write a refcount, flushing it if `config` asks.  Flushing the directory
+// is left to the caller, which may have other changes there.
+fn set_refcount(storage_path: &Path, config: &Config, key: &FileKey, refcount: u64)
+                -> Result<(), Error>
 {
     let storage_refcount_path = storage_refcount_path(storage_path, key);
+    let change = usage::change(storage_path, usage::Part::Refcounts, &storage_refcount_path);
 
     // If zero, delete the refcount file
     if refcount < 1 {
         fs::remove_file( &storage_refcount_path )
             .map_err(|e| { (e, "Unable to remove refcount file") } )?;
+        change.done();
         return Ok(());
     }
 
-    // Otherwise, write the new refcount
-    let mut f = OpenOptions::new()
-        .create(true).write(true).truncate(true).open(&storage_refcount_path)
-        .map_err(|e| { (e, "Unable to open/create new refcount file") } )?;
-    f.write_u32::<BigEndian>(refcount)?;
-    Ok(())
+    // Otherwise, write the new refcount beside it and swap it in, so that
+    // readers never see a partly written count
+    let temp_path = spool::temp_path(storage_path);
+    let written = OpenOptions::new().write(true).create_new(true).open(&temp_path)
+        .map_err(|e| { Error::from((e, "Unable to create new refcount file")) } )
+        .and_then(|mut f| {
+            f.write_all(&refcount::encode(refcount))
+                .map_err(|e| { (e, "Unable to write refcount file") } )?;
+            if config.durability >= Durability::FlushData {
+                f.sync_data()
+                    .map_err(|e| { (e, "Unable to flush refcount file") } )?;
+            }
+            Ok(())
+        })
+        .and_then(|()| replace::replace(&temp_path, &storage_refcount_path)
+                  .map_err(|e| { From::from((e, "Unable to replace refcount file")) } ));
+    match written {
+        Ok(()) => change.done(),
+        Err(_) => { let _ = fs::remove_file(&temp_path); },
+    }
+    written
 }
@@ -0,0 +1,63 @@
+// Limits on how many operations hash and copy content into a store at once
+// (`Config::max_concurrent_operations`), so that a burst of stores doesn't
+// run the process out of file descriptors or have the disk seeking between
+// them all.  The count of operations in progress is kept per store, shared
+// by every handle on it in this process; each caller waits until fewer than
+// its own handle's limit are running.
+
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Condvar,Mutex,MutexGuard,OnceLock};
+
+use super::handles;
+
+#[derive(Default)]
+struct Limiter {
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl Limiter {
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.running.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+type Registry = Mutex<HashMap<PathBuf, Arc<Limiter>>>;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+fn limiter(storage_path: &Path) -> Arc<Limiter> {
+    let canonical = handles::canonical(storage_path);
+    let mut registry = REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+        .lock().unwrap_or_else(|e| e.into_inner());
+    registry.entry(canonical).or_default().clone()
+}
+
+/// A place among the operations running on a store, given up when dropped
+pub(crate) struct Permit {
+    limiter: Arc<Limiter>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.limiter.lock() -= 1;
+        // Waiters may have different limits, so any of them might go next
+        self.limiter.finished.notify_all();
+    }
+}
+
+/// Wait until fewer than `limit` operations are running on the store, and
+/// count this one among them.  Without a limit, returns `None` at once.
+pub(crate) fn acquire(storage_path: &Path, limit: Option<usize>) -> Option<Permit> {
+    let limit = limit?.max(1);
+    let limiter = limiter(storage_path);
+    {
+        let mut running = limiter.lock();
+        while *running >= limit {
+            running = limiter.finished.wait(running).unwrap_or_else(|e| e.into_inner());
+        }
+        *running += 1;
+    }
+    Some(Permit { limiter })
+}
@@ -0,0 +1,516 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Whole-store inspection and repair.  These walk every object in the store
+//! and so are intended for operators and maintenance jobs rather than hot paths.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap,BinaryHeap};
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+
+use super::{Config,Error,FileKey};
+use super::{get_refcount,index,keylock,keys,remove_object,storage_file_path,storage_refcount_name,
+            storage_refcount_path};
+use super::error::ErrorKind;
+use super::hashable::Hashable;
+use super::journal::{self,Intent,Resolution};
+use super::manifest;
+use super::pin;
+use super::quarantine;
+use super::spool;
+use super::transaction;
+use super::usage::{self,Part};
+
+/// Summary figures for a store, returned by `stats()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of distinct objects stored
+    pub objects: u64,
+    /// Bytes occupied by object content (each object counted once)
+    pub bytes: u64,
+    /// Total references held across all objects
+    pub references: u64,
+}
+
+/// Where a store's space is going, returned by `space_report()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpaceReport {
+    /// The largest objects and their sizes, largest first
+    pub largest: Vec<(FileKey, u64)>,
+    /// Usage of each shard directory, by its two-character name
+    pub shards: BTreeMap<String, ShardUsage>,
+    /// Usage of the whole store
+    pub total: ShardUsage,
+}
+
+/// Space used by a set of objects
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShardUsage {
+    /// Number of objects
+    pub objects: u64,
+    /// Bytes of object content
+    pub bytes: u64,
+    /// Bytes of disk allocated to the objects, which may be more than their
+    /// content (whole blocks) or less (sparse or compressed files)
+    pub disk_bytes: u64,
+}
+
+impl ShardUsage {
+    fn add(&mut self, bytes: u64, disk_bytes: u64) {
+        self.objects += 1;
+        self.bytes += bytes;
+        self.disk_bytes += disk_bytes;
+    }
+}
+
+/// How much deduplication is saving, returned by `dedup_report()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DedupReport {
+    /// Number of objects with each refcount
+    pub refcounts: BTreeMap<u64, u64>,
+    /// Bytes that would be stored without deduplication (each object's
+    /// size times its refcount)
+    pub logical_bytes: u64,
+    /// Bytes actually stored (each object counted once)
+    pub physical_bytes: u64,
+}
+
+impl DedupReport {
+    /// Number of objects with at least `references` references
+    pub fn objects_with_at_least(&self, references: u64) -> u64 {
+        self.refcounts.range(references..).map(|(_, &objects)| objects).sum()
+    }
+
+    /// Logical bytes per physical byte; 1.0 means nothing is shared
+    pub fn ratio(&self) -> f64 {
+        if self.physical_bytes == 0 {
+            return 1.0;
+        }
+        self.logical_bytes as f64 / self.physical_bytes as f64
+    }
+}
+
+/// What a call to `gc()` removed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Objects that had no references and were deleted
+    pub objects_removed: Vec<FileKey>,
+    /// Bytes freed by deleting those objects
+    pub bytes_freed: u64,
+    /// Refcount files that had no object and were deleted
+    pub refcounts_removed: Vec<FileKey>,
+    /// Tags (key, tag) left on objects that are no longer stored
+    pub tags_removed: Vec<(FileKey, String)>,
+    /// Derived artifacts (source key, transform) of sources that are no
+    /// longer stored, whose references were released
+    pub derived_released: Vec<(FileKey, String)>,
+}
+
+/// How the store's refcounts differ from what an application expected,
+/// returned by `audit_refcounts()`.  Each list is in key order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefcountAudit {
+    /// Keys expected to be stored that aren't, with the references expected
+    pub missing: Vec<(FileKey, u64)>,
+    /// Keys stored with other than the expected number of references, with
+    /// the number expected and the refcount stored
+    pub mismatched: Vec<(FileKey, u64, u64)>,
+    /// Keys stored with references that weren't expected at all, with their
+    /// refcounts
+    pub unexpected: Vec<(FileKey, u64)>,
+}
+
+impl RefcountAudit {
+    /// Whether every refcount was as expected
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+/// What a call to `recover()` found and fixed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Keys whose interrupted refcount change was undone
+    pub rolled_back: Vec<FileKey>,
+    /// Keys whose interrupted refcount change was completed
+    pub rolled_forward: Vec<FileKey>,
+    /// Temporary files that were removed
+    pub temp_files_removed: Vec<PathBuf>,
+    /// Pins held by processes that are no longer running, which were removed
+    pub pins_removed: Vec<PathBuf>,
+    /// Records of transactions left by processes that are no longer
+    /// running, which were finished off or rolled back
+    pub transactions_resolved: Vec<PathBuf>,
+}
+
+/// The state of a store, returned by `health()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    /// Whether the storage directory exists
+    pub root_exists: bool,
+    /// Whether a file could be created in the storage directory
+    pub writable: bool,
+    /// Whether the store's manifest could be read and is understood, or
+    /// `None` for a store without one (created before manifests, or
+    /// without `FileStore::init()`)
+    pub manifest_valid: Option<bool>,
+    /// Bytes available on the filesystem holding the store, if that can be
+    /// determined here
+    pub available: Option<u64>,
+    /// Temporary files left by writers that are no longer running, which
+    /// `recover()` removes
+    pub temp_leftovers: u64,
+    /// Shard directories, by two-character name, that don't exist.  Stores
+    /// made by `FileStore::init()` have every shard from the start; those
+    /// without a manifest create them as objects first land in them, so
+    /// gaps are expected until they hold a few thousand objects.
+    pub missing_shards: Vec<String>,
+}
+
+impl Health {
+    /// Whether the store can serve stores and retrievals
+    pub fn is_healthy(&self) -> bool {
+        self.root_exists && self.writable && match self.manifest_valid {
+            Some(valid) => valid && self.missing_shards.is_empty(),
+            None => true,
+        }
+    }
+}
+
+/// Check that a store is usable, without walking its objects, so that this
+/// is cheap enough to run as a readiness probe.  Problems are reported in
+/// the result rather than as errors.
+pub fn health(storage_path: &Path) -> Result<Health, Error>
+{
+    let mut health = Health::default();
+    match fs::metadata(storage_path) {
+        Ok(metadata) => health.root_exists = metadata.is_dir(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(From::from((e, "Unable to stat storage directory"))),
+    }
+    if !health.root_exists {
+        return Ok(health);
+    }
+    // Removed again on drop
+    health.writable = spool::Spool::new(storage_path, false).is_ok();
+    health.available = super::space::available(storage_path);
+    health.manifest_valid = match manifest::read(storage_path) {
+        Ok(_) => Some(true),
+        Err(ref e) if e.kind == ErrorKind::NotAStore => None,
+        Err(_) => Some(false),
+    };
+
+    let mut shards = [false; 256];
+    for entry in fs::read_dir(storage_path)
+        .map_err(|e| { (e, "Unable to read storage directory") } )?
+    {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read storage directory") } )?;
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        if let Some(pid) = spool::owner(&name) {
+            if !spool::is_running(pid) {
+                health.temp_leftovers += 1;
+            }
+            continue;
+        }
+        if name.len() != 2 || name.bytes().any(|b| b.is_ascii_uppercase()) {
+            continue;
+        }
+        if let Ok(shard) = u8::from_str_radix(&name, 16) {
+            shards[shard as usize] = entry.path().is_dir();
+        }
+    }
+    health.missing_shards = (0..256)
+        .filter(|&shard| !shards[shard])
+        .map(|shard| format!("{:02x}", shard))
+        .collect();
+    Ok(health)
+}
+
+/// Count the objects, bytes and references in a store
+pub fn stats(storage_path: &Path) -> Result<Stats, Error>
+{
+    let mut stats = Stats::default();
+    for key in keys(storage_path)? {
+        let key = key?;
+        let metadata = fs::metadata(storage_file_path(storage_path, &key))
+            .map_err(|e| { (e, "Unable to stat stored file") } )?;
+        stats.objects += 1;
+        stats.bytes += metadata.len();
+        stats.references += get_refcount(storage_path, &key)?;
+    }
+    Ok(stats)
+}
+
+/// Report the `largest` biggest objects, and the space used by each shard
+/// and by the whole store
+pub fn space_report(storage_path: &Path, largest: usize) -> Result<SpaceReport, Error>
+{
+    let mut report = SpaceReport::default();
+    // The largest objects seen so far, smallest at the top
+    let mut heap: BinaryHeap<Reverse<(u64, FileKey)>> = BinaryHeap::new();
+    for key in keys(storage_path)? {
+        let key = key?;
+        let metadata = fs::metadata(storage_file_path(storage_path, &key))
+            .map_err(|e| { (e, "Unable to stat stored file") } )?;
+        let bytes = metadata.len();
+        let disk_bytes = usage::disk_usage(&metadata);
+        report.total.add(bytes, disk_bytes);
+        report.shards.entry(key[..2].to_owned()).or_default().add(bytes, disk_bytes);
+        if largest > 0 {
+            heap.push(Reverse((bytes, key)));
+            if heap.len() > largest {
+                heap.pop();
+            }
+        }
+    }
+    report.largest = heap.into_sorted_vec().into_iter()
+        .map(|Reverse((bytes, key))| (key, bytes))
+        .collect();
+    Ok(report)
+}
+
+/// Report the distribution of refcounts, and the bytes stored against the
+/// bytes that would be stored without deduplication
+pub fn dedup_report(storage_path: &Path) -> Result<DedupReport, Error>
+{
+    let mut report = DedupReport::default();
+    for key in keys(storage_path)? {
+        let key = key?;
+        let metadata = fs::metadata(storage_file_path(storage_path, &key))
+            .map_err(|e| { (e, "Unable to stat stored file") } )?;
+        let refcount = get_refcount(storage_path, &key)?;
+        *report.refcounts.entry(refcount).or_insert(0) += 1;
+        report.physical_bytes += metadata.len();
+        report.logical_bytes = report.logical_bytes
+            .saturating_add(metadata.len().saturating_mul(refcount));
+    }
+    Ok(report)
+}
+
+/// Compare the store's refcounts with the references an application
+/// believes it holds, as counted from its own records, changing nothing.
+/// A key given more than once is expected to have the sum of its counts.
+/// Objects held only by pins or weak references aren't reported as
+/// unexpected.
+///
+/// Counts are read one object at a time without stopping other changes, so
+/// stores and deletes made meanwhile may show up as discrepancies.
+pub fn audit_refcounts<I>(storage_path: &Path, expected: I) -> Result<RefcountAudit, Error>
+    where I: IntoIterator<Item = (FileKey, u32)>
+{
+    let mut remaining: BTreeMap<FileKey, u64> = BTreeMap::new();
+    for (key, references) in expected {
+        *remaining.entry(key).or_insert(0) += u64::from(references);
+    }
+
+    let mut audit = RefcountAudit::default();
+    for key in keys(storage_path)? {
+        let key = key?;
+        let refcount = get_refcount(storage_path, &key)?;
+        match remaining.remove(&key) {
+            Some(references) if references != refcount => {
+                audit.mismatched.push((key, references, refcount));
+            },
+            Some(_) => {},
+            None if refcount > 0 => audit.unexpected.push((key, refcount)),
+            None => {},
+        }
+    }
+    audit.missing = remaining.into_iter()
+        .filter(|&(_, references)| references > 0)
+        .collect();
+    audit.mismatched.sort();
+    audit.unexpected.sort();
+    Ok(audit)
+}
+
+/// Check that a stored object still hashes to its key.  Returns `Ok(false)`
+/// if the content has been altered.
+pub fn verify_key(storage_path: &Path, key: &FileKey) -> Result<bool, Error>
+{
+    let algorithm = key.algorithm().unwrap_or_default();
+    let hash = storage_file_path(storage_path, key).hash_with(algorithm)?;
+    Ok(hash == key.0)
+}
+
+/// Re-hash every object in the store, returning the keys of any whose
+/// content no longer matches.  Those are moved into quarantine (see the
+/// `quarantine` module), with their references, but for any pinned by a
+/// running process, which are left in place.
+pub fn verify(storage_path: &Path) -> Result<Vec<FileKey>, Error>
+{
+    let mut corrupt: Vec<FileKey> = Vec::new();
+    for key in keys(storage_path)? {
+        let key = key?;
+        if !verify_key(storage_path, &key)? {
+            quarantine::quarantine(storage_path, &key)?;
+            corrupt.push(key);
+        }
+    }
+    Ok(corrupt)
+}
+
+/// Remove objects that have no references, and refcount files that have
+/// no object, as left behind by interrupted stores and deletes.
+///
+/// Each object is removed under its key's lock, and only if it is then
+/// unreferenced and unpinned, so `gc()` can run alongside the store's other
+/// users.  Content stored with `store_weak()` and not referenced since goes
+/// at the next `gc()`.
+pub fn gc(storage_path: &Path) -> Result<GcReport, Error>
+{
+    gc_with_config(storage_path, &Config::default())
+}
+
+// As `gc()`, removing objects as `config` says to
+pub(crate) fn gc_with_config(storage_path: &Path, config: &Config) -> Result<GcReport, Error>
+{
+    let op = operation!("filestore.gc", objects);
+    let result = collect(storage_path, config);
+    if let Ok(ref report) = result {
+        op.record("objects", report.objects_removed.len() as u64);
+        op.record("bytes", report.bytes_freed);
+    }
+    op.finish(result)
+}
+
+fn collect(storage_path: &Path, config: &Config) -> Result<GcReport, Error>
+{
+    // Artifacts first, so that those left unreferenced go in this pass
+    let mut report = GcReport {
+        derived_released: super::derived::prune(storage_path)?,
+        ..GcReport::default()
+    };
+
+    for key in keys(storage_path)? {
+        let key = key?;
+        let _lock = keylock::lock(storage_path, &key)?;
+        if get_refcount(storage_path, &key)? > 0 || pin::is_pinned(storage_path, &key)? {
+            continue;
+        }
+        let metadata = match fs::metadata(storage_file_path(storage_path, &key)) {
+            Ok(metadata) => metadata,
+            // Removed by a delete since it was listed
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(From::from((e, "Unable to stat stored file"))),
+        };
+        // Journalled so that a removal cut short is finished off, and the
+        // object's index entry with it
+        let intent = Intent::record(storage_path, config, &key, 0, 0)?;
+        remove_object(storage_path, config, &key)?;
+        index::update(storage_path, config, &key)?;
+        intent.done()?;
+        report.bytes_freed += metadata.len();
+        report.objects_removed.push(key);
+    }
+
+    for key in orphaned_refcounts(storage_path)? {
+        let path = storage_refcount_path(storage_path, &key);
+        let change = usage::change(storage_path, Part::Refcounts, &path);
+        fs::remove_file(&path)
+            .map_err(|e| { (e, "Unable to remove refcount file") } )?;
+        change.done();
+        index::update(storage_path, config, &key)?;
+        report.refcounts_removed.push(key);
+    }
+
+    report.tags_removed = super::tags::prune(storage_path)?;
+
+    Ok(report)
+}
+
+// The keys of refcount files that have no object beside them
+pub(crate) fn orphaned_refcounts(storage_path: &Path) -> Result<Vec<FileKey>, Error> {
+    let mut orphans: Vec<FileKey> = Vec::new();
+    // Refcount files are not yielded by keys(), so walk the shards for them
+    for shard in fs::read_dir(storage_path)
+        .map_err(|e| { (e, "Unable to read storage directory") } )?
+    {
+        let shard = shard
+            .map_err(|e| { (e, "Unable to read storage directory") } )?;
+        let prefix = match shard.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        if prefix.len() != 2 || !shard.path().is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(shard.path())
+            .map_err(|e| { (e, "Unable to read shard directory") } )?
+        {
+            let entry = entry
+                .map_err(|e| { (e, "Unable to read shard directory") } )?;
+            let name = match entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            if !name.ends_with(".refcount") {
+                continue;
+            }
+            let key = FileKey(format!("{}{}", prefix, &name[..name.len() - 9]));
+            if !key.is_well_formed() || name != storage_refcount_name(&key) {
+                continue;
+            }
+            match fs::metadata(storage_file_path(storage_path, &key)) {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => orphans.push(key),
+                Err(e) => return Err( From::from(e) ),
+            }
+        }
+    }
+    Ok(orphans)
+}
+
+/// Tidy up after writers that crashed or were killed part way through an
+/// operation:
+///
+/// * refcount changes they had journalled but not completed are rolled
+///   back (or, if the new refcount was already written, finished off), so
+///   that no object is left with the wrong count or with partial content;
+/// * their transactions are finished off if they had committed, and
+///   otherwise rolled back;
+/// * the temporary files that stores write content into before moving it
+///   into place are removed.
+///
+/// Work belonging to processes that are still running (including this one)
+/// is left alone, so this is safe to run while the store is in use.
+pub fn recover(storage_path: &Path) -> Result<RecoveryReport, Error>
+{
+    let mut report = RecoveryReport::default();
+    for resolution in journal::replay(storage_path)? {
+        match resolution {
+            Resolution::RolledBack(key) => report.rolled_back.push(key),
+            Resolution::RolledForward(key) => report.rolled_forward.push(key),
+        }
+    }
+    report.transactions_resolved = transaction::replay(storage_path)?;
+
+    let removed = &mut report.temp_files_removed;
+    for entry in fs::read_dir(storage_path)
+        .map_err(|e| { (e, "Unable to read storage directory") } )?
+    {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read storage directory") } )?;
+        let pid = match entry.file_name().to_str().and_then(spool::owner) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if spool::is_running(pid) {
+            continue;
+        }
+        match fs::remove_file(entry.path()) {
+            Ok(()) => removed.push(entry.path()),
+            // Its writer finished after all
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(From::from((e, "Unable to remove temporary file"))),
+        }
+    }
+    report.pins_removed = pin::remove_stale(storage_path)?;
+    Ok(report)
+}
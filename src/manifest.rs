@@ -0,0 +1,179 @@
+// The store manifest, `manifest` in the storage path, which marks a
+// directory as a filestore and records how it is laid out.  `init()` writes
+// it last, so that a directory only counts as a store once its layout is
+// complete, and `open()` refuses directories without one.
+//
+// The manifest is text: a `filestore-store 1` header, then one setting per
+// line as a name, a space and a value.  Settings not understood by this
+// version are rejected rather than ignored, since they would change how the
+// store must be read.
+//
+// The digest content is keyed by is looked up on every store, so the
+// manifest is cached per store, and read again only when its modification
+// time changes (as when another process runs `rehash_store()`).
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self,Write};
+use std::path::{Path,PathBuf};
+use std::sync::{Mutex,OnceLock};
+use std::time::SystemTime;
+
+use super::{Error,handles,spool};
+use super::error::ErrorKind;
+use super::encoding::KeyEncoding;
+use super::hashable::HashAlgorithm;
+use super::spool::Spool;
+
+const HEADER: &str = "filestore-store 1";
+
+const FILE_NAME: &str = "manifest";
+
+// Entries other than shards that stores create in the storage path
+const ROOT_ENTRIES: &[&str] = &[
+    FILE_NAME, "journal", "uploads", "namespaces", "refs", "tags", "access", "pins",
+    "hash-cache", "refcount.lock", ".lock", "key-map", "types", "derived",
+    "macs", "audit", ".quarantine", "scrub", "bloom", "index",
+    "snapshots", "transactions", "merkle", "weak", "owners",
+];
+
+type Registry = Mutex<HashMap<PathBuf, (Option<SystemTime>, Manifest)>>;
+
+static MANIFESTS: OnceLock<Registry> = OnceLock::new();
+
+/// What the manifest records about a store
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    /// The digest content is keyed by
+    pub hash: HashAlgorithm,
+    /// How keys are written for users
+    pub keys: KeyEncoding,
+    /// Whether keys are written behind their algorithm's name
+    pub prefixed: bool,
+}
+
+fn manifest_path(storage_path: &Path) -> PathBuf {
+    storage_path.join(FILE_NAME)
+}
+
+fn damaged(message: &str) -> Error {
+    From::from((io::Error::new(io::ErrorKind::InvalidData, "malformed manifest"), message))
+}
+
+/// Read the manifest, failing with `ErrorKind::NotAStore` if there is none
+pub(crate) fn read(storage_path: &Path) -> Result<Manifest, Error> {
+    let text = match fs::read_to_string(manifest_path(storage_path)) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(From::from((ErrorKind::NotAStore, "Directory has no store manifest")));
+        },
+        Err(e) => return Err(From::from((e, "Unable to read store manifest"))),
+    };
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(damaged("Store manifest has an unsupported format"));
+    }
+    let mut manifest = Manifest::default();
+    for line in lines {
+        let (name, value) = line.split_once(' ')
+            .ok_or_else(|| damaged("Store manifest is damaged"))?;
+        match name {
+            "hash" => {
+                manifest.hash = HashAlgorithm::from_name(value)
+                    .ok_or_else(|| damaged(&format!("Store uses unsupported hash {}", value)))?;
+            },
+            "keys" => {
+                manifest.keys = KeyEncoding::from_name(value)
+                    .ok_or_else(|| damaged(&format!("Store uses unsupported key encoding {}",
+                                                    value)))?;
+            },
+            "key-prefix" => {
+                manifest.prefixed = match value {
+                    "algorithm" => true,
+                    "none" => false,
+                    _ => return Err(damaged(&format!("Store uses unsupported key prefix {}",
+                                                     value))),
+                };
+            },
+            _ => return Err(damaged(&format!("Store manifest has unknown setting {}", name))),
+        }
+    }
+    Ok(manifest)
+}
+
+/// Write the manifest, replacing any already there
+pub(crate) fn write(storage_path: &Path, manifest: &Manifest) -> Result<(), Error> {
+    let mut spool = Spool::new(storage_path, false)?;
+    write!(spool, "{}\nhash {}\n", HEADER, manifest.hash.name())
+        .map_err(|e| { (e, "Unable to write store manifest") } )?;
+    // Settings at their defaults are left out, so that such manifests read
+    // in older versions
+    if manifest.keys != KeyEncoding::Hex {
+        writeln!(spool, "keys {}", manifest.keys.name())
+            .map_err(|e| { (e, "Unable to write store manifest") } )?;
+    }
+    if manifest.prefixed {
+        writeln!(spool, "key-prefix algorithm")
+            .map_err(|e| { (e, "Unable to write store manifest") } )?;
+    }
+    spool.persist(&manifest_path(storage_path))?;
+    // Cached as written, as a change made within the same tick of the
+    // filesystem's clock leaves the modification time as it was
+    let modified = fs::metadata(manifest_path(storage_path)).ok()
+        .and_then(|metadata| metadata.modified().ok());
+    MANIFESTS.get_or_init(|| Mutex::new(HashMap::new()))
+        .lock().unwrap_or_else(|e| e.into_inner())
+        .insert(handles::canonical(storage_path), (modified, manifest.clone()));
+    Ok(())
+}
+
+/// The digest the store at `storage_path` keys content by: sha224 for
+/// stores without a manifest
+pub(crate) fn algorithm(storage_path: &Path) -> Result<HashAlgorithm, Error> {
+    Ok(current(storage_path)?.hash)
+}
+
+/// The manifest of the store at `storage_path`, the default for stores
+/// without one, from the cache if it hasn't changed since it was read
+pub(crate) fn current(storage_path: &Path) -> Result<Manifest, Error> {
+    let modified = match fs::metadata(manifest_path(storage_path)) {
+        Ok(metadata) => metadata.modified().ok(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Manifest::default()),
+        Err(e) => return Err(From::from((e, "Unable to stat store manifest"))),
+    };
+    let canonical = handles::canonical(storage_path);
+    let registry = MANIFESTS.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some((cached, manifest)) = registry.lock().unwrap_or_else(|e| e.into_inner())
+        .get(&canonical)
+    {
+        if modified.is_some() && *cached == modified {
+            return Ok(manifest.clone());
+        }
+    }
+    let manifest = read(storage_path)?;
+    registry.lock().unwrap_or_else(|e| e.into_inner())
+        .insert(canonical, (modified, manifest.clone()));
+    Ok(manifest)
+}
+
+/// Whether every entry in `storage_path` is one a store could have made,
+/// so that a store created before manifests can be adopted by `init()`
+/// without mistaking an arbitrary directory for one
+pub(crate) fn holds_only_store_entries(storage_path: &Path) -> Result<bool, Error> {
+    for entry in fs::read_dir(storage_path)
+        .map_err(|e| { (e, "Unable to read storage directory") } )?
+    {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read storage directory") } )?;
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => return Ok(false),
+        };
+        let is_shard = name.len() == 2
+            && name.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        if !is_shard && !ROOT_ENTRIES.contains(&&*name) && spool::owner(&name).is_none() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
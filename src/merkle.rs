@@ -0,0 +1,463 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! The `sha512-tree` digest, and checking parts of objects keyed by it.
+//!
+//! Content is cut into `CHUNK_SIZE` chunks (the last may be shorter; empty
+//! content is one empty chunk).  Each chunk's leaf digest is the SHA-512 of
+//! a 0 byte followed by the chunk, and two subtrees are joined by the
+//! SHA-512 of a 1 byte followed by their digests, so that a leaf can never
+//! pass for a node.  The tree is shaped as in RFC 6962: the left subtree
+//! holds the largest power of two of the leaves that is less than their
+//! number.  The key is the root's digest, so the chunks can be hashed on
+//! all cores at once.
+//!
+//! Because the leaf digests fold up to the key, they can be trusted once
+//! checked against it, and then each chunk checked on its own.
+//! `retrieve_range_verified()` reads and checks only the chunks a range
+//! touches, and `corrupt_regions()` tells which chunks of a damaged object
+//! are bad.  The leaf digests of an object are recorded under `merkle/` in
+//! the storage path, laid out as the shards are, the first time they are
+//! asked for or the object is scrubbed (see the `scrub` module), and go
+//! when the object does.
+
+use std::fs::{self,File};
+use std::io::{self,Read,Seek,SeekFrom};
+use std::ops::Range;
+use std::path::{Path,PathBuf};
+use std::sync::OnceLock;
+use std::thread;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha512;
+
+use super::{Error,FileKey,replace,spool,storage_file_path,usage};
+use super::hashable::HashAlgorithm;
+use super::usage::Part;
+
+/// The length of the chunks hashed as the tree's leaves
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+// The most threads hashing one piece of content
+const MAX_THREADS: usize = 16;
+
+const DIGEST_LEN: usize = 64;
+
+type Digest512 = [u8; DIGEST_LEN];
+
+fn threads() -> usize {
+    static THREADS: OnceLock<usize> = OnceLock::new();
+    *THREADS.get_or_init(|| {
+        thread::available_parallelism().map(|n| n.get()).unwrap_or(1).min(MAX_THREADS)
+    })
+}
+
+fn leaf_digest(chunk: &[u8]) -> Digest512 {
+    let mut hash = Sha512::new();
+    hash.input(&[0]);
+    hash.input(chunk);
+    let mut digest = [0_u8; DIGEST_LEN];
+    hash.result(&mut digest);
+    digest
+}
+
+fn node_digest(left: &Digest512, right: &Digest512) -> Digest512 {
+    let mut hash = Sha512::new();
+    hash.input(&[1]);
+    hash.input(left);
+    hash.input(right);
+    let mut digest = [0_u8; DIGEST_LEN];
+    hash.result(&mut digest);
+    digest
+}
+
+// The leaf digests of `data`, a whole number of chunks but for the last,
+// hashed across threads
+fn leaf_digests(data: &[u8]) -> Vec<Digest512> {
+    let chunks: Vec<&[u8]> = data.chunks(CHUNK_SIZE).collect();
+    let threads = threads().min(chunks.len());
+    if threads <= 1 {
+        return chunks.iter().map(|chunk| leaf_digest(chunk)).collect();
+    }
+    let per_thread = chunks.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let workers: Vec<_> = chunks.chunks(per_thread)
+            .map(|chunks| scope.spawn(move || {
+                chunks.iter().map(|chunk| leaf_digest(chunk)).collect::<Vec<_>>()
+            }))
+            .collect();
+        workers.into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+/// A `Digest` computing the `sha512-tree` digest
+#[derive(Clone, Default)]
+pub(crate) struct TreeHasher {
+    // Content not yet hashed, less than a batch
+    pending: Vec<u8>,
+    // The digests of complete subtrees, with their heights, tallest first
+    subtrees: Vec<(Digest512, u32)>,
+}
+
+impl TreeHasher {
+    pub(crate) fn new() -> TreeHasher {
+        TreeHasher::default()
+    }
+
+    fn push_leaf(&mut self, leaf: Digest512) {
+        let mut node = (leaf, 0);
+        while let Some(&(left, height)) = self.subtrees.last() {
+            if height != node.1 {
+                break;
+            }
+            self.subtrees.pop();
+            node = (node_digest(&left, &node.0), height + 1);
+        }
+        self.subtrees.push(node);
+    }
+
+    fn hash_pending(&mut self) {
+        for leaf in leaf_digests(&self.pending) {
+            self.push_leaf(leaf);
+        }
+        self.pending.clear();
+    }
+
+    fn root(&self) -> Digest512 {
+        let mut tree = TreeHasher { pending: Vec::new(), subtrees: self.subtrees.clone() };
+        for chunk in self.pending.chunks(CHUNK_SIZE) {
+            tree.push_leaf(leaf_digest(chunk));
+        }
+        let mut subtrees = tree.subtrees;
+        // The smaller subtrees on the right are joined up first
+        let mut root = match subtrees.pop() {
+            Some((digest, _)) => digest,
+            None => return leaf_digest(&[]),
+        };
+        while let Some((left, _)) = subtrees.pop() {
+            root = node_digest(&left, &root);
+        }
+        root
+    }
+}
+
+impl Digest for TreeHasher {
+    fn input(&mut self, mut input: &[u8]) {
+        let batch = threads() * CHUNK_SIZE;
+        while !input.is_empty() {
+            let take = (batch - self.pending.len()).min(input.len());
+            self.pending.extend_from_slice(&input[..take]);
+            input = &input[take..];
+            if self.pending.len() == batch {
+                self.hash_pending();
+            }
+        }
+    }
+
+    fn result(&mut self, out: &mut [u8]) {
+        out[..DIGEST_LEN].copy_from_slice(&self.root());
+    }
+
+    fn reset(&mut self) {
+        *self = TreeHasher::new();
+    }
+
+    fn output_bits(&self) -> usize {
+        DIGEST_LEN * 8
+    }
+
+    fn block_size(&self) -> usize {
+        CHUNK_SIZE
+    }
+}
+
+// The root digest of a tree with these leaves
+fn root_of(leaves: &[Digest512]) -> Digest512 {
+    let mut tree = TreeHasher::new();
+    for leaf in leaves {
+        tree.push_leaf(*leaf);
+    }
+    tree.root()
+}
+
+// The number of leaves of content `len` bytes long
+fn leaf_count(len: u64) -> usize {
+    (len.div_ceil(CHUNK_SIZE as u64) as usize).max(1)
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The leaf digests of an object keyed by `sha512-tree`, checked against
+/// its key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkDigests {
+    content_len: u64,
+    leaves: Vec<Digest512>,
+}
+
+impl ChunkDigests {
+    /// The length of the object's content in bytes
+    pub fn content_len(&self) -> u64 {
+        self.content_len
+    }
+
+    /// The number of chunks, at least one
+    pub fn chunk_count(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// The bytes of the content in chunk `index`
+    pub fn chunk_range(&self, index: usize) -> Range<u64> {
+        let start = (index as u64 * CHUNK_SIZE as u64).min(self.content_len);
+        start..(start + CHUNK_SIZE as u64).min(self.content_len)
+    }
+
+    /// The chunks holding any of the bytes in `range`
+    pub fn chunks_covering(&self, range: &Range<u64>) -> Range<usize> {
+        let end = range.end.min(self.content_len);
+        if range.start >= end {
+            return 0..0;
+        }
+        (range.start / CHUNK_SIZE as u64) as usize..end.div_ceil(CHUNK_SIZE as u64) as usize
+    }
+
+    /// The lowercase hex leaf digest of chunk `index`
+    pub fn digest(&self, index: usize) -> Option<String> {
+        self.leaves.get(index).map(|leaf| to_hex(leaf))
+    }
+
+    /// Whether `data` is the content of chunk `index`
+    pub fn verify_chunk(&self, index: usize, data: &[u8]) -> bool {
+        self.leaves.get(index) == Some(&leaf_digest(data))
+    }
+}
+
+fn merkle_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("merkle")
+}
+
+fn digests_path(storage_path: &Path, key: &FileKey) -> PathBuf {
+    merkle_dir(storage_path).join(&key[..2]).join(&key[2..])
+}
+
+fn check_key(key: &FileKey) -> Result<(), Error> {
+    match key.is_well_formed() && key.algorithm() == Some(HashAlgorithm::Sha512Tree) {
+        true => Ok(()),
+        false => Err(From::from((io::Error::from(io::ErrorKind::Unsupported),
+                                 "Only sha512-tree keys have chunk digests"))),
+    }
+}
+
+fn mismatch(message: &str) -> Error {
+    From::from((io::Error::from(io::ErrorKind::InvalidData), message))
+}
+
+/// The chunk digests of the object stored under `key`, a `sha512-tree` key.
+/// They are read from the record if there is one that checks out against
+/// the key, and otherwise computed from the content, which must match the
+/// key, and recorded.
+pub fn chunk_digests(storage_path: &Path, key: &FileKey) -> Result<ChunkDigests, Error> {
+    check_key(key)?;
+    let path = storage_file_path(storage_path, key);
+    let content_len = fs::metadata(&path)
+        .map_err(|e| { (e, "Unable to stat stored file") } )?
+        .len();
+    if let Some(digests) = recorded(storage_path, key, content_len)? {
+        return Ok(digests);
+    }
+    let leaves = hash_leaves(&path)?;
+    if to_hex(&root_of(&leaves)) != key.0 {
+        return Err(mismatch("Stored object does not match its key"));
+    }
+    let digests = ChunkDigests { content_len, leaves };
+    record(storage_path, key, &digests)?;
+    Ok(digests)
+}
+
+// The recorded digests of `key`, if they are there and fold up to it
+fn recorded(storage_path: &Path, key: &FileKey, content_len: u64)
+            -> Result<Option<ChunkDigests>, Error>
+{
+    let bytes = match fs::read(digests_path(storage_path, key)) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(From::from((e, "Unable to read chunk digests"))),
+    };
+    if bytes.len() != leaf_count(content_len) * DIGEST_LEN {
+        return Ok(None);
+    }
+    let leaves: Vec<Digest512> = bytes.chunks(DIGEST_LEN)
+        .map(|leaf| {
+            let mut digest = [0_u8; DIGEST_LEN];
+            digest.copy_from_slice(leaf);
+            digest
+        })
+        .collect();
+    if to_hex(&root_of(&leaves)) != key.0 {
+        // Damaged, so computed again
+        return Ok(None);
+    }
+    Ok(Some(ChunkDigests { content_len, leaves }))
+}
+
+fn record(storage_path: &Path, key: &FileKey, digests: &ChunkDigests) -> Result<(), Error> {
+    let path = digests_path(storage_path, key);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| { (e, "Unable to create merkle directory") } )?;
+    }
+    // Written aside and moved into place, so readers never see part of it
+    let change = usage::change(storage_path, Part::Metadata, &path);
+    let temp_path = spool::temp_path(storage_path);
+    let written = fs::write(&temp_path, digests.leaves.concat())
+        .and_then(|()| replace::replace(&temp_path, &path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(From::from((e, "Unable to write chunk digests")));
+    }
+    change.done();
+    Ok(())
+}
+
+// The leaf digests of the file at `path`, read a batch of chunks at a time
+fn hash_leaves(path: &Path) -> Result<Vec<Digest512>, Error> {
+    let mut file = File::open(path)
+        .map_err(|e| { (e, "Unable to open stored file") } )?;
+    let mut leaves: Vec<Digest512> = Vec::new();
+    let mut batch: Vec<u8> = Vec::with_capacity(threads() * CHUNK_SIZE);
+    loop {
+        batch.clear();
+        (&mut file).take((threads() * CHUNK_SIZE) as u64).read_to_end(&mut batch)
+            .map_err(|e| { (e, "Unable to read stored file") } )?;
+        if batch.is_empty() {
+            break;
+        }
+        leaves.extend(leaf_digests(&batch));
+    }
+    if leaves.is_empty() {
+        leaves.push(leaf_digest(&[]));
+    }
+    Ok(leaves)
+}
+
+/// Read the bytes in `range` (clamped to its length) of the object stored
+/// under `key`, a `sha512-tree` key, checking each chunk they fall in
+/// against its digest.  Only those chunks are read, once the object's chunk
+/// digests have been recorded.  Fails with an `io` of kind `InvalidData`
+/// if a chunk doesn't match.
+pub fn retrieve_range_verified(storage_path: &Path, key: &FileKey, range: Range<u64>)
+                               -> Result<Vec<u8>, Error>
+{
+    let file = super::retrieve_open(storage_path, key)?;
+    read_range_verified(storage_path, key, file, range)
+}
+
+pub(crate) fn read_range_verified(storage_path: &Path, key: &FileKey, mut file: File,
+                                  range: Range<u64>) -> Result<Vec<u8>, Error>
+{
+    let op = operation!("filestore.retrieve_range_verified");
+    op.key(key);
+    let digests = chunk_digests(storage_path, key)?;
+    let chunks = digests.chunks_covering(&range);
+    let mut data: Vec<u8> = Vec::new();
+    let mut chunk: Vec<u8> = Vec::with_capacity(CHUNK_SIZE);
+    for index in chunks {
+        let chunk_range = digests.chunk_range(index);
+        chunk.clear();
+        file.seek(SeekFrom::Start(chunk_range.start))
+            .and_then(|_| (&mut file).take(chunk_range.end - chunk_range.start)
+                      .read_to_end(&mut chunk))
+            .map_err(|e| { (e, "Unable to read stored file") } )?;
+        if !digests.verify_chunk(index, &chunk) {
+            return op.finish(Err(mismatch(
+                &format!("Chunk {} of {} does not match its digest", index, key))));
+        }
+        let start = range.start.max(chunk_range.start) - chunk_range.start;
+        let end = range.end.min(chunk_range.end) - chunk_range.start;
+        data.extend_from_slice(&chunk[start as usize..end as usize]);
+    }
+    op.record("bytes", data.len() as u64);
+    op.finish(Ok(data))
+}
+
+/// The byte ranges of the object stored under `key`, a `sha512-tree` key,
+/// whose chunks no longer match the digests recorded for them.  Nothing is
+/// known to be corrupt (and the whole object is given back) if no digests
+/// were recorded while it was intact.  Adjacent bad chunks are given as one
+/// range.
+pub fn corrupt_regions(storage_path: &Path, key: &FileKey) -> Result<Vec<Range<u64>>, Error> {
+    check_key(key)?;
+    let path = storage_file_path(storage_path, key);
+    let leaves = hash_leaves(&path)?;
+    let content_len = fs::metadata(&path)
+        .map_err(|e| { (e, "Unable to stat stored file") } )?
+        .len();
+    regions(storage_path, key, content_len, &leaves)
+}
+
+// The ranges of the chunks with `leaves` that differ from those recorded
+fn regions(storage_path: &Path, key: &FileKey, content_len: u64, leaves: &[Digest512])
+           -> Result<Vec<Range<u64>>, Error>
+{
+    if to_hex(&root_of(leaves)) == key.0 {
+        return Ok(Vec::new());
+    }
+    // Content that has changed length can't be compared with its old self
+    let recorded = match recorded(storage_path, key, content_len)? {
+        Some(recorded) => recorded,
+        None => return Ok(std::iter::once(0..content_len).collect()),
+    };
+    let mut regions: Vec<Range<u64>> = Vec::new();
+    for (index, leaf) in leaves.iter().enumerate() {
+        if recorded.leaves[index] == *leaf {
+            continue;
+        }
+        let range = recorded.chunk_range(index);
+        match regions.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => regions.push(range),
+        }
+    }
+    Ok(regions)
+}
+
+/// What scrubbing an object keyed by `sha512-tree` found
+pub(crate) enum Scrubbed {
+    Intact,
+    Corrupt(Vec<Range<u64>>),
+}
+
+/// Check the object `key` against its key in one read, recording its chunk
+/// digests if it is intact, or finding its bad regions if not
+pub(crate) fn scrub(storage_path: &Path, key: &FileKey) -> Result<Scrubbed, Error> {
+    let path = storage_file_path(storage_path, key);
+    let content_len = fs::metadata(&path)
+        .map_err(|e| { (e, "Unable to stat stored file") } )?
+        .len();
+    let leaves = hash_leaves(&path)?;
+    if to_hex(&root_of(&leaves)) != key.0 {
+        return Ok(Scrubbed::Corrupt(regions(storage_path, key, content_len, &leaves)?));
+    }
+    if recorded(storage_path, key, content_len)?.is_none() {
+        record(storage_path, key, &ChunkDigests { content_len, leaves })?;
+    }
+    Ok(Scrubbed::Intact)
+}
+
+/// Drop the chunk digests of an object that has been removed
+pub(crate) fn forget(storage_path: &Path, key: &FileKey) -> Result<(), Error> {
+    let path = digests_path(storage_path, key);
+    let change = usage::change(storage_path, Part::Metadata, &path);
+    match fs::remove_file(&path) {
+        Ok(()) => {
+            change.done();
+            Ok(())
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(From::from((e, "Unable to remove chunk digests"))),
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! File attributes kept alongside a reference, for using a store as a
+//! backup target.
+//!
+//! Objects are shared between every file with the same content, so their
+//! own permissions and timestamps mean nothing.  `store_file_with_metadata()`
+//! instead captures the permission bits, modification time and ownership of
+//! the file being stored, for the caller to keep with the key, and
+//! `retrieve_to_with_metadata()` puts them back on the copy it makes.  Tree
+//! manifests can record them for every entry (see `store_tree_with_metadata()`).
+
+use std::fs::{self,OpenOptions};
+use std::path::Path;
+use std::time::SystemTime;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+use super::Error;
+
+/// Permissions, modification time and ownership of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FileMetadata {
+    /// Permission bits (`0o7777` mask on Unix; elsewhere `0o444` or `0o644`
+    /// from the read-only attribute)
+    pub mode: u32,
+    /// Last modification time, if the platform reports it
+    pub mtime: Option<SystemTime>,
+    /// Owning user and group ids (Unix only)
+    pub owner: Option<(u32, u32)>,
+}
+
+impl FileMetadata {
+    /// Capture the attributes of the file (or directory) at `path`
+    pub fn capture(path: &Path) -> Result<FileMetadata, Error> {
+        let metadata = fs::metadata(path)
+            .map_err(|e| { (e, "Unable to stat file for metadata") } )?;
+        Ok(FileMetadata::from_metadata(&metadata))
+    }
+
+    pub(crate) fn from_metadata(metadata: &fs::Metadata) -> FileMetadata {
+        FileMetadata {
+            mode: mode_of(metadata),
+            mtime: metadata.modified().ok(),
+            owner: owner_of(metadata),
+        }
+    }
+
+    /// Set these attributes on the file (or directory) at `path`.
+    /// Ownership is only changed where the process has the privilege to do
+    /// so, and is otherwise left as it is.
+    pub fn apply(&self, path: &Path) -> Result<(), Error> {
+        // Ownership first, as changing it can clear set-id bits; then the
+        // time, before the mode can deny writes
+        if let Some((uid, gid)) = self.owner {
+            set_owner(path, uid, gid)?;
+        }
+        if let Some(mtime) = self.mtime {
+            set_mtime(path, mtime)?;
+        }
+        set_mode(path, self.mode)
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn mode_of(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub(crate) fn mode_of(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .map_err(|e| { From::from((e, "Unable to set permissions")) } )
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    let mut permissions = fs::metadata(path)
+        .map_err(|e| { (e, "Unable to stat file") } )?
+        .permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+        .map_err(|e| { From::from((e, "Unable to set permissions")) } )
+}
+
+#[cfg(unix)]
+fn owner_of(metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn owner_of(_metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(unix)]
+fn set_owner(path: &Path, uid: u32, gid: u32) -> Result<(), Error> {
+    use std::io;
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(path)
+        .map_err(|e| { (e, "Unable to stat file") } )?;
+    if metadata.uid() == uid && metadata.gid() == gid {
+        return Ok(());
+    }
+    match std::os::unix::fs::chown(path, Some(uid), Some(gid)) {
+        Ok(()) => Ok(()),
+        // Only the superuser may give files away
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+        Err(e) => Err(From::from((e, "Unable to set ownership"))),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_owner(_path: &Path, _uid: u32, _gid: u32) -> Result<(), Error> {
+    Ok(())
+}
+
+fn set_mtime(path: &Path, mtime: SystemTime) -> Result<(), Error> {
+    // Windows only sets times through handles opened for writing, and
+    // can't open directories as files
+    #[cfg(not(unix))]
+    {
+        if path.is_dir() {
+            return Ok(());
+        }
+    }
+    OpenOptions::new().read(true).write(cfg!(windows)).open(path)
+        .and_then(|f| f.set_modified(mtime))
+        .map_err(|e| { From::from((e, "Unable to set modification time")) } )
+}
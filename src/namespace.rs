@@ -0,0 +1,158 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Logically separate collections within one store.
+//!
+//! A `Namespace` (from `FileStore::namespace()`) keeps its own refcounts and
+//! listing under `namespaces/<name>/` in the storage path, while content is
+//! stored, and deduplicated, in the store as usual.  Each reference a
+//! namespace holds is also a reference on the underlying object, so the
+//! store's own refcounts and `keys()` cover every namespace.
+
+use std::fs;
+use std::io::{self,Read};
+use std::iter;
+use std::path::{Path,PathBuf};
+
+use super::{keylock,Error,FileKey,Keys};
+use super::error::ErrorKind;
+use super::store::{FileStore,ObjectStat,Store};
+
+/// A collection within a store, with its own references and listing
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    store: FileStore,
+    name: String,
+    path: PathBuf,
+}
+
+fn namespaces_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("namespaces")
+}
+
+// Move every namespace's references on `old` to `new`, for `rehash_store()`
+pub(crate) fn rename_key(storage_path: &Path, old: &FileKey, new: &FileKey) -> Result<(), Error> {
+    let entries = match fs::read_dir(namespaces_dir(storage_path)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(From::from((e, "Unable to read namespaces directory"))),
+    };
+    for entry in entries {
+        let path = entry
+            .map_err(|e| { (e, "Unable to read namespaces directory") } )?
+            .path();
+        let from = super::storage_refcount_path(&path, old);
+        if !from.exists() {
+            continue;
+        }
+        fs::create_dir_all(super::storage_file_dir(&path, new))
+            .map_err(|e| { (e, "Unable to create namespace directory") } )?;
+        fs::rename(&from, super::storage_refcount_path(&path, new))
+            .map_err(|e| { (e, "Unable to move namespace refcount") } )?;
+    }
+    Ok(())
+}
+
+impl Namespace {
+    pub(crate) fn new(store: &FileStore, name: &str) -> Result<Namespace, Error> {
+        if !super::is_valid_name(name) {
+            return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput,
+                                                  "malformed namespace name"),
+                                   "Invalid namespace name")));
+        }
+        Ok(Namespace {
+            store: store.clone(),
+            name: name.to_owned(),
+            path: namespaces_dir(store.path()).join(name),
+        })
+    }
+
+    /// The namespace's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The store the namespace is in
+    pub fn store(&self) -> &FileStore {
+        &self.store
+    }
+
+    /// Number of references this namespace holds on `key`
+    pub fn refcount(&self, key: &FileKey) -> Result<u64, Error> {
+        super::get_refcount(&self.path, key)
+    }
+
+    // Record one more reference from this namespace, having taken one on
+    // the object itself
+    fn add_reference(&self, key: &FileKey) -> Result<(), Error> {
+        fs::create_dir_all(super::storage_file_dir(&self.path, key))
+            .map_err(|e| { (e, "Unable to create namespace directory") } )?;
+        let _lock = keylock::lock(self.store.path(), key)?;
+        let refcount = self.refcount(key)?.checked_add(1)
+            .ok_or_else(|| Error::from((ErrorKind::RefcountOverflow,
+                                        &*format!("Too many references to {}", key))))?;
+        super::set_refcount(&self.path, self.store.config(), key, refcount)
+    }
+
+    fn stored(&self, key: FileKey) -> Result<FileKey, Error> {
+        if let Err(e) = self.add_reference(&key) {
+            // Give back the reference taken on the object
+            if let Err(e) = self.store.delete(&key) {
+                log::warn!("Unable to release {} after failed store: {:?}", key, e);
+            }
+            return Err(e);
+        }
+        Ok(key)
+    }
+}
+
+impl Store for Namespace {
+    fn store_data(&self, input: &[u8]) -> Result<FileKey, Error> {
+        let key = self.store.store_data(input)?;
+        self.stored(key)
+    }
+
+    fn store_reader(&self, input: &mut dyn Read) -> Result<FileKey, Error> {
+        let key = self.store.store_reader(input)?;
+        self.stored(key)
+    }
+
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>
+    {
+        if self.refcount(key)? < 1 {
+            return Ok(None);
+        }
+        self.store.retrieve_reader(key)
+    }
+
+    fn delete(&self, key: &FileKey) -> Result<(), Error> {
+        self.store.check_writable()?;
+        {
+            let _lock = keylock::lock(self.store.path(), key)?;
+            let refcount = self.refcount(key)?;
+            if refcount < 1 {
+                return Ok(()); // nothing to delete
+            }
+            // The namespace's reference goes first, so that a failure part
+            // way leaves the object referenced rather than a dangling listing
+            super::set_refcount(&self.path, self.store.config(), key, refcount - 1)?;
+        }
+        self.store.delete(key)
+    }
+
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error> {
+        let refcount = self.refcount(key)?;
+        if refcount < 1 {
+            return Ok(None);
+        }
+        Ok(self.store.stat(key)?.map(|stat| ObjectStat { refcount, ..stat }))
+    }
+
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error> {
+        if !self.path.is_dir() {
+            return Ok(Box::new(iter::empty()));
+        }
+        Ok(Box::new(Keys::refcounted(&self.path)?))
+    }
+}
@@ -0,0 +1,30 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Callbacks on store changes, for applications that mirror the store into
+//! something else such as a search index or a message queue.
+//!
+//! Observers are registered on a `FileStore` handle with `add_observer()`,
+//! and see the changes made through that handle (and its clones) only;
+//! the free functions and other handles on the same directory do not
+//! notify them.  Callbacks run synchronously on the calling thread after
+//! the change has been made, so slow observers should hand work off.
+
+use super::FileKey;
+
+/// Receives notice of changes made through a `FileStore`.  Every method
+/// has an empty default, so implementations need only provide the events
+/// they care about.
+pub trait Observer: Send + Sync {
+    /// A reference was taken on `key`.  `new_object` is true if the content
+    /// was not already in the store.
+    fn on_stored(&self, key: &FileKey, new_object: bool) {
+        let _ = (key, new_object);
+    }
+
+    /// A reference on `key` was dropped.  `last_reference` is true if that
+    /// was the last one, so the object is now gone from the store.
+    fn on_deleted(&self, key: &FileKey, last_reference: bool) {
+        let _ = (key, last_reference);
+    }
+}
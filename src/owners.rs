@@ -0,0 +1,236 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! References that carry the name of their owner, such as the id of the
+//! database row that holds the key.
+//!
+//! An owner holds at most one reference on an object: storing or
+//! referencing it again under the same owner takes no more, and
+//! `delete_owned()` releases the owner's reference only if it still holds
+//! one, so an application that deletes twice (retrying after a crash, say)
+//! doesn't release a reference some other owner holds.  `list_owners()`
+//! names who holds an object's references, and an object whose refcount is
+//! more than its owners has references taken without one, or leaked by a
+//! process that died part way through.
+//!
+//! Owners are kept under `owners/` in the storage path, one small text file
+//! per object laid out as the shards are, holding an owner per line.  The
+//! reference is taken before its owner is recorded and released after its
+//! owner is dropped, so an interruption can leave a reference without an
+//! owner but never an owner without a reference.
+
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+
+use super::{Config,DeleteOutcome,Error,FileKey};
+use super::{keylock,replace,spool,usage};
+use super::usage::Part;
+
+fn owners_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("owners")
+}
+
+fn owners_path(storage_path: &Path, key: &FileKey) -> PathBuf {
+    owners_dir(storage_path).join(&key[..2]).join(&key[2..])
+}
+
+fn check_owner(owner: &str) -> Result<(), Error> {
+    if owner.is_empty() || owner.contains(['\n', '\r']) {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "malformed owner"),
+                               "Invalid owner")));
+    }
+    Ok(())
+}
+
+/// Store data, as `store_data()` does, with its reference owned by `owner`.
+/// If `owner` already holds a reference on the content no other is taken.
+///
+/// Owners may be any text but the empty string, without line breaks.
+pub fn store_owned<D: AsRef<[u8]> + ?Sized>(storage_path: &Path, input: &D, owner: &str)
+                                           -> Result<FileKey, Error>
+{
+    store_owned_new(storage_path, &Config::default(), input.as_ref(), owner)
+        .map(|(key, _, _)| key)
+}
+
+// As `store_owned()`, also returning whether the content was newly stored
+// and whether a reference was taken
+pub(crate) fn store_owned_new(storage_path: &Path, config: &Config, input: &[u8], owner: &str)
+                              -> Result<(FileKey, bool, bool), Error>
+{
+    check_owner(owner)?;
+    let (key, new_object) = super::store_data_new(storage_path, config, input)?;
+    let taken = own(storage_path, config, &key, owner)?;
+    Ok((key, new_object, taken))
+}
+
+/// Take a reference on `key`, which must be stored, owned by `owner`.
+/// Returns `false`, taking none, if `owner` already holds one.
+pub fn add_owned_reference(storage_path: &Path, key: &FileKey, owner: &str)
+                           -> Result<bool, Error>
+{
+    add_owned_reference_new(storage_path, &Config::default(), key, owner)
+}
+
+pub(crate) fn add_owned_reference_new(storage_path: &Path, config: &Config, key: &FileKey,
+                                      owner: &str) -> Result<bool, Error>
+{
+    check_owner(owner)?;
+    if !key.is_well_formed() {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "malformed key"),
+                               "Invalid key")));
+    }
+    super::add_reference(storage_path, config, key)?;
+    own(storage_path, config, key, owner)
+}
+
+// Record `owner` as the owner of a reference just taken on `key`, releasing
+// it again if `owner` already held one.  Returns whether it was kept.
+fn own(storage_path: &Path, config: &Config, key: &FileKey, owner: &str) -> Result<bool, Error> {
+    let recorded = keylock::lock(storage_path, key).and_then(|_lock| {
+        update(storage_path, key, |owners| {
+            match owners.binary_search_by(|o| (**o).cmp(owner)) {
+                Ok(_) => false,
+                Err(i) => {
+                    owners.insert(i, owner.to_owned());
+                    true
+                },
+            }
+        })
+    });
+    match recorded {
+        Ok(true) => Ok(true),
+        Ok(false) => {
+            super::release(storage_path, config, key)?;
+            Ok(false)
+        },
+        Err(e) => {
+            if let Err(e) = super::release(storage_path, config, key) {
+                log::warn!("Unable to release {} after failing to record its owner: {:?}",
+                           key, e);
+            }
+            Err(e)
+        },
+    }
+}
+
+/// Release the reference `owner` holds on `key`, removing the object if it
+/// was the last, as `delete()` does.  Returns `false`, releasing nothing,
+/// if `owner` holds none, so deleting twice is harmless.
+pub fn delete_owned(storage_path: &Path, key: &FileKey, owner: &str) -> Result<bool, Error>
+{
+    delete_owned_new(storage_path, &Config::default(), key, owner)
+        .map(|outcome| outcome != DeleteOutcome::NotFound)
+}
+
+// As `delete_owned()`, telling what became of the object, or `NotFound` if
+// `owner` held no reference
+pub(crate) fn delete_owned_new(storage_path: &Path, config: &Config, key: &FileKey,
+                               owner: &str) -> Result<DeleteOutcome, Error>
+{
+    check_owner(owner)?;
+    if !key.is_well_formed() {
+        return Ok(DeleteOutcome::NotFound);
+    }
+    let dropped = {
+        let _lock = keylock::lock(storage_path, key)?;
+        update(storage_path, key, |owners| {
+            match owners.binary_search_by(|o| (**o).cmp(owner)) {
+                Ok(i) => {
+                    owners.remove(i);
+                    true
+                },
+                Err(_) => false,
+            }
+        })?
+    };
+    match dropped {
+        true => super::release_checked(storage_path, config, key),
+        false => Ok(DeleteOutcome::NotFound),
+    }
+}
+
+/// The owners of the references on `key`, in order
+pub fn list_owners(storage_path: &Path, key: &FileKey) -> Result<Vec<String>, Error>
+{
+    if !key.is_well_formed() {
+        return Ok(Vec::new());
+    }
+    read(&owners_path(storage_path, key))
+}
+
+fn read(path: &Path) -> Result<Vec<String>, Error> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(text.lines().map(|line| line.to_owned()).collect()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(From::from((e, "Unable to read owners"))),
+    }
+}
+
+// Apply `f` to the owners of `key`, writing them back if it returns `true`.
+// The key's lock must be held.
+fn update<F>(storage_path: &Path, key: &FileKey, f: F) -> Result<bool, Error>
+    where F: FnOnce(&mut Vec<String>) -> bool
+{
+    let path = owners_path(storage_path, key);
+    let mut owners = read(&path)?;
+    if !f(&mut owners) {
+        return Ok(false);
+    }
+    write(storage_path, &path, &owners)?;
+    Ok(true)
+}
+
+fn write(storage_path: &Path, path: &Path, owners: &[String]) -> Result<(), Error> {
+    let change = usage::change(storage_path, Part::Metadata, path);
+    if owners.is_empty() {
+        match fs::remove_file(path) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(From::from((e, "Unable to remove owners"))),
+        }
+        change.done();
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| { (e, "Unable to create owners directory") } )?;
+    }
+    // Written aside and moved into place, so readers never see part of it
+    let mut text = String::new();
+    for owner in owners {
+        text.push_str(owner);
+        text.push('\n');
+    }
+    let temp_path = spool::temp_path(storage_path);
+    let written = fs::write(&temp_path, text)
+        .and_then(|()| replace::replace(&temp_path, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(From::from((e, "Unable to write owners")));
+    }
+    change.done();
+    Ok(())
+}
+
+/// Move the owners of `old` to `new`, for `rehash_store()`
+pub(crate) fn rename_key(storage_path: &Path, old: &FileKey, new: &FileKey) -> Result<(), Error> {
+    let from = owners_path(storage_path, old);
+    if !from.exists() {
+        return Ok(());
+    }
+    let to = owners_path(storage_path, new);
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| { (e, "Unable to create owners directory") } )?;
+    }
+    fs::rename(&from, &to)
+        .map_err(|e| { From::from((e, "Unable to move owners")) } )
+}
+
+/// Drop the owners of an object that has been removed, so that they don't
+/// release references taken on it if it is stored again
+pub(crate) fn forget(storage_path: &Path, key: &FileKey) -> Result<(), Error> {
+    write(storage_path, &owners_path(storage_path, key), &[])
+}
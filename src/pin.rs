@@ -0,0 +1,318 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Holding an object in place while its file is in use.
+//!
+//! The path from `retrieve_file()` can be deleted by another thread or
+//! process as soon as the last reference is dropped.  `retrieve_pinned()`
+//! instead returns a `Pinned` guard, and while any guard on an object is
+//! alive its file is not removed: dropping the last reference leaves it in
+//! place with a refcount of zero, and it is removed when the last guard is
+//! dropped (or by `gc()`).
+//!
+//! Each pin is a file under `pins/<key>/` in the storage path, named after
+//! the process holding it, so pins left by a process that died are ignored
+//! and removed by `recover()`.
+//!
+//! `pin()` instead pins an object until `unpin()` is called, whatever
+//! becomes of the process, for objects that must not go even if their
+//! references are dropped by mistake.  Such a pin is the file `persistent`
+//! in the same directory.  Deletes, `gc()`, journal recovery, snapshot
+//! restores and cache eviction all leave pinned objects in place.
+
+use std::fs::{self,OpenOptions};
+use std::io;
+use std::path::{Path,PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+use super::{Config,Error,FileKey};
+use super::{get_refcount,index,keylock,remove_object,spool,storage_file_path};
+
+static PIN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// The pin file left by `pin()`
+const PERSISTENT: &str = "persistent";
+
+fn pins_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("pins")
+}
+
+fn key_pins_dir(storage_path: &Path, key: &FileKey) -> PathBuf {
+    pins_dir(storage_path).join(&key.0)
+}
+
+fn persistent_path(storage_path: &Path, key: &FileKey) -> PathBuf {
+    key_pins_dir(storage_path, key).join(PERSISTENT)
+}
+
+// The id of the process holding the pin file `name`
+fn owner(name: &str) -> Option<u32> {
+    let (pid, counter) = name.split_once('-')?;
+    counter.parse::<usize>().ok()?;
+    pid.parse().ok()
+}
+
+/// A stored object held in place until the guard is dropped
+#[derive(Debug)]
+pub struct Pinned {
+    storage_path: PathBuf,
+    config: Config,
+    key: FileKey,
+    pin_path: PathBuf,
+}
+
+impl Pinned {
+    /// The key of the pinned object
+    pub fn key(&self) -> &FileKey {
+        &self.key
+    }
+
+    /// The path to the stored file, valid while the guard is alive.  As with
+    /// `retrieve_file()`, this is the only copy; do not modify it.
+    pub fn path(&self) -> PathBuf {
+        storage_file_path(&self.storage_path, &self.key)
+    }
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.pin_path) {
+            log::warn!("Unable to remove pin on {}: {:?}", self.key, e);
+            return;
+        }
+        if let Err(e) = remove_if_released(&self.storage_path, &self.config, &self.key) {
+            log::warn!("Unable to remove unreferenced {}: {:?}", self.key, e);
+        }
+    }
+}
+
+// Finish off a deletion that was held back by a pin just removed, if no
+// other pin or reference holds the object
+fn remove_if_released(storage_path: &Path, config: &Config, key: &FileKey) -> Result<(), Error> {
+    let result = keylock::lock(storage_path, key).and_then(|_lock| {
+        is_pinned(storage_path, key)
+            .and_then(|pinned| match pinned {
+                true => Ok(false),
+                false => get_refcount(storage_path, key).map(|count| count == 0),
+            })
+            .and_then(|unreferenced| match unreferenced {
+                true => remove_object(storage_path, config, key)
+                    .and_then(|()| index::update(storage_path, config, key)),
+                false => Ok(()),
+            })
+    });
+    let _ = fs::remove_dir(key_pins_dir(storage_path, key));
+    result
+}
+
+/// Pin an object so its file stays in place until the returned guard is
+/// dropped, or `None` if the key is not stored
+pub fn retrieve_pinned(storage_path: &Path, key: &FileKey) -> Result<Option<Pinned>, Error>
+{
+    hold(storage_path, &Config::default(), key)
+}
+
+pub(crate) fn hold(storage_path: &Path, config: &Config, key: &FileKey)
+                   -> Result<Option<Pinned>, Error>
+{
+    // Under the key's lock, as for `pin()`, and released before a pin on a
+    // missing object is dropped, as dropping it takes the lock
+    let lock = keylock::lock(storage_path, key)?;
+    let dir = key_pins_dir(storage_path, key);
+    let pin_path = loop {
+        let path = dir.join(format!(
+            "{}-{}", process::id(), PIN_COUNTER.fetch_add(1, Ordering::SeqCst)));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => break path,
+            // Left over from an earlier process with the same pid
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir_all(&dir)
+                    .map_err(|e| { (e, "Unable to create pin directory") } )?;
+            },
+            Err(e) => return Err(From::from((e, "Unable to create pin"))),
+        }
+    };
+    let pinned = Pinned {
+        storage_path: storage_path.to_path_buf(),
+        config: config.clone(),
+        key: key.clone(),
+        pin_path,
+    };
+    let stored = fs::metadata(storage_file_path(storage_path, key));
+    drop(lock);
+    match stored {
+        Ok(_) => Ok(Some(pinned)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(From::from((e, "Unable to stat stored file"))),
+    }
+}
+
+/// Pin the object `key` until `unpin()` is called, so that it stays in the
+/// store with no references left.  Returns `false`, pinning nothing, if the
+/// key is not stored.
+pub fn pin(storage_path: &Path, key: &FileKey) -> Result<bool, Error>
+{
+    if !key.is_well_formed() {
+        return Ok(false);
+    }
+    // Under the key's lock, so that a delete or `gc()` deciding to remove
+    // the object either sees the pin or has removed the object already
+    let _lock = keylock::lock(storage_path, key)?;
+    let path = persistent_path(storage_path, key);
+    let created = loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => break true,
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => break false,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir_all(key_pins_dir(storage_path, key))
+                    .map_err(|e| { (e, "Unable to create pin directory") } )?;
+            },
+            Err(e) => return Err(From::from((e, "Unable to create pin"))),
+        }
+    };
+
+    match fs::metadata(storage_file_path(storage_path, key)) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            if created {
+                let _ = fs::remove_file(&path);
+                let _ = fs::remove_dir(key_pins_dir(storage_path, key));
+            }
+            Ok(false)
+        },
+        Err(e) => Err(From::from((e, "Unable to stat stored file"))),
+    }
+}
+
+/// Remove the pin left on `key` by `pin()`, removing the object too if it
+/// has no references left and no other pins.  Returns `false` if it wasn't
+/// pinned.
+pub fn unpin(storage_path: &Path, key: &FileKey) -> Result<bool, Error>
+{
+    remove_pin(storage_path, &Config::default(), key)
+}
+
+pub(crate) fn remove_pin(storage_path: &Path, config: &Config, key: &FileKey)
+                         -> Result<bool, Error>
+{
+    if !key.is_well_formed() {
+        return Ok(false);
+    }
+    match fs::remove_file(persistent_path(storage_path, key)) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(From::from((e, "Unable to remove pin"))),
+    }
+    remove_if_released(storage_path, config, key)?;
+    Ok(true)
+}
+
+/// The keys pinned by `pin()`
+pub fn list_pinned(storage_path: &Path) -> Result<Vec<FileKey>, Error>
+{
+    let mut pinned: Vec<FileKey> = Vec::new();
+    let entries = match fs::read_dir(pins_dir(storage_path)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(pinned),
+        Err(e) => return Err(From::from((e, "Unable to read pin directory"))),
+    };
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read pin directory") } )?;
+        if let Ok(name) = entry.file_name().into_string() {
+            let key = FileKey(name);
+            if key.is_well_formed() && persistent_path(storage_path, &key).exists() {
+                pinned.push(key);
+            }
+        }
+    }
+    pinned.sort();
+    Ok(pinned)
+}
+
+/// Whether `key` is pinned, by `pin()` or by a running process's `Pinned`
+/// guard
+pub fn is_pinned(storage_path: &Path, key: &FileKey) -> Result<bool, Error> {
+    find_pins(storage_path, key, true)
+}
+
+/// Whether a running process holds a `Pinned` guard on `key`
+pub(crate) fn is_held(storage_path: &Path, key: &FileKey) -> Result<bool, Error> {
+    find_pins(storage_path, key, false)
+}
+
+// Whether there is a pin on `key` held by a running process or, if
+// `persistent`, left by `pin()`
+fn find_pins(storage_path: &Path, key: &FileKey, persistent: bool) -> Result<bool, Error> {
+    let entries = match fs::read_dir(key_pins_dir(storage_path, key)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(From::from((e, "Unable to read pin directory"))),
+    };
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read pin directory") } )?;
+        let name = entry.file_name();
+        if persistent && name == PERSISTENT {
+            return Ok(true);
+        }
+        if let Some(pid) = name.to_str().and_then(owner) {
+            if spool::is_running(pid) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Move the pin left by `pin()` on `old` to `new`, for `rehash_store()`
+pub(crate) fn rename_key(storage_path: &Path, old: &FileKey, new: &FileKey) -> Result<(), Error> {
+    let from = persistent_path(storage_path, old);
+    if !from.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(key_pins_dir(storage_path, new))
+        .map_err(|e| { (e, "Unable to create pin directory") } )?;
+    fs::rename(&from, persistent_path(storage_path, new))
+        .map_err(|e| { (e, "Unable to move pin") } )?;
+    let _ = fs::remove_dir(key_pins_dir(storage_path, old));
+    Ok(())
+}
+
+/// Remove the pins of processes that are no longer running, returning the
+/// paths removed
+pub(crate) fn remove_stale(storage_path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut removed: Vec<PathBuf> = Vec::new();
+    let keys = match fs::read_dir(pins_dir(storage_path)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(removed),
+        Err(e) => return Err(From::from((e, "Unable to read pin directory"))),
+    };
+    for key_dir in keys {
+        let key_dir = key_dir
+            .map_err(|e| { (e, "Unable to read pin directory") } )?;
+        for entry in fs::read_dir(key_dir.path())
+            .map_err(|e| { (e, "Unable to read pin directory") } )?
+        {
+            let entry = entry
+                .map_err(|e| { (e, "Unable to read pin directory") } )?;
+            let pid = match entry.file_name().to_str().and_then(owner) {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if spool::is_running(pid) {
+                continue;
+            }
+            match fs::remove_file(entry.path()) {
+                Ok(()) => removed.push(entry.path()),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(From::from((e, "Unable to remove pin"))),
+            }
+        }
+        // Still in use if anything is left in it
+        let _ = fs::remove_dir(key_dir.path());
+    }
+    Ok(removed)
+}
@@ -0,0 +1,92 @@
+// A cheap first pass for bulk ingestion (`Config::precheck`).  Each stored
+// object has a fingerprint: an xxh3 hash of its size and first few KiB.  A
+// file whose fingerprint matches no stored object cannot already be stored,
+// so it is hashed while being copied into the store, reading it once,
+// instead of being hashed first to find out whether a copy is needed.
+//
+// A store's fingerprints are gathered the first time a file is checked
+// against it, and objects stored by this process are added as they are
+// placed.  Fingerprints are never removed, and objects stored by other
+// processes are missed; either way a file only takes the slower path, as
+// keys always come from the sha224 digest.
+
+use std::collections::{HashMap,HashSet};
+use std::fs::File;
+use std::io::{self,Read};
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,OnceLock};
+
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+use super::{handles,keys,storage_file_path,Error};
+
+const PREFIX: usize = 4096;
+
+type Fingerprints = Arc<Mutex<HashSet<u64>>>;
+type Registry = Mutex<HashMap<PathBuf, Fingerprints>>;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// The fingerprints for a store, if they have been gathered
+fn tracked(storage_path: &Path) -> Option<Fingerprints> {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    if registry.is_empty() {
+        return None;
+    }
+    registry.get(&handles::canonical(storage_path)).cloned()
+}
+
+fn track(storage_path: &Path) -> Result<Fingerprints, Error> {
+    if let Some(fingerprints) = tracked(storage_path) {
+        return Ok(fingerprints);
+    }
+    // Gathered outside the registry lock, as this reads every object
+    let mut gathered: HashSet<u64> = HashSet::new();
+    for key in keys(storage_path)? {
+        let key = key?;
+        match fingerprint(&storage_file_path(storage_path, &key)) {
+            Ok(f) => { gathered.insert(f); },
+            // Deleted while being listed
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(From::from((e, "Unable to read stored file"))),
+        }
+    }
+    let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    Ok(registry.entry(handles::canonical(storage_path))
+       .or_insert_with(|| Arc::new(Mutex::new(gathered)))
+       .clone())
+}
+
+fn fingerprint(path: &Path) -> io::Result<u64> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut prefix: Vec<u8> = Vec::with_capacity(PREFIX);
+    file.take(PREFIX as u64).read_to_end(&mut prefix)?;
+    Ok(xxh3_64_with_seed(&prefix, len))
+}
+
+/// Whether the file at `input` might already be stored.  `false` means no
+/// object known to this process matches it.
+pub(crate) fn may_be_stored(storage_path: &Path, input: &Path) -> Result<bool, Error> {
+    let fingerprints = track(storage_path)?;
+    let fingerprint = fingerprint(input)
+        .map_err(|e| { (e, "Unable to read content file") } )?;
+    let fingerprints = fingerprints.lock().unwrap_or_else(|e| e.into_inner());
+    Ok(fingerprints.contains(&fingerprint))
+}
+
+/// Account for the new object at `object_path`
+pub(crate) fn add(storage_path: &Path, object_path: &Path) {
+    if let Some(fingerprints) = tracked(storage_path) {
+        match fingerprint(object_path) {
+            Ok(f) => {
+                fingerprints.lock().unwrap_or_else(|e| e.into_inner()).insert(f);
+            },
+            Err(e) => log::warn!("Unable to fingerprint {}: {:?}", object_path.display(), e),
+        }
+    }
+}
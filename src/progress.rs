@@ -0,0 +1,30 @@
+
+use std::io::{self,Read};
+
+/// Wraps a reader, reporting `(bytes_done, total)` to a callback after
+/// every read that returns data
+pub struct ProgressReader<R, P> {
+    inner: R,
+    done: u64,
+    total: u64,
+    progress: P,
+}
+
+impl<R: Read, P: FnMut(u64, u64)> ProgressReader<R, P> {
+    pub fn new(inner: R, total: u64, progress: P) -> ProgressReader<R, P> {
+        ProgressReader { inner, done: 0, total, progress }
+    }
+}
+
+impl<R: Read, P: FnMut(u64, u64)> Read for ProgressReader<R, P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if count > 0 {
+            self.done += count as u64;
+            // The input may have grown since its size was taken
+            self.total = self.total.max(self.done);
+            (self.progress)(self.done, self.total);
+        }
+        Ok(count)
+    }
+}
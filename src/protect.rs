@@ -0,0 +1,50 @@
+// Write permission on stored objects (`Config::read_only_objects`).  Object
+// content never changes once stored, so the files can be made read-only to
+// stop callers of `retrieve_file()` modifying the canonical copy by mistake.
+// Write permission is given back before an object is overwritten or removed,
+// which also covers objects copied from files that were already read-only.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::Error;
+
+/// Take away write permission from the file at `path` (0444 from 0644)
+pub(crate) fn make_read_only(path: &Path) -> Result<(), Error> {
+    let mut permissions = fs::metadata(path)
+        .map_err(|e| { (e, "Unable to stat stored file") } )?
+        .permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)
+        .map_err(|e| { From::from((e, "Unable to make stored file read-only")) } )
+}
+
+/// Give the owner write permission on the file at `path` again, if it is
+/// read-only.  A missing file is left missing.
+pub(crate) fn make_writable(path: &Path) -> Result<(), Error> {
+    let mut permissions = match fs::metadata(path) {
+        Ok(m) => m.permissions(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(From::from((e, "Unable to stat stored file"))),
+    };
+    if !permissions.readonly() {
+        return Ok(());
+    }
+    allow_owner_write(&mut permissions);
+    fs::set_permissions(path, permissions)
+        .map_err(|e| { From::from((e, "Unable to make stored file writable")) } )
+}
+
+#[cfg(unix)]
+fn allow_owner_write(permissions: &mut fs::Permissions) {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = permissions.mode();
+    permissions.set_mode(mode | 0o200);
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::permissions_set_readonly_false)]
+fn allow_owner_write(permissions: &mut fs::Permissions) {
+    permissions.set_readonly(false);
+}
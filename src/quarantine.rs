@@ -0,0 +1,180 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Objects found corrupt by `verify()`, set aside rather than deleted so
+//! that they can be examined, and restored if need be.
+//!
+//! A quarantined object's content and refcount file are moved, as they
+//! are, to `.quarantine/` in the storage path.  Until it is restored, or
+//! the same content is stored again, retrievals of it fail with
+//! `ErrorKind::Quarantined` rather than reporting it missing, and `gc()`
+//! leaves its tags and derived artifacts alone.
+
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+
+use super::{Config,Error,FileKey,index,keylock,pin,usage,writelock};
+use super::{get_refcount,set_refcount,storage_file_dir,storage_file_path,storage_refcount_path};
+use super::error::ErrorKind;
+use super::usage::Part;
+
+fn quarantine_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join(".quarantine")
+}
+
+fn content_path(storage_path: &Path, key: &FileKey) -> PathBuf {
+    quarantine_dir(storage_path).join(&key.0)
+}
+
+fn refcount_path(storage_path: &Path, key: &FileKey) -> PathBuf {
+    quarantine_dir(storage_path).join(format!("{}.refcount", key))
+}
+
+/// The keys of the objects in quarantine
+pub fn list_quarantined(storage_path: &Path) -> Result<Vec<FileKey>, Error> {
+    let mut keys: Vec<FileKey> = Vec::new();
+    let dir = match fs::read_dir(quarantine_dir(storage_path)) {
+        Ok(dir) => dir,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(keys),
+        Err(e) => return Err(From::from((e, "Unable to read quarantine directory"))),
+    };
+    for entry in dir {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read quarantine directory") } )?;
+        if let Ok(name) = entry.file_name().into_string() {
+            let key = FileKey(name);
+            if key.is_well_formed() {
+                keys.push(key);
+            }
+        }
+    }
+    keys.sort();
+    Ok(keys)
+}
+
+/// The path of the quarantined content of `key`, for examining it, or
+/// `None` if it is not in quarantine
+pub fn quarantined_file(storage_path: &Path, key: &FileKey) -> Option<PathBuf> {
+    if !key.is_well_formed() {
+        return None;
+    }
+    let path = content_path(storage_path, key);
+    match path.exists() {
+        true => Some(path),
+        false => None,
+    }
+}
+
+/// Put the quarantined object `key` back in the store as it is, with its
+/// references.  If the same content has been stored again meanwhile, the
+/// quarantined references are added to the stored object's and the
+/// quarantined copy is removed.  Returns `false` if `key` is not in
+/// quarantine.
+///
+/// The content is not checked: restore an object found corrupt by mistake
+/// (as through a failing disk read), or once its references no longer
+/// matter and it can be deleted through the usual means.
+pub fn restore_from_quarantine(storage_path: &Path, key: &FileKey) -> Result<bool, Error> {
+    let content = match quarantined_file(storage_path, key) {
+        Some(content) => content,
+        None => return Ok(false),
+    };
+    let _writing = writelock::begin(storage_path)?;
+    let _lock = keylock::lock(storage_path, key)?;
+    let refcount = read_refcount(storage_path, key)?;
+    let path = storage_file_path(storage_path, key);
+
+    if path.exists() {
+        let total = get_refcount(storage_path, key)?.checked_add(refcount)
+            .ok_or_else(|| Error::from((ErrorKind::RefcountOverflow,
+                                        &*format!("Too many references to {}", key))))?;
+        set_refcount(storage_path, &Config::default(), key, total)?;
+        super::protect::make_writable(&content)?;
+        fs::remove_file(&content)
+            .map_err(|e| { (e, "Unable to remove quarantined file") } )?;
+    } else {
+        fs::create_dir_all(storage_file_dir(storage_path, key))
+            .map_err(|e| { (e, "Unable to create storage directory") } )?;
+        // The refcount first, so that the object is never there unreferenced
+        if refcount > 0 {
+            let to = storage_refcount_path(storage_path, key);
+            let change = usage::change(storage_path, Part::Refcounts, &to);
+            fs::rename(refcount_path(storage_path, key), &to)
+                .map_err(|e| { (e, "Unable to restore refcount file") } )?;
+            change.done();
+        }
+        fs::rename(&content, &path)
+            .map_err(|e| { (e, "Unable to restore quarantined file") } )?;
+        usage::add(storage_path, &path, None)?;
+    }
+    match fs::remove_file(refcount_path(storage_path, key)) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(From::from((e, "Unable to remove quarantined refcount file"))),
+    }
+    index::update(storage_path, &Config::default(), key)?;
+    Ok(true)
+}
+
+// The refcount kept with a quarantined object
+fn read_refcount(storage_path: &Path, key: &FileKey) -> Result<u64, Error> {
+    match fs::read(refcount_path(storage_path, key)) {
+        Ok(record) => super::refcount::decode(&record)
+            .ok_or_else(|| From::from((ErrorKind::CorruptRefcount,
+                                       &*format!("Quarantined refcount file for {} is damaged",
+                                                 key)))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(From::from((e, "Unable to read quarantined refcount file"))),
+    }
+}
+
+/// Move the stored object `key`, with its refcount, into quarantine.
+/// Returns `false`, leaving it in place, if it is not stored or is pinned
+/// (whose holders rely on its path).
+pub(crate) fn quarantine(storage_path: &Path, key: &FileKey) -> Result<bool, Error> {
+    let _writing = writelock::begin(storage_path)?;
+    let _lock = keylock::lock(storage_path, key)?;
+    let path = storage_file_path(storage_path, key);
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(From::from((e, "Unable to stat stored file"))),
+    };
+    if pin::is_pinned(storage_path, key)? {
+        log::warn!("Not quarantining {}, which is pinned", key);
+        return Ok(false);
+    }
+    fs::create_dir_all(quarantine_dir(storage_path))
+        .map_err(|e| { (e, "Unable to create quarantine directory") } )?;
+    // The content last, as an object that is still in place can be
+    // quarantined again
+    let from = storage_refcount_path(storage_path, key);
+    let change = usage::change(storage_path, Part::Refcounts, &from);
+    match fs::rename(&from, refcount_path(storage_path, key)) {
+        Ok(()) => change.done(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(From::from((e, "Unable to quarantine refcount file"))),
+    }
+    fs::rename(&path, content_path(storage_path, key))
+        .map_err(|e| { (e, "Unable to quarantine stored file") } )?;
+    usage::remove(storage_path, &metadata);
+    index::update(storage_path, &Config::default(), key)?;
+    log::warn!("Quarantined {}, whose content doesn't match its key", key);
+    Ok(true)
+}
+
+/// Whether `key` is in quarantine
+pub(crate) fn is_quarantined(storage_path: &Path, key: &FileKey) -> bool {
+    quarantined_file(storage_path, key).is_some()
+}
+
+/// Fail with `ErrorKind::Quarantined` if `key`, found missing, is in
+/// quarantine
+pub(crate) fn check(storage_path: &Path, key: &FileKey) -> Result<(), Error> {
+    if is_quarantined(storage_path, key) {
+        return Err(From::from((ErrorKind::Quarantined,
+                               &*format!("{} was found corrupt and quarantined", key))));
+    }
+    Ok(())
+}
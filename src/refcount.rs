@@ -0,0 +1,49 @@
+// The on-disk form of a refcount: a magic number, the count, and a checksum
+// of both, so that a truncated or damaged refcount file is detected rather
+// than read as some other count.  Files from older versions of the crate,
+// holding a 4 byte count, or a checksummed 4 byte count, are still read.
+
+use byteorder::{BigEndian,ByteOrder};
+use crypto::digest::Digest;
+use crypto::sha2::Sha224;
+
+const MAGIC: &[u8; 4] = b"FSRC";
+const LEN: usize = 16;
+const LEN_U32: usize = 12;
+
+fn checksum(body: &[u8]) -> [u8; 4] {
+    let mut hash = Sha224::new();
+    hash.input(body);
+    let mut digest = [0_u8; 28];
+    hash.result(&mut digest);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+fn is_valid(record: &[u8]) -> bool {
+    let (body, sum) = record.split_at(record.len() - 4);
+    &body[..4] == MAGIC && sum == checksum(body)
+}
+
+pub fn encode(refcount: u64) -> Vec<u8> {
+    let mut record: Vec<u8> = Vec::with_capacity(LEN);
+    record.extend_from_slice(MAGIC);
+    let mut count = [0_u8; 8];
+    BigEndian::write_u64(&mut count, refcount);
+    record.extend_from_slice(&count);
+    let sum = checksum(&record);
+    record.extend_from_slice(&sum);
+    record
+}
+
+/// The count in a refcount record, or `None` if it is damaged
+pub fn decode(record: &[u8]) -> Option<u64> {
+    match record.len() {
+        // Written before refcount files were checksummed, unless it is all
+        // that is left of a checksummed record
+        4 if record != MAGIC => Some(BigEndian::read_u32(record) as u64),
+        // Written before refcounts were 64 bit
+        LEN_U32 if is_valid(record) => Some(BigEndian::read_u32(&record[4..8]) as u64),
+        LEN if is_valid(record) => Some(BigEndian::read_u64(&record[4..12])),
+        _ => None,
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Named references to stored objects, in the manner of git refs, giving
+//! human-friendly handles such as "latest-backup" for content-addressed
+//! objects.
+//!
+//! Refs are kept under `refs/` in the storage path, one file per ref.  Each
+//! ref holds a reference on its target, so the target is kept until the ref
+//! is moved or deleted.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self,Write};
+use std::path::{Path,PathBuf};
+
+use super::{Config,Error,FileKey};
+use super::spool::Spool;
+use super::writelock;
+
+fn refs_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("refs")
+}
+
+fn ref_path(storage_path: &Path, name: &str) -> Result<PathBuf, Error> {
+    if !super::is_valid_name(name) {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "malformed ref name"),
+                               "Invalid ref name")));
+    }
+    Ok(refs_dir(storage_path).join(name))
+}
+
+/// Point the ref `name` at `key`, which must be stored, creating the ref or
+/// moving it from its previous target.  Returns the previous target.
+///
+/// Names may use ASCII letters, digits, `-`, `_` and `.`.
+pub fn set_ref(storage_path: &Path, name: &str, key: &FileKey)
+               -> Result<Option<FileKey>, Error>
+{
+    let path = ref_path(storage_path, name)?;
+    let _writing = writelock::begin(storage_path)?;
+    if !key.is_well_formed() {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "malformed key"),
+                               "Invalid key")));
+    }
+    let config = Config::default();
+    let previous = get_ref(storage_path, name)?;
+    if previous.as_ref() == Some(key) {
+        return Ok(previous);
+    }
+
+    // The new target is referenced before the ref is moved, and the old one
+    // released after, so an interruption can leak a reference but never
+    // leave the ref dangling
+    super::add_reference(storage_path, &config, key)?;
+    if let Err(e) = write_ref(storage_path, &path, key) {
+        if let Err(e) = super::release(storage_path, &config, key) {
+            log::warn!("Unable to release {} after failed ref update: {:?}", key, e);
+        }
+        return Err(e);
+    }
+    if let Some(ref previous) = previous {
+        super::release(storage_path, &config, previous)?;
+    }
+    Ok(previous)
+}
+
+// Replace the ref file at `path` in one step
+fn write_ref(storage_path: &Path, path: &Path, key: &FileKey) -> Result<(), Error> {
+    if let Err(e) = fs::create_dir(refs_dir(storage_path)) {
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(From::from((e, "Unable to create refs directory")));
+        }
+    }
+    let mut spool = Spool::new(storage_path, false)?;
+    writeln!(spool, "{}", key)
+        .map_err(|e| { (e, "Unable to write ref") } )?;
+    spool.persist(path)
+}
+
+// Point the ref `name` at `key` without moving any reference, for
+// `rehash_store()`, which moves the references along with the object
+pub(crate) fn retarget(storage_path: &Path, name: &str, key: &FileKey) -> Result<(), Error> {
+    write_ref(storage_path, &ref_path(storage_path, name)?, key)
+}
+
+// Remove the ref `name` without releasing its reference, for
+// `restore_snapshot()`, which restores the references along with the refs
+pub(crate) fn forget(storage_path: &Path, name: &str) -> Result<(), Error> {
+    match fs::remove_file(ref_path(storage_path, name)?) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(From::from((e, "Unable to remove ref"))),
+    }
+}
+
+/// The key the ref `name` points at, or `None` if there is no such ref
+pub fn get_ref(storage_path: &Path, name: &str) -> Result<Option<FileKey>, Error>
+{
+    read_ref(&ref_path(storage_path, name)?)
+}
+
+fn read_ref(path: &Path) -> Result<Option<FileKey>, Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(From::from((e, "Unable to read ref"))),
+    };
+    let key = FileKey(text.trim_end().to_owned());
+    if !key.is_well_formed() {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidData, "malformed ref"),
+                               "Ref file is damaged")));
+    }
+    Ok(Some(key))
+}
+
+/// Delete the ref `name`, releasing its reference on its target.  Returns
+/// the target, or `None` if there was no such ref.
+pub fn delete_ref(storage_path: &Path, name: &str) -> Result<Option<FileKey>, Error>
+{
+    let path = ref_path(storage_path, name)?;
+    let _writing = writelock::begin(storage_path)?;
+    let key = match read_ref(&path)? {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    fs::remove_file(&path)
+        .map_err(|e| { (e, "Unable to remove ref") } )?;
+    super::release(storage_path, &Config::default(), &key)?;
+    Ok(Some(key))
+}
+
+/// Every ref in the store, with its target
+pub fn list_refs(storage_path: &Path) -> Result<BTreeMap<String, FileKey>, Error>
+{
+    let mut refs: BTreeMap<String, FileKey> = BTreeMap::new();
+    let entries = match fs::read_dir(refs_dir(storage_path)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(refs),
+        Err(e) => return Err(From::from((e, "Unable to read refs directory"))),
+    };
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read refs directory") } )?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if !super::is_valid_name(&name) {
+            continue;
+        }
+        if let Some(key) = read_ref(&entry.path())? {
+            refs.insert(name, key);
+        }
+    }
+    Ok(refs)
+}
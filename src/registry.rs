@@ -0,0 +1,212 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Reference counts kept in a Postgres table (requires the `postgres`
+//! feature), changed inside the application's own transactions.
+//!
+//! Where the rows that hold keys live in Postgres, a `Registry` counts
+//! their references in a table beside them (`schema()` gives its
+//! definition), through the same transaction that adds or removes the rows.
+//! A rollback then takes the reference changes with it, and any number of
+//! nodes can share the store without their counts drifting from the rows.
+//!
+//! The store holds one reference on each object with a row, owned by
+//! `postgres:<table>` (see the `owners` module), so that `gc()` and the
+//! store's other users leave it alone.  Content is stored, and that
+//! reference taken, as soon as `store_data()` is called, whether or not the
+//! transaction goes on to commit; `collect()` releases the store's
+//! reference on objects whose count is zero or never committed, removing
+//! them unless something else holds them.
+//!
+//! Every change locks the object's row before touching the store, and
+//! `collect()` locks it before releasing anything, so a collection waits
+//! for a transaction referencing the same object to commit or roll back,
+//! and a store waits for a collection of its object to finish.
+
+use std::io;
+
+use postgres::GenericClient;
+
+use super::{Error,FileKey,Hashable,manifest};
+use super::store::FileStore;
+
+/// The table name a registry uses unless given another
+pub const DEFAULT_TABLE: &str = "filestore_refs";
+
+/// Reference counts for the objects in a store, kept in a Postgres table
+#[derive(Debug, Clone)]
+pub struct Registry {
+    store: FileStore,
+    table: String,
+    owner: String,
+}
+
+fn postgres_error(e: postgres::Error) -> Error {
+    From::from((io::Error::other(e), "Postgres query failed"))
+}
+
+// Whether `table` can go into a query as it is
+fn is_valid_table(table: &str) -> bool {
+    !table.is_empty() && table.len() <= 63
+        && !table.starts_with(|c: char| c.is_ascii_digit())
+        && table.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+impl Registry {
+    /// Count references on the objects in `store` in `DEFAULT_TABLE`
+    pub fn new(store: FileStore) -> Registry {
+        Registry::with_table(store, DEFAULT_TABLE)
+            .expect("DEFAULT_TABLE is a valid table name")
+    }
+
+    /// Count references on the objects in `store` in `table`, which may use
+    /// lowercase ASCII letters, digits and `_`
+    pub fn with_table(store: FileStore, table: &str) -> Result<Registry, Error> {
+        if !is_valid_table(table) {
+            return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput,
+                                                  "malformed table name"),
+                                   "Invalid registry table name")));
+        }
+        Ok(Registry {
+            store,
+            table: table.to_owned(),
+            owner: format!("postgres:{}", table),
+        })
+    }
+
+    /// The store the registry counts references for
+    pub fn store(&self) -> &FileStore {
+        &self.store
+    }
+
+    /// The definition of the registry's table
+    pub fn schema(&self) -> String {
+        format!("CREATE TABLE IF NOT EXISTS {} (\n\
+                 \x20   key TEXT PRIMARY KEY,\n\
+                 \x20   refcount BIGINT NOT NULL CHECK (refcount >= 0)\n\
+                 )", self.table)
+    }
+
+    /// Create the registry's table, if it isn't there already
+    pub fn create_schema<C: GenericClient>(&self, client: &mut C) -> Result<(), Error> {
+        client.batch_execute(&self.schema())
+            .map_err(postgres_error)
+    }
+
+    /// Store data, counting its reference through `client`, normally the
+    /// transaction that records the key.  The content stays in the store
+    /// if the transaction rolls back, until `collect()` is next run.
+    pub fn store_data<C: GenericClient>(&self, client: &mut C, input: &[u8])
+                                        -> Result<FileKey, Error>
+    {
+        self.store.check_writable()?;
+        self.store.config().check_object_size(input.len() as u64)?;
+        let key = FileKey(input.hash_with(manifest::algorithm(self.store.path())?)?);
+        self.increment(client, &key)?;
+        self.store.store_owned(input, &self.owner)?;
+        Ok(key)
+    }
+
+    /// Take another reference on `key`, which must be stored, through
+    /// `client`
+    pub fn add_reference<C: GenericClient>(&self, client: &mut C, key: &FileKey)
+                                           -> Result<(), Error>
+    {
+        self.store.check_writable()?;
+        self.increment(client, key)?;
+        self.store.add_owned_reference(key, &self.owner)?;
+        Ok(())
+    }
+
+    // Count another reference on `key`, locking its row until `client`'s
+    // transaction ends
+    fn increment<C: GenericClient>(&self, client: &mut C, key: &FileKey) -> Result<(), Error> {
+        let query = format!("INSERT INTO {0} (key, refcount) VALUES ($1, 1) \
+                             ON CONFLICT (key) DO UPDATE SET refcount = {0}.refcount + 1",
+                            self.table);
+        client.execute(&*query, &[&key.0])
+            .map_err(postgres_error)?;
+        Ok(())
+    }
+
+    /// Drop a reference on `key` through `client`.  Returns `false` if it
+    /// had none.  The object itself goes at the next `collect()` after the
+    /// last reference is dropped.
+    pub fn release<C: GenericClient>(&self, client: &mut C, key: &FileKey)
+                                     -> Result<bool, Error>
+    {
+        let query = format!("UPDATE {} SET refcount = refcount - 1 \
+                             WHERE key = $1 AND refcount > 0 RETURNING refcount",
+                            self.table);
+        let row = client.query_opt(&*query, &[&key.0])
+            .map_err(postgres_error)?;
+        let refcount: i64 = match row {
+            Some(row) => row.get(0),
+            None => return Ok(false),
+        };
+        if refcount == 0 {
+            let query = format!("DELETE FROM {} WHERE key = $1 AND refcount = 0", self.table);
+            client.execute(&*query, &[&key.0])
+                .map_err(postgres_error)?;
+        }
+        Ok(true)
+    }
+
+    /// The references counted on `key`, as `client` sees them
+    pub fn refcount<C: GenericClient>(&self, client: &mut C, key: &FileKey)
+                                      -> Result<u64, Error>
+    {
+        let query = format!("SELECT refcount FROM {} WHERE key = $1", self.table);
+        let row = client.query_opt(&*query, &[&key.0])
+            .map_err(postgres_error)?;
+        Ok(row.map(|row| row.get::<_, i64>(0) as u64).unwrap_or(0))
+    }
+
+    /// Release the store's reference on each object held for the registry
+    /// that has no references counted, each in a transaction of its own on
+    /// `client`, returning their keys.  Run it from time to time, as `gc()`
+    /// is run, to remove what releases and rolled back stores leave.
+    pub fn collect<C: GenericClient>(&self, client: &mut C) -> Result<Vec<FileKey>, Error> {
+        self.store.check_writable()?;
+        let mut released: Vec<FileKey> = Vec::new();
+        for key in super::keys(self.store.path())? {
+            let key = key?;
+            if !self.store.list_owners(&key)?.contains(&self.owner) {
+                continue;
+            }
+            if self.collect_one(client, &key)? {
+                released.push(key);
+            }
+        }
+        Ok(released)
+    }
+
+    // Release the store's reference on `key` if it has no references
+    // counted, holding its row locked meanwhile
+    fn collect_one<C: GenericClient>(&self, client: &mut C, key: &FileKey)
+                                     -> Result<bool, Error>
+    {
+        let mut transaction = client.transaction()
+            .map_err(postgres_error)?;
+        // A row to lock, waiting on any transaction inserting one
+        let query = format!("INSERT INTO {} (key, refcount) VALUES ($1, 0) \
+                             ON CONFLICT (key) DO NOTHING", self.table);
+        transaction.execute(&*query, &[&key.0])
+            .map_err(postgres_error)?;
+        let query = format!("SELECT refcount FROM {} WHERE key = $1 FOR UPDATE", self.table);
+        let refcount: i64 = transaction.query_one(&*query, &[&key.0])
+            .map_err(postgres_error)?
+            .get(0);
+        if refcount > 0 {
+            // Nothing was changed
+            return Ok(false);
+        }
+        self.store.delete_owned(key, &self.owner)?;
+        let query = format!("DELETE FROM {} WHERE key = $1", self.table);
+        transaction.execute(&*query, &[&key.0])
+            .map_err(postgres_error)?;
+        transaction.commit()
+            .map_err(postgres_error)?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,193 @@
+// Moving a store from one digest to another (`rehash_store()`), for when
+// the digest content is keyed by has to change.  Every object is hashed
+// again and moved to its new key, taking its refcount, refs, tags,
+// namespace references, access record, content type, authenticity tag and
+// derived artifacts with it.  Tags cover the key, so they are made again
+// for the new one, which needs the secret: without it they are dropped.
+//
+// Each object's new copy is a hard link to the old (a copy where links
+// aren't supported), and is in place, along with everything that names it,
+// before the old one is removed, so the migration can be interrupted and
+// run again: objects already moved have keys of the new digest and are
+// passed over.  Each move is also appended to `key-map` in the storage
+// path (see keymap.rs), so that keys handed out before the migration can
+// still be translated afterwards.
+
+use std::collections::{BTreeMap,HashMap};
+use std::fs;
+use std::io::{self,Write};
+use std::path::Path;
+
+use super::{Config,Error,FileKey,Keys,access,authtag,bloom,contenttype,derived,index,keymap,
+            manifest,merkle,namespace,owners,pin,protect,refs,replace,spool,tags,usage,weak,
+            writelock};
+use super::authtag::AuthSecret;
+use super::{get_refcount,set_refcount,storage_file_dir,storage_file_path,storage_refcount_path};
+use super::hashable::{HashAlgorithm,Hashable};
+use super::manifest::Manifest;
+use super::usage::Part;
+
+/// Move every object in the store at `storage_path` to its key under
+/// `algorithm`, and record `algorithm` in the manifest so that content
+/// stored from then on is keyed by it.  Returns the new key of each object
+/// moved, by its old key.
+///
+/// The store is taken exclusively for the migration (see
+/// `FileStore::open_exclusive()`), failing with `ErrorKind::Locked` if
+/// another process is writing to it; if this process already holds it
+/// exclusively, nothing else in the process may use the store meanwhile.
+/// Fails if an object is pinned by a running process, as its pin would be
+/// left on the old key.
+///
+/// Authenticity tags (see `Config::authenticate`) are dropped, as moving
+/// them needs the secret: use `FileStore::rehash_store()` on a handle that
+/// has it to keep them.
+pub fn rehash_store(storage_path: &Path, algorithm: HashAlgorithm)
+                    -> Result<BTreeMap<FileKey, FileKey>, Error>
+{
+    migrate(storage_path, algorithm, None)
+}
+
+// As `rehash_store()`, moving authenticity tags if given their secret
+pub(crate) fn migrate(storage_path: &Path, algorithm: HashAlgorithm,
+                      secret: Option<&AuthSecret>)
+                      -> Result<BTreeMap<FileKey, FileKey>, Error>
+{
+    let op = operation!("filestore.rehash_store", objects);
+    let result = move_all(storage_path, algorithm, secret);
+    if let Ok(ref moved) = result {
+        op.record("objects", moved.len() as u64);
+    }
+    op.finish(result)
+}
+
+fn move_all(storage_path: &Path, algorithm: HashAlgorithm, secret: Option<&AuthSecret>)
+            -> Result<BTreeMap<FileKey, FileKey>, Error>
+{
+    let _exclusive = writelock::exclusive_or_held(storage_path)?;
+    super::recover(storage_path)?;
+
+    let mut keys: Vec<FileKey> = Vec::new();
+    for key in Keys::new(storage_path)? {
+        let key = key?;
+        if key.algorithm() == Some(algorithm) {
+            continue;
+        }
+        if pin::is_held(storage_path, &key)? {
+            return Err(From::from((io::Error::from(io::ErrorKind::ResourceBusy),
+                                   &*format!("Unable to move {} while it is pinned", key))));
+        }
+        keys.push(key);
+    }
+
+    // Content stored from here on is keyed by the new digest, so that an
+    // interrupted migration leaves nothing new to move
+    let mut manifest = match manifest::read(storage_path) {
+        Ok(manifest) => manifest,
+        Err(ref e) if e.kind == super::error::ErrorKind::NotAStore => Manifest::default(),
+        Err(e) => return Err(e),
+    };
+    if manifest.hash != algorithm {
+        manifest.hash = algorithm;
+        manifest::write(storage_path, &manifest)?;
+    }
+
+    access::flush(storage_path)?;
+    let mut refs_by_key: HashMap<FileKey, Vec<String>> = HashMap::new();
+    for (name, key) in refs::list_refs(storage_path)? {
+        refs_by_key.entry(key).or_default().push(name);
+    }
+    let mut derived_by_key: HashMap<FileKey, Vec<(FileKey, String)>> = HashMap::new();
+    for (source, transform, key) in derived::list_all(storage_path)? {
+        derived_by_key.entry(key).or_default().push((source, transform));
+    }
+    let mut key_map = keymap::open_for_append(storage_path)?;
+
+    let mut moved: BTreeMap<FileKey, FileKey> = BTreeMap::new();
+    for old in keys {
+        let new = FileKey(storage_file_path(storage_path, &old).hash_with(algorithm)?);
+        move_object(storage_path, &old, &new)?;
+        for name in refs_by_key.get(&old).into_iter().flatten() {
+            refs::retarget(storage_path, name, &new)?;
+        }
+        tags::rename_key(storage_path, &old, &new)?;
+        namespace::rename_key(storage_path, &old, &new)?;
+        access::rename_key(storage_path, &old, &new)?;
+        contenttype::rename_key(storage_path, &old, &new)?;
+        pin::rename_key(storage_path, &old, &new)?;
+        weak::rename_key(storage_path, &old, &new)?;
+        owners::rename_key(storage_path, &old, &new)?;
+        merkle::forget(storage_path, &old)?;
+        authtag::rename_key(storage_path, secret, &old, &new)?;
+        derived::rename_source(storage_path, &old, &new)?;
+        for (source, transform) in derived_by_key.get(&old).into_iter().flatten() {
+            // Where the source's entries are now
+            let source = match source == &old {
+                true => &new,
+                false => moved.get(source).unwrap_or(source),
+            };
+            derived::retarget(storage_path, source, transform, &new)?;
+        }
+        writeln!(key_map, "{} {}", old, new)
+            .map_err(|e| { (e, "Unable to write key map") } )?;
+        remove_old(storage_path, &old, &new)?;
+        moved.insert(old, new);
+    }
+    Ok(moved)
+}
+
+// Place the object `old` at `new` as well, with the same refcount
+fn move_object(storage_path: &Path, old: &FileKey, new: &FileKey) -> Result<(), Error> {
+    let from = storage_file_path(storage_path, old);
+    let to = storage_file_path(storage_path, new);
+    bloom::add(storage_path, new)?;
+    fs::create_dir_all(storage_file_dir(storage_path, new))
+        .map_err(|e| { (e, "Unable to create storage directory") } )?;
+    match fs::hard_link(&from, &to) {
+        Ok(()) => {},
+        // Left by an interrupted migration
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+        Err(_) => {
+            let temp_path = spool::temp_path(storage_path);
+            let copied = fs::copy(&from, &temp_path)
+                .map_err(|e| { Error::from((e, "Unable to copy stored file")) } )
+                .and_then(|_| replace::replace(&temp_path, &to)
+                          .map_err(|e| { From::from((e, "Unable to move stored file")) } ));
+            if copied.is_err() {
+                let _ = fs::remove_file(&temp_path);
+            }
+            copied?;
+        },
+    }
+    let refcount = get_refcount(storage_path, old)?;
+    if refcount > 0 {
+        set_refcount(storage_path, &Config::default(), new, refcount)?;
+    }
+    index::update(storage_path, &Config::default(), new)
+}
+
+// Remove the object `old` and its refcount, once moved to `new`
+fn remove_old(storage_path: &Path, old: &FileKey, new: &FileKey) -> Result<(), Error> {
+    let path = storage_file_path(storage_path, old);
+    let read_only = fs::metadata(&path)
+        .map_err(|e| { (e, "Unable to stat stored file") } )?
+        .permissions().readonly();
+    // A hard link shares its permissions with the new key's object, which
+    // are put back once the old name is gone
+    if read_only {
+        protect::make_writable(&path)?;
+    }
+    fs::remove_file(&path)
+        .map_err(|e| { (e, "Unable to remove file") } )?;
+    if read_only {
+        protect::make_read_only(&storage_file_path(storage_path, new))?;
+    }
+    let refcount_path = storage_refcount_path(storage_path, old);
+    let change = usage::change(storage_path, Part::Refcounts, &refcount_path);
+    match fs::remove_file(&refcount_path) {
+        Ok(()) => change.done(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(From::from((e, "Unable to remove refcount file"))),
+    }
+    index::update(storage_path, &Config::default(), old)
+}
@@ -0,0 +1,109 @@
+// Healing a store from a replica (`repair_from()`): objects found corrupt
+// and quarantined, and objects that are referenced but missing, are
+// fetched from another store holding the same content, checked against
+// their keys, and put back in place.
+//
+// Fetched content is spooled and hashed before anything is placed, so a
+// replica that is itself damaged can't make things worse.  References are
+// left as they were, but for those of a quarantined object, which are
+// taken back from quarantine once it is healed, and its damaged copy
+// discarded.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::{Config,Error,FileKey,keylock,place_if_absent,quarantine,storage_file_dir,
+            storage_file_path,writelock};
+use super::maintenance::orphaned_refcounts;
+use super::spool::Spool;
+use super::store::Store;
+
+/// What a call to `repair_from()` found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Objects fetched from the other store and put back in place
+    pub healed: Vec<FileKey>,
+    /// Objects still quarantined or missing, as the other store doesn't
+    /// hold them, or its copy doesn't match its key either
+    pub broken: Vec<FileKey>,
+}
+
+/// Fetch each quarantined object, and each object missing from the store
+/// at `storage_path` while its refcount file remains, from `other` (such
+/// as a replica, or a remote client), and put it back if its content
+/// matches its key.
+///
+/// Run this before `gc()`, which removes the refcount files of missing
+/// objects.
+pub fn repair_from<S: Store + ?Sized>(storage_path: &Path, other: &S)
+                                      -> Result<RepairReport, Error>
+{
+    repair(storage_path, &Config::default(), other)
+}
+
+pub(crate) fn repair<S: Store + ?Sized>(storage_path: &Path, config: &Config, other: &S)
+                                        -> Result<RepairReport, Error>
+{
+    let op = operation!("filestore.repair_from", objects);
+    let result = heal_all(storage_path, config, other);
+    if let Ok(ref report) = result {
+        op.record("objects", report.healed.len() as u64);
+    }
+    op.finish(result)
+}
+
+fn heal_all<S: Store + ?Sized>(storage_path: &Path, config: &Config, other: &S)
+                               -> Result<RepairReport, Error>
+{
+    let mut wanted = quarantine::list_quarantined(storage_path)?;
+    wanted.extend(orphaned_refcounts(storage_path)?);
+    wanted.sort();
+    wanted.dedup();
+
+    let mut report = RepairReport::default();
+    for key in wanted {
+        match heal(storage_path, config, other, &key)? {
+            true => report.healed.push(key),
+            false => report.broken.push(key),
+        }
+    }
+    Ok(report)
+}
+
+// Fetch `key` from `other` and put it in place.  Returns `false` if it
+// can't be had, failing only on trouble with this store.
+fn heal<S: Store + ?Sized>(storage_path: &Path, config: &Config, other: &S, key: &FileKey)
+                           -> Result<bool, Error>
+{
+    let mut reader = match other.retrieve_reader(key) {
+        Ok(Some(reader)) => reader,
+        Ok(None) => return Ok(false),
+        Err(e) => {
+            log::warn!("Unable to fetch {} for repair: {:?}", key, e);
+            return Ok(false);
+        },
+    };
+    let algorithm = key.algorithm().unwrap_or_default();
+    let mut spool = Spool::with_algorithm(storage_path, false, algorithm)?;
+    if let Err(e) = io::copy(&mut reader, &mut spool) {
+        log::warn!("Unable to fetch {} for repair: {:?}", key, e);
+        return Ok(false);
+    }
+    if spool.hash() != key.0 {
+        log::warn!("Copy of {} to repair from doesn't match its key", key);
+        return Ok(false);
+    }
+
+    {
+        let _writing = writelock::begin(storage_path)?;
+        fs::create_dir_all(storage_file_dir(storage_path, key))
+            .map_err(|e| { (e, "Unable to create storage directory") } )?;
+        let _lock = keylock::lock(storage_path, key)?;
+        // Stored again since, perhaps, in which case the spool is dropped
+        place_if_absent(storage_path, config, key, &storage_file_path(storage_path, key),
+                        |dest_path| spool.persist(dest_path))?;
+    }
+    quarantine::restore_from_quarantine(storage_path, key)?;
+    Ok(true)
+}
@@ -0,0 +1,40 @@
+// Replacing one file with another atomically, for refcount and ref updates
+// and for moving spooled content into place.
+//
+// On Unix this is rename(2).  On Windows std's rename is MoveFileExW with
+// MOVEFILE_REPLACE_EXISTING, which is atomic within an NTFS volume but fails
+// with a sharing violation (or access denied) while another process has the
+// destination open without FILE_SHARE_DELETE.  Virus scanners, indexers and
+// backup agents do that briefly, so there it is retried for a short while.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Move `from` to `to`, replacing anything already at `to`
+#[cfg(not(windows))]
+pub(crate) fn replace(from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to)
+}
+
+#[cfg(windows)]
+pub(crate) fn replace(from: &Path, to: &Path) -> io::Result<()> {
+    use std::thread;
+    use std::time::Duration;
+    use windows_sys::Win32::Foundation::ERROR_SHARING_VIOLATION;
+
+    const ATTEMPTS: u64 = 10;
+    let mut attempt: u64 = 1;
+    loop {
+        match fs::rename(from, to) {
+            Err(ref e) if attempt < ATTEMPTS
+                && (e.kind() == io::ErrorKind::PermissionDenied
+                    || e.raw_os_error() == Some(ERROR_SHARING_VIOLATION as i32)) =>
+            {
+                thread::sleep(Duration::from_millis(10 * attempt));
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
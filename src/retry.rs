@@ -0,0 +1,158 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Retrying operations that fail for passing reasons.
+//!
+//! Network filesystems and remote backends intermittently fail with errors
+//! that go away on their own (`EAGAIN`, `ESTALE`, timeouts, dropped
+//! connections; see `Error::is_transient()`).  `RetryStore` wraps any store
+//! so that its operations are tried again, after a growing delay, up to the
+//! `RetryPolicy`'s limit, and an error that outlasts the limit records how
+//! many attempts were made in `Error::attempts`.
+//!
+//! Input to `store_reader()` is spooled to a temporary file first, so that
+//! it can be read again for each attempt.  A store whose failed attempt had
+//! already taken its reference ends up holding an extra one, which costs
+//! space but never content; a delete is only retried if its failed attempt
+//! left the refcount as it was.
+
+use std::env;
+use std::fs::File;
+use std::io::{self,Read,Seek,SeekFrom};
+use std::thread;
+use std::time::Duration;
+
+use super::{Error,FileKey,HashAlgorithm};
+use super::spool::Spool;
+use super::store::{ObjectStat,Store};
+
+/// How often, and how patiently, to retry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in all, including the first (at least one is always made)
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each one after
+    pub initial_backoff: Duration,
+    /// Longest delay between attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub fn none() -> RetryPolicy {
+        RetryPolicy { max_attempts: 1, ..RetryPolicy::default() }
+    }
+
+    /// Call `operation` until it succeeds, fails with an error that isn't
+    /// transient, or has been tried `max_attempts` times
+    pub fn run<T, F>(&self, mut operation: F) -> Result<T, Error>
+        where F: FnMut() -> Result<T, Error>
+    {
+        let mut backoff = self.initial_backoff;
+        let mut attempt: u32 = 1;
+        loop {
+            match operation() {
+                Ok(value) => return Ok(value),
+                Err(e) if !e.is_transient() || attempt >= self.max_attempts => {
+                    return Err(Error { attempts: attempt, ..e });
+                },
+                Err(e) => {
+                    log::debug!("Retrying after attempt {}: {:?}", attempt, e);
+                    // Jittered, so that clients failing together don't
+                    // retry together
+                    let half = backoff / 2;
+                    thread::sleep(half + half.mul_f64(rand::random::<f64>()));
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    attempt += 1;
+                },
+            }
+        }
+    }
+}
+
+/// A store whose operations are retried according to a `RetryPolicy`
+#[derive(Debug, Clone)]
+pub struct RetryStore<S> {
+    inner: S,
+    policy: RetryPolicy,
+}
+
+impl<S: Store> RetryStore<S> {
+    pub fn new(inner: S, policy: RetryPolicy) -> RetryStore<S> {
+        RetryStore { inner, policy }
+    }
+
+    /// The store being retried
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The policy applied
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+}
+
+impl<S: Store> Store for RetryStore<S> {
+    fn store_data(&self, input: &[u8]) -> Result<FileKey, Error> {
+        self.policy.run(|| self.inner.store_data(input))
+    }
+
+    fn store_reader(&self, input: &mut dyn Read) -> Result<FileKey, Error> {
+        let mut spool = Spool::with_algorithm(&env::temp_dir(), false, HashAlgorithm::Sha224)?;
+        io::copy(input, &mut spool)
+            .map_err(|e| { (e, "Unable to spool input") } )?;
+        let mut file = File::open(spool.path())
+            .map_err(|e| { (e, "Unable to read spooled input") } )?;
+        self.policy.run(|| {
+            file.seek(SeekFrom::Start(0))
+                .map_err(|e| { (e, "Unable to rewind spooled input") } )?;
+            self.inner.store_reader(&mut file)
+        })
+    }
+
+    fn retrieve_data(&self, key: &FileKey) -> Result<Option<Vec<u8>>, Error> {
+        self.policy.run(|| self.inner.retrieve_data(key))
+    }
+
+    fn retrieve_data_into(&self, key: &FileKey, buf: &mut Vec<u8>) -> Result<usize, Error> {
+        self.policy.run(|| self.inner.retrieve_data_into(key, buf))
+    }
+
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>
+    {
+        self.policy.run(|| self.inner.retrieve_reader(key))
+    }
+
+    fn delete(&self, key: &FileKey) -> Result<(), Error> {
+        let before = self.policy.run(|| self.inner.stat(key))?.map(|stat| stat.refcount);
+        self.policy.run(|| {
+            self.inner.delete(key).or_else(|e| {
+                // The failed attempt may have dropped the reference anyway
+                match self.inner.stat(key) {
+                    Ok(ref stat) if stat.as_ref().map(|stat| stat.refcount) != before => Ok(()),
+                    _ => Err(e),
+                }
+            })
+        })
+    }
+
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error> {
+        self.policy.run(|| self.inner.stat(key))
+    }
+
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error> {
+        self.policy.run(|| self.inner.keys())
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Checking a store's integrity a little at a time, so that scrubbing a
+//! large store doesn't take its disks from everything else.
+//!
+//! A `Scrubber` re-hashes a bounded number of objects (and optionally
+//! bytes) each time `tick()` is called, quarantining any found corrupt as
+//! `verify()` does, and picks up where the last tick left off.  Objects are
+//! visited in key order, and the position is kept in `scrub` in the storage
+//! path, so that a pass carries on across restarts; when a pass is
+//! complete the next tick starts another.  Call `tick()` from a scheduler
+//! of your own (to scrub only off-peak, say), or `spawn()` a thread that
+//! ticks at an interval.
+//!
+//! Objects in stores keyed by `sha512-tree` have their chunk digests
+//! recorded as they are scrubbed, so that a later scrub that finds one
+//! corrupt can tell which parts of it are bad.
+
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Condvar,Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::{Error,FileKey,merkle,quarantine,verify_key};
+use super::hashable::HashAlgorithm;
+use super::merkle::Scrubbed;
+use super::spool::Spool;
+
+const DEFAULT_OBJECTS_PER_TICK: u64 = 100;
+
+/// What a call to `tick()` did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Number of objects re-hashed
+    pub objects: u64,
+    /// Bytes of content read
+    pub bytes: u64,
+    /// Objects whose content no longer matches their key, which have been
+    /// quarantined (unless pinned)
+    pub corrupt: Vec<FileKey>,
+    /// The bad byte ranges of the corrupt objects keyed by `sha512-tree`,
+    /// as `corrupt_regions()` gives them
+    pub corrupt_regions: Vec<(FileKey, Vec<Range<u64>>)>,
+    /// Whether this tick reached the end of the store, so that the next one
+    /// starts a new pass
+    pub pass_complete: bool,
+}
+
+/// Verifies a store a bounded amount at a time
+#[derive(Debug, Clone)]
+pub struct Scrubber {
+    storage_path: PathBuf,
+    objects_per_tick: u64,
+    bytes_per_tick: Option<u64>,
+}
+
+impl Scrubber {
+    /// A scrubber of the store at `storage_path`, re-hashing 100 objects a
+    /// tick
+    pub fn new(storage_path: &Path) -> Scrubber {
+        Scrubber {
+            storage_path: storage_path.to_path_buf(),
+            objects_per_tick: DEFAULT_OBJECTS_PER_TICK,
+            bytes_per_tick: None,
+        }
+    }
+
+    /// Re-hash at most `objects` objects a tick
+    pub fn with_objects_per_tick(mut self, objects: u64) -> Scrubber {
+        self.objects_per_tick = objects;
+        self
+    }
+
+    /// Stop a tick once it has read `bytes` bytes.  The object that passes
+    /// the limit is finished, so each tick makes progress.
+    pub fn with_bytes_per_tick(mut self, bytes: u64) -> Scrubber {
+        self.bytes_per_tick = Some(bytes);
+        self
+    }
+
+    /// Re-hash the next objects in turn, up to the limits
+    pub fn tick(&self) -> Result<ScrubReport, Error> {
+        let op = operation!("filestore.scrub", objects);
+        let result = self.scrub();
+        if let Ok(ref report) = result {
+            op.record("objects", report.objects);
+            op.record("bytes", report.bytes);
+        }
+        op.finish(result)
+    }
+
+    fn scrub(&self) -> Result<ScrubReport, Error> {
+        let storage_path = &self.storage_path;
+        let mut report = ScrubReport::default();
+        let cursor = read_cursor(storage_path)?;
+        let first_shard = match cursor {
+            Some(ref key) => u8::from_str_radix(&key[..2], 16).unwrap_or(0),
+            None => 0,
+        };
+        let mut last: Option<FileKey> = None;
+        for shard in first_shard..=255u8 {
+            for key in shard_keys(storage_path, shard)? {
+                if cursor.as_ref().is_some_and(|cursor| key <= *cursor) {
+                    continue;
+                }
+                if self.spent(&report) {
+                    write_cursor(storage_path, last.as_ref().or(cursor.as_ref()))?;
+                    return Ok(report);
+                }
+                let len = match fs::metadata(super::storage_file_path(storage_path, &key)) {
+                    Ok(metadata) => metadata.len(),
+                    // Deleted since the shard was listed
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(From::from((e, "Unable to stat stored file"))),
+                };
+                // Tree keyed objects have their chunks checked too
+                let checked = match key.algorithm() {
+                    Some(HashAlgorithm::Sha512Tree) => merkle::scrub(storage_path, &key),
+                    _ => verify_key(storage_path, &key).map(|intact| match intact {
+                        true => Scrubbed::Intact,
+                        false => Scrubbed::Corrupt(Vec::new()),
+                    }),
+                };
+                match checked {
+                    Ok(Scrubbed::Intact) => {},
+                    Ok(Scrubbed::Corrupt(regions)) => {
+                        quarantine::quarantine(storage_path, &key)?;
+                        report.corrupt.push(key.clone());
+                        if !regions.is_empty() {
+                            report.corrupt_regions.push((key.clone(), regions));
+                        }
+                    },
+                    Err(ref e) if e.io.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                }
+                report.objects += 1;
+                report.bytes += len;
+                last = Some(key);
+            }
+        }
+        write_cursor(storage_path, None)?;
+        report.pass_complete = true;
+        Ok(report)
+    }
+
+    // Whether the tick has done all it may
+    fn spent(&self, report: &ScrubReport) -> bool {
+        report.objects >= self.objects_per_tick
+            || self.bytes_per_tick.is_some_and(|bytes| report.bytes >= bytes)
+    }
+
+    /// Tick every `interval` on a thread of its own until the returned task
+    /// is stopped or dropped.  Errors are logged, and the next tick tries
+    /// again.
+    pub fn spawn(self, interval: Duration) -> Result<ScrubTask, Error> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+        let worker_shared = shared.clone();
+        let worker = thread::Builder::new()
+            .name("filestore-scrub".to_owned())
+            .spawn(move || worker_shared.run(&self, interval))
+            .map_err(|e| { (e, "Unable to start scrub worker") } )?;
+        Ok(ScrubTask { shared, worker: Some(worker) })
+    }
+}
+
+/// A scrubber ticking in the background, started by `Scrubber::spawn()`
+pub struct ScrubTask {
+    shared: Arc<Shared>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    corrupt: Vec<FileKey>,
+    passes: u64,
+    stopping: bool,
+}
+
+impl Shared {
+    fn run(&self, scrubber: &Scrubber, interval: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        while !state.stopping {
+            drop(state);
+            let result = scrubber.tick();
+            state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            match result {
+                Ok(report) => {
+                    state.corrupt.extend(report.corrupt);
+                    state.passes += u64::from(report.pass_complete);
+                },
+                Err(e) => log::warn!("Unable to scrub {}: {:?}",
+                                     scrubber.storage_path.display(), e),
+            }
+            if state.stopping {
+                break;
+            }
+            state = self.changed.wait_timeout(state, interval)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+}
+
+impl ScrubTask {
+    /// The objects found corrupt so far
+    pub fn corrupt(&self) -> Vec<FileKey> {
+        self.shared.state.lock().unwrap_or_else(|e| e.into_inner()).corrupt.clone()
+    }
+
+    /// The number of passes over the whole store completed so far
+    pub fn passes(&self) -> u64 {
+        self.shared.state.lock().unwrap_or_else(|e| e.into_inner()).passes
+    }
+
+    /// Stop ticking, waiting for a tick under way to finish
+    pub fn stop(self) {
+        // Done on drop
+    }
+}
+
+impl Drop for ScrubTask {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap_or_else(|e| e.into_inner()).stopping = true;
+        self.shared.changed.notify_all();
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                log::warn!("Scrub worker panicked");
+            }
+        }
+    }
+}
+
+fn cursor_path(storage_path: &Path) -> PathBuf {
+    storage_path.join("scrub")
+}
+
+// The last key verified by the pass under way, if any
+fn read_cursor(storage_path: &Path) -> Result<Option<FileKey>, Error> {
+    let text = match fs::read_to_string(cursor_path(storage_path)) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(From::from((e, "Unable to read scrub position"))),
+    };
+    let key = FileKey(text.trim_end().to_owned());
+    // A damaged position only costs a pass starting over
+    Ok(match key.is_well_formed() {
+        true => Some(key),
+        false => None,
+    })
+}
+
+fn write_cursor(storage_path: &Path, key: Option<&FileKey>) -> Result<(), Error> {
+    let key = match key {
+        Some(key) => key,
+        None => {
+            return match fs::remove_file(cursor_path(storage_path)) {
+                Ok(()) => Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(From::from((e, "Unable to remove scrub position"))),
+            };
+        },
+    };
+    let mut spool = Spool::new(storage_path, false)?;
+    io::Write::write_all(&mut spool, key.as_bytes())
+        .map_err(|e| { (e, "Unable to write scrub position") } )?;
+    spool.persist(&cursor_path(storage_path))
+}
+
+// The keys of the objects in one shard, in order
+fn shard_keys(storage_path: &Path, shard: u8) -> Result<Vec<FileKey>, Error> {
+    let prefix = format!("{:02x}", shard);
+    let mut keys: Vec<FileKey> = Vec::new();
+    let dir = match fs::read_dir(storage_path.join(&prefix)) {
+        Ok(dir) => dir,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(keys),
+        Err(e) => return Err(From::from((e, "Unable to read shard directory"))),
+    };
+    for entry in dir {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read shard directory") } )?;
+        if let Ok(name) = entry.file_name().into_string() {
+            let key = FileKey(format!("{}{}", prefix, name));
+            if key.is_well_formed() {
+                keys.push(key);
+            }
+        }
+    }
+    keys.sort();
+    Ok(keys)
+}
@@ -0,0 +1,74 @@
+// Sending stored content to a socket without copying it through this
+// process (`send_to()`), for servers built on plain `TcpStream`s.  On Linux
+// the kernel moves it from the page cache to the socket with sendfile(2);
+// elsewhere, or where the filesystem doesn't support that, it is copied
+// through a buffer as usual.  Objects never change once stored, so the
+// range asked for is clamped to the object's length once, up front.
+
+use std::fs::File;
+use std::io::{self,Read,Seek,SeekFrom};
+use std::net::TcpStream;
+use std::ops::Range;
+
+use super::Error;
+
+/// Send the bytes of `file` in `range` (or all of it) to `stream`,
+/// returning the number sent.  The range is clamped to the file's length.
+pub(crate) fn send_range(file: &File, stream: &TcpStream, range: Option<Range<u64>>)
+                         -> Result<u64, Error>
+{
+    let size = file.metadata()
+        .map_err(|e| { (e, "Unable to stat stored file") } )?
+        .len();
+    let range = match range {
+        Some(range) => range.start.min(size)..range.end.min(size),
+        None => 0..size,
+    };
+    let len = range.end.saturating_sub(range.start);
+    send(file, stream, range.start, len)
+        .map_err(|e| { From::from((e, "Unable to send stored file")) } )
+}
+
+#[cfg(target_os = "linux")]
+fn send(file: &File, stream: &TcpStream, start: u64, len: u64) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    // The most sendfile() moves in one call
+    const MAX_CHUNK: u64 = 0x7fff_f000;
+
+    let mut offset: libc::off_t = std::convert::TryFrom::try_from(start)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut sent: u64 = 0;
+    while sent < len {
+        let count = (len - sent).min(MAX_CHUNK) as usize;
+        let n = unsafe {
+            libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, count)
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                _ if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Not supported for this file, so copied instead
+                Some(libc::EINVAL) | Some(libc::ENOSYS) if sent == 0 => {
+                    return copy(file, stream, start, len);
+                },
+                _ => return Err(e),
+            }
+        }
+        if n == 0 {
+            break;
+        }
+        sent += n as u64;
+    }
+    Ok(sent)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(file: &File, stream: &TcpStream, start: u64, len: u64) -> io::Result<u64> {
+    copy(file, stream, start, len)
+}
+
+fn copy(mut file: &File, mut stream: &TcpStream, start: u64, len: u64) -> io::Result<u64> {
+    file.seek(SeekFrom::Start(start))?;
+    io::copy(&mut file.take(len), &mut stream)
+}
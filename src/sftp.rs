@@ -0,0 +1,423 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A store on a file server, reached over SFTP (requires the `sftp`
+//! feature).
+//!
+//! `SftpStore` implements `Store` on a store directory laid out exactly as a
+//! local one, so the server (or anything with the directory mounted) can
+//! also open it as a `FileStore`.  Content is uploaded to a temporary file
+//! beside its final name and renamed into place, and every refcount change
+//! is made while holding a lock file (`<object>.lock`, created exclusively),
+//! so any number of application hosts can share the store.
+//!
+//! Only SFTP clients take these locks: stores and deletes made through a
+//! `FileStore` on the same directory while clients are connected can lose
+//! refcount updates.  A lock left behind by a client that died is broken
+//! once it has gone unchanged for 30 seconds, as locks are only held for a
+//! few round trips.
+
+use std::env;
+use std::fs::File;
+use std::io::{self,Cursor,Read,Seek,SeekFrom,Write};
+use std::net::TcpStream;
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration,Instant,UNIX_EPOCH};
+
+use ssh2::{CheckResult,ErrorCode,KnownHostFileKind,OpenFlags,OpenType,Session,Sftp};
+
+use super::{Error,FileKey,HashAlgorithm,KeyHasher};
+use super::error::ErrorKind;
+use super::refcount;
+use super::spool::Spool;
+use super::store::{ObjectStat,Store};
+
+// How long a lock must go unchanged before it is taken to be abandoned
+const LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long to wait between attempts at a held lock
+const LOCK_RETRY: Duration = Duration::from_millis(20);
+
+/// A store in a directory on an SFTP server
+#[derive(Clone)]
+pub struct SftpStore {
+    sftp: Arc<Sftp>,
+    root: String,
+}
+
+impl SftpStore {
+    /// A store in the directory `root` on the server, over an already
+    /// authenticated `session`.  The directory must exist.
+    pub fn new(session: &Session, root: &str) -> Result<SftpStore, Error> {
+        let sftp = session.sftp()
+            .map_err(|e| { (io::Error::from(e), "Unable to start SFTP") } )?;
+        let root = root.trim_end_matches('/');
+        let stat = sftp.stat(Path::new(root))
+            .map_err(|e| { (io::Error::from(e), "Unable to open storage directory") } )?;
+        if !stat.is_dir() {
+            return Err(From::from((io::Error::from(io::ErrorKind::NotADirectory),
+                                   "Storage path is not a directory")));
+        }
+        Ok(SftpStore { sftp: Arc::new(sftp), root: root.to_owned() })
+    }
+
+    /// Connect to `host` as `user`, checking the server's key against
+    /// `~/.ssh/known_hosts` and authenticating with the SSH agent, and open
+    /// the store in the directory `root` there
+    pub fn connect(host: &str, port: u16, user: &str, root: &str) -> Result<SftpStore, Error> {
+        let tcp = TcpStream::connect((host, port))
+            .map_err(|e| { (e, "Unable to connect to SFTP server") } )?;
+        let mut session = Session::new()
+            .map_err(|e| { (io::Error::from(e), "Unable to start SSH session") } )?;
+        session.set_tcp_stream(tcp);
+        session.handshake()
+            .map_err(|e| { (io::Error::from(e), "SSH handshake failed") } )?;
+        check_host_key(&session, host, port)?;
+        session.userauth_agent(user)
+            .map_err(|e| { (io::Error::from(e), "SSH authentication failed") } )?;
+        SftpStore::new(&session, root)
+    }
+
+    fn shard_path(&self, key: &FileKey) -> String {
+        format!("{}/{}", self.root, &key[..2])
+    }
+
+    fn object_path(&self, key: &FileKey) -> String {
+        format!("{}/{}/{}", self.root, &key[..2], &key[2..])
+    }
+
+    fn refcount_path(&self, key: &FileKey) -> String {
+        self.object_path(key) + ".refcount"
+    }
+
+    // Whether there is a file at `path`
+    fn exists(&self, path: &str) -> Result<bool, Error> {
+        match self.sftp.stat(Path::new(path)) {
+            Ok(_) => Ok(true),
+            Err(ref e) if is_missing(e) => Ok(false),
+            Err(e) => Err(From::from((io::Error::from(e), "Unable to stat remote file"))),
+        }
+    }
+
+    fn make_shard(&self, key: &FileKey) -> Result<(), Error> {
+        let shard = self.shard_path(key);
+        // Servers don't agree on how to say it already exists
+        if let Err(e) = self.sftp.mkdir(Path::new(&shard), 0o755) {
+            if !self.exists(&shard)? {
+                return Err(From::from((io::Error::from(e), "Unable to create shard directory")));
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), Error> {
+        match self.sftp.unlink(Path::new(path)) {
+            Ok(()) => Ok(()),
+            Err(ref e) if is_missing(e) => Ok(()),
+            Err(e) => Err(From::from((io::Error::from(e), "Unable to remove remote file"))),
+        }
+    }
+
+    // Remove a temporary file, only warning on failure, as whatever failed
+    // before it matters more
+    fn discard(&self, path: &str) {
+        if let Err(e) = self.remove(path) {
+            log::warn!("Unable to remove {}: {:?}", path, e);
+        }
+    }
+
+    // Copy `content` to a new temporary file beside the object, returning
+    // its path
+    fn upload<R: Read + Seek + ?Sized>(&self, key: &FileKey, content: &mut R)
+                                       -> Result<String, Error>
+    {
+        let part = format!("{}.{:016x}.part", self.object_path(key), rand::random::<u64>());
+        content.seek(SeekFrom::Start(0))
+            .map_err(|e| { (e, "Unable to rewind content") } )?;
+        let mut file = self.sftp.open_mode(Path::new(&part),
+                                           OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE,
+                                           0o644, OpenType::File)
+            .map_err(|e| { (io::Error::from(e), "Unable to create remote file") } )?;
+        let written = io::copy(content, &mut file)
+            .map_err(|e| { Error::from((e, "Unable to upload content")) } )
+            .and_then(|_| {
+                // Not every server supports the fsync extension
+                if let Err(e) = file.fsync() {
+                    log::debug!("Unable to flush {}: {:?}", part, e);
+                }
+                file.close()
+                    .map_err(|e| { From::from((io::Error::from(e), "Unable to close remote file")) } )
+            });
+        if let Err(e) = written {
+            self.discard(&part);
+            return Err(e);
+        }
+        Ok(part)
+    }
+
+    // Move `from` to `to`, replacing anything there.  Plain SFTP renames
+    // refuse to replace, so that is done in two steps where necessary.
+    fn replace(&self, from: &str, to: &str) -> Result<(), Error> {
+        if self.sftp.rename(Path::new(from), Path::new(to), None).is_ok() {
+            return Ok(());
+        }
+        self.remove(to)?;
+        self.sftp.rename(Path::new(from), Path::new(to), None)
+            .map_err(|e| { From::from((io::Error::from(e), "Unable to move remote file into place")) } )
+    }
+
+    // Take the lock on `key`, waiting for whoever holds it
+    fn lock(&self, key: &FileKey) -> Result<Lock<'_>, Error> {
+        let path = self.object_path(key) + ".lock";
+        // The lock as last seen (by modification time) and when that was,
+        // timed by this host's clock as the server's may differ
+        let mut seen: Option<(Option<u64>, Instant)> = None;
+        loop {
+            let created = self.sftp.open_mode(Path::new(&path),
+                                              OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE,
+                                              0o644, OpenType::File);
+            let error = match created {
+                Ok(mut file) => {
+                    let owner = format!("{}\n", std::process::id());
+                    if let Err(e) = file.write_all(owner.as_bytes()) {
+                        log::debug!("Unable to write lock owner: {:?}", e);
+                    }
+                    return Ok(Lock { store: self, path });
+                },
+                Err(e) => e,
+            };
+            let mtime = match self.sftp.stat(Path::new(&path)) {
+                Ok(stat) => stat.mtime,
+                // Released in the meantime
+                Err(ref e) if is_missing(e) => continue,
+                Err(_) => return Err(From::from((io::Error::from(error), "Unable to create lock file"))),
+            };
+            match seen {
+                Some((ref last, since)) if *last == mtime => {
+                    if since.elapsed() >= LOCK_TIMEOUT {
+                        log::warn!("Breaking abandoned lock {}", path);
+                        self.remove(&path)?;
+                        seen = None;
+                        continue;
+                    }
+                },
+                _ => seen = Some((mtime, Instant::now())),
+            }
+            thread::sleep(LOCK_RETRY);
+        }
+    }
+
+    // Fails with `ErrorKind::CorruptRefcount` if the refcount file is
+    // damaged.  Zero if there is none.
+    fn get_refcount(&self, key: &FileKey) -> Result<u64, Error> {
+        let mut file = match self.sftp.open(Path::new(&self.refcount_path(key))) {
+            Ok(file) => file,
+            Err(ref e) if is_missing(e) => return Ok(0),
+            Err(e) => return Err(From::from((io::Error::from(e), "Unable to open refcount file"))),
+        };
+        let mut record: Vec<u8> = Vec::new();
+        file.read_to_end(&mut record)
+            .map_err(|e| { (e, "Unable to read refcount file") } )?;
+        refcount::decode(&record)
+            .ok_or_else(|| Error::from((ErrorKind::CorruptRefcount,
+                                        &*format!("Refcount file for {} is damaged", key))))
+    }
+
+    // Only called with the key's lock held
+    fn set_refcount(&self, key: &FileKey, refcount: u64) -> Result<(), Error> {
+        let path = self.refcount_path(key);
+        if refcount < 1 {
+            return self.remove(&path);
+        }
+        let part = path.clone() + ".part";
+        let mut file = self.sftp.open_mode(Path::new(&part),
+                                           OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                                           0o644, OpenType::File)
+            .map_err(|e| { (io::Error::from(e), "Unable to create new refcount file") } )?;
+        file.write_all(&refcount::encode(refcount))
+            .map_err(|e| { (e, "Unable to write new refcount file") } )?;
+        drop(file);
+        self.replace(&part, &path)
+    }
+
+    // Take a reference on the object `content` holds, uploading it unless
+    // it is already there
+    fn store_content<R: Read + Seek + ?Sized>(&self, key: &FileKey, content: &mut R)
+                                              -> Result<(), Error>
+    {
+        self.make_shard(key)?;
+        let object = self.object_path(key);
+        let mut part: Option<String> = None;
+        let result = loop {
+            if part.is_none() && !self.exists(&object)? {
+                part = Some(self.upload(key, content)?);
+            }
+            let _lock = self.lock(key)?;
+            if !self.exists(&object)? {
+                match part.take() {
+                    Some(ref part) => self.replace(part, &object)?,
+                    // Deleted since it was looked for
+                    None => continue,
+                }
+            }
+            let after = self.get_refcount(key)?.checked_add(1)
+                .ok_or_else(|| Error::from((ErrorKind::RefcountOverflow,
+                                            &*format!("Too many references to {}", key))));
+            break after.and_then(|after| self.set_refcount(key, after));
+        };
+        // Someone else stored it while this was uploading
+        if let Some(ref part) = part {
+            self.discard(part);
+        }
+        result
+    }
+}
+
+// A key's lock, released when dropped
+struct Lock<'a> {
+    store: &'a SftpStore,
+    path: String,
+}
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.store.remove(&self.path) {
+            log::warn!("Unable to release lock {}: {:?}", self.path, e);
+        }
+    }
+}
+
+fn is_missing(e: &ssh2::Error) -> bool {
+    // No such file, or no such path
+    matches!(e.code(), ErrorCode::SFTP(2) | ErrorCode::SFTP(10))
+}
+
+fn check_host_key(session: &Session, host: &str, port: u16) -> Result<(), Error> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))
+        .ok_or_else(|| Error::from((io::Error::from(io::ErrorKind::NotFound),
+                                    "Unable to find home directory for known_hosts")))?;
+    let path = PathBuf::from(home).join(".ssh").join("known_hosts");
+    let mut known_hosts = session.known_hosts()
+        .map_err(|e| { (io::Error::from(e), "Unable to read known hosts") } )?;
+    known_hosts.read_file(&path, KnownHostFileKind::OpenSSH)
+        .map_err(|e| { (io::Error::from(e), "Unable to read known hosts") } )?;
+    let (key, _) = session.host_key()
+        .ok_or_else(|| Error::from((io::Error::from(io::ErrorKind::InvalidData),
+                                    "Server sent no host key")))?;
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(From::from((io::Error::from(io::ErrorKind::PermissionDenied),
+                                                 "Host key does not match known_hosts"))),
+        CheckResult::NotFound => Err(From::from((io::Error::from(io::ErrorKind::PermissionDenied),
+                                                 "Host is not in known_hosts"))),
+        CheckResult::Failure => Err(From::from((io::Error::from(io::ErrorKind::Other),
+                                                "Unable to check host key"))),
+    }
+}
+
+impl Store for SftpStore {
+    fn store_data(&self, input: &[u8]) -> Result<FileKey, Error> {
+        let mut hasher = KeyHasher::new();
+        hasher.write_all(input)
+            .map_err(|e| { (e, "Unable to hash input") } )?;
+        let key = hasher.finish();
+        self.store_content(&key, &mut Cursor::new(input))?;
+        Ok(key)
+    }
+
+    fn store_reader(&self, input: &mut dyn Read) -> Result<FileKey, Error> {
+        // Spooled locally first, as the key must be known before uploading
+        let mut spool = Spool::with_algorithm(&env::temp_dir(), false, HashAlgorithm::Sha224)?;
+        io::copy(input, &mut spool)
+            .map_err(|e| { (e, "Unable to spool input") } )?;
+        let key = FileKey(spool.hash());
+        let mut file = File::open(spool.path())
+            .map_err(|e| { (e, "Unable to read spooled input") } )?;
+        self.store_content(&key, &mut file)?;
+        Ok(key)
+    }
+
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>
+    {
+        match self.sftp.open(Path::new(&self.object_path(key))) {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(ref e) if is_missing(e) => Ok(None),
+            Err(e) => Err(From::from((io::Error::from(e), "Unable to open stored file"))),
+        }
+    }
+
+    fn delete(&self, key: &FileKey) -> Result<(), Error> {
+        let _lock = self.lock(key)?;
+        match self.get_refcount(key)? {
+            0 => Ok(()),
+            1 => {
+                self.remove(&self.object_path(key))?;
+                self.set_refcount(key, 0)
+            },
+            refcount => self.set_refcount(key, refcount - 1),
+        }
+    }
+
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error> {
+        let _lock = self.lock(key)?;
+        let stat = match self.sftp.stat(Path::new(&self.object_path(key))) {
+            Ok(stat) => stat,
+            Err(ref e) if is_missing(e) => return Ok(None),
+            Err(e) => return Err(From::from((io::Error::from(e), "Unable to stat stored file"))),
+        };
+        Ok(Some(ObjectStat {
+            size: stat.size.unwrap_or(0),
+            refcount: self.get_refcount(key)?,
+            // Objects are renamed into place and never written again
+            stored: stat.mtime.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+            last_access: None,
+        }))
+    }
+
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error> {
+        let shards: Vec<String> = self.sftp.readdir(Path::new(&self.root))
+            .map_err(|e| { (io::Error::from(e), "Unable to read storage directory") } )?
+            .into_iter()
+            .filter(|(_, stat)| stat.is_dir())
+            .filter_map(|(path, _)| path.file_name()?.to_str().map(|s| s.to_owned()))
+            .filter(|name| name.len() == 2)
+            .collect();
+        Ok(Box::new(Keys { store: self, shards: shards.into_iter(), page: Vec::new().into_iter() }))
+    }
+}
+
+// The keys in the store, a shard directory at a time
+struct Keys<'a> {
+    store: &'a SftpStore,
+    shards: std::vec::IntoIter<String>,
+    page: std::vec::IntoIter<FileKey>,
+}
+
+impl Iterator for Keys<'_> {
+    type Item = Result<FileKey, Error>;
+
+    fn next(&mut self) -> Option<Result<FileKey, Error>> {
+        loop {
+            if let Some(key) = self.page.next() {
+                return Some(Ok(key));
+            }
+            let shard = self.shards.next()?;
+            let path = format!("{}/{}", self.store.root, shard);
+            let entries = match self.store.sftp.readdir(Path::new(&path)) {
+                Ok(entries) => entries,
+                Err(e) => return Some(Err(From::from((io::Error::from(e),
+                                                      "Unable to read shard directory")))),
+            };
+            // Skipping refcounts, locks and uploads in progress
+            let keys: Vec<FileKey> = entries.into_iter()
+                .filter_map(|(path, _)| path.file_name()?.to_str()
+                            .map(|name| FileKey(format!("{}{}", shard, name))))
+                .filter(|key| key.is_well_formed())
+                .collect();
+            self.page = keys.into_iter();
+        }
+    }
+}
@@ -0,0 +1,48 @@
+// Overwriting object content before it is unlinked (`Config::secure_delete`),
+// so that the freed blocks no longer hold it.  This is only as good as the
+// filesystem and device allow: copy-on-write and log-structured filesystems,
+// snapshots, and SSD wear levelling can all keep the old content elsewhere.
+
+use std::fs::{Metadata,OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use super::Error;
+
+const CHUNK: usize = 64 * 1024;
+
+/// Overwrite the content of the file at `path` with zeros, flushing the
+/// zeros to disk.  Content hard linked elsewhere (by `clone_to()` or
+/// `link_into()`) is still in use there, so is left alone.
+pub(crate) fn overwrite(path: &Path) -> Result<(), Error> {
+    let mut file = OpenOptions::new().write(true).open(path)
+        .map_err(|e| { (e, "Unable to open file to overwrite") } )?;
+    let metadata = file.metadata()
+        .map_err(|e| { (e, "Unable to stat file to overwrite") } )?;
+    if links(&metadata) > 1 {
+        log::warn!("Not overwriting {}, which is linked elsewhere", path.display());
+        return Ok(());
+    }
+    let len = metadata.len();
+    let zeros = [0_u8; CHUNK];
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = remaining.min(CHUNK as u64) as usize;
+        file.write_all(&zeros[..count])
+            .map_err(|e| { (e, "Unable to overwrite file") } )?;
+        remaining -= count as u64;
+    }
+    file.sync_data()
+        .map_err(|e| { From::from((e, "Unable to flush overwritten file")) } )
+}
+
+#[cfg(unix)]
+fn links(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn links(_metadata: &Metadata) -> u64 {
+    1
+}
@@ -0,0 +1,396 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Point-in-time snapshots of a store, for backing it up consistently
+//! while writes carry on.
+//!
+//! A snapshot records the objects stored at a moment with their refcounts,
+//! and the refs, under `snapshots/<name>` in the storage path.  Objects
+//! never change, so nothing is copied: the snapshot holds a reference on
+//! each of its objects until it is deleted, which keeps them stored
+//! whatever is deleted meanwhile.  Refcounts are recorded, and restored,
+//! without the references snapshots hold.
+//!
+//! `open_snapshot()` gives a read-only `Store` of the objects as they were,
+//! which can be walked, diffed or synced to a backup at leisure.
+//! `restore_snapshot()` puts the store's refcounts and refs back as they
+//! were, removing objects stored since.  Namespaces, derived artifacts and
+//! uploads keep their own records, which are not part of a snapshot, so
+//! restore a store they are used in only if those can be dropped too.
+
+use std::collections::{BTreeMap,HashMap};
+use std::fs::{self,File};
+use std::io::{self,Read,Write};
+use std::path::{Path,PathBuf};
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use super::{Config,Error,FileKey,Keys,index,keylock,pin,quarantine,refs,writelock};
+use super::{get_refcount,is_valid_name,remove_object,set_refcount,storage_file_path};
+use super::error::ErrorKind;
+use super::journal::Intent;
+use super::spool::Spool;
+use super::store::{ObjectStat,Store};
+
+const HEADER: &str = "filestore-snapshot 1";
+
+fn snapshots_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("snapshots")
+}
+
+fn snapshot_path(storage_path: &Path, name: &str) -> Result<PathBuf, Error> {
+    if !is_valid_name(name) {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput,
+                                              "malformed snapshot name"),
+                               "Invalid snapshot name")));
+    }
+    Ok(snapshots_dir(storage_path).join(name))
+}
+
+/// The objects and refs of a store at the moment a snapshot was taken,
+/// read-only
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    storage_path: PathBuf,
+    name: String,
+    time: SystemTime,
+    objects: BTreeMap<FileKey, u64>,
+    refs: BTreeMap<String, FileKey>,
+}
+
+impl Snapshot {
+    /// The snapshot's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// When the snapshot was taken
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// The objects stored when the snapshot was taken, with their refcounts
+    pub fn objects(&self) -> &BTreeMap<FileKey, u64> {
+        &self.objects
+    }
+
+    /// The refs when the snapshot was taken, with their targets
+    pub fn refs(&self) -> &BTreeMap<String, FileKey> {
+        &self.refs
+    }
+
+    fn read_only() -> Error {
+        From::from((ErrorKind::ReadOnly, "Snapshots are read-only"))
+    }
+}
+
+impl Store for Snapshot {
+    fn store_data(&self, _input: &[u8]) -> Result<FileKey, Error> {
+        Err(Snapshot::read_only())
+    }
+
+    fn store_reader(&self, _input: &mut dyn Read) -> Result<FileKey, Error> {
+        Err(Snapshot::read_only())
+    }
+
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>
+    {
+        if !self.objects.contains_key(key) {
+            return Ok(None);
+        }
+        match File::open(storage_file_path(&self.storage_path, key)) {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                quarantine::check(&self.storage_path, key)?;
+                Ok(None)
+            },
+            Err(e) => Err(From::from((e, "Unable to open stored file"))),
+        }
+    }
+
+    fn delete(&self, _key: &FileKey) -> Result<(), Error> {
+        Err(Snapshot::read_only())
+    }
+
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error> {
+        let refcount = match self.objects.get(key) {
+            Some(&refcount) => refcount,
+            None => return Ok(None),
+        };
+        let metadata = match fs::metadata(storage_file_path(&self.storage_path, key)) {
+            Ok(m) => m,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(From::from((e, "Unable to stat stored file"))),
+        };
+        let stored = metadata.created().or_else(|_| metadata.modified()).ok();
+        Ok(Some(ObjectStat { size: metadata.len(), refcount, stored, last_access: None }))
+    }
+
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error> {
+        Ok(Box::new(self.objects.keys().cloned().map(Ok)))
+    }
+}
+
+/// Take a snapshot named `name` of the store at `storage_path`, failing
+/// with an error of kind `AlreadyExists` if there is one by that name.
+/// Names may use ASCII letters, digits, `-`, `_` and `.`.
+///
+/// The store is taken exclusively while the snapshot is taken, failing
+/// with `ErrorKind::Locked` if another process is writing to it; changes
+/// made meanwhile by other threads of this process may or may not be
+/// included.  Taking a reference on every object, this costs about as much
+/// as storing each again.
+pub fn snapshot(storage_path: &Path, name: &str) -> Result<Snapshot, Error> {
+    let op = operation!("filestore.snapshot", objects);
+    let result = take(storage_path, name);
+    if let Ok(ref snapshot) = result {
+        op.record("objects", snapshot.objects.len() as u64);
+    }
+    op.finish(result)
+}
+
+fn take(storage_path: &Path, name: &str) -> Result<Snapshot, Error> {
+    let path = snapshot_path(storage_path, name)?;
+    let _exclusive = writelock::exclusive_or_held(storage_path)?;
+    if path.exists() {
+        return Err(From::from((io::Error::from(io::ErrorKind::AlreadyExists),
+                               "A snapshot by that name exists")));
+    }
+    let held = held_by_snapshots(storage_path)?;
+    let refs = refs::list_refs(storage_path)?;
+    let config = Config::default();
+    let mut objects: BTreeMap<FileKey, u64> = BTreeMap::new();
+    let taken = Keys::new(storage_path).and_then(|keys| {
+        for key in keys {
+            let key = key?;
+            let refcount = get_refcount(storage_path, &key)?
+                .saturating_sub(held.get(&key).copied().unwrap_or(0));
+            // Kept only by other snapshots, or unreferenced and due to be
+            // collected
+            if refcount == 0 {
+                continue;
+            }
+            super::add_reference(storage_path, &config, &key)?;
+            objects.insert(key, refcount);
+        }
+        Ok(())
+    });
+
+    let snapshot = Snapshot {
+        storage_path: storage_path.to_path_buf(),
+        name: name.to_owned(),
+        time: SystemTime::now(),
+        objects,
+        refs,
+    };
+    // References are taken before the snapshot is recorded, so that an
+    // interruption can leak some but never leave it short
+    if let Err(e) = taken.and_then(|()| write_snapshot(storage_path, &path, &snapshot)) {
+        release_all(storage_path, &snapshot);
+        return Err(e);
+    }
+    Ok(snapshot)
+}
+
+// Give back the references taken for `snapshot`
+fn release_all(storage_path: &Path, snapshot: &Snapshot) {
+    for key in snapshot.objects.keys() {
+        if let Err(e) = super::release(storage_path, &Config::default(), key) {
+            log::warn!("Unable to release {} held by snapshot {}: {:?}", key, snapshot.name, e);
+        }
+    }
+}
+
+fn write_snapshot(storage_path: &Path, path: &Path, snapshot: &Snapshot) -> Result<(), Error> {
+    fs::create_dir_all(snapshots_dir(storage_path))
+        .map_err(|e| { (e, "Unable to create snapshots directory") } )?;
+    let since_epoch = snapshot.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut spool = Spool::new(storage_path, false)?;
+    let mut written = writeln!(spool, "{}\ntime {}.{:09}", HEADER, since_epoch.as_secs(),
+                               since_epoch.subsec_nanos());
+    for (key, refcount) in &snapshot.objects {
+        written = written.and_then(|()| writeln!(spool, "object {} {}", key, refcount));
+    }
+    for (name, key) in &snapshot.refs {
+        written = written.and_then(|()| writeln!(spool, "ref {} {}", name, key));
+    }
+    written.map_err(|e| { (e, "Unable to write snapshot") } )?;
+    spool.persist(path)
+}
+
+/// The snapshot named `name`, or `None` if there is no such snapshot
+pub fn open_snapshot(storage_path: &Path, name: &str) -> Result<Option<Snapshot>, Error> {
+    let path = snapshot_path(storage_path, name)?;
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(From::from((e, "Unable to read snapshot"))),
+    };
+    match parse(storage_path, name, &text) {
+        Some(snapshot) => Ok(Some(snapshot)),
+        None => Err(From::from((io::Error::new(io::ErrorKind::InvalidData, "malformed snapshot"),
+                                &*format!("Snapshot {} is damaged", name)))),
+    }
+}
+
+fn parse(storage_path: &Path, name: &str, text: &str) -> Option<Snapshot> {
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return None;
+    }
+    let (secs, nanos) = lines.next()?.strip_prefix("time ")?.split_once('.')?;
+    let mut snapshot = Snapshot {
+        storage_path: storage_path.to_path_buf(),
+        name: name.to_owned(),
+        time: UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?),
+        objects: BTreeMap::new(),
+        refs: BTreeMap::new(),
+    };
+    for line in lines {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields[..] {
+            ["object", key, refcount] => {
+                let key = FileKey(key.to_owned());
+                if !key.is_well_formed() {
+                    return None;
+                }
+                snapshot.objects.insert(key, refcount.parse().ok()?);
+            },
+            ["ref", ref_name, key] => {
+                let key = FileKey(key.to_owned());
+                if !key.is_well_formed() || !is_valid_name(ref_name) {
+                    return None;
+                }
+                snapshot.refs.insert(ref_name.to_owned(), key);
+            },
+            _ => return None,
+        }
+    }
+    Some(snapshot)
+}
+
+/// The names of the store's snapshots
+pub fn list_snapshots(storage_path: &Path) -> Result<Vec<String>, Error> {
+    let mut names: Vec<String> = Vec::new();
+    let entries = match fs::read_dir(snapshots_dir(storage_path)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(From::from((e, "Unable to read snapshots directory"))),
+    };
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read snapshots directory") } )?;
+        if let Ok(name) = entry.file_name().into_string() {
+            if is_valid_name(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+// The number of snapshots holding a reference on each object
+fn held_by_snapshots(storage_path: &Path) -> Result<HashMap<FileKey, u64>, Error> {
+    let mut held: HashMap<FileKey, u64> = HashMap::new();
+    for name in list_snapshots(storage_path)? {
+        if let Some(snapshot) = open_snapshot(storage_path, &name)? {
+            for key in snapshot.objects.into_keys() {
+                *held.entry(key).or_insert(0) += 1;
+            }
+        }
+    }
+    Ok(held)
+}
+
+/// Delete the snapshot named `name`, releasing its references.  Returns
+/// `false` if there is no such snapshot.
+pub fn delete_snapshot(storage_path: &Path, name: &str) -> Result<bool, Error> {
+    let path = snapshot_path(storage_path, name)?;
+    let _writing = writelock::begin(storage_path)?;
+    let snapshot = match open_snapshot(storage_path, name)? {
+        Some(snapshot) => snapshot,
+        None => return Ok(false),
+    };
+    // Removed first, so that an interruption can leak references but never
+    // leave the snapshot without them
+    fs::remove_file(&path)
+        .map_err(|e| { (e, "Unable to remove snapshot") } )?;
+    for key in snapshot.objects.keys() {
+        super::release(storage_path, &Config::default(), key)?;
+    }
+    Ok(true)
+}
+
+/// Put the store's refcounts and refs back as they were when the snapshot
+/// named `name` was taken, removing objects stored since (unless pinned)
+/// and restoring refs moved or deleted since.  Snapshots, this one
+/// included, keep their references.  Returns `false` if there is no such
+/// snapshot.
+///
+/// The store is taken exclusively, failing with `ErrorKind::Locked` if
+/// another process is writing to it.
+pub fn restore_snapshot(storage_path: &Path, name: &str) -> Result<bool, Error> {
+    let op = operation!("filestore.restore_snapshot");
+    let result = restore(storage_path, name);
+    op.finish(result)
+}
+
+fn restore(storage_path: &Path, name: &str) -> Result<bool, Error> {
+    let _exclusive = writelock::exclusive_or_held(storage_path)?;
+    let snapshot = match open_snapshot(storage_path, name)? {
+        Some(snapshot) => snapshot,
+        None => return Ok(false),
+    };
+    let held = held_by_snapshots(storage_path)?;
+    let config = Config::default();
+
+    for key in Keys::new(storage_path)? {
+        let key = key?;
+        if !snapshot.objects.contains_key(&key) {
+            let refcount = held.get(&key).copied().unwrap_or(0);
+            set_references(storage_path, &config, &key, refcount)?;
+        }
+    }
+    for (key, refcount) in &snapshot.objects {
+        let refcount = refcount + held.get(key).copied().unwrap_or(0);
+        set_references(storage_path, &config, key, refcount)?;
+    }
+
+    // The references refs hold are restored with the rest
+    let current = refs::list_refs(storage_path)?;
+    for (ref_name, key) in &snapshot.refs {
+        if current.get(ref_name) != Some(key) {
+            refs::retarget(storage_path, ref_name, key)?;
+        }
+    }
+    for ref_name in current.keys() {
+        if !snapshot.refs.contains_key(ref_name) {
+            refs::forget(storage_path, ref_name)?;
+        }
+    }
+    Ok(true)
+}
+
+// Set the refcount of `key`, which is stored, removing it if that leaves
+// it unreferenced and unpinned
+fn set_references(storage_path: &Path, config: &Config, key: &FileKey, refcount: u64)
+                  -> Result<(), Error>
+{
+    let _lock = keylock::lock(storage_path, key)?;
+    if !storage_file_path(storage_path, key).exists() {
+        log::warn!("Unable to restore {}, which is no longer stored", key);
+        return Ok(());
+    }
+    let before = get_refcount(storage_path, key)?;
+    if before == refcount {
+        return Ok(());
+    }
+    let intent = Intent::record(storage_path, config, key, before, refcount)?;
+    set_refcount(storage_path, config, key, refcount)?;
+    if refcount == 0 && !pin::is_pinned(storage_path, key)? {
+        remove_object(storage_path, config, key)?;
+    }
+    index::update(storage_path, config, key)?;
+    intent.done()
+}
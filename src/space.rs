@@ -0,0 +1,180 @@
+// Free space checks, so that large copies fail before they start rather
+// than partway through, and preallocation of new objects whose length is
+// known, so that the filesystem can lay each out in one piece.
+//
+// With `Config::low_space_watermark`, a store that would leave less than
+// the watermark free first reclaims what it can by collecting garbage.
+// That walks the whole store, so it is done at most once per
+// `RECLAIM_INTERVAL` for each store in this process; stores arriving in
+// between fail at once if there is still too little space.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path,PathBuf};
+use std::sync::{Mutex,OnceLock};
+use std::time::{Duration,Instant};
+
+use super::{Config,Error,handles};
+use super::error::ErrorKind;
+
+/// Objects shorter than this are written without preallocating, which
+/// would cost a system call for little gain
+pub(crate) const PREALLOCATE_MIN: u64 = 64 * 1024;
+
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(60);
+
+type Registry = Mutex<HashMap<PathBuf, Instant>>;
+
+static RECLAIMED: OnceLock<Registry> = OnceLock::new();
+
+/// Bytes available to unprivileged users on the filesystem holding `path`,
+/// or `None` if that can't be determined here
+#[cfg(unix)]
+pub(crate) fn available(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    // Field widths vary between platforms
+    #[allow(clippy::unnecessary_cast)]
+    let bytes = (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64);
+    Some(bytes)
+}
+
+#[cfg(windows)]
+pub(crate) fn available(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut bytes: u64 = 0;
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(path.as_ptr(), &mut bytes, std::ptr::null_mut(), std::ptr::null_mut())
+    };
+    if ok == 0 {
+        return None;
+    }
+    Some(bytes)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn available(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Fail with `ErrorKind::DiskFull` if the filesystem holding `path` has
+/// fewer than `required` bytes available.  Passes if space can't be checked.
+pub(crate) fn ensure_available(path: &Path, required: u64) -> Result<(), Error> {
+    match available(path) {
+        Some(available) if available < required => {
+            Err(From::from((ErrorKind::DiskFull { required, available },
+                            "Not enough space for content")))
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Fail with `ErrorKind::DiskFull` unless `len` bytes of content can be
+/// stored leaving `config.low_space_watermark` free, reclaiming space first
+/// if need be.  Without a watermark, as `ensure_available()`.
+pub(crate) fn ensure_room(storage_path: &Path, config: &Config, len: u64) -> Result<(), Error> {
+    let watermark = match config.low_space_watermark {
+        Some(watermark) => watermark,
+        None => return ensure_available(storage_path, len),
+    };
+    let required = len.saturating_add(watermark);
+    match available(storage_path) {
+        Some(available) if available < required => {
+            reclaim(storage_path)?;
+            ensure_available(storage_path, required)
+        },
+        _ => Ok(()),
+    }
+}
+
+// Collect garbage, unless that was done lately.  Objects being stored are
+// safe from it, being locked until they are referenced.
+fn reclaim(storage_path: &Path) -> Result<(), Error> {
+    {
+        let mut reclaimed = RECLAIMED.get_or_init(|| Mutex::new(HashMap::new()))
+            .lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        match reclaimed.get(&handles::canonical(storage_path)) {
+            Some(last) if now.duration_since(*last) < RECLAIM_INTERVAL => return Ok(()),
+            _ => { reclaimed.insert(handles::canonical(storage_path), now); },
+        }
+    }
+    let report = super::gc(storage_path)?;
+    log::info!("Low on space in {}: freed {} bytes in {} objects",
+               storage_path.display(), report.bytes_freed, report.objects_removed.len());
+    Ok(())
+}
+
+/// Reserve `len` bytes of disk for `file`, newly created at `path`, before
+/// content is written to it, failing with `ErrorKind::DiskFull` if the
+/// filesystem can't hold that much.  The file's length is left as it is.
+/// Does nothing for lengths below `PREALLOCATE_MIN`, or where the platform
+/// or filesystem can't preallocate.
+pub(crate) fn preallocate(file: &File, path: &Path, len: u64) -> Result<(), Error> {
+    if len < PREALLOCATE_MIN {
+        return Ok(());
+    }
+    match allocate(file, len) {
+        Err(ref e) if e.kind() == io::ErrorKind::StorageFull => {
+            let available = available(path.parent().unwrap_or(path)).unwrap_or(0);
+            Err(From::from((ErrorKind::DiskFull { required: len, available },
+                            "Not enough space for content")))
+        },
+        // Only ever a help
+        _ => Ok(()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let len: libc::off_t = std::convert::TryFrom::try_from(len)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    loop {
+        if unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FileAllocationInfo, SetFileInformationByHandle, FILE_ALLOCATION_INFO,
+    };
+
+    let info = FILE_ALLOCATION_INFO {
+        AllocationSize: std::convert::TryFrom::try_from(len)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+    };
+    let ok = unsafe {
+        SetFileInformationByHandle(file.as_raw_handle() as _, FileAllocationInfo,
+                                   &info as *const FILE_ALLOCATION_INFO as *const _,
+                                   std::mem::size_of::<FILE_ALLOCATION_INFO>() as u32)
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn allocate(_file: &File, _len: u64) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
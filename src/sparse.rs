@@ -0,0 +1,96 @@
+// Copying files with holes (sparse files, such as disk images and VM
+// volumes) without filling the holes in, so that they don't grow to their
+// full logical size inside the store or when copied back out.
+//
+// Where the platform reports holes (SEEK_DATA and SEEK_HOLE), only the data
+// regions are read and written, each at its own offset, and the destination
+// is extended over any trailing hole.  Elsewhere, and for files without
+// holes, callers copy byte for byte as usual.
+
+use std::fs::{self,File};
+use std::io;
+
+/// Whether `metadata` describes a file with fewer bytes allocated than its
+/// length, so that it is worth copying with `copy()`
+#[cfg(unix)]
+pub(crate) fn has_holes(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.is_file() && metadata.blocks().saturating_mul(512) < metadata.len()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn has_holes(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// The bytes of disk the file `metadata` describes occupies
+#[cfg(unix)]
+pub(crate) fn allocated(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.len().min(metadata.blocks().saturating_mul(512))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn allocated(metadata: &fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+/// Copy the first `len` bytes of `src` to the empty file `dest`, leaving
+/// holes where `src` has them, and calling `progress` with the offset
+/// reached after each region.  Returns `false`, having written nothing, if
+/// holes can't be found here.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub(crate) fn copy(src: &File, dest: &File, len: u64, progress: &mut dyn FnMut(u64))
+                   -> io::Result<bool>
+{
+    use std::os::unix::fs::FileExt;
+
+    let mut buf = vec![0_u8; 256 * 1024];
+    let mut offset: u64 = 0;
+    while offset < len {
+        let data = match seek(src, offset, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // Nothing but a hole from here on
+            Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            // The filesystem can't say where its holes are
+            Err(ref e) if offset == 0 && e.raw_os_error() == Some(libc::EINVAL) => {
+                return Ok(false);
+            },
+            Err(e) => return Err(e),
+        };
+        let hole = seek(src, data, libc::SEEK_HOLE)?.min(len);
+        let mut at = data;
+        while at < hole {
+            let want = ((hole - at) as usize).min(buf.len());
+            let count = src.read_at(&mut buf[..want], at)?;
+            if count == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "file shrank while being copied"));
+            }
+            dest.write_all_at(&buf[..count], at)?;
+            at += count as u64;
+        }
+        offset = hole;
+        progress(offset);
+    }
+    dest.set_len(len)?;
+    progress(len);
+    Ok(true)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub(crate) fn copy(_src: &File, _dest: &File, _len: u64, _progress: &mut dyn FnMut(u64))
+                   -> io::Result<bool>
+{
+    Ok(false)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+    let position = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if position < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(position as u64)
+}
@@ -0,0 +1,178 @@
+
+use std::fs::{self,OpenOptions};
+use std::io::{self,Write};
+use std::path::{Path,PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use crypto::digest::Digest;
+use super::Error;
+use super::direct::DirectWriter;
+use super::hashable::HashAlgorithm;
+
+static SPOOL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary file inside the store that content is written into while
+/// being hashed, for inputs that can only be read once.  The file is removed
+/// on drop unless it has been persisted.
+pub struct Spool {
+    path: PathBuf,
+    file: Option<DirectWriter>,
+    hash: Box<dyn Digest + Send + Sync>,
+}
+
+impl Spool {
+    /// Create a spool file, written with direct I/O if `direct`, hashing
+    /// with the store's algorithm
+    pub fn new(storage_path: &Path, direct: bool) -> Result<Spool, Error> {
+        let algorithm = super::manifest::algorithm(storage_path)?;
+        Spool::with_algorithm(storage_path, direct, algorithm)
+    }
+
+    /// Create a spool file in `dir`, which needn't be a store, hashing with
+    /// `algorithm`
+    pub fn with_algorithm(dir: &Path, direct: bool, algorithm: HashAlgorithm)
+                          -> Result<Spool, Error>
+    {
+        loop {
+            let path = temp_path(dir);
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            match DirectWriter::open(&options, &path, direct) {
+                Ok(file) => return Ok(Spool {
+                    path,
+                    file: Some(file),
+                    hash: algorithm.hasher(),
+                }),
+                // Left over from an earlier process with the same pid
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(From::from((e, "Unable to create temporary file"))),
+            }
+        }
+    }
+
+    /// The path of the spool file, which holds everything written so far
+    /// unless it was opened for direct I/O and not yet closed
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The hex digest of the content.  Call only once all content has been
+    /// written.
+    pub fn hash(&mut self) -> String {
+        self.hash.result_str()
+    }
+
+    /// Reserve disk for `len` bytes of content, as `space::preallocate()`
+    /// does
+    pub fn preallocate(&self, len: u64) -> Result<(), Error> {
+        match self.file {
+            Some(ref file) => super::space::preallocate(file.file(), &self.path, len),
+            None => Ok(()),
+        }
+    }
+
+    /// Write out anything still buffered, after which nothing more can be
+    /// written
+    pub fn close(&mut self) -> Result<(), Error> {
+        if let Some(file) = self.file.take() {
+            file.finish()
+                .map_err(|e| { (e, "Unable to write temporary file") } )?;
+        }
+        Ok(())
+    }
+
+    /// Move the spooled content to its final location
+    pub fn persist(mut self, dest_path: &Path) -> Result<(), Error> {
+        self.close()?;
+        super::replace::replace(&self.path, dest_path)
+            .map_err(|e| { (e, "Unable to move temporary file into place") } )?;
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = match self.file {
+            Some(ref mut file) => file.write(buf)?,
+            None => return Err(io::Error::other("spool is closed")),
+        };
+        self.hash.input(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file {
+            Some(ref mut file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A path for a temporary file inside the store, named as spool files are
+/// so that `recover()` removes it if this process dies
+pub fn temp_path(storage_path: &Path) -> PathBuf {
+    storage_path.join(format!(
+        "{}-{}.tmp", process::id(), SPOOL_COUNTER.fetch_add(1, Ordering::SeqCst)))
+}
+
+/// The id of the process that created a spool file, if `name` is the name
+/// of one
+pub fn owner(name: &str) -> Option<u32> {
+    let (pid, counter) = name.strip_suffix(".tmp")?.split_once('-')?;
+    counter.parse::<usize>().ok()?;
+    pid.parse().ok()
+}
+
+/// Whether a process that may own spool files is still running
+#[cfg(unix)]
+pub fn is_running(pid: u32) -> bool {
+    if pid == process::id() {
+        return true;
+    }
+    let pid = match std::convert::TryFrom::try_from(pid) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    // Signal 0 only checks that the process exists; EPERM means it exists
+    // but belongs to someone else
+    let found = unsafe { libc::kill(pid, 0) } == 0;
+    found || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+pub fn is_running(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle,GetLastError,ERROR_ACCESS_DENIED,STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{GetExitCodeProcess,OpenProcess};
+    use windows_sys::Win32::System::Threading::PROCESS_QUERY_LIMITED_INFORMATION;
+
+    if pid == process::id() {
+        return true;
+    }
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            // Access denied means it exists but belongs to someone else
+            return GetLastError() == ERROR_ACCESS_DENIED;
+        }
+        // Handles to exited processes stay valid while anyone holds one
+        let mut code: u32 = 0;
+        let found = GetExitCodeProcess(handle, &mut code) != 0 && code == STILL_ACTIVE as u32;
+        CloseHandle(handle);
+        found
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn is_running(pid: u32) -> bool {
+    pid == process::id()
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        self.file.take();
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
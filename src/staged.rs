@@ -0,0 +1,92 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Storing content in two steps, so that it can be checked before it is
+//! referenced.
+//!
+//! `FileStore::stage()` reads and hashes content into a temporary file
+//! inside the store, giving back a `StagedObject` that knows its key and
+//! size and can be read again, to be validated, scanned or shown to a user.
+//! `FileStore::commit()` then stores it as `store_reader()` would have,
+//! while `FileStore::discard()`, or dropping the staged object, removes it
+//! without the store ever having referenced it.  Staged content left by a
+//! process that died is removed by `recover()`.
+
+use std::fs::File;
+use std::io::{self,Read};
+use std::path::Path;
+
+use super::{Error,FileKey,limit,space};
+use super::spool::Spool;
+use super::store::FileStore;
+
+/// Content hashed and written into the store but not yet stored under its
+/// key, from `FileStore::stage()`
+pub struct StagedObject {
+    spool: Spool,
+    key: FileKey,
+    size: u64,
+}
+
+impl StagedObject {
+    pub(crate) fn new(store: &FileStore, input: &mut dyn Read) -> Result<StagedObject, Error> {
+        store.check_writable()?;
+        let op = operation!("filestore.stage");
+        let _permit = limit::acquire(store.path(), store.config().max_concurrent_operations);
+        let result = spool(store, input);
+        if let Ok(ref staged) = result {
+            op.key(&staged.key);
+            op.record("bytes", staged.size);
+        }
+        op.finish(result)
+    }
+
+    /// The key the content will be stored under
+    pub fn key(&self) -> &FileKey {
+        &self.key
+    }
+
+    /// The length of the content in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The temporary file holding the content, for tools that want a path.
+    /// It must not be changed, or the content will be stored under a key
+    /// that doesn't match it.
+    pub fn path(&self) -> &Path {
+        self.spool.path()
+    }
+
+    /// Open the content for reading
+    pub fn open(&self) -> Result<File, Error> {
+        File::open(self.spool.path())
+            .map_err(|e| { From::from((e, "Unable to open staged content")) } )
+    }
+
+    // Store the content under its key, returning whether it was newly stored
+    pub(crate) fn commit(self, store: &FileStore) -> Result<bool, Error> {
+        store.check_writable()?;
+        if self.spool.path().parent() != Some(store.path()) {
+            return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "other store"),
+                                   "Content was staged in a different store")));
+        }
+        let op = operation!("filestore.commit_staged", new_object);
+        op.record("bytes", self.size);
+        let StagedObject { spool, key, .. } = self;
+        let result = super::commit(store.path(), store.config(), &key, 1,
+                                   |dest_path| spool.persist(dest_path))
+            .map(|new_object| (key, new_object));
+        op.finish_stored(result).map(|(_, new_object)| new_object)
+    }
+}
+
+fn spool(store: &FileStore, input: &mut dyn Read) -> Result<StagedObject, Error> {
+    space::ensure_room(store.path(), store.config(), 0)?;
+    let mut spool = Spool::new(store.path(), store.config().direct_io)?;
+    let size = super::spool_input(store.config(), input, &mut spool)?;
+    // Read back by the application before it is stored
+    spool.close()?;
+    let key = FileKey(spool.hash());
+    Ok(StagedObject { spool, key, size })
+}
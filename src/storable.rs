@@ -1,24 +1,47 @@
 
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Writing content into the store and reading it back, implemented for byte
+//! buffers and files.  Implement `Storable` and `Hashable` for other sources,
+//! such as rope buffers or memory maps, and store them with
+//! `store_storable()`.
+
 use std::fs::{File,OpenOptions};
 use std::path::{Path,PathBuf};
 use std::io::{Read,Write};
 use super::Error;
+use super::{space,sparse};
 
 /// A trait for things which can be stored and retrieved
-pub trait Storable: Sized {
+pub trait Storable {
+    /// Write the content to a new file at `dest_path`.  The bytes written
+    /// must be those the type's `Hashable::hash()` digests.  This is only
+    /// called when the content is not already stored; if it fails, anything
+    /// left at `dest_path` is removed.
     fn store(&self, dest_path: &Path) -> Result<(), Error>;
-    fn retrieve(dest_path: &Path) -> Result<Self, Error>;
+
+    /// Build a value from the stored content at `dest_path`, which must not
+    /// be modified or removed
+    fn retrieve(dest_path: &Path) -> Result<Self, Error> where Self: Sized;
 }
 
-impl Storable for Vec<u8> {
+impl Storable for [u8] {
     fn store(&self, dest_path: &Path) -> Result<(), Error> {
         let mut file = OpenOptions::new()
             .create(true).write(true).truncate(true).open(dest_path)
             .map_err(|e| { (e, "Unable to open/creat new file") } )?;
-        file.write_all( &*self )
+        space::preallocate(&file, dest_path, self.len() as u64)?;
+        file.write_all( self )
             .map_err(|e| { (e, "Unable to write new file") } )?;
         Ok(())
     }
+}
+
+impl Storable for Vec<u8> {
+    fn store(&self, dest_path: &Path) -> Result<(), Error> {
+        self[..].store(dest_path)
+    }
 
     fn retrieve(dest_path: &Path) -> Result<Vec<u8>, Error>
     {
@@ -33,11 +56,72 @@ impl Storable for Vec<u8> {
 
 impl Storable for PathBuf {
     fn store(&self, dest_path: &Path) -> Result<(), Error> {
-        ::std::fs::copy(self, dest_path)
-            .map_err(|e| { (e, "Unable to copy file") } )?;
+        let metadata = ::std::fs::metadata(self)
+            .map_err(|e| { (e, "Unable to stat file to copy") } )?;
+        if let Some(dir) = dest_path.parent() {
+            super::space::ensure_available(dir, sparse::allocated(&metadata))?;
+        }
+        if sparse::has_holes(&metadata) {
+            match copy_sparse(self, dest_path, &metadata) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {},
+                Err(e) => {
+                    let _ = ::std::fs::remove_file(dest_path);
+                    return Err(From::from((e, "Unable to copy file")));
+                },
+            }
+        }
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            if let Some(result) = super::uring::copy_file(self, dest_path) {
+                if result.is_err() {
+                    let _ = ::std::fs::remove_file(dest_path);
+                }
+                return result;
+            }
+        }
+        if metadata.len() >= space::PREALLOCATE_MIN {
+            let copied = copy_preallocated(self, dest_path, &metadata);
+            if copied.is_err() {
+                let _ = ::std::fs::remove_file(dest_path);
+            }
+            return copied;
+        }
+        if let Err(e) = ::std::fs::copy(self, dest_path) {
+            // Don't leave a partial copy where the object belongs
+            let _ = ::std::fs::remove_file(dest_path);
+            return Err(From::from((e, "Unable to copy file")));
+        }
         Ok(())
     }
     fn retrieve(dest_path: &Path) -> Result<PathBuf,Error> {
         Ok(dest_path.to_path_buf())
     }
 }
+
+// Copy a file into a destination given its full length of disk first
+fn copy_preallocated(src: &Path, dest: &Path, metadata: &::std::fs::Metadata)
+                     -> Result<(), Error>
+{
+    let mut input = File::open(src)
+        .map_err(|e| { (e, "Unable to open file to copy") } )?;
+    let mut output = OpenOptions::new().create(true).write(true).truncate(true).open(dest)
+        .map_err(|e| { (e, "Unable to open/creat new file") } )?;
+    space::preallocate(&output, dest, metadata.len())?;
+    ::std::io::copy(&mut input, &mut output)
+        .and_then(|_| ::std::fs::set_permissions(dest, metadata.permissions()))
+        .map_err(|e| { From::from((e, "Unable to copy file")) } )
+}
+
+// Copy a file with holes, keeping them, or return false if that can't be
+// done here
+fn copy_sparse(src: &Path, dest: &Path, metadata: &::std::fs::Metadata) -> ::std::io::Result<bool> {
+    let input = File::open(src)?;
+    let output = OpenOptions::new().create(true).write(true).truncate(true).open(dest)?;
+    if !sparse::copy(&input, &output, metadata.len(), &mut |_| {})? {
+        return Ok(false);
+    }
+    // As fs::copy() does
+    ::std::fs::set_permissions(dest, metadata.permissions())?;
+    Ok(true)
+}
@@ -0,0 +1,931 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! The `Store` trait, implemented both by the local `FileStore` handle and by
+//! remote clients, so applications can switch deployments without changing
+//! the code that stores and retrieves content.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap,BTreeSet};
+use std::fmt;
+use std::fs::{self,File};
+use std::io::{self,Read};
+use std::net::TcpStream;
+use std::ops::Range;
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::{CompactKey,Config,DiskUsage,Error,FileKey,KeyEncoding,FileMetadata,GcReport,HashAlgorithm,Observer,RepairReport};
+use super::audit::{self,AuditAction,AuditEntry,AuditFilter};
+use super::authtag;
+use super::error::ErrorKind;
+use super::manifest::{self,Manifest};
+use super::merkle::ChunkDigests;
+use super::namespace::Namespace;
+use super::owners;
+use super::pin::{self,Pinned};
+use super::weak;
+use super::quarantine;
+use super::scrub::Scrubber;
+use super::snapshot::{self,Snapshot};
+use super::staged::StagedObject;
+#[cfg(feature = "watch")]
+use super::watch::InboxWatcher;
+use super::transaction::Transaction;
+use super::writelock::{self,Exclusive};
+use super::writer::StoreWriter;
+
+/// The size, reference count and age of a stored object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectStat {
+    /// Length of the content in bytes
+    pub size: u64,
+    /// Number of references currently held
+    pub refcount: u64,
+    /// When the content was first stored, if the store can tell.  Storing
+    /// the same content again only adds a reference, so this stays put.
+    pub stored: Option<SystemTime>,
+    /// When the object was last retrieved, if that has been recorded (see
+    /// `Config::track_access`)
+    pub last_access: Option<SystemTime>,
+}
+
+/// What a delete did, returned by `delete_checked()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteOutcome {
+    /// A reference was dropped, leaving this many.  Zero means the object
+    /// was kept by a pin.
+    RefDecremented(u64),
+    /// The last reference was dropped and the object removed
+    ObjectRemoved,
+    /// There was no reference to drop, as the key was never stored, was
+    /// already deleted, or is held only by pins or weak references
+    NotFound,
+}
+
+/// Operations common to every kind of store.  Keys are portable between
+/// implementations, as all of them key content by its sha224 digest (unless
+/// a local store has been moved to another digest by `rehash_store()`).
+pub trait Store {
+    /// Store data from memory
+    fn store_data(&self, input: &[u8]) -> Result<FileKey, Error>;
+
+    /// Store everything that can be read from `input`
+    fn store_reader(&self, input: &mut dyn Read) -> Result<FileKey, Error>;
+
+    /// Retrieve an object into memory, or `None` if the key is not stored
+    fn retrieve_data(&self, key: &FileKey) -> Result<Option<Vec<u8>>, Error> {
+        match self.retrieve_reader(key)? {
+            Some(mut reader) => {
+                let mut buf: Vec<u8> = Vec::new();
+                reader.read_to_end(&mut buf)
+                    .map_err(|e| { (e, "Unable to read stored content") } )?;
+                Ok(Some(buf))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve an object into `buf`, replacing what it held, and return its
+    /// length.  If the key is not stored the error's `io` is of kind
+    /// `NotFound`.
+    fn retrieve_data_into(&self, key: &FileKey, buf: &mut Vec<u8>) -> Result<usize, Error> {
+        match self.retrieve_reader(key)? {
+            Some(mut reader) => {
+                buf.clear();
+                reader.read_to_end(buf)
+                    .map_err(|e| { From::from((e, "Unable to read stored content")) } )
+            },
+            None => Err(From::from((io::Error::from(io::ErrorKind::NotFound),
+                                    "Key is not stored"))),
+        }
+    }
+
+    /// Stream an object's content, or `None` if the key is not stored
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>;
+
+    /// Drop one reference to an object, deleting it with the last
+    fn delete(&self, key: &FileKey) -> Result<(), Error>;
+
+    /// Size and reference count of an object, or `None` if it is not stored
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error>;
+
+    /// Iterate over the keys of every object
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error>;
+}
+
+/// How two stores differ, returned by `diff()`.  Each list is in key order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreDiff {
+    /// Keys stored in the first store but not the second
+    pub only_in_a: Vec<FileKey>,
+    /// Keys stored in the second store but not the first
+    pub only_in_b: Vec<FileKey>,
+    /// Keys stored in both, with their refcounts in the first and second
+    /// where those differ
+    pub refcounts_differ: Vec<(FileKey, u64, u64)>,
+}
+
+impl StoreDiff {
+    /// Whether the stores hold the same objects with the same refcounts
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.refcounts_differ.is_empty()
+    }
+}
+
+/// Compare the objects held by two stores, of any kind, as to validate a
+/// replica or plan a sync.  The stores are walked one after the other and
+/// shared keys are then stat'ed in each, so changes made meanwhile may show
+/// up as differences.
+pub fn diff<A, B>(a: &A, b: &B) -> Result<StoreDiff, Error>
+    where A: Store + ?Sized, B: Store + ?Sized
+{
+    let keys_a = compact_keys(a)?;
+    let keys_b = compact_keys(b)?;
+    let mut diff = StoreDiff {
+        only_in_a: keys_a.difference(&keys_b).map(CompactKey::to_file_key).collect(),
+        only_in_b: keys_b.difference(&keys_a).map(CompactKey::to_file_key).collect(),
+        refcounts_differ: Vec::new(),
+    };
+    for key in keys_a.intersection(&keys_b) {
+        let key = &key.to_file_key();
+        // Deleted since the walk if missing now
+        match (a.stat(key)?, b.stat(key)?) {
+            (Some(stat_a), Some(stat_b)) => {
+                if stat_a.refcount != stat_b.refcount {
+                    diff.refcounts_differ.push((key.clone(), stat_a.refcount, stat_b.refcount));
+                }
+            },
+            (Some(_), None) => diff.only_in_a.push(key.clone()),
+            (None, Some(_)) => diff.only_in_b.push(key.clone()),
+            (None, None) => {},
+        }
+    }
+    diff.only_in_a.sort();
+    diff.only_in_b.sort();
+    Ok(diff)
+}
+
+// Every key of `store`, held compactly as there may be millions
+fn compact_keys<S: Store + ?Sized>(store: &S) -> Result<BTreeSet<CompactKey>, Error> {
+    let mut keys: BTreeSet<CompactKey> = BTreeSet::new();
+    for key in store.keys()? {
+        let key = key?;
+        match key.compact() {
+            Some(compact) => keys.insert(compact),
+            None => return Err(From::from((io::Error::from(io::ErrorKind::InvalidData),
+                                           &*format!("Store listed malformed key {}", key)))),
+        };
+    }
+    Ok(keys)
+}
+
+/// A handle on a store directory on the local filesystem.  This is
+/// equivalent to passing the storage path to the crate's free functions,
+/// except that changes made through it are reported to its observers.
+///
+/// Handles are `Send` and `Sync`, so one handle (or clones of it) can serve
+/// any number of threads without external locking: changes to each
+/// object's refcount are serialized within the process.
+#[derive(Clone)]
+pub struct FileStore {
+    storage_path: PathBuf,
+    config: Config,
+    read_only: bool,
+    // Shared by clones, and released with the last of them
+    exclusive: Option<Arc<Exclusive>>,
+    observers: Vec<Arc<dyn Observer>>,
+    audit_context: String,
+}
+
+// Keep the guarantee above from being lost by accident
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<FileStore>();
+};
+
+impl FileStore {
+    pub fn new(storage_path: &Path) -> FileStore {
+        FileStore::with_config(storage_path, Config::default())
+    }
+
+    /// A handle that applies `config` to operations through it
+    pub fn with_config(storage_path: &Path, config: Config) -> FileStore {
+        FileStore {
+            storage_path: storage_path.to_path_buf(),
+            config,
+            read_only: false,
+            exclusive: None,
+            observers: Vec::new(),
+            audit_context: String::new(),
+        }
+    }
+
+    /// Create a store at `storage_path`, laying out its shard directories
+    /// and writing the manifest that `open()` looks for, and return a
+    /// handle applying `config`.  The directory is created if need be; if
+    /// it exists it must be empty, or hold a store created before
+    /// manifests, which is adopted.  Fails with `AlreadyExists` if there is
+    /// a store there already.
+    pub fn init(storage_path: &Path, config: Config) -> Result<FileStore, Error> {
+        fs::create_dir_all(storage_path)
+            .map_err(|e| { (e, "Unable to create storage directory") } )?;
+        match manifest::read(storage_path) {
+            Ok(_) => {
+                return Err(From::from((io::Error::from(io::ErrorKind::AlreadyExists),
+                                       "A store already exists there")));
+            },
+            Err(ref e) if e.kind == ErrorKind::NotAStore => {},
+            Err(e) => return Err(e),
+        }
+        if !manifest::holds_only_store_entries(storage_path)? {
+            return Err(From::from((io::Error::from(io::ErrorKind::DirectoryNotEmpty),
+                                   "Storage directory holds files that are not a store's")));
+        }
+        super::create_shards(storage_path)?;
+        // Last, so that an interrupted init isn't taken for a store
+        manifest::write(storage_path, &Manifest::default())?;
+        Ok(FileStore::with_config(storage_path, config))
+    }
+
+    /// Open the store at `storage_path`, which must have been created by
+    /// `init()`.  Fails with `ErrorKind::NotAStore` for any other
+    /// directory, so that a mistaken path isn't filled with shards.
+    ///
+    /// Work left unfinished by writers that crashed is tidied up first, as
+    /// by `recover()`, unless another process has the store open
+    /// exclusively.
+    pub fn open(storage_path: &Path) -> Result<FileStore, Error> {
+        FileStore::open_with_config(storage_path, Config::default())
+    }
+
+    /// As `open()`, returning a handle that applies `config`
+    pub fn open_with_config(storage_path: &Path, config: Config) -> Result<FileStore, Error> {
+        let metadata = fs::metadata(storage_path)
+            .map_err(|e| { (e, "Unable to open storage directory") } )?;
+        if !metadata.is_dir() {
+            return Err(From::from((io::Error::from(io::ErrorKind::NotADirectory),
+                                   "Storage path is not a directory")));
+        }
+        manifest::read(storage_path)?;
+        match super::recover(storage_path) {
+            Ok(report) => log::debug!("Recovered {}: {:?}", storage_path.display(), report),
+            Err(ref e) if e.kind == ErrorKind::Locked => {
+                log::debug!("Skipping recovery of {} held exclusively", storage_path.display());
+            },
+            Err(e) => return Err(e),
+        }
+        Ok(FileStore::with_config(storage_path, config))
+    }
+
+    /// Open an existing store for retrieval only, as for a replica on a
+    /// read-only snapshot or network share.  Stores and deletes through the
+    /// handle fail with `ErrorKind::ReadOnly`.
+    pub fn open_read_only(storage_path: &Path) -> Result<FileStore, Error> {
+        let metadata = fs::metadata(storage_path)
+            .map_err(|e| { (e, "Unable to open storage directory") } )?;
+        if !metadata.is_dir() {
+            return Err(From::from((io::Error::from(io::ErrorKind::NotADirectory),
+                                   "Storage path is not a directory")));
+        }
+        Ok(FileStore {
+            read_only: true,
+            ..FileStore::new(storage_path)
+        })
+    }
+
+    /// Open an existing store as its only writer, as for a migration or a
+    /// `gc()` that must not race other processes.  Until this handle and
+    /// its clones are dropped, other processes can still retrieve, but
+    /// their stores, deletes and other changes fail with
+    /// `ErrorKind::Locked`.  Fails at once with `ErrorKind::Locked` if
+    /// another process already has the store open exclusively or is part
+    /// way through a change.
+    ///
+    /// The lock is advisory, taken on `.lock` in the storage path, and only
+    /// binds processes using this crate.
+    pub fn open_exclusive(storage_path: &Path) -> Result<FileStore, Error> {
+        let metadata = fs::metadata(storage_path)
+            .map_err(|e| { (e, "Unable to open storage directory") } )?;
+        if !metadata.is_dir() {
+            return Err(From::from((io::Error::from(io::ErrorKind::NotADirectory),
+                                   "Storage path is not a directory")));
+        }
+        let exclusive = writelock::exclusive(storage_path)?;
+        Ok(FileStore {
+            exclusive: Some(Arc::new(exclusive)),
+            ..FileStore::new(storage_path)
+        })
+    }
+
+    /// The directory the store lives in
+    pub fn path(&self) -> &Path {
+        &self.storage_path
+    }
+
+    /// The settings applied through this handle
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Whether the handle was opened with `open_read_only()`
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether the handle was opened with `open_exclusive()`
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive.is_some()
+    }
+
+    /// A clone of this handle that records `context` in the audit log with
+    /// each change made through it (see `Config::audit`), such as the user
+    /// or request it was made for
+    pub fn with_audit_context(&self, context: &str) -> FileStore {
+        FileStore {
+            audit_context: context.to_owned(),
+            ..self.clone()
+        }
+    }
+
+    /// Delete objects with no references left, as `gc()` does, logging
+    /// each one removed if the handle keeps an audit log
+    pub fn gc(&self) -> Result<GcReport, Error> {
+        self.check_writable()?;
+        let report = super::maintenance::gc_with_config(&self.storage_path, &self.config)?;
+        for key in &report.objects_removed {
+            self.audit(AuditAction::Gc, key)?;
+        }
+        Ok(report)
+    }
+
+    /// The space on disk taken by the store, as `usage()` gives
+    pub fn usage(&self) -> Result<DiskUsage, Error> {
+        super::usage(&self.storage_path)
+    }
+
+    /// A scrubber to verify the store a little at a time (see the `scrub`
+    /// module)
+    pub fn scrubber(&self) -> Scrubber {
+        Scrubber::new(&self.storage_path)
+    }
+
+    /// A watcher to store files dropped into `inbox` (see the `watch`
+    /// module)
+    #[cfg(feature = "watch")]
+    pub fn inbox_watcher(&self, inbox: &Path) -> InboxWatcher {
+        InboxWatcher::new(self, inbox)
+    }
+
+    /// Heal quarantined and missing objects from `other`, as
+    /// `repair_from()` does
+    pub fn repair_from<S: Store + ?Sized>(&self, other: &S) -> Result<RepairReport, Error> {
+        self.check_writable()?;
+        super::repair::repair(&self.storage_path, &self.config, other)
+    }
+
+    /// Create a copy of the store at `dest` sharing its content, as
+    /// `clone_to()` does, and return a handle on it with this handle's
+    /// configuration
+    pub fn clone_to(&self, dest: &Path) -> Result<FileStore, Error> {
+        super::clone_to(&self.storage_path, dest)?;
+        Ok(FileStore::with_config(dest, self.config.clone()))
+    }
+
+    /// Take a snapshot of the store named `name`, as `snapshot()` does
+    pub fn snapshot(&self, name: &str) -> Result<Snapshot, Error> {
+        self.check_writable()?;
+        snapshot::snapshot(&self.storage_path, name)
+    }
+
+    /// The snapshot named `name`, or `None` if there is none
+    pub fn open_snapshot(&self, name: &str) -> Result<Option<Snapshot>, Error> {
+        snapshot::open_snapshot(&self.storage_path, name)
+    }
+
+    /// The names of the store's snapshots
+    pub fn list_snapshots(&self) -> Result<Vec<String>, Error> {
+        snapshot::list_snapshots(&self.storage_path)
+    }
+
+    /// Put the store back as it was when the snapshot named `name` was
+    /// taken, as `restore_snapshot()` does
+    pub fn restore_snapshot(&self, name: &str) -> Result<bool, Error> {
+        self.check_writable()?;
+        snapshot::restore_snapshot(&self.storage_path, name)
+    }
+
+    /// Delete the snapshot named `name`, releasing its references
+    pub fn delete_snapshot(&self, name: &str) -> Result<bool, Error> {
+        self.check_writable()?;
+        snapshot::delete_snapshot(&self.storage_path, name)
+    }
+
+    /// The entries of the store's audit log that match `filter`, as
+    /// `audit::audit_log()` gives
+    pub fn audit_log(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>, Error> {
+        audit::audit_log(&self.storage_path, filter)
+    }
+
+    /// Begin a transaction, whose stores and deletes take effect together
+    /// when it is committed, or not at all
+    pub fn begin(&self) -> Result<Transaction, Error> {
+        Transaction::begin(self)
+    }
+
+    /// A handle on the collection `name` within this store, sharing this
+    /// handle's settings and observers.  Names may use ASCII letters,
+    /// digits, `-`, `_` and `.`.
+    pub fn namespace(&self, name: &str) -> Result<Namespace, Error> {
+        Namespace::new(self, name)
+    }
+
+    /// The key of the one stored object whose key begins with `prefix`, as
+    /// `resolve_prefix()` gives
+    pub fn resolve_prefix(&self, prefix: &str) -> Result<Option<FileKey>, Error> {
+        super::resolve_prefix(&self.storage_path, prefix)
+    }
+
+    /// Move every object to its key under `algorithm`, as `rehash_store()`
+    /// does, making authenticity tags again for the new keys if the handle
+    /// has the secret
+    pub fn rehash_store(&self, algorithm: HashAlgorithm)
+                        -> Result<BTreeMap<FileKey, FileKey>, Error>
+    {
+        self.check_writable()?;
+        super::rehash::migrate(&self.storage_path, algorithm, self.config.authenticate.as_ref())
+    }
+
+    /// The key that `key`, from before the store was moved to another
+    /// digest, is stored under now, as `translate_key()` gives
+    pub fn translate_key(&self, key: &FileKey) -> Result<Option<FileKey>, Error> {
+        super::translate_key(&self.storage_path, key)
+    }
+
+    // The key to retrieve `key` by: `key` itself, unless the handle accepts
+    // legacy keys, it is one, and nothing is stored under it
+    fn current_key<'a>(&self, key: &'a FileKey) -> Result<Cow<'a, FileKey>, Error> {
+        if self.config.legacy_hash.is_none() || key.algorithm() != self.config.legacy_hash
+            || super::storage_file_path(&self.storage_path, key).exists()
+        {
+            return Ok(Cow::Borrowed(key));
+        }
+        Ok(match super::translate_key(&self.storage_path, key)? {
+            Some(current) => Cow::Owned(current),
+            None => Cow::Borrowed(key),
+        })
+    }
+
+    // Check `key`, open as `file`, against its authenticity tag, if the
+    // handle asks
+    fn authenticate(&self, key: &FileKey, file: &mut File) -> Result<(), Error> {
+        match self.config.authenticate {
+            Some(ref secret) => authtag::check_file(&self.storage_path, secret, key, file),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether an object is stored under `key`, as `exists()` tells
+    pub fn exists(&self, key: &FileKey) -> Result<bool, Error> {
+        let key = &*self.current_key(key)?;
+        super::exists(&self.storage_path, key)
+    }
+
+    /// The MIME type recorded for `key`, as `content_type()` gives
+    pub fn content_type(&self, key: &FileKey) -> Result<Option<String>, Error> {
+        let key = &*self.current_key(key)?;
+        super::content_type(&self.storage_path, key)
+    }
+
+    /// Open stored content for reading, as `retrieve_open()` does
+    pub fn retrieve_open(&self, key: &FileKey) -> Result<File, Error> {
+        let key = &*self.current_key(key)?;
+        let mut file = super::retrieve_open(&self.storage_path, key)?;
+        self.authenticate(key, &mut file)?;
+        self.accessed(key);
+        Ok(file)
+    }
+
+    /// Send stored content to a socket, as `send_to()` does
+    pub fn send_to(&self, key: &FileKey, stream: &TcpStream, range: Option<Range<u64>>)
+                   -> Result<u64, Error>
+    {
+        let file = self.retrieve_open(key)?;
+        super::sendfile::send_range(&file, stream, range)
+    }
+
+    /// The encoding the store gives keys out in, as `key_encoding()` tells
+    pub fn key_encoding(&self) -> Result<KeyEncoding, Error> {
+        super::key_encoding(&self.storage_path)
+    }
+
+    /// Give the store's keys out in `encoding`, as `set_key_encoding()` does
+    pub fn set_key_encoding(&self, encoding: KeyEncoding) -> Result<(), Error> {
+        self.check_writable()?;
+        super::set_key_encoding(&self.storage_path, encoding)
+    }
+
+    /// Whether the store gives keys out behind their algorithm's name, as
+    /// `key_prefixed()` tells
+    pub fn key_prefixed(&self) -> Result<bool, Error> {
+        super::key_prefixed(&self.storage_path)
+    }
+
+    /// Give the store's keys out behind their algorithm's name, or not, as
+    /// `set_key_prefixed()` does
+    pub fn set_key_prefixed(&self, prefixed: bool) -> Result<(), Error> {
+        self.check_writable()?;
+        super::set_key_prefixed(&self.storage_path, prefixed)
+    }
+
+    /// `key` written as the store gives keys out, for showing to users, as
+    /// `encode_key()` does
+    pub fn encode_key(&self, key: &FileKey) -> Result<String, Error> {
+        super::encode_key(&self.storage_path, key)
+    }
+
+    /// The key written as `text` as the store takes keys in (see
+    /// `parse_key()`).  Text written any other way is refused, with an `io`
+    /// of kind `InvalidInput`.
+    pub fn parse_key(&self, text: &str) -> Result<FileKey, Error> {
+        match super::parse_key(&self.storage_path, text)? {
+            Some(key) => Ok(key),
+            None => Err(From::from((io::Error::from(io::ErrorKind::InvalidInput),
+                                    "Key is not written as this store takes keys"))),
+        }
+    }
+
+    /// The chunk digests of a `sha512-tree` key, as `chunk_digests()` gives
+    pub fn chunk_digests(&self, key: &FileKey) -> Result<ChunkDigests, Error> {
+        let key = &*self.current_key(key)?;
+        super::chunk_digests(&self.storage_path, key)
+    }
+
+    /// Read part of stored content, checking the chunks it falls in, as
+    /// `retrieve_range_verified()` does
+    pub fn retrieve_range_verified(&self, key: &FileKey, range: Range<u64>)
+                                   -> Result<Vec<u8>, Error>
+    {
+        let key = &*self.current_key(key)?;
+        let file = self.retrieve_open(key)?;
+        super::merkle::read_range_verified(&self.storage_path, key, file, range)
+    }
+
+    /// Hard link (or copy) stored content into `dest_dir`, as `link_into()`
+    /// does
+    pub fn link_into(&self, key: &FileKey, dest_dir: &Path, name: &str)
+                     -> Result<PathBuf, Error>
+    {
+        let key = &*self.current_key(key)?;
+        let path = super::link_into(&self.storage_path, key, dest_dir, name)?;
+        // Checked through the link (or copy) made, which stays what it is
+        let checked = File::open(&path)
+            .map_err(|e| { Error::from((e, "Unable to open linked file")) } )
+            .and_then(|mut file| self.authenticate(key, &mut file));
+        if let Err(e) = checked {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        self.accessed(key);
+        Ok(path)
+    }
+
+    /// Pin an object so its file stays in place until the returned guard is
+    /// dropped, as `retrieve_pinned()` does
+    pub fn retrieve_pinned(&self, key: &FileKey) -> Result<Option<Pinned>, Error> {
+        let key = &*self.current_key(key)?;
+        let pinned = pin::hold(&self.storage_path, &self.config, key)?;
+        match pinned {
+            Some(ref pinned) => {
+                let mut file = File::open(pinned.path())
+                    .map_err(|e| { Error::from((e, "Unable to open stored file")) } )?;
+                self.authenticate(key, &mut file)?;
+                self.accessed(key);
+            },
+            None => quarantine::check(&self.storage_path, key)?,
+        }
+        Ok(pinned)
+    }
+
+    /// Pin an object until `unpin()` is called, as `pin()` does
+    pub fn pin(&self, key: &FileKey) -> Result<bool, Error> {
+        self.check_writable()?;
+        let key = &*self.current_key(key)?;
+        pin::pin(&self.storage_path, key)
+    }
+
+    /// Remove a pin left by `pin()`, as `unpin()` does, applying the
+    /// handle's settings to the object's removal
+    pub fn unpin(&self, key: &FileKey) -> Result<bool, Error> {
+        self.check_writable()?;
+        let key = &*self.current_key(key)?;
+        pin::remove_pin(&self.storage_path, &self.config, key)
+    }
+
+    /// Whether an object is pinned, as `is_pinned()` tells
+    pub fn is_pinned(&self, key: &FileKey) -> Result<bool, Error> {
+        let key = &*self.current_key(key)?;
+        pin::is_pinned(&self.storage_path, key)
+    }
+
+    /// Drop a reference, as `delete()` does, telling what became of the
+    /// object, as `delete_checked()` does
+    pub fn delete_checked(&self, key: &FileKey) -> Result<DeleteOutcome, Error> {
+        self.check_writable()?;
+        let outcome = super::release_checked(&self.storage_path, &self.config, key)?;
+        self.deleted(key, outcome)?;
+        Ok(outcome)
+    }
+
+    /// Store data with a weak reference rather than a reference, as
+    /// `store_weak()` does
+    pub fn store_weak(&self, input: &[u8]) -> Result<FileKey, Error> {
+        self.check_writable()?;
+        let (key, new_object) = weak::store_weak_new(&self.storage_path, &self.config, input)?;
+        self.stored(&key, new_object)?;
+        Ok(key)
+    }
+
+    /// Take a weak reference on an object, as `add_weak_reference()` does
+    pub fn add_weak_reference(&self, key: &FileKey) -> Result<bool, Error> {
+        self.check_writable()?;
+        let key = &*self.current_key(key)?;
+        weak::add_weak_reference(&self.storage_path, key)
+    }
+
+    /// Drop a weak reference, as `release_weak()` does
+    pub fn release_weak(&self, key: &FileKey) -> Result<bool, Error> {
+        self.check_writable()?;
+        let key = &*self.current_key(key)?;
+        weak::release_weak(&self.storage_path, key)
+    }
+
+    /// The number of weak references on an object, as `weak_count()` tells
+    pub fn weak_count(&self, key: &FileKey) -> Result<u64, Error> {
+        let key = &*self.current_key(key)?;
+        weak::weak_count(&self.storage_path, key)
+    }
+
+    /// Whether a weakly held key is still stored, as `resolves()` tells
+    pub fn resolves(&self, key: &FileKey) -> Result<bool, Error> {
+        let key = &*self.current_key(key)?;
+        weak::resolves(&self.storage_path, key)
+    }
+
+    /// Take a reference on a weakly held object if it is still stored, as
+    /// `upgrade_weak()` does
+    pub fn upgrade_weak(&self, key: &FileKey) -> Result<bool, Error> {
+        self.check_writable()?;
+        let key = &*self.current_key(key)?;
+        weak::take_reference(&self.storage_path, &self.config, key)
+    }
+
+    /// The weakly held keys no longer stored, as `dangling_weak()` lists
+    pub fn dangling_weak(&self) -> Result<Vec<FileKey>, Error> {
+        weak::dangling_weak(&self.storage_path)
+    }
+
+    /// Store data with its reference owned by `owner`, as `store_owned()`
+    /// does
+    pub fn store_owned(&self, input: &[u8], owner: &str) -> Result<FileKey, Error> {
+        self.check_writable()?;
+        let (key, new_object, taken) =
+            owners::store_owned_new(&self.storage_path, &self.config, input, owner)?;
+        if taken {
+            self.stored(&key, new_object)?;
+        }
+        Ok(key)
+    }
+
+    /// Take a reference owned by `owner`, as `add_owned_reference()` does
+    pub fn add_owned_reference(&self, key: &FileKey, owner: &str) -> Result<bool, Error> {
+        self.check_writable()?;
+        let key = &*self.current_key(key)?;
+        owners::add_owned_reference_new(&self.storage_path, &self.config, key, owner)
+    }
+
+    /// Release the reference `owner` holds, as `delete_owned()` does
+    pub fn delete_owned(&self, key: &FileKey, owner: &str) -> Result<bool, Error> {
+        self.check_writable()?;
+        let key = &*self.current_key(key)?;
+        let outcome = owners::delete_owned_new(&self.storage_path, &self.config, key, owner)?;
+        self.deleted(key, outcome)?;
+        Ok(outcome != DeleteOutcome::NotFound)
+    }
+
+    /// The owners of an object's references, as `list_owners()` gives
+    pub fn list_owners(&self, key: &FileKey) -> Result<Vec<String>, Error> {
+        let key = &*self.current_key(key)?;
+        owners::list_owners(&self.storage_path, key)
+    }
+
+    /// A writer to store content through a piece at a time, as
+    /// `store_writer()` gives
+    pub fn writer(&self) -> Result<StoreWriter, Error> {
+        StoreWriter::new(self)
+    }
+
+    /// Read and hash everything from `input` without storing it yet, so
+    /// that it can be checked before `commit()` stores it or `discard()`
+    /// throws it away
+    pub fn stage(&self, input: &mut dyn Read) -> Result<StagedObject, Error> {
+        StagedObject::new(self, input)
+    }
+
+    /// Store content staged by `stage()` on this store, returning its key
+    pub fn commit(&self, staged: StagedObject) -> Result<FileKey, Error> {
+        let key = staged.key().clone();
+        let new_object = staged.commit(self)?;
+        self.stored(&key, new_object)?;
+        Ok(key)
+    }
+
+    /// Throw away content staged by `stage()`
+    pub fn discard(&self, staged: StagedObject) {
+        // The staged file is removed as it is dropped
+        drop(staged);
+    }
+
+    pub(crate) fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(From::from((ErrorKind::ReadOnly, "Store was opened read-only")));
+        }
+        Ok(())
+    }
+
+    /// Register an observer to be told of changes made through this handle.
+    /// Clones made afterwards share it.
+    pub fn add_observer(&mut self, observer: Arc<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    /// Store a copy of a file, as `store_file()` does
+    pub fn store_file(&self, input: &Path) -> Result<FileKey, Error> {
+        self.check_writable()?;
+        let (key, new_object) = super::store_file_new(&self.storage_path, &self.config, input)?;
+        self.stored(&key, new_object)?;
+        Ok(key)
+    }
+
+    /// Store content of known length from `input`, as
+    /// `store_reader_sized()` does
+    pub fn store_reader_sized(&self, input: &mut dyn Read, len: u64) -> Result<FileKey, Error> {
+        self.check_writable()?;
+        let (key, new_object) = super::store_reader_sized_new(&self.storage_path, &self.config,
+                                                              input, len)?;
+        self.stored(&key, new_object)?;
+        Ok(key)
+    }
+
+    /// Store a copy of a file with its attributes, as
+    /// `store_file_with_metadata()` does
+    pub fn store_file_with_metadata(&self, input: &Path)
+                                    -> Result<(FileKey, FileMetadata), Error>
+    {
+        let metadata = FileMetadata::capture(input)?;
+        let key = self.store_file(input)?;
+        Ok((key, metadata))
+    }
+
+    /// Store every regular file under `dir`, as `import_dir()` does
+    pub fn import_dir(&self, dir: &Path, concurrency: usize)
+                      -> Result<BTreeMap<PathBuf, FileKey>, Error>
+    {
+        self.check_writable()?;
+        let (files, new_objects) = super::import::import_dir_new(
+            &self.storage_path, &self.config, dir, concurrency)?;
+        let mut new_objects = new_objects;
+        for key in files.values() {
+            self.stored(key, new_objects.remove(key))?;
+        }
+        Ok(files)
+    }
+
+    // Note a retrieval, if the handle tracks them
+    fn accessed(&self, key: &FileKey) {
+        if self.config.track_access {
+            if let Err(e) = super::access::record(&self.storage_path, key) {
+                log::warn!("Unable to record access to {}: {:?}", key, e);
+            }
+        }
+    }
+
+    // Record a change in the audit log, if the handle keeps one
+    fn audit(&self, action: AuditAction, key: &FileKey) -> Result<(), Error> {
+        if !self.config.audit {
+            return Ok(());
+        }
+        audit::record(&self.storage_path, &self.config, action, key, &self.audit_context)
+    }
+
+    // Audit a delete and tell the observers, if a reference was dropped
+    fn deleted(&self, key: &FileKey, outcome: DeleteOutcome) -> Result<(), Error> {
+        if outcome == DeleteOutcome::NotFound {
+            return Ok(());
+        }
+        self.audit(AuditAction::Delete, key)?;
+        for observer in &self.observers {
+            observer.on_deleted(key, outcome == DeleteOutcome::ObjectRemoved);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn stored(&self, key: &FileKey, new_object: bool) -> Result<(), Error> {
+        self.audit(AuditAction::Store, key)?;
+        for observer in &self.observers {
+            observer.on_stored(key, new_object);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for FileStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FileStore")
+            .field("storage_path", &self.storage_path)
+            .field("config", &self.config)
+            .field("read_only", &self.read_only)
+            .field("exclusive", &self.exclusive.is_some())
+            .field("observers", &self.observers.len())
+            .field("audit_context", &self.audit_context)
+            .finish()
+    }
+}
+
+impl Store for FileStore {
+    fn store_data(&self, input: &[u8]) -> Result<FileKey, Error> {
+        self.check_writable()?;
+        let (key, new_object) = super::store_data_new(&self.storage_path, &self.config, input)?;
+        self.stored(&key, new_object)?;
+        Ok(key)
+    }
+
+    fn store_reader(&self, input: &mut dyn Read) -> Result<FileKey, Error> {
+        self.check_writable()?;
+        let (key, new_object) = super::store_reader_new(&self.storage_path, &self.config, input)?;
+        self.stored(&key, new_object)?;
+        Ok(key)
+    }
+
+    fn retrieve_data(&self, key: &FileKey) -> Result<Option<Vec<u8>>, Error> {
+        let key = &*self.current_key(key)?;
+        let data = super::retrieve_data(&self.storage_path, key);
+        match data {
+            Some(ref data) => {
+                if let Some(ref secret) = self.config.authenticate {
+                    authtag::check_data(&self.storage_path, secret, key, data)?;
+                }
+                self.accessed(key);
+            },
+            None => quarantine::check(&self.storage_path, key)?,
+        }
+        Ok(data)
+    }
+
+    fn retrieve_data_into(&self, key: &FileKey, buf: &mut Vec<u8>) -> Result<usize, Error> {
+        let key = &*self.current_key(key)?;
+        let len = super::retrieve_data_into(&self.storage_path, key, buf)?;
+        if let Some(ref secret) = self.config.authenticate {
+            authtag::check_data(&self.storage_path, secret, key, buf)?;
+        }
+        self.accessed(key);
+        Ok(len)
+    }
+
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>
+    {
+        let key = &*self.current_key(key)?;
+        match File::open(super::storage_file_path(&self.storage_path, key)) {
+            Ok(mut file) => {
+                self.authenticate(key, &mut file)?;
+                self.accessed(key);
+                Ok(Some(Box::new(file)))
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                quarantine::check(&self.storage_path, key)?;
+                Ok(None)
+            },
+            Err(e) => Err(From::from((e, "Unable to open stored file"))),
+        }
+    }
+
+    fn delete(&self, key: &FileKey) -> Result<(), Error> {
+        self.delete_checked(key).map(|_| ())
+    }
+
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error> {
+        let key = &*self.current_key(key)?;
+        match super::stat(&self.storage_path, key) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(ref e) if e.io.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error> {
+        Ok(Box::new(super::keys(&self.storage_path)?))
+    }
+}
@@ -0,0 +1,29 @@
+// Flushing files and directories to disk, for `Durability` settings
+
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use super::Error;
+
+pub(crate) fn sync_file(path: &Path) -> Result<(), Error> {
+    // Windows only flushes handles opened for writing
+    OpenOptions::new().read(true).write(cfg!(windows)).open(path)
+        .and_then(|f| f.sync_all())
+        .map_err(|e| { From::from((e, "Unable to flush stored file")) } )
+}
+
+// Flush a directory, making the creation, renaming and removal of its
+// entries durable
+#[cfg(unix)]
+pub(crate) fn sync_dir(path: &Path) -> Result<(), Error> {
+    OpenOptions::new().read(true).open(path)
+        .and_then(|f| f.sync_all())
+        .map_err(|e| { From::from((e, "Unable to flush directory")) } )
+}
+
+// Directories can't be opened (or flushed) as files here; their entries
+// are flushed along with file metadata
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
@@ -0,0 +1,176 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! String tags on stored objects, and queries by tag.
+//!
+//! Tags are kept under `tags/` in the storage path, as a directory per tag
+//! holding an empty file named after each tagged key.  Tags do not hold
+//! references: an object is deleted with its last reference whatever its
+//! tags, and `gc()` later removes the tags it leaves behind.  Until then,
+//! queries skip keys that are no longer stored.
+
+use std::collections::BTreeSet;
+use std::fs::{self,OpenOptions};
+use std::io;
+use std::path::{Path,PathBuf};
+
+use super::{Error,FileKey};
+use super::quarantine;
+use super::writelock;
+
+fn tags_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("tags")
+}
+
+fn tag_dir(storage_path: &Path, tag: &str) -> Result<PathBuf, Error> {
+    if !super::is_valid_name(tag) {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "malformed tag"),
+                               "Invalid tag")));
+    }
+    Ok(tags_dir(storage_path).join(tag))
+}
+
+fn is_stored(storage_path: &Path, key: &FileKey) -> Result<bool, Error> {
+    match fs::metadata(super::storage_file_path(storage_path, key)) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(From::from((e, "Unable to stat stored file"))),
+    }
+}
+
+/// Attach `tag` to the stored object `key`.  Tagging an object again with
+/// the same tag has no effect.
+///
+/// Tags may use ASCII letters, digits, `-`, `_` and `.`.
+pub fn tag(storage_path: &Path, key: &FileKey, tag: &str) -> Result<(), Error>
+{
+    let dir = tag_dir(storage_path, tag)?;
+    let _writing = writelock::begin(storage_path)?;
+    if !key.is_well_formed() {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "malformed key"),
+                               "Invalid key")));
+    }
+    if !is_stored(storage_path, key)? {
+        return Err(From::from((io::Error::from(io::ErrorKind::NotFound),
+                               "Unable to find stored file")));
+    }
+    fs::create_dir_all(&dir)
+        .map_err(|e| { (e, "Unable to create tag directory") } )?;
+    OpenOptions::new().write(true).create(true).truncate(false).open(dir.join(&key.0))
+        .map_err(|e| { (e, "Unable to write tag") } )?;
+    Ok(())
+}
+
+/// Remove `tag` from `key`, returning whether it had the tag
+pub fn untag(storage_path: &Path, key: &FileKey, tag: &str) -> Result<bool, Error>
+{
+    let dir = tag_dir(storage_path, tag)?;
+    let _writing = writelock::begin(storage_path)?;
+    if !key.is_well_formed() {
+        return Ok(false);
+    }
+    match fs::remove_file(dir.join(&key.0)) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(From::from((e, "Unable to remove tag"))),
+    }
+    // Fails, harmlessly, while other keys have the tag
+    let _ = fs::remove_dir(&dir);
+    Ok(true)
+}
+
+/// The stored objects tagged with `tag`
+pub fn tagged(storage_path: &Path, tag: &str) -> Result<Vec<FileKey>, Error>
+{
+    let mut keys: Vec<FileKey> = Vec::new();
+    for key in tag_entries(&tag_dir(storage_path, tag)?)? {
+        if is_stored(storage_path, &key)? {
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+/// The tags on `key`
+pub fn tags_of(storage_path: &Path, key: &FileKey) -> Result<BTreeSet<String>, Error>
+{
+    let mut tags: BTreeSet<String> = BTreeSet::new();
+    if !key.is_well_formed() {
+        return Ok(tags);
+    }
+    for tag in list_tags(storage_path)? {
+        if tags_dir(storage_path).join(&tag).join(&key.0).exists() {
+            tags.insert(tag);
+        }
+    }
+    Ok(tags)
+}
+
+/// Every tag in use in the store
+pub fn list_tags(storage_path: &Path) -> Result<BTreeSet<String>, Error>
+{
+    let mut tags: BTreeSet<String> = BTreeSet::new();
+    let entries = match fs::read_dir(tags_dir(storage_path)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(tags),
+        Err(e) => return Err(From::from((e, "Unable to read tags directory"))),
+    };
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read tags directory") } )?;
+        if let Ok(name) = entry.file_name().into_string() {
+            if super::is_valid_name(&name) {
+                tags.insert(name);
+            }
+        }
+    }
+    Ok(tags)
+}
+
+// The keys in a tag directory, stored or not
+fn tag_entries(dir: &Path) -> Result<Vec<FileKey>, Error> {
+    let mut keys: Vec<FileKey> = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(keys),
+        Err(e) => return Err(From::from((e, "Unable to read tag directory"))),
+    };
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read tag directory") } )?;
+        if let Ok(name) = entry.file_name().into_string() {
+            let key = FileKey(name);
+            if key.is_well_formed() {
+                keys.push(key);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+// Move the tags of `old` to `new`, for `rehash_store()`
+pub(crate) fn rename_key(storage_path: &Path, old: &FileKey, new: &FileKey) -> Result<(), Error> {
+    for tag in tags_of(storage_path, old)? {
+        let dir = tags_dir(storage_path).join(&tag);
+        fs::rename(dir.join(&old.0), dir.join(&new.0))
+            .map_err(|e| { (e, "Unable to rename tag") } )?;
+    }
+    Ok(())
+}
+
+// Remove the tags of objects that are no longer stored, for `gc()`
+pub(crate) fn prune(storage_path: &Path) -> Result<Vec<(FileKey, String)>, Error> {
+    let mut removed: Vec<(FileKey, String)> = Vec::new();
+    for tag in list_tags(storage_path)? {
+        let dir = tags_dir(storage_path).join(&tag);
+        for key in tag_entries(&dir)? {
+            if !is_stored(storage_path, &key)? && !quarantine::is_quarantined(storage_path, &key) {
+                fs::remove_file(dir.join(&key.0))
+                    .map_err(|e| { (e, "Unable to remove tag") } )?;
+                removed.push((key, tag.clone()));
+            }
+        }
+        let _ = fs::remove_dir(&dir);
+    }
+    Ok(removed)
+}
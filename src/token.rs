@@ -0,0 +1,145 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Signed, expiring tokens granting temporary access to one stored object
+//! (or a byte range of it), so that a server can hand out download links
+//! without exposing keys that would grant access forever.
+//!
+//! A token is the key, the expiry time in seconds since the Unix epoch,
+//! optionally the range as `start-end`, and an HMAC-SHA256 of those made
+//! with a secret known only to the servers, joined by `.`, e.g.
+//! `<key>.1700000000.0-1024.<hex signature>`.  Tokens are safe to put in
+//! URLs.  Only servers holding the secret can mint tokens, and any of them
+//! can check one; nothing is recorded in the store, so tokens cannot be
+//! revoked other than by changing the secret.
+
+use std::fmt;
+use std::ops::Range;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac,MacResult};
+use crypto::sha2::Sha256;
+
+use super::{Error,FileKey};
+use super::error::ErrorKind;
+
+/// What a valid token grants
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    /// The object that may be retrieved
+    pub key: FileKey,
+    /// When the grant ends
+    pub expires: SystemTime,
+    /// The bytes of the object that may be retrieved, or `None` for all
+    pub range: Option<Range<u64>>,
+}
+
+/// Mints and checks tokens with a secret
+#[derive(Clone)]
+pub struct TokenSigner {
+    secret: Vec<u8>,
+}
+
+// Keeps the secret out of logs
+impl fmt::Debug for TokenSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TokenSigner").finish_non_exhaustive()
+    }
+}
+
+impl TokenSigner {
+    /// A signer using `secret`, which should be at least 32 random bytes
+    /// and shared only by the servers that mint or check tokens
+    pub fn new(secret: &[u8]) -> TokenSigner {
+        TokenSigner { secret: secret.to_vec() }
+    }
+
+    /// A token granting access to `key` for `valid_for` from now
+    pub fn mint(&self, key: &FileKey, valid_for: Duration) -> String {
+        self.mint_grant(&Grant {
+            key: key.clone(),
+            expires: SystemTime::now() + valid_for,
+            range: None,
+        })
+    }
+
+    /// A token granting what `grant` describes.  The expiry is kept to
+    /// the second, rounded down.
+    pub fn mint_grant(&self, grant: &Grant) -> String {
+        let expires = grant.expires.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut payload = format!("{}.{}", grant.key, expires);
+        if let Some(ref range) = grant.range {
+            payload.push_str(&format!(".{}-{}", range.start, range.end));
+        }
+        let signature = hex(self.sign(&payload).code());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// What `token` grants, failing with `ErrorKind::InvalidToken` if it
+    /// is malformed or was not signed with this signer's secret, and with
+    /// `ErrorKind::TokenExpired` if its time is up
+    pub fn verify(&self, token: &str) -> Result<Grant, Error> {
+        self.verify_at(token, SystemTime::now())
+    }
+
+    /// As `verify()`, taking the time to check the expiry against
+    pub fn verify_at(&self, token: &str, now: SystemTime) -> Result<Grant, Error> {
+        let (payload, signature) = token.rsplit_once('.')
+            .ok_or_else(invalid)?;
+        let signature = unhex(signature).ok_or_else(invalid)?;
+        // Compared in constant time
+        if self.sign(payload) != MacResult::new_from_owned(signature) {
+            return Err(invalid());
+        }
+
+        let mut fields = payload.split('.');
+        let key = FileKey(fields.next().unwrap_or("").to_owned());
+        let expires = fields.next()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or_else(invalid)?;
+        let range = match fields.next() {
+            Some(range) => {
+                let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+                match (start.parse::<u64>(), end.parse::<u64>()) {
+                    (Ok(start), Ok(end)) if start <= end => Some(start..end),
+                    _ => return Err(invalid()),
+                }
+            },
+            None => None,
+        };
+        if !key.is_well_formed() || fields.next().is_some() {
+            return Err(invalid());
+        }
+        let expires = UNIX_EPOCH + Duration::from_secs(expires);
+        if now >= expires {
+            return Err(From::from((ErrorKind::TokenExpired, "Token has expired")));
+        }
+        Ok(Grant { key, expires, range })
+    }
+
+    fn sign(&self, payload: &str) -> MacResult {
+        let mut mac = Hmac::new(Sha256::new(), &self.secret);
+        mac.input(payload.as_bytes());
+        mac.result()
+    }
+}
+
+fn invalid() -> Error {
+    From::from((ErrorKind::InvalidToken, "Token is not valid"))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,107 @@
+// Spans and events for store operations.  With the `tracing` feature each
+// operation runs inside a span carrying its key and byte count, and ends with
+// an event giving its duration.  Without the feature this all compiles away.
+
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+use std::fmt::Debug;
+
+use super::FileKey;
+
+/// Start an `Operation` with a span named `$name`.  Any extra field names
+/// are declared on the span so they can be recorded later.
+#[cfg(feature = "tracing")]
+macro_rules! operation {
+    ($name:literal $(, $field:ident)*) => {
+        $crate::trace::Operation::new(tracing::info_span!(
+            $name,
+            key = tracing::field::Empty,
+            bytes = tracing::field::Empty
+            $(, $field = tracing::field::Empty)*))
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! operation {
+    ($name:literal $(, $field:ident)*) => {
+        $crate::trace::Operation::new()
+    }
+}
+
+pub(crate) struct Operation {
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+    #[cfg(feature = "tracing")]
+    start: Instant,
+}
+
+#[cfg(feature = "tracing")]
+impl Operation {
+    pub fn new(span: tracing::Span) -> Operation {
+        Operation { span: span.entered(), start: Instant::now() }
+    }
+
+    pub fn key(&self, key: &FileKey) {
+        self.span.record("key", tracing::field::display(key));
+    }
+
+    pub fn record(&self, field: &'static str, value: u64) {
+        self.span.record(field, value);
+    }
+
+    /// End the operation, reporting its outcome
+    pub fn finish<T, E: Debug>(self, result: Result<T, E>) -> Result<T, E> {
+        let elapsed_us = self.start.elapsed().as_micros() as u64;
+        match result {
+            Ok(_) => tracing::debug!(elapsed_us, "done"),
+            Err(ref e) => tracing::warn!(elapsed_us, error = ?e, "failed"),
+        }
+        result
+    }
+
+    /// End a store operation, recording the key and whether it was new first
+    pub fn finish_stored<E: Debug>(self, result: Result<(FileKey, bool), E>)
+                                   -> Result<(FileKey, bool), E>
+    {
+        if let Ok((ref key, new_object)) = result {
+            self.key(key);
+            self.span.record("new_object", new_object);
+        }
+        self.finish(result)
+    }
+
+    /// End an operation whose result is only present or absent
+    pub fn finish_option<T>(self, result: Option<T>) -> Option<T> {
+        let elapsed_us = self.start.elapsed().as_micros() as u64;
+        match result {
+            Some(_) => tracing::debug!(elapsed_us, "done"),
+            None => tracing::debug!(elapsed_us, "not found"),
+        }
+        result
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Operation {
+    pub fn new() -> Operation {
+        Operation {}
+    }
+
+    pub fn key(&self, _key: &FileKey) {}
+
+    pub fn record(&self, _field: &'static str, _value: u64) {}
+
+    pub fn finish<T, E: Debug>(self, result: Result<T, E>) -> Result<T, E> {
+        result
+    }
+
+    pub fn finish_stored<E: Debug>(self, result: Result<(FileKey, bool), E>)
+                                   -> Result<(FileKey, bool), E>
+    {
+        result
+    }
+
+    pub fn finish_option<T>(self, result: Option<T>) -> Option<T> {
+        result
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Batches of stores and deletes that take effect together or not at all.
+//!
+//! A `Transaction` (from `FileStore::begin()`) stores content as it is
+//! given, so that its keys can be used at once, but holds deletes back
+//! until `commit()`.  `rollback()`, or dropping the transaction without
+//! committing, releases the references its stores took, leaving the store
+//! as it was.  Objects stored by a transaction are visible to other
+//! handles before it commits, as any stored object is.
+//!
+//! Each transaction is recorded under `transactions/` in the storage path
+//! as it goes: the keys stored, then at commit the keys to delete, and
+//! each reference as it is about to be released.  If the process dies, a
+//! later `recover()` finishes off the deletes of a committed transaction,
+//! and otherwise rolls it back.  A reference is marked released before it
+//! is, so a crash at the wrong moment can leak a reference but never drop
+//! one twice.
+
+use std::collections::HashMap;
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,Read,Write};
+use std::path::{Path,PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+use super::{Config,Durability,Error,FileKey,get_refcount,spool};
+use super::error::ErrorKind;
+use super::store::{FileStore,Store};
+
+static TRANSACTION_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+const HEADER: &str = "filestore-transaction 1";
+
+fn transactions_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("transactions")
+}
+
+/// A batch of stores and deletes, committed or rolled back as one
+pub struct Transaction {
+    store: FileStore,
+    record: File,
+    record_path: PathBuf,
+    stored: Vec<FileKey>,
+    deletes: Vec<FileKey>,
+    finished: bool,
+}
+
+impl Transaction {
+    pub(crate) fn begin(store: &FileStore) -> Result<Transaction, Error> {
+        store.check_writable()?;
+        let dir = transactions_dir(store.path());
+        fs::create_dir_all(&dir)
+            .map_err(|e| { (e, "Unable to create transactions directory") } )?;
+        loop {
+            let path = dir.join(format!(
+                "{}-{}", process::id(), TRANSACTION_COUNTER.fetch_add(1, Ordering::SeqCst)));
+            let record = match OpenOptions::new().append(true).create_new(true).open(&path) {
+                Ok(record) => record,
+                // Left over from an earlier process with the same pid
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(From::from((e, "Unable to create transaction record"))),
+            };
+            let mut transaction = Transaction {
+                store: store.clone(),
+                record,
+                record_path: path,
+                stored: Vec::new(),
+                deletes: Vec::new(),
+                finished: false,
+            };
+            transaction.append(&format!("{}\n", HEADER))?;
+            return Ok(transaction);
+        }
+    }
+
+    /// The store the transaction is in
+    pub fn store(&self) -> &FileStore {
+        &self.store
+    }
+
+    /// Store data from memory, as `FileStore::store_data()` does
+    pub fn store_data(&mut self, input: &[u8]) -> Result<FileKey, Error> {
+        let key = self.store.store_data(input)?;
+        self.stored(key)
+    }
+
+    /// Store everything that can be read from `input`
+    pub fn store_reader(&mut self, input: &mut dyn Read) -> Result<FileKey, Error> {
+        let key = self.store.store_reader(input)?;
+        self.stored(key)
+    }
+
+    /// Store the file at `input`, as `FileStore::store_file()` does
+    pub fn store_file(&mut self, input: &Path) -> Result<FileKey, Error> {
+        let key = self.store.store_file(input)?;
+        self.stored(key)
+    }
+
+    fn stored(&mut self, key: FileKey) -> Result<FileKey, Error> {
+        if let Err(e) = self.append(&format!("store {}\n", key)) {
+            // Not recorded, so given back now
+            if let Err(e) = self.store.delete(&key) {
+                log::warn!("Unable to release {} after failed store: {:?}", key, e);
+            }
+            return Err(e);
+        }
+        self.stored.push(key.clone());
+        Ok(key)
+    }
+
+    /// Drop one reference to `key` when the transaction commits.  Deleting
+    /// a key more times than it has references fails the commit.
+    pub fn delete(&mut self, key: &FileKey) -> Result<(), Error> {
+        if !key.is_well_formed() {
+            return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "malformed key"),
+                                   "Invalid key")));
+        }
+        self.deletes.push(key.clone());
+        Ok(())
+    }
+
+    /// Apply the deletes, making the whole transaction take effect.  Fails,
+    /// rolling the transaction back, if any key to delete doesn't have the
+    /// references to drop.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let storage_path = self.store.path().to_path_buf();
+        let mut wanted: HashMap<&FileKey, u64> = HashMap::new();
+        for key in &self.deletes {
+            *wanted.entry(key).or_insert(0) += 1;
+        }
+        for (key, count) in wanted {
+            if get_refcount(&storage_path, key)? < count {
+                let e = Error::from((io::Error::from(io::ErrorKind::NotFound),
+                                     &*format!("Not enough references to {} to delete", key)));
+                self.roll_back()?;
+                return Err(e);
+            }
+        }
+
+        // The commit point: once the closing `commit` line is written the
+        // deletes are finished off even if this process dies
+        let mut commit = String::new();
+        for key in &self.deletes {
+            commit.push_str(&format!("delete {}\n", key));
+        }
+        commit.push_str("commit\n");
+        self.append(&commit)?;
+        self.finished = true;
+        for key in std::mem::take(&mut self.deletes) {
+            self.append(&format!("release {}\n", key))?;
+            self.store.delete(&key)?;
+        }
+        self.remove_record()
+    }
+
+    /// Release the references taken by the transaction's stores and forget
+    /// its deletes, leaving the store as it was
+    pub fn rollback(mut self) -> Result<(), Error> {
+        self.roll_back()
+    }
+
+    fn roll_back(&mut self) -> Result<(), Error> {
+        self.finished = true;
+        self.deletes.clear();
+        while let Some(key) = self.stored.pop() {
+            self.append(&format!("release {}\n", key))?;
+            self.store.delete(&key)?;
+        }
+        self.remove_record()
+    }
+
+    // Add `lines` to the record in one write, flushed if the store's
+    // configuration asks
+    fn append(&mut self, lines: &str) -> Result<(), Error> {
+        self.record.write_all(lines.as_bytes())
+            .map_err(|e| { (e, "Unable to write transaction record") } )?;
+        if self.store.config().durability >= Durability::FlushData {
+            self.record.sync_data()
+                .map_err(|e| { (e, "Unable to flush transaction record") } )?;
+        }
+        Ok(())
+    }
+
+    fn remove_record(&self) -> Result<(), Error> {
+        fs::remove_file(&self.record_path)
+            .map_err(|e| { From::from((e, "Unable to remove transaction record")) } )
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Err(e) = self.roll_back() {
+            log::warn!("Unable to roll back transaction: {:?}", e);
+        }
+    }
+}
+
+/// Finish off the committed transactions of processes that are no longer
+/// running, and roll back the rest, returning their records' paths
+pub(crate) fn replay(storage_path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut resolved: Vec<PathBuf> = Vec::new();
+    let entries = match fs::read_dir(transactions_dir(storage_path)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(resolved),
+        Err(e) => return Err(From::from((e, "Unable to read transactions directory"))),
+    };
+    let config = Config::default();
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read transactions directory") } )?;
+        let pid = match entry.file_name().to_str()
+            .and_then(|n| n.split_once('-'))
+            .and_then(|(pid, _)| pid.parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if spool::is_running(pid) {
+            continue;
+        }
+        let path = entry.path();
+        for key in unreleased(&path)? {
+            match super::release(storage_path, &config, &key) {
+                Ok(_) => {},
+                // Left for the object's own repair
+                Err(ref e) if e.kind == ErrorKind::CorruptRefcount => {
+                    log::warn!("Unable to release {} for transaction {}: {:?}",
+                               key, path.display(), e);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        fs::remove_file(&path)
+            .map_err(|e| { (e, "Unable to remove transaction record") } )?;
+        resolved.push(path);
+    }
+    Ok(resolved)
+}
+
+// The references a dead transaction still needs released: its deletes if
+// it committed, and otherwise its stores, less those marked released
+fn unreleased(path: &Path) -> Result<Vec<FileKey>, Error> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| { (e, "Unable to read transaction record") } )?;
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return Ok(Vec::new());
+    }
+    let mut stored: Vec<FileKey> = Vec::new();
+    let mut deletes: Vec<FileKey> = Vec::new();
+    let mut released: Vec<FileKey> = Vec::new();
+    let mut committed = false;
+    for line in lines {
+        let (action, key) = match line.split_once(' ') {
+            Some((action, key)) => (action, FileKey(key.to_owned())),
+            // Releases since were of deletes
+            None if line == "commit" => {
+                committed = true;
+                released.clear();
+                continue;
+            },
+            None => continue,
+        };
+        if !key.is_well_formed() {
+            // Cut short as it was written
+            continue;
+        }
+        match action {
+            "store" => stored.push(key),
+            "delete" => deletes.push(key),
+            "release" => released.push(key),
+            _ => {},
+        }
+    }
+    let mut pending = match committed {
+        true => deletes,
+        false => stored,
+    };
+    for key in released {
+        if let Some(i) = pending.iter().position(|k| *k == key) {
+            pending.remove(i);
+        }
+    }
+    Ok(pending)
+}
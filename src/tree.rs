@@ -0,0 +1,365 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Whole directory trees stored as a single logical object.
+//!
+//! `store_tree()` stores every file in a tree (deduplicated like any other
+//! content) and then stores a manifest listing each path, its permission bits
+//! and, for files, its key.  The manifest's key stands for the whole tree and
+//! is given to `retrieve_tree()` to materialize it again.  The manifest holds
+//! a reference on each file, which `delete_tree()` releases.
+//!
+//! `store_tree_with_metadata()` also records each entry's modification time
+//! and ownership, which `retrieve_tree()` then restores, for backups.
+//!
+//! The manifest is text: a `filestore-tree 1` header, then one line per
+//! entry of the form `<f|d> <octal mode> <key or -> <path>`, where the path
+//! uses `/` separators with `%`, control and non-ASCII bytes written as `%XX`.
+//! Manifests with metadata have a `filestore-tree 2` header and lines of the
+//! form `<f|d> <octal mode> <mtime> <uid:gid> <key or -> <path>`, the mtime
+//! being `<seconds>.<nanoseconds>` since the Unix epoch, and either of those
+//! fields `-` where it is unknown.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Component,Path,PathBuf};
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use super::{Config,Error,FileKey};
+use super::meta::FileMetadata;
+
+const HEADER: &str = "filestore-tree 1";
+const HEADER_METADATA: &str = "filestore-tree 2";
+
+/// What a tree entry is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeEntryKind {
+    /// A directory
+    Dir,
+    /// A file, with the key of its content
+    File(FileKey),
+}
+
+/// One path recorded in a tree manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    /// Path relative to the root of the tree
+    pub path: PathBuf,
+    /// Permission bits (`0o7777` mask on Unix)
+    pub mode: u32,
+    /// Modification time, if the manifest records metadata
+    pub mtime: Option<SystemTime>,
+    /// Owning user and group ids, if the manifest records metadata
+    pub owner: Option<(u32, u32)>,
+    pub kind: TreeEntryKind,
+}
+
+/// Store the tree rooted at `dir`, returning the key of its manifest.
+///
+/// Files are stored using up to `concurrency` threads.  Symbolic links and
+/// special files are skipped.
+pub fn store_tree(storage_path: &Path, dir: &Path, concurrency: usize)
+                  -> Result<FileKey, Error>
+{
+    store_tree_as(storage_path, dir, concurrency, false)
+}
+
+/// Store a tree as `store_tree()` does, also recording the modification
+/// time and ownership of every entry for `retrieve_tree()` to restore.
+/// Trees that differ only in those get different keys.
+pub fn store_tree_with_metadata(storage_path: &Path, dir: &Path, concurrency: usize)
+                                -> Result<FileKey, Error>
+{
+    store_tree_as(storage_path, dir, concurrency, true)
+}
+
+fn store_tree_as(storage_path: &Path, dir: &Path, concurrency: usize, with_metadata: bool)
+                 -> Result<FileKey, Error>
+{
+    let mut entries: Vec<(PathBuf, FileMetadata, bool)> = Vec::new();
+    walk(dir, Path::new(""), &mut entries)?;
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let files: Vec<PathBuf> = entries.iter()
+        .filter(|e| e.2)
+        .map(|e| dir.join(&e.0))
+        .collect();
+    let (keys, _) = super::import::import_files(storage_path, &Config::default(), &files,
+                                                concurrency)?;
+
+    let mut file_keys = keys.iter();
+    let mut manifest = String::from(if with_metadata { HEADER_METADATA } else { HEADER });
+    manifest.push('\n');
+    for (path, metadata, is_file) in &entries {
+        let key = if *is_file {
+            file_keys.next().expect("one key per file").0.clone()
+        } else {
+            "-".to_owned()
+        };
+        manifest.push_str(&format!("{} {:o} ", if *is_file { "f" } else { "d" },
+                                   metadata.mode));
+        if with_metadata {
+            manifest.push_str(&format!("{} {} ", encode_mtime(metadata.mtime),
+                                       encode_owner(metadata.owner)));
+        }
+        manifest.push_str(&format!("{} {}\n", key, encode_path(path)?));
+    }
+
+    match super::store_data(storage_path, &manifest.into_bytes()) {
+        Ok(key) => Ok(key),
+        Err(e) => {
+            for key in &keys {
+                if let Err(e) = super::delete(storage_path, key) {
+                    log::warn!("Unable to release {} after failed tree store: {:?}", key, e);
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Read the entries of a tree manifest, parents before children
+pub fn read_tree(storage_path: &Path, key: &FileKey) -> Result<Vec<TreeEntry>, Error>
+{
+    let data = match super::retrieve_data(storage_path, key) {
+        Some(d) => d,
+        None => return Err(From::from((io::Error::from(io::ErrorKind::NotFound),
+                                       "Tree manifest not found"))),
+    };
+    let text = String::from_utf8(data)
+        .map_err(|_| invalid("Tree manifest is not text"))?;
+    let mut lines = text.lines();
+    let with_metadata = match lines.next() {
+        Some(HEADER) => false,
+        Some(HEADER_METADATA) => true,
+        _ => return Err(invalid("Not a tree manifest")),
+    };
+
+    let mut entries: Vec<TreeEntry> = Vec::new();
+    for line in lines {
+        let mut fields = line.splitn(if with_metadata { 6 } else { 4 }, ' ');
+        let (kind, mode) = match (fields.next(), fields.next()) {
+            (Some(k), Some(m)) => (k, m),
+            _ => return Err(invalid("Malformed tree manifest entry")),
+        };
+        let (mtime, owner) = match with_metadata {
+            true => match (fields.next(), fields.next()) {
+                (Some(t), Some(o)) => (decode_mtime(t)?, decode_owner(o)?),
+                _ => return Err(invalid("Malformed tree manifest entry")),
+            },
+            false => (None, None),
+        };
+        let (key, path) = match (fields.next(), fields.next()) {
+            (Some(f), Some(p)) => (f, p),
+            _ => return Err(invalid("Malformed tree manifest entry")),
+        };
+        let mode = u32::from_str_radix(mode, 8)
+            .map_err(|_| invalid("Malformed mode in tree manifest"))?;
+        let kind = match kind {
+            "d" => TreeEntryKind::Dir,
+            "f" => {
+                let key = FileKey(key.to_owned());
+                if !key.is_well_formed() {
+                    return Err(invalid("Malformed key in tree manifest"));
+                }
+                TreeEntryKind::File(key)
+            },
+            _ => return Err(invalid("Unknown entry type in tree manifest")),
+        };
+        entries.push(TreeEntry { path: decode_path(path)?, mode, mtime, owner, kind });
+    }
+    Ok(entries)
+}
+
+/// Recreate the tree whose manifest has `key` at `dest`, which is created
+/// if needed.  Files already present at `dest` are overwritten.
+pub fn retrieve_tree(storage_path: &Path, key: &FileKey, dest: &Path) -> Result<(), Error>
+{
+    let entries = read_tree(storage_path, key)?;
+    fs::create_dir_all(dest)
+        .map_err(|e| { (e, "Unable to create tree destination") } )?;
+
+    for entry in &entries {
+        let path = dest.join(&entry.path);
+        match entry.kind {
+            TreeEntryKind::Dir => {
+                if let Err(e) = fs::create_dir(&path) {
+                    if e.kind() != io::ErrorKind::AlreadyExists {
+                        return Err(From::from((e, "Unable to create tree directory")));
+                    }
+                }
+            },
+            TreeEntryKind::File(ref key) => {
+                // Replace rather than truncate, as the old file may be read-only
+                if let Err(e) = fs::remove_file(&path) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(From::from((e, "Unable to replace tree file")));
+                    }
+                }
+                super::retrieve_to(storage_path, key, &path)?;
+                entry.metadata().apply(&path)?;
+            },
+        }
+    }
+
+    // Directory attributes last (deepest first), in case they deny writes,
+    // and as adding entries changes a directory's modification time
+    for entry in entries.iter().rev() {
+        if entry.kind == TreeEntryKind::Dir {
+            entry.metadata().apply(&dest.join(&entry.path))?;
+        }
+    }
+    Ok(())
+}
+
+impl TreeEntry {
+    fn metadata(&self) -> FileMetadata {
+        FileMetadata { mode: self.mode, mtime: self.mtime, owner: self.owner }
+    }
+}
+
+/// Release a stored tree: the manifest's reference on each file, and then
+/// one reference on the manifest itself
+pub fn delete_tree(storage_path: &Path, key: &FileKey) -> Result<(), Error>
+{
+    for entry in read_tree(storage_path, key)? {
+        if let TreeEntryKind::File(ref file_key) = entry.kind {
+            super::delete(storage_path, file_key)?;
+        }
+    }
+    super::delete(storage_path, key)
+}
+
+fn invalid(message: &str) -> Error {
+    From::from((io::Error::from(io::ErrorKind::InvalidData), message))
+}
+
+// Collect (relative path, metadata, is_file) for everything under `dir`
+fn walk(dir: &Path, relative: &Path, entries: &mut Vec<(PathBuf, FileMetadata, bool)>)
+        -> Result<(), Error>
+{
+    for entry in fs::read_dir(dir)
+        .map_err(|e| { (e, "Unable to read tree directory") } )?
+    {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read tree directory") } )?;
+        let metadata = fs::symlink_metadata(entry.path())
+            .map_err(|e| { (e, "Unable to stat tree entry") } )?;
+        let path = relative.join(entry.file_name());
+        if metadata.is_dir() {
+            entries.push((path.clone(), FileMetadata::from_metadata(&metadata), false));
+            walk(&entry.path(), &path, entries)?;
+        } else if metadata.is_file() {
+            entries.push((path, FileMetadata::from_metadata(&metadata), true));
+        }
+    }
+    Ok(())
+}
+
+fn encode_mtime(mtime: Option<SystemTime>) -> String {
+    match mtime.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        Some(d) => format!("{}.{:09}", d.as_secs(), d.subsec_nanos()),
+        None => "-".to_owned(),
+    }
+}
+
+fn decode_mtime(field: &str) -> Result<Option<SystemTime>, Error> {
+    if field == "-" {
+        return Ok(None);
+    }
+    let (secs, nanos) = field.split_once('.')
+        .and_then(|(s, n)| Some((s.parse::<u64>().ok()?, n.parse::<u32>().ok()?)))
+        .filter(|&(_, nanos)| nanos < 1_000_000_000)
+        .ok_or_else(|| invalid("Malformed time in tree manifest"))?;
+    Ok(UNIX_EPOCH.checked_add(Duration::new(secs, nanos)))
+}
+
+fn encode_owner(owner: Option<(u32, u32)>) -> String {
+    match owner {
+        Some((uid, gid)) => format!("{}:{}", uid, gid),
+        None => "-".to_owned(),
+    }
+}
+
+fn decode_owner(field: &str) -> Result<Option<(u32, u32)>, Error> {
+    if field == "-" {
+        return Ok(None);
+    }
+    field.split_once(':')
+        .and_then(|(u, g)| Some((u.parse::<u32>().ok()?, g.parse::<u32>().ok()?)))
+        .map(Some)
+        .ok_or_else(|| invalid("Malformed owner in tree manifest"))
+}
+
+#[cfg(unix)]
+fn name_bytes(name: &std::ffi::OsStr) -> Result<Vec<u8>, Error> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(name.as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn name_bytes(name: &std::ffi::OsStr) -> Result<Vec<u8>, Error> {
+    match name.to_str() {
+        Some(s) => Ok(s.as_bytes().to_vec()),
+        None => Err(invalid("Tree path is not valid Unicode")),
+    }
+}
+
+#[cfg(unix)]
+fn name_from_bytes(bytes: Vec<u8>) -> Result<OsString, Error> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn name_from_bytes(bytes: Vec<u8>) -> Result<OsString, Error> {
+    String::from_utf8(bytes)
+        .map(OsString::from)
+        .map_err(|_| invalid("Tree path is not valid Unicode"))
+}
+
+fn encode_path(path: &Path) -> Result<String, Error> {
+    let mut out = String::new();
+    for component in path.components() {
+        if !out.is_empty() {
+            out.push('/');
+        }
+        for b in name_bytes(component.as_os_str())? {
+            if b == b'%' || !(0x20..0x7f).contains(&b) {
+                out.push_str(&format!("%{:02X}", b));
+            } else {
+                out.push(b as char);
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Decode a manifest path, refusing anything that could escape the
+// destination directory
+fn decode_path(encoded: &str) -> Result<PathBuf, Error> {
+    let mut path = PathBuf::new();
+    for part in encoded.split('/') {
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut iter = part.bytes();
+        while let Some(b) = iter.next() {
+            if b == b'%' {
+                let hex: Vec<u8> = iter.by_ref().take(2).collect();
+                let byte = std::str::from_utf8(&hex).ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| invalid("Malformed path in tree manifest"))?;
+                bytes.push(byte);
+            } else {
+                bytes.push(b);
+            }
+        }
+        let name = name_from_bytes(bytes)?;
+        let mut components = Path::new(&name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => path.push(&name),
+            _ => return Err(invalid("Unsafe path in tree manifest")),
+        }
+    }
+    Ok(path)
+}
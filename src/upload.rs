@@ -0,0 +1,203 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Resumable upload sessions, for content that arrives in pieces over
+//! unreliable connections.
+//!
+//! `begin_upload()` issues a token, `append_upload()` adds data to the
+//! session (hashing it as it goes), and `finish_upload()` stores the result
+//! and returns its key.  Sessions are kept on disk under `uploads/` in the
+//! storage path, so they survive restarts; a client that lost its connection
+//! can ask `upload_offset()` where to resume from.  Abandoned sessions are
+//! only removed by `abort_upload()`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,Read,Write};
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,OnceLock};
+
+use crypto::digest::Digest;
+
+use super::{Config,Error,FileKey};
+use super::hashable::HashAlgorithm;
+
+/// A token identifying an upload session, issued by `begin_upload()`
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct UploadToken(pub String);
+
+impl UploadToken {
+    /// Whether this has the shape of a token issued by `begin_upload()`.
+    /// Tokens from clients should be checked before use.
+    pub fn is_well_formed(&self) -> bool {
+        self.0.len() == 32 &&
+            self.0.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    }
+}
+
+impl fmt::Display for UploadToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", &self.0)
+    }
+}
+
+// Hash state of a session as of `len` bytes.  This lives only in memory;
+// after a restart (or a change of the store's algorithm) it is rebuilt from
+// the session file on the next append.
+struct Session {
+    hash: Box<dyn Digest + Send + Sync>,
+    algorithm: HashAlgorithm,
+    len: u64,
+}
+
+type Sessions = Mutex<HashMap<PathBuf, Arc<Mutex<Session>>>>;
+
+static SESSIONS: OnceLock<Sessions> = OnceLock::new();
+
+fn sessions() -> &'static Sessions {
+    SESSIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// The in-memory state for a session file, creating it if needed
+fn session_for(path: &Path) -> Arc<Mutex<Session>> {
+    let mut sessions = sessions().lock().unwrap_or_else(|e| e.into_inner());
+    sessions.entry(path.to_path_buf())
+        .or_insert_with(|| Arc::new(Mutex::new(Session {
+            // Never matches a real length, forcing a rehash on first use
+            hash: HashAlgorithm::default().hasher(),
+            algorithm: HashAlgorithm::default(),
+            len: u64::MAX,
+        })))
+        .clone()
+}
+
+fn forget_session(path: &Path) {
+    let mut sessions = sessions().lock().unwrap_or_else(|e| e.into_inner());
+    sessions.remove(path);
+}
+
+fn uploads_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("uploads")
+}
+
+fn upload_path(storage_path: &Path, token: &UploadToken) -> Result<PathBuf, Error> {
+    if !token.is_well_formed() {
+        return Err(From::from((io::Error::new(io::ErrorKind::InvalidInput, "malformed token"),
+                               "Invalid upload token")));
+    }
+    Ok(uploads_dir(storage_path).join(&token.0))
+}
+
+// Rebuild a session's hash state from what is on disk
+fn rehash(path: &Path, algorithm: HashAlgorithm) -> Result<Session, Error> {
+    let mut file = File::open(path)
+        .map_err(|e| { (e, "Unable to open upload session") } )?;
+    let mut session = Session { hash: algorithm.hasher(), algorithm, len: 0 };
+    let mut buf = vec![0_u8; super::hashable::DEFAULT_BUFFER_SIZE];
+    loop {
+        let count = file.read(&mut buf)
+            .map_err(|e| { (e, "Unable to read upload session") } )?;
+        if count==0 { return Ok(session); }
+        session.hash.input(&buf[..count]);
+        session.len += count as u64;
+    }
+}
+
+/// Start an upload session, returning the token for later calls
+pub fn begin_upload(storage_path: &Path) -> Result<UploadToken, Error>
+{
+    let dir = uploads_dir(storage_path);
+    if let Err(e) = fs::create_dir(&dir) {
+        if e.kind() != io::ErrorKind::AlreadyExists { return Err( From::from(e) ); }
+    }
+    loop {
+        let token = UploadToken(format!("{:032x}", rand::random::<u128>()));
+        match OpenOptions::new().write(true).create_new(true).open(dir.join(&token.0)) {
+            Ok(_) => return Ok(token),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(From::from((e, "Unable to create upload session"))),
+        }
+    }
+}
+
+/// Add data to the end of an upload session, returning the session's total
+/// length so far
+pub fn append_upload(storage_path: &Path, token: &UploadToken, data: &[u8])
+                     -> Result<u64, Error>
+{
+    let path = upload_path(storage_path, token)?;
+    let algorithm = super::manifest::algorithm(storage_path)?;
+    let session = session_for(&path);
+    let mut session = session.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut file = OpenOptions::new().append(true).open(&path)
+        .map_err(|e| { (e, "Unable to open upload session") } )?;
+    let len = file.metadata()
+        .map_err(|e| { (e, "Unable to stat upload session") } )?
+        .len();
+    if len != session.len || session.algorithm != algorithm {
+        *session = rehash(&path, algorithm)?;
+    }
+
+    // On failure the session length no longer matches the file, so the
+    // next append rehashes whatever was actually written
+    file.write_all(data)
+        .map_err(|e| { (e, "Unable to write upload session") } )?;
+    session.hash.input(data);
+    session.len += data.len() as u64;
+    Ok(session.len)
+}
+
+/// How many bytes an upload session holds, i.e. where a client should
+/// resume appending from
+pub fn upload_offset(storage_path: &Path, token: &UploadToken) -> Result<u64, Error>
+{
+    let path = upload_path(storage_path, token)?;
+    let metadata = fs::metadata(&path)
+        .map_err(|e| { (e, "Unable to stat upload session") } )?;
+    Ok(metadata.len())
+}
+
+/// Store the content of an upload session, ending the session
+pub fn finish_upload(storage_path: &Path, token: &UploadToken) -> Result<FileKey, Error>
+{
+    let path = upload_path(storage_path, token)?;
+    let algorithm = super::manifest::algorithm(storage_path)?;
+    let session = session_for(&path);
+    let mut session = session.lock().unwrap_or_else(|e| e.into_inner());
+
+    let len = fs::metadata(&path)
+        .map_err(|e| { (e, "Unable to stat upload session") } )?
+        .len();
+    if len != session.len || session.algorithm != algorithm {
+        *session = rehash(&path, algorithm)?;
+    }
+    let key = FileKey(session.hash.result_str());
+    // The hash is finished, so should storing fail the session is rehashed
+    // on its next use
+    session.len = u64::MAX;
+
+    super::commit(storage_path, &Config::default(), &key, 1, |dest_path| {
+        super::replace::replace(&path, dest_path)
+            .map_err(|e| { From::from((e, "Unable to move upload into place")) } )
+    })?;
+    // Still present if the content was already stored
+    if let Err(e) = fs::remove_file(&path) {
+        if e.kind() != io::ErrorKind::NotFound { return Err( From::from(e) ); }
+    }
+    forget_session(&path);
+    Ok(key)
+}
+
+/// Discard an upload session and everything appended to it
+pub fn abort_upload(storage_path: &Path, token: &UploadToken) -> Result<(), Error>
+{
+    let path = upload_path(storage_path, token)?;
+    let session = session_for(&path);
+    let _session = session.lock().unwrap_or_else(|e| e.into_inner());
+    fs::remove_file(&path)
+        .map_err(|e| { (e, "Unable to remove upload session") } )?;
+    forget_session(&path);
+    Ok(())
+}
@@ -0,0 +1,237 @@
+// io_uring versions of the hashing and copy loops (the `io-uring` feature,
+// Linux only).  Several chunks of a file are kept in flight at once, so a
+// single store call keeps a fast device busy, and each thread keeps one ring
+// for all its calls.  Where the kernel (or a sandbox) refuses io_uring these
+// return `None`, and callers fall back to ordinary reads and writes.
+
+use std::cell::RefCell;
+use std::fs::{self,File,OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use io_uring::{opcode,types,IoUring};
+
+use super::Error;
+use super::hashable::HashAlgorithm;
+
+const DEPTH: usize = 8;
+const CHUNK: usize = 256 * 1024;
+
+thread_local! {
+    // None until first use; then Some(None) if io_uring is unavailable
+    static RING: RefCell<Option<Option<IoUring>>> = const { RefCell::new(None) };
+}
+
+// Run `f` with this thread's ring, or return `None` if there isn't one
+fn with_ring<T, F>(f: F) -> Option<T>
+    where F: FnOnce(&mut IoUring) -> (T, bool)
+{
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        if ring.is_none() {
+            *ring = Some(IoUring::new(DEPTH as u32 * 2).ok());
+        }
+        let (result, reusable) = f(ring.as_mut().unwrap().as_mut()?);
+        if !reusable {
+            // Operations may still be in flight on it
+            *ring = Some(None);
+        }
+        Some(result)
+    })
+}
+
+/// Hash a file as `Hashable` does
+pub(crate) fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Option<Result<String, Error>> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Some(Err(From::from((e, "Cannot open content file for hashing")))),
+    };
+    let total = match file.metadata() {
+        Ok(m) => m.len(),
+        Err(e) => return Some(Err(From::from((e, "Cannot stat content file for hashing")))),
+    };
+    let mut hash = algorithm.hasher();
+    with_ring(|ring| {
+        let (result, reusable) = pipeline(ring, &file, total, None, &mut |data| hash.input(data));
+        let result = result
+            .map(|()| hash.result_str())
+            .map_err(|e| { From::from((e, "Unable to read file to hash")) } );
+        (result, reusable)
+    })
+}
+
+/// Copy a file into the store as `Storable` does
+pub(crate) fn copy_file(src: &Path, dest: &Path) -> Option<Result<(), Error>> {
+    let file = match File::open(src) {
+        Ok(f) => f,
+        Err(e) => return Some(Err(From::from((e, "Unable to open file to copy")))),
+    };
+    let metadata = match file.metadata() {
+        Ok(m) => m,
+        Err(e) => return Some(Err(From::from((e, "Unable to stat file to copy")))),
+    };
+    let output = match OpenOptions::new().create(true).write(true).truncate(true).open(dest) {
+        Ok(f) => f,
+        Err(e) => return Some(Err(From::from((e, "Unable to open/creat new file")))),
+    };
+    if let Err(e) = super::space::preallocate(&output, dest, metadata.len()) {
+        return Some(Err(e));
+    }
+    with_ring(|ring| {
+        let (result, reusable) = pipeline(ring, &file, metadata.len(), Some(&output),
+                                          &mut |_| {});
+        // As fs::copy() does
+        let result = result
+            .and_then(|()| fs::set_permissions(dest, metadata.permissions()))
+            .map_err(|e| { From::from((e, "Unable to copy file")) } );
+        (result, reusable)
+    })
+}
+
+#[derive(PartialEq)]
+enum State {
+    Free,
+    Reading,
+    Read,
+    Writing,
+}
+
+struct Slot {
+    buf: Vec<u8>,
+    offset: u64,
+    want: usize,
+    done: usize,
+    state: State,
+}
+
+const WRITE: u64 = 1;
+
+// Read the first `total` bytes of `src` a chunk at a time, passing each
+// chunk to `consume` in order and, with a `dest`, writing it to the same
+// offset there.  Also returns whether the ring is safe to use again.
+fn pipeline(ring: &mut IoUring, src: &File, total: u64, dest: Option<&File>,
+            consume: &mut dyn FnMut(&[u8])) -> (io::Result<()>, bool)
+{
+    let mut slots: Vec<Slot> = (0..DEPTH)
+        .map(|_| Slot { buf: vec![0_u8; CHUNK], offset: 0, want: 0, done: 0, state: State::Free })
+        .collect();
+    let mut in_flight: usize = 0;
+
+    let result = run(ring, src, total, dest, consume, &mut slots, &mut in_flight);
+    if result.is_ok() {
+        return (result, true);
+    }
+
+    // The kernel may still write into the buffers, so wait for everything
+    // submitted before they can be freed
+    while in_flight > 0 {
+        if ring.submit_and_wait(1).is_err() {
+            std::mem::forget(slots);
+            return (result, false);
+        }
+        in_flight -= ring.completion().count();
+    }
+    (result, true)
+}
+
+fn run(ring: &mut IoUring, src: &File, total: u64, dest: Option<&File>,
+       consume: &mut dyn FnMut(&[u8]), slots: &mut [Slot], in_flight: &mut usize)
+       -> io::Result<()>
+{
+    let chunks = total.div_ceil(CHUNK as u64);
+    let mut next_read: u64 = 0;
+    let mut next_consume: u64 = 0;
+
+    loop {
+        while next_consume < chunks {
+            let index = (next_consume % DEPTH as u64) as usize;
+            let slot = &mut slots[index];
+            if slot.state != State::Read {
+                break;
+            }
+            consume(&slot.buf[..slot.want]);
+            match dest {
+                Some(dest) => {
+                    slot.done = 0;
+                    slot.state = State::Writing;
+                    submit(ring, dest, slot, index, true)?;
+                    *in_flight += 1;
+                },
+                None => slot.state = State::Free,
+            }
+            next_consume += 1;
+        }
+
+        while next_read < chunks && slots[(next_read % DEPTH as u64) as usize].state == State::Free {
+            let index = (next_read % DEPTH as u64) as usize;
+            let slot = &mut slots[index];
+            slot.offset = next_read * CHUNK as u64;
+            slot.want = (total - slot.offset).min(CHUNK as u64) as usize;
+            slot.done = 0;
+            slot.state = State::Reading;
+            submit(ring, src, slot, index, false)?;
+            *in_flight += 1;
+            next_read += 1;
+        }
+
+        if *in_flight == 0 {
+            // Nothing left to read, consume or write
+            debug_assert_eq!(next_consume, chunks);
+            return Ok(());
+        }
+
+        ring.submit_and_wait(1)?;
+        let completions: Vec<(u64, i32)> = ring.completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (user_data, result) in completions {
+            *in_flight -= 1;
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            let index = (user_data >> 1) as usize;
+            let writing = user_data & WRITE != 0;
+            let slot = &mut slots[index];
+            if result == 0 && !writing {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "file shrank while being read"));
+            }
+            slot.done += result as usize;
+            if slot.done < slot.want {
+                // Short transfer; continue where it stopped
+                let file = if writing { dest.unwrap_or(src) } else { src };
+                submit(ring, file, slot, index, writing)?;
+                *in_flight += 1;
+            } else if writing {
+                slot.state = State::Free;
+            } else {
+                slot.state = State::Read;
+            }
+        }
+    }
+}
+
+// Queue the rest of a slot's transfer
+fn submit(ring: &mut IoUring, file: &File, slot: &mut Slot, index: usize, write: bool)
+          -> io::Result<()>
+{
+    let fd = types::Fd(file.as_raw_fd());
+    let offset = slot.offset + slot.done as u64;
+    let len = (slot.want - slot.done) as u32;
+    let user_data = (index as u64) << 1 | if write { WRITE } else { 0 };
+    let entry = if write {
+        opcode::Write::new(fd, slot.buf[slot.done..].as_ptr(), len)
+            .offset(offset).build().user_data(user_data)
+    } else {
+        opcode::Read::new(fd, slot.buf[slot.done..].as_mut_ptr(), len)
+            .offset(offset).build().user_data(user_data)
+    };
+    // Safe as the buffer outlives the operation: pipeline() waits for every
+    // submitted operation before its slots are dropped
+    unsafe {
+        ring.submission().push(&entry)
+            .map_err(|_| io::Error::other("io_uring submission queue full"))?;
+    }
+    Ok(())
+}
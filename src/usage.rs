@@ -0,0 +1,251 @@
+// Running totals of a store's size, for quota checks and `usage()`.  A
+// store's totals are counted from disk the first time they are asked for,
+// and from then on are kept up to date by every store and delete in this
+// process.  Changes made by other processes are not seen.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,OnceLock};
+
+use super::{Error,FileKey,handles,keys,storage_file_path,storage_refcount_path};
+use super::config::Quota;
+use super::error::ErrorKind;
+
+// The directories holding a record per object, which count as metadata
+const RECORD_DIRS: &[&str] = &["types", "merkle", "macs", "access", "owners"];
+
+/// Space on disk taken by a store, from `usage()`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Number of objects
+    pub objects: u64,
+    /// Bytes of disk allocated to object content
+    pub object_bytes: u64,
+    /// Bytes of disk allocated to refcount files
+    pub refcount_bytes: u64,
+    /// Bytes of disk allocated to the records kept about each object:
+    /// content types, chunk digests, MACs and access records
+    pub metadata_bytes: u64,
+}
+
+impl DiskUsage {
+    /// Bytes of disk allocated to all of the above
+    pub fn total(&self) -> u64 {
+        self.object_bytes + self.refcount_bytes + self.metadata_bytes
+    }
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    // Bytes of object content, which quotas are counted in
+    bytes: u64,
+    disk: DiskUsage,
+}
+
+type Registry = Mutex<HashMap<PathBuf, Arc<Mutex<Usage>>>>;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// The totals for a store, if they are being kept
+fn tracked(storage_path: &Path) -> Option<Arc<Mutex<Usage>>> {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    if registry.is_empty() {
+        return None;
+    }
+    registry.get(&handles::canonical(storage_path)).cloned()
+}
+
+// The totals for a store, counting them first if need be.  Also returns
+// whether they were just counted.
+fn track(storage_path: &Path) -> Result<(Arc<Mutex<Usage>>, bool), Error> {
+    if let Some(usage) = tracked(storage_path) {
+        return Ok((usage, false));
+    }
+    // Counted outside the registry lock, as this walks the whole store
+    let counted = count(storage_path)?;
+    let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let mut fresh = false;
+    let usage = registry.entry(handles::canonical(storage_path))
+        .or_insert_with(|| {
+            fresh = true;
+            Arc::new(Mutex::new(counted))
+        })
+        .clone();
+    Ok((usage, fresh))
+}
+
+fn count(storage_path: &Path) -> Result<Usage, Error> {
+    let mut usage = Usage::default();
+    for key in keys(storage_path)? {
+        let key: FileKey = key?;
+        let metadata = fs::metadata(storage_file_path(storage_path, &key))
+            .map_err(|e| { (e, "Unable to stat stored file") } )?;
+        usage.bytes += metadata.len();
+        usage.disk.objects += 1;
+        usage.disk.object_bytes += disk_usage(&metadata);
+        usage.disk.refcount_bytes += file_disk_usage(&storage_refcount_path(storage_path, &key))?;
+    }
+    for dir in RECORD_DIRS {
+        usage.disk.metadata_bytes += dir_disk_usage(&storage_path.join(dir))?;
+    }
+    Ok(usage)
+}
+
+/// Bytes of disk allocated to a file, which may be more than its length
+/// (whole blocks) or less (sparse or compressed files)
+#[cfg(unix)]
+pub(crate) fn disk_usage(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+pub(crate) fn disk_usage(metadata: &fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+// Bytes of disk allocated to the file at `path`, or 0 if there is none
+fn file_disk_usage(path: &Path) -> Result<u64, Error> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(disk_usage(&metadata)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(From::from((e, "Unable to stat file"))),
+    }
+}
+
+// Bytes of disk allocated to the files under `dir`
+fn dir_disk_usage(dir: &Path) -> Result<u64, Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(From::from((e, "Unable to read directory"))),
+    };
+    let mut bytes = 0;
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| { (e, "Unable to read directory") } )?;
+        let metadata = entry.metadata()
+            .map_err(|e| { (e, "Unable to stat file") } )?;
+        bytes += match metadata.is_dir() {
+            true => dir_disk_usage(&entry.path())?,
+            false => disk_usage(&metadata),
+        };
+    }
+    Ok(bytes)
+}
+
+/// The space on disk taken by the store at `storage_path`.  The first call
+/// for a store counts it from disk; after that the totals are kept up to
+/// date as this process stores and deletes, so calls are cheap.  Changes
+/// made by other processes are not seen.
+pub fn usage(storage_path: &Path) -> Result<DiskUsage, Error>
+{
+    let (usage, _) = track(storage_path)?;
+    let usage = usage.lock().unwrap_or_else(|e| e.into_inner());
+    Ok(usage.disk)
+}
+
+/// Account for the new object at `object_path`.  With a `quota`, fails with
+/// `ErrorKind::QuotaExceeded` (leaving the totals alone) if the object
+/// does not fit.
+pub(crate) fn add(storage_path: &Path, object_path: &Path, quota: Option<&Quota>)
+                  -> Result<(), Error>
+{
+    let (usage, fresh) = match quota {
+        Some(_) => track(storage_path)?,
+        None => match tracked(storage_path) {
+            Some(usage) => (usage, false),
+            None => return Ok(()),
+        },
+    };
+    let metadata = fs::metadata(object_path)
+        .map_err(|e| { (e, "Unable to stat stored file") } )?;
+    let bytes = metadata.len();
+    let mut usage = usage.lock().unwrap_or_else(|e| e.into_inner());
+    if fresh {
+        // The count from disk already included the new object
+        usage.subtract(&metadata);
+    }
+    if let Some(quota) = quota {
+        let over_bytes = quota.max_bytes.is_some_and(|max| usage.bytes + bytes > max);
+        let over_objects = quota.max_objects.is_some_and(|max| usage.disk.objects + 1 > max);
+        if over_bytes || over_objects {
+            return Err(From::from((ErrorKind::QuotaExceeded, "Store quota exceeded")));
+        }
+    }
+    usage.bytes += bytes;
+    usage.disk.objects += 1;
+    usage.disk.object_bytes += disk_usage(&metadata);
+    Ok(())
+}
+
+/// Whether a store's totals are being kept, so removals need reporting
+pub(crate) fn is_tracked(storage_path: &Path) -> bool {
+    tracked(storage_path).is_some()
+}
+
+/// Account for the removal of an object, whose `metadata` was taken
+/// beforehand
+pub(crate) fn remove(storage_path: &Path, metadata: &fs::Metadata) {
+    if let Some(usage) = tracked(storage_path) {
+        usage.lock().unwrap_or_else(|e| e.into_inner()).subtract(metadata);
+    }
+}
+
+impl Usage {
+    fn subtract(&mut self, metadata: &fs::Metadata) {
+        self.bytes = self.bytes.saturating_sub(metadata.len());
+        self.disk.objects = self.disk.objects.saturating_sub(1);
+        self.disk.object_bytes = self.disk.object_bytes.saturating_sub(disk_usage(metadata));
+    }
+}
+
+/// What a file other than an object's content counts towards
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Part {
+    Refcounts,
+    Metadata,
+}
+
+/// A file about to be written or removed, whose space on disk is counted
+/// again once `done()` is called
+pub(crate) struct Change {
+    usage: Option<Arc<Mutex<Usage>>>,
+    part: Part,
+    path: PathBuf,
+    before: u64,
+}
+
+/// Note the space taken by the file at `path`, about to be written or
+/// removed.  Nothing is done unless the store's totals are being kept.
+pub(crate) fn change(storage_path: &Path, part: Part, path: &Path) -> Change {
+    let usage = tracked(storage_path);
+    let before = match usage {
+        Some(_) => file_disk_usage(path).unwrap_or(0),
+        None => 0,
+    };
+    Change { usage, part, path: path.to_owned(), before }
+}
+
+impl Change {
+    /// Account for the difference the write or removal made
+    pub(crate) fn done(self) {
+        let usage = match self.usage {
+            Some(usage) => usage,
+            None => return,
+        };
+        let after = file_disk_usage(&self.path).unwrap_or(0);
+        let mut usage = usage.lock().unwrap_or_else(|e| e.into_inner());
+        let bytes = match self.part {
+            Part::Refcounts => &mut usage.disk.refcount_bytes,
+            Part::Metadata => &mut usage.disk.metadata_bytes,
+        };
+        *bytes = bytes.saturating_sub(self.before).saturating_add(after);
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Storing serde values, making the store a content-addressed object store
+//! for structs.
+//!
+//! A value is serialized with a wire `Format` and the bytes stored as any
+//! other content, so equal values share one object.  That relies on the
+//! format writing equal values the same way: true of the formats here for
+//! structs, vectors and ordered maps, but not for `HashMap`, whose order
+//! varies.  `Bincode` needs the `bincode` feature and `Cbor` the `ciborium`
+//! feature.
+
+#[cfg(any(feature = "bincode", feature = "ciborium"))]
+use std::io;
+use std::path::Path;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::{Error,FileKey};
+
+/// A wire format for values
+pub trait Format {
+    /// Serialize `value` to bytes
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, Error>;
+
+    /// Deserialize a value from bytes written by `encode()`
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error>;
+}
+
+/// The bincode format: compact, and fast, but not self-describing
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Format for Bincode {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        bincode::serialize(value)
+            .map_err(|e| { From::from((io::Error::new(io::ErrorKind::InvalidInput, e),
+                                       "Unable to encode value")) } )
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        bincode::deserialize(bytes)
+            .map_err(|e| { From::from((io::Error::new(io::ErrorKind::InvalidData, e),
+                                       "Unable to decode value")) } )
+    }
+}
+
+/// CBOR (RFC 8949): self-describing, and readable from other languages
+#[cfg(feature = "ciborium")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "ciborium")]
+impl Format for Cbor {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        ciborium::into_writer(value, &mut bytes)
+            .map_err(|e| { (io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+                            "Unable to encode value") } )?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        ciborium::from_reader(bytes)
+            .map_err(|e| { From::from((io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+                                       "Unable to decode value")) } )
+    }
+}
+
+/// Store `value`, serialized with `format`
+pub fn store_value<F, T>(storage_path: &Path, format: &F, value: &T) -> Result<FileKey, Error>
+    where F: Format,
+          T: Serialize + ?Sized
+{
+    let bytes = format.encode(value)?;
+    super::store_data(storage_path, &bytes)
+}
+
+/// Retrieve a value stored with `store_value()` in the same `format`, or
+/// `None` if the key is not stored
+pub fn retrieve_value<F, T>(storage_path: &Path, format: &F, key: &FileKey)
+                            -> Result<Option<T>, Error>
+    where F: Format,
+          T: DeserializeOwned
+{
+    match super::retrieve_data(storage_path, key) {
+        Some(bytes) => format.decode(&bytes).map(Some),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Ingesting files dropped into an inbox directory (needs the `watch`
+//! feature).
+//!
+//! An `InboxWatcher` (from `FileStore::inbox_watcher()`) is `spawn()`ed on
+//! a thread of its own, which stores each file that appears in the inbox,
+//! hands its original path and key to a callback, then removes the
+//! original.  Changes are noticed through the operating system's file
+//! notifications (inotify on Linux), with the inbox also rescanned every
+//! minute in case any were missed.  Files already there when the watcher
+//! starts are ingested too.
+//!
+//! A file is taken once its length and modification time have stayed the
+//! same for the settle time (a second, by default), so that writers still
+//! filling it are left to finish.  Writers that can should write elsewhere
+//! (or to a name beginning with `.`, which is ignored) and rename the
+//! finished file into the inbox.  Subdirectories are ignored.  A file that
+//! can't be stored or removed is logged and left alone until it changes,
+//! so that it isn't stored again and again.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Condvar,Mutex};
+use std::thread;
+use std::time::{Duration,Instant,SystemTime};
+
+use notify::{RecursiveMode,Watcher as _};
+
+use super::{Error,FileKey};
+use super::store::FileStore;
+
+const DEFAULT_SETTLE_TIME: Duration = Duration::from_secs(1);
+
+// How often the inbox is scanned without being told of a change
+const RESCAN: Duration = Duration::from_secs(60);
+
+// How soon the inbox is scanned again at most, so that a busy writer's
+// changes are taken together
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Stores files dropped into an inbox directory
+#[derive(Debug, Clone)]
+pub struct InboxWatcher {
+    store: FileStore,
+    inbox: PathBuf,
+    settle_time: Duration,
+}
+
+impl InboxWatcher {
+    /// A watcher storing files that appear in `inbox` into `store`
+    pub fn new(store: &FileStore, inbox: &Path) -> InboxWatcher {
+        InboxWatcher {
+            store: store.clone(),
+            inbox: inbox.to_path_buf(),
+            settle_time: DEFAULT_SETTLE_TIME,
+        }
+    }
+
+    /// Take files once they have been left unchanged for `settle_time`
+    pub fn with_settle_time(mut self, settle_time: Duration) -> InboxWatcher {
+        self.settle_time = settle_time;
+        self
+    }
+
+    /// Watch the inbox on a thread of its own, creating it if need be,
+    /// until the returned task is stopped or dropped.  `callback` is given
+    /// the original path of each file stored and its key, before the
+    /// original is removed.
+    pub fn spawn<F>(self, callback: F) -> Result<WatchTask, Error>
+        where F: FnMut(&Path, &FileKey) + Send + 'static
+    {
+        self.store.check_writable()?;
+        fs::create_dir_all(&self.inbox)
+            .map_err(|e| { (e, "Unable to create inbox directory") } )?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { dirty: true, ..State::default() }),
+            changed: Condvar::new(),
+        });
+
+        // Any news, even of an error, is taken as a reason to look
+        let notify_shared = shared.clone();
+        let mut watcher = notify::recommended_watcher(move |_| {
+            notify_shared.state.lock().unwrap_or_else(|e| e.into_inner()).dirty = true;
+            notify_shared.changed.notify_all();
+        }).map_err(|e| { (notify_error(e), "Unable to watch inbox") } )?;
+        watcher.watch(&self.inbox, RecursiveMode::NonRecursive)
+            .map_err(|e| { (notify_error(e), "Unable to watch inbox") } )?;
+
+        let worker_shared = shared.clone();
+        let worker = thread::Builder::new()
+            .name("filestore-watch".to_owned())
+            .spawn(move || worker_shared.run(&self, callback))
+            .map_err(|e| { (e, "Unable to start inbox watcher") } )?;
+        Ok(WatchTask { shared, worker: Some(worker), _watcher: watcher })
+    }
+
+    // Store the files that have settled, noting how the others look now
+    fn scan<F>(&self, files: &mut Files, callback: &mut F) -> Result<u64, Error>
+        where F: FnMut(&Path, &FileKey)
+    {
+        let now = Instant::now();
+        let mut present: HashMap<PathBuf, Signature> = HashMap::new();
+        for entry in fs::read_dir(&self.inbox)
+            .map_err(|e| { (e, "Unable to read inbox directory") } )?
+        {
+            let entry = entry
+                .map_err(|e| { (e, "Unable to read inbox directory") } )?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                // Taken away meanwhile
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(From::from((e, "Unable to stat inbox file"))),
+            };
+            if metadata.is_file() {
+                present.insert(entry.path(), (metadata.len(), metadata.modified().ok()));
+            }
+        }
+        files.failed.retain(|path, signature| present.get(path) == Some(signature));
+        files.seen.retain(|path, _| present.contains_key(path));
+
+        let mut ingested: u64 = 0;
+        for (path, signature) in present {
+            if files.failed.contains_key(&path) {
+                continue;
+            }
+            match files.seen.get(&path) {
+                Some(&(seen, since)) if seen == signature => {
+                    if now.duration_since(since) < self.settle_time {
+                        continue;
+                    }
+                },
+                _ => {
+                    files.seen.insert(path, (signature, now));
+                    continue;
+                },
+            }
+            files.seen.remove(&path);
+            match self.ingest(&path, callback) {
+                Ok(()) => ingested += 1,
+                Err(e) => {
+                    log::warn!("Unable to ingest {}: {:?}", path.display(), e);
+                    files.failed.insert(path, signature);
+                },
+            }
+        }
+        Ok(ingested)
+    }
+
+    fn ingest<F>(&self, path: &Path, callback: &mut F) -> Result<(), Error>
+        where F: FnMut(&Path, &FileKey)
+    {
+        let key = self.store.store_file(path)?;
+        callback(path, &key);
+        fs::remove_file(path)
+            .map_err(|e| { From::from((e, "Unable to remove ingested file")) } )
+    }
+}
+
+// A file's length and modification time
+type Signature = (u64, Option<SystemTime>);
+
+// What the worker knows of the files in the inbox
+#[derive(Default)]
+struct Files {
+    // How each file looked, and since when
+    seen: HashMap<PathBuf, (Signature, Instant)>,
+    // Files that couldn't be ingested, left alone until they change
+    failed: HashMap<PathBuf, Signature>,
+}
+
+/// An inbox being watched, started by `InboxWatcher::spawn()`
+pub struct WatchTask {
+    shared: Arc<Shared>,
+    worker: Option<thread::JoinHandle<()>>,
+    // Kept for as long as the task runs, as dropping it stops notifications
+    _watcher: notify::RecommendedWatcher,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    ingested: u64,
+    dirty: bool,
+    stopping: bool,
+}
+
+impl Shared {
+    fn run<F>(&self, watcher: &InboxWatcher, mut callback: F)
+        where F: FnMut(&Path, &FileKey)
+    {
+        let mut files = Files::default();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        while !state.stopping {
+            state.dirty = false;
+            drop(state);
+            let result = watcher.scan(&mut files, &mut callback);
+            state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            match result {
+                Ok(ingested) => state.ingested += ingested,
+                Err(e) => log::warn!("Unable to scan inbox {}: {:?}",
+                                     watcher.inbox.display(), e),
+            }
+            // Files waiting to settle are looked at again once they may have
+            let timeout = match files.seen.is_empty() {
+                true => RESCAN,
+                false => watcher.settle_time,
+            };
+            state = self.changed.wait_timeout_while(state, MIN_INTERVAL.min(timeout),
+                                                    |s| !s.stopping)
+                .unwrap_or_else(|e| e.into_inner()).0;
+            state = self.changed.wait_timeout_while(state, timeout.saturating_sub(MIN_INTERVAL),
+                                                    |s| !s.dirty && !s.stopping)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+}
+
+impl WatchTask {
+    /// The number of files stored so far
+    pub fn ingested(&self) -> u64 {
+        self.shared.state.lock().unwrap_or_else(|e| e.into_inner()).ingested
+    }
+
+    /// Stop watching, waiting for a file being stored to finish
+    pub fn stop(self) {
+        // Done on drop
+    }
+}
+
+impl Drop for WatchTask {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap_or_else(|e| e.into_inner()).stopping = true;
+        self.shared.changed.notify_all();
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                log::warn!("Inbox watcher panicked");
+            }
+        }
+    }
+}
+
+fn notify_error(e: notify::Error) -> io::Error {
+    match e.kind {
+        notify::ErrorKind::Io(e) => e,
+        _ => io::Error::other(e),
+    }
+}
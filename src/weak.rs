@@ -0,0 +1,243 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Weak references, which note an interest in an object without keeping it
+//! in the store.
+//!
+//! A weak reference doesn't count towards an object's refcount: the object
+//! goes as usual once its last reference is dropped or, if it never had
+//! one, at the next `gc()`.  This suits cache-like secondary indexes, which
+//! want to find content again while it is there but mustn't be what keeps
+//! it.  `resolves()` tells whether a weakly held key is still stored, and
+//! `upgrade_weak()` takes a reference on it if it is, with no gap in
+//! between for it to be removed in.
+//!
+//! Weak references are counted under `weak/` in the storage path, one
+//! small text file per key laid out as the shards are.  A count outlives
+//! its object, so that `dangling_weak()` can list the keys an index has
+//! entries to prune for, until `release_weak()` drops them.
+
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+
+use super::{Config,Error,FileKey,Hashable,Storable};
+use super::{keylock,limit,manifest,replace,space,spool,storage_file_path};
+
+fn weak_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("weak")
+}
+
+fn weak_path(storage_path: &Path, key: &FileKey) -> PathBuf {
+    weak_dir(storage_path).join(&key[..2]).join(&key[2..])
+}
+
+/// Store data, as `store_data()` does, but take a weak reference on it
+/// rather than a reference.  Content not already stored is kept only until
+/// the next `gc()`, unless something takes a reference on it first.
+pub fn store_weak<D: AsRef<[u8]> + ?Sized>(storage_path: &Path, input: &D)
+                                          -> Result<FileKey, Error>
+{
+    store_weak_new(storage_path, &Config::default(), input.as_ref()).map(|(key, _)| key)
+}
+
+// As `store_weak()`, also returning whether the content was newly stored
+pub(crate) fn store_weak_new(storage_path: &Path, config: &Config, input: &[u8])
+                             -> Result<(FileKey, bool), Error>
+{
+    let op = operation!("filestore.store_weak", new_object);
+    op.record("bytes", input.len() as u64);
+    let _permit = limit::acquire(storage_path, config.max_concurrent_operations);
+    let result = config.check_object_size(input.len() as u64)
+        .and_then(|()| space::ensure_room(storage_path, config, input.len() as u64))
+        .and_then(|()| input.hash_with(manifest::algorithm(storage_path)?).map(FileKey))
+        .and_then(|key| {
+            // Counted first, so that the reference is there as soon as the
+            // content is
+            adjust(storage_path, &key, true)?;
+            match super::commit(storage_path, config, &key, 0, |dest_path| input.store(dest_path)) {
+                Ok(new_object) => Ok((key, new_object)),
+                Err(e) => {
+                    if let Err(e) = adjust(storage_path, &key, false) {
+                        log::warn!("Unable to drop weak reference to {}: {:?}", key, e);
+                    }
+                    Err(e)
+                },
+            }
+        });
+    op.finish_stored(result)
+}
+
+/// Take a weak reference on `key`.  Returns `false`, taking none, if the
+/// key is not stored.
+pub fn add_weak_reference(storage_path: &Path, key: &FileKey) -> Result<bool, Error>
+{
+    if !key.is_well_formed() {
+        return Ok(false);
+    }
+    // Under the key's lock, so that it isn't removed before it is counted
+    let _lock = keylock::lock(storage_path, key)?;
+    if !is_stored(storage_path, key)? {
+        return Ok(false);
+    }
+    adjust_locked(storage_path, key, true)?;
+    Ok(true)
+}
+
+/// Drop a weak reference on `key`, whether or not it is still stored.
+/// Returns `false` if there was none.
+pub fn release_weak(storage_path: &Path, key: &FileKey) -> Result<bool, Error>
+{
+    if !key.is_well_formed() {
+        return Ok(false);
+    }
+    adjust(storage_path, key, false)
+}
+
+/// The number of weak references on `key`
+pub fn weak_count(storage_path: &Path, key: &FileKey) -> Result<u64, Error>
+{
+    if !key.is_well_formed() {
+        return Ok(0);
+    }
+    read_count(&weak_path(storage_path, key))
+}
+
+/// Whether `key`, weakly held or not, is still stored
+pub fn resolves(storage_path: &Path, key: &FileKey) -> Result<bool, Error>
+{
+    if !key.is_well_formed() {
+        return Ok(false);
+    }
+    is_stored(storage_path, key)
+}
+
+/// Take a reference on `key` if it is still stored, keeping it in the store
+/// until that reference is released.  Returns `false`, taking none, if it
+/// isn't.  Any weak reference on it is left as it was.
+pub fn upgrade_weak(storage_path: &Path, key: &FileKey) -> Result<bool, Error>
+{
+    take_reference(storage_path, &Config::default(), key)
+}
+
+pub(crate) fn take_reference(storage_path: &Path, config: &Config, key: &FileKey)
+                             -> Result<bool, Error>
+{
+    if !key.is_well_formed() {
+        return Ok(false);
+    }
+    match super::add_reference(storage_path, config, key) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.io.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The keys with weak references on them that are no longer stored
+pub fn dangling_weak(storage_path: &Path) -> Result<Vec<FileKey>, Error>
+{
+    let mut dangling: Vec<FileKey> = Vec::new();
+    let shards = match fs::read_dir(weak_dir(storage_path)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(dangling),
+        Err(e) => return Err(From::from((e, "Unable to read weak reference directory"))),
+    };
+    for shard in shards {
+        let shard = shard
+            .map_err(|e| { (e, "Unable to read weak reference directory") } )?;
+        let prefix = match shard.file_name().into_string() {
+            Ok(prefix) => prefix,
+            Err(_) => continue,
+        };
+        for entry in fs::read_dir(shard.path())
+            .map_err(|e| { (e, "Unable to read weak reference directory") } )?
+        {
+            let entry = entry
+                .map_err(|e| { (e, "Unable to read weak reference directory") } )?;
+            let rest = match entry.file_name().into_string() {
+                Ok(rest) => rest,
+                Err(_) => continue,
+            };
+            let key = FileKey(format!("{}{}", prefix, rest));
+            if key.is_well_formed() && !is_stored(storage_path, &key)? {
+                dangling.push(key);
+            }
+        }
+    }
+    dangling.sort();
+    Ok(dangling)
+}
+
+/// Move the weak references on `old` to `new`, for `rehash_store()`
+pub(crate) fn rename_key(storage_path: &Path, old: &FileKey, new: &FileKey) -> Result<(), Error> {
+    let from = weak_path(storage_path, old);
+    let count = read_count(&from)?;
+    if count == 0 {
+        return Ok(());
+    }
+    let to = weak_path(storage_path, new);
+    write_count(storage_path, &to, read_count(&to)?.saturating_add(count))?;
+    fs::remove_file(&from)
+        .map_err(|e| { From::from((e, "Unable to remove weak references")) } )
+}
+
+fn is_stored(storage_path: &Path, key: &FileKey) -> Result<bool, Error> {
+    match fs::metadata(storage_file_path(storage_path, key)) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(From::from((e, "Unable to stat stored file"))),
+    }
+}
+
+// Add one to the weak count on `key` or take one away, returning `false`
+// if there was none to take
+fn adjust(storage_path: &Path, key: &FileKey, add: bool) -> Result<bool, Error> {
+    let _lock = keylock::lock(storage_path, key)?;
+    adjust_locked(storage_path, key, add)
+}
+
+fn adjust_locked(storage_path: &Path, key: &FileKey, add: bool) -> Result<bool, Error> {
+    let path = weak_path(storage_path, key);
+    let count = read_count(&path)?;
+    let count = match add {
+        true => count.saturating_add(1),
+        false if count == 0 => return Ok(false),
+        false => count - 1,
+    };
+    match count {
+        0 => match fs::remove_file(&path) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(From::from((e, "Unable to remove weak references"))),
+        },
+        _ => write_count(storage_path, &path, count)?,
+    }
+    Ok(true)
+}
+
+fn read_count(path: &Path) -> Result<u64, Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(From::from((e, "Unable to read weak references"))),
+    };
+    text.trim_end().parse()
+        .map_err(|_| { From::from((io::Error::from(io::ErrorKind::InvalidData),
+                                   "Weak reference count is damaged")) } )
+}
+
+// Written aside and moved into place, so readers never see part of it
+fn write_count(storage_path: &Path, path: &Path, count: u64) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| { (e, "Unable to create weak reference directory") } )?;
+    }
+    let temp_path = spool::temp_path(storage_path);
+    let written = fs::write(&temp_path, format!("{}\n", count))
+        .and_then(|()| replace::replace(&temp_path, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(From::from((e, "Unable to write weak references")));
+    }
+    Ok(())
+}
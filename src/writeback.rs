@@ -0,0 +1,289 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A write-back buffer in front of another store.
+//!
+//! `WriteBackStore` lands stores in a local `FileStore` and returns as soon
+//! as the content is there, while a background thread uploads it to the
+//! remote store, retrying with backoff while the remote is failing.  The
+//! local store is the queue: it holds one reference per upload still to be
+//! made, given up once the remote has taken its own, so nothing waiting is
+//! lost if the process stops and `new()` picks the work up again.
+//!
+//! Retrievals, stats and listings cover both content still waiting and
+//! content already uploaded.  `flush()` waits for everything stored so far
+//! to reach the remote, and `drain()` does so and then stops the worker,
+//! which should be done before the process exits.
+
+use std::collections::{BTreeSet,VecDeque};
+use std::io::{self,Read};
+use std::sync::{Arc,Condvar,Mutex,MutexGuard};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use super::{Error,FileKey};
+use super::store::{FileStore,ObjectStat,Store};
+
+// Delay before retrying a failed upload, doubling up to the maximum with
+// each failure in a row
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A store that buffers stores in `local` and uploads them to `remote` in
+/// the background
+pub struct WriteBackStore<R: Store + Send + Sync + 'static> {
+    shared: Arc<Shared<R>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+struct Shared<R> {
+    local: FileStore,
+    remote: R,
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    // One entry per reference still to be uploaded
+    pending: VecDeque<FileKey>,
+    uploading: Option<FileKey>,
+    // Callers in flush(), for whom retries shouldn't wait
+    flushing: usize,
+    failures: u64,
+    error: Option<Error>,
+    stopping: bool,
+}
+
+impl Queue {
+    fn waiting(&self, key: &FileKey) -> u64 {
+        let queued = self.pending.iter().filter(|k| *k == key).count() as u64;
+        queued + u64::from(self.uploading.as_ref() == Some(key))
+    }
+}
+
+impl<R: Store + Send + Sync + 'static> WriteBackStore<R> {
+    /// A buffer in `local` in front of `remote`, starting the upload worker.
+    /// Content left in `local` by an earlier run is queued for upload.
+    pub fn new(local: FileStore, remote: R) -> Result<WriteBackStore<R>, Error> {
+        let mut queue = Queue::default();
+        for key in local.keys()? {
+            let key = key?;
+            if let Some(stat) = local.stat(&key)? {
+                for _ in 0..stat.refcount {
+                    queue.pending.push_back(key.clone());
+                }
+            }
+        }
+        let shared = Arc::new(Shared {
+            local,
+            remote,
+            queue: Mutex::new(queue),
+            changed: Condvar::new(),
+        });
+        let worker_shared = shared.clone();
+        let worker = thread::Builder::new()
+            .name("filestore-write-back".to_owned())
+            .spawn(move || worker_shared.run())
+            .map_err(|e| { (e, "Unable to start upload worker") } )?;
+        Ok(WriteBackStore { shared, worker: Some(worker) })
+    }
+
+    /// The local store buffering uploads
+    pub fn local(&self) -> &FileStore {
+        &self.shared.local
+    }
+
+    /// The store being uploaded to
+    pub fn remote(&self) -> &R {
+        &self.shared.remote
+    }
+
+    /// Number of stores not yet uploaded
+    pub fn pending(&self) -> usize {
+        let queue = self.shared.lock();
+        queue.pending.len() + usize::from(queue.uploading.is_some())
+    }
+
+    /// Wait until everything stored so far has been uploaded, retrying
+    /// failed uploads at once.  Fails with the error from the first upload
+    /// that fails meanwhile, leaving it queued.
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut queue = self.shared.lock();
+        let failures = queue.failures;
+        queue.flushing += 1;
+        self.shared.changed.notify_all();
+        let result = loop {
+            if queue.failures != failures {
+                break Err(queue.error.take().unwrap_or_else(|| Error::from((
+                    io::Error::other("upload failed"), "Unable to upload to remote store"))));
+            }
+            if queue.pending.is_empty() && queue.uploading.is_none() {
+                break Ok(());
+            }
+            queue = self.shared.wait(queue);
+        };
+        queue.flushing -= 1;
+        result
+    }
+
+    /// Flush, then stop the upload worker
+    pub fn drain(self) -> Result<(), Error> {
+        self.flush()
+    }
+
+    fn queued(&self, key: FileKey) -> FileKey {
+        self.shared.lock().pending.push_back(key.clone());
+        self.shared.changed.notify_all();
+        key
+    }
+}
+
+impl<R: Store> Shared<R> {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait<'a>(&self, queue: MutexGuard<'a, Queue>) -> MutexGuard<'a, Queue> {
+        self.changed.wait(queue).unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The worker: upload queued references until stopped
+    fn run(&self) {
+        let mut backoff = MIN_BACKOFF;
+        let mut queue = self.lock();
+        while !queue.stopping {
+            let key = match queue.pending.pop_front() {
+                Some(key) => key,
+                None => {
+                    queue = self.wait(queue);
+                    continue;
+                },
+            };
+            queue.uploading = Some(key.clone());
+            drop(queue);
+            let result = self.upload(&key);
+            queue = self.lock();
+            queue.uploading = None;
+            match result {
+                Ok(()) => backoff = MIN_BACKOFF,
+                Err(e) => {
+                    log::warn!("Unable to upload {}: {:?}", key, e);
+                    queue.pending.push_front(key);
+                    queue.failures += 1;
+                    queue.error = Some(e);
+                    self.changed.notify_all();
+                    let deadline = Instant::now() + backoff;
+                    while !queue.stopping && queue.flushing == 0 {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            break;
+                        }
+                        queue = self.changed.wait_timeout(queue, deadline - now)
+                            .unwrap_or_else(|e| e.into_inner()).0;
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                },
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    // Give the remote a reference on `key`, then drop the local one
+    fn upload(&self, key: &FileKey) -> Result<(), Error> {
+        let mut reader = match self.local.retrieve_reader(key)? {
+            Some(reader) => reader,
+            // Deleted before it could be uploaded
+            None => return Ok(()),
+        };
+        let uploaded = self.remote.store_reader(&mut reader)?;
+        if uploaded != *key {
+            self.remote.delete(&uploaded)?;
+            return Err(From::from((io::Error::new(io::ErrorKind::InvalidData,
+                                                  "local content does not match its key"),
+                                   &*format!("Unable to upload {}", key))));
+        }
+        self.local.delete(key)
+    }
+}
+
+impl<R: Store + Send + Sync + 'static> Drop for WriteBackStore<R> {
+    fn drop(&mut self) {
+        self.shared.lock().stopping = true;
+        self.shared.changed.notify_all();
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                log::warn!("Upload worker panicked");
+            }
+        }
+    }
+}
+
+impl<R: Store + Send + Sync + 'static> Store for WriteBackStore<R> {
+    fn store_data(&self, input: &[u8]) -> Result<FileKey, Error> {
+        let key = self.shared.local.store_data(input)?;
+        Ok(self.queued(key))
+    }
+
+    fn store_reader(&self, input: &mut dyn Read) -> Result<FileKey, Error> {
+        let key = self.shared.local.store_reader(input)?;
+        Ok(self.queued(key))
+    }
+
+    fn retrieve_reader(&self, key: &FileKey)
+                       -> Result<Option<Box<dyn Read + Send>>, Error>
+    {
+        match self.shared.local.retrieve_reader(key)? {
+            Some(reader) => Ok(Some(reader)),
+            None => self.shared.remote.retrieve_reader(key),
+        }
+    }
+
+    fn delete(&self, key: &FileKey) -> Result<(), Error> {
+        let mut queue = self.shared.lock();
+        loop {
+            // Cancel an upload not yet made, rather than add and remove a
+            // reference on the remote
+            if let Some(index) = queue.pending.iter().rposition(|k| k == key) {
+                queue.pending.remove(index);
+                return self.shared.local.delete(key);
+            }
+            if queue.uploading.as_ref() != Some(key) {
+                break;
+            }
+            // The remote must have the reference before it is dropped
+            queue = self.shared.wait(queue);
+        }
+        drop(queue);
+        self.shared.remote.delete(key)
+    }
+
+    fn stat(&self, key: &FileKey) -> Result<Option<ObjectStat>, Error> {
+        let waiting = self.shared.lock().waiting(key);
+        match self.shared.remote.stat(key)? {
+            Some(mut stat) => {
+                stat.refcount += waiting;
+                Ok(Some(stat))
+            },
+            None if waiting == 0 => Ok(None),
+            None => Ok(self.shared.local.stat(key)?.map(|stat| ObjectStat {
+                refcount: waiting,
+                ..stat
+            })),
+        }
+    }
+
+    fn keys(&self) -> Result<Box<dyn Iterator<Item = Result<FileKey, Error>> + Send + '_>, Error> {
+        let waiting: BTreeSet<FileKey> = {
+            let queue = self.shared.lock();
+            queue.pending.iter().chain(queue.uploading.iter()).cloned().collect()
+        };
+        let uploaded = self.shared.remote.keys()?;
+        let skip = waiting.clone();
+        Ok(Box::new(waiting.into_iter().map(Ok)
+                    .chain(uploaded.filter(move |key| match *key {
+                        Ok(ref key) => !skip.contains(key),
+                        Err(_) => true,
+                    }))))
+    }
+}
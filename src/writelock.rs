@@ -0,0 +1,215 @@
+// The store's writer lock, `.lock` in the storage path, which lets one
+// process (through `FileStore::open_exclusive()`) shut every other process
+// out of changing the store while they go on reading it.
+//
+// Every change to refcounts or content takes a shared lock on the file for
+// its duration, failing at once with `ErrorKind::Locked` if another process
+// holds the exclusive lock, and taking the exclusive lock fails at once if
+// any other process is part way through a change.  Record locks belong to
+// the process as a whole, so this process's holders are counted here, the
+// shared lock being taken by the first and dropped by the last, and changes
+// made within the process holding the exclusive lock need no lock of their
+// own.
+
+use std::collections::HashMap;
+use std::fs::{File,OpenOptions};
+use std::io;
+use std::path::{Path,PathBuf};
+use std::sync::{Mutex,MutexGuard,OnceLock};
+
+use super::{Error,handles};
+use super::error::ErrorKind;
+
+const LOCK_FILE: &str = ".lock";
+
+struct State {
+    file: File,
+    // Changes in progress in this process, holding the shared lock
+    writing: usize,
+    exclusive: bool,
+}
+
+type Registry = Mutex<HashMap<PathBuf, State>>;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+fn registry() -> MutexGuard<'static, HashMap<PathBuf, State>> {
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+        .lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// The store's state, opening its lock file on first use
+fn state<'a>(registry: &'a mut HashMap<PathBuf, State>, storage_path: &Path)
+             -> Result<&'a mut State, Error>
+{
+    let canonical = handles::canonical(storage_path);
+    if !registry.contains_key(&canonical) {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(canonical.join(LOCK_FILE))
+            .map_err(|e| { (e, "Unable to open writer lock file") } )?;
+        registry.insert(canonical.clone(), State { file, writing: 0, exclusive: false });
+    }
+    Ok(registry.get_mut(&canonical).expect("just inserted"))
+}
+
+fn locked() -> Error {
+    From::from((ErrorKind::Locked, "Store is locked by another writer"))
+}
+
+/// A change in progress, holding off the exclusive lock until dropped
+pub(crate) struct Writing {
+    storage_path: PathBuf,
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        let mut registry = registry();
+        let state = match registry.get_mut(&handles::canonical(&self.storage_path)) {
+            Some(state) => state,
+            None => return,
+        };
+        state.writing -= 1;
+        if state.writing == 0 && !state.exclusive {
+            if let Err(e) = set_lock(&state.file, Lock::None) {
+                log::warn!("Unable to release writer lock: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Begin a change to the store, failing with `ErrorKind::Locked` if
+/// another process holds the exclusive lock
+pub(crate) fn begin(storage_path: &Path) -> Result<Writing, Error> {
+    let mut registry = registry();
+    let state = state(&mut registry, storage_path)?;
+    if state.writing == 0 && !state.exclusive {
+        match set_lock(&state.file, Lock::Shared) {
+            Ok(true) => {},
+            Ok(false) => return Err(locked()),
+            Err(e) => return Err(From::from((e, "Unable to take writer lock"))),
+        }
+    }
+    state.writing += 1;
+    Ok(Writing { storage_path: storage_path.to_path_buf() })
+}
+
+/// The exclusive writer lock on a store, held until dropped
+#[derive(Debug)]
+pub(crate) struct Exclusive {
+    storage_path: PathBuf,
+}
+
+impl Drop for Exclusive {
+    fn drop(&mut self) {
+        let mut registry = registry();
+        let state = match registry.get_mut(&handles::canonical(&self.storage_path)) {
+            Some(state) => state,
+            None => return,
+        };
+        state.exclusive = false;
+        // Back to what this process's changes in progress need
+        let lock = if state.writing > 0 { Lock::Shared } else { Lock::None };
+        if let Err(e) = set_lock(&state.file, lock) {
+            log::warn!("Unable to release exclusive writer lock: {:?}", e);
+        }
+    }
+}
+
+/// Take the exclusive writer lock, failing with `ErrorKind::Locked` if
+/// another process holds it or is changing the store
+pub(crate) fn exclusive(storage_path: &Path) -> Result<Exclusive, Error> {
+    let mut registry = registry();
+    let state = state(&mut registry, storage_path)?;
+    if state.exclusive {
+        return Err(From::from((ErrorKind::Locked, "Store is already opened exclusively")));
+    }
+    match set_lock(&state.file, Lock::Exclusive) {
+        Ok(true) => {},
+        Ok(false) => return Err(locked()),
+        Err(e) => return Err(From::from((e, "Unable to take exclusive writer lock"))),
+    }
+    state.exclusive = true;
+    Ok(Exclusive { storage_path: storage_path.to_path_buf() })
+}
+
+/// As `exclusive()`, but returns `None` if this process already holds the
+/// exclusive lock, for work that may run under `FileStore::open_exclusive()`
+pub(crate) fn exclusive_or_held(storage_path: &Path) -> Result<Option<Exclusive>, Error> {
+    {
+        let mut registry = registry();
+        if state(&mut registry, storage_path)?.exclusive {
+            return Ok(None);
+        }
+    }
+    exclusive(storage_path).map(Some)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Lock {
+    None,
+    Shared,
+    Exclusive,
+}
+
+// Set this process's lock on the file without waiting, returning false if
+// another process's lock is in the way
+#[cfg(unix)]
+fn set_lock(file: &File, lock: Lock) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut range: libc::flock = unsafe { std::mem::zeroed() };
+    range.l_type = match lock {
+        Lock::None => libc::F_UNLCK,
+        Lock::Shared => libc::F_RDLCK,
+        Lock::Exclusive => libc::F_WRLCK,
+    } as _;
+    range.l_whence = libc::SEEK_SET as _;
+    range.l_start = 0;
+    range.l_len = 1;
+    loop {
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &range) } == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EAGAIN) | Some(libc::EACCES) => return Ok(false),
+            _ => return Err(e),
+        }
+    }
+}
+
+// Windows locks don't convert between shared and exclusive, so the old one
+// is released first; a change of this process's can then slip in between
+#[cfg(windows)]
+fn set_lock(file: &File, lock: Lock) -> io::Result<bool> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::ERROR_LOCK_VIOLATION;
+    use windows_sys::Win32::Storage::FileSystem::{
+        LockFileEx,UnlockFileEx,LOCKFILE_EXCLUSIVE_LOCK,LOCKFILE_FAIL_IMMEDIATELY};
+    use windows_sys::Win32::System::IO::OVERLAPPED;
+
+    let handle = file.as_raw_handle() as _;
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    // Fails harmlessly if nothing was held
+    unsafe { UnlockFileEx(handle, 0, 1, 0, &mut overlapped) };
+    let flags = match lock {
+        Lock::None => return Ok(true),
+        Lock::Shared => LOCKFILE_FAIL_IMMEDIATELY,
+        Lock::Exclusive => LOCKFILE_FAIL_IMMEDIATELY | LOCKFILE_EXCLUSIVE_LOCK,
+    };
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    if unsafe { LockFileEx(handle, flags, 0, 1, 0, &mut overlapped) } != 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(code) if code == ERROR_LOCK_VIOLATION as i32 => Ok(false),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn set_lock(_file: &File, _lock: Lock) -> io::Result<bool> {
+    Ok(true)
+}
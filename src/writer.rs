@@ -0,0 +1,74 @@
+// Copyright © 2014 - 2015 by Optimal Computing Limited (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Storing content that arrives a piece at a time.
+//!
+//! A `StoreWriter` (from `FileStore::writer()` or `store_writer()`) accepts
+//! writes into a temporary file inside the store, hashing as it goes.
+//! `finalize()` moves the content to its content-addressed location, or adds
+//! a reference if it is already stored, and returns its key.  Dropping the
+//! writer, or calling `abort()`, discards what was written.
+
+use std::io::{self,Write};
+
+use super::{Error,FileKey};
+use super::spool::Spool;
+use super::store::FileStore;
+
+/// A handle that content is written into before being committed to the store
+pub struct StoreWriter {
+    store: FileStore,
+    spool: Spool,
+    bytes: u64,
+}
+
+impl StoreWriter {
+    pub(crate) fn new(store: &FileStore) -> Result<StoreWriter, Error> {
+        store.check_writable()?;
+        super::space::ensure_room(store.path(), store.config(), 0)?;
+        Ok(StoreWriter {
+            spool: Spool::new(store.path(), store.config().direct_io)?,
+            store: store.clone(),
+            bytes: 0,
+        })
+    }
+
+    /// The number of bytes written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Store everything written, returning its key
+    pub fn finalize(self) -> Result<FileKey, Error> {
+        let op = operation!("filestore.store_writer", new_object);
+        op.record("bytes", self.bytes);
+        let StoreWriter { store, spool, .. } = self;
+        let result = super::commit_spool(store.path(), store.config(), spool);
+        let (key, new_object) = op.finish_stored(result)?;
+        store.stored(&key, new_object)?;
+        Ok(key)
+    }
+
+    /// Discard everything written
+    pub fn abort(self) {
+        // The spool file is removed as it is dropped
+    }
+}
+
+impl Write for StoreWriter {
+    // Writes that would take the content over `Config::max_object_size`
+    // fail, and leave it as it was
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.store.config().check_object_size(self.bytes + buf.len() as u64).is_err() {
+            return Err(io::Error::new(io::ErrorKind::FileTooLarge,
+                                      "content is larger than the store accepts"));
+        }
+        let count = self.spool.write(buf)?;
+        self.bytes += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.spool.flush()
+    }
+}